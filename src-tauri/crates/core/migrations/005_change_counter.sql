@@ -0,0 +1,33 @@
+-- Every insert or update of a folder or file takes the next number of a counter
+-- shared by both tables, so the other process can reload just the rows that changed.
+ALTER TABLE folders ADD COLUMN changed INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE files ADD COLUMN changed INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS idx_folders_changed ON folders(changed);
+CREATE INDEX IF NOT EXISTS idx_files_changed ON files(changed);
+
+CREATE TABLE IF NOT EXISTS change_counter (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    value INTEGER NOT NULL
+);
+INSERT OR IGNORE INTO change_counter (id, value) VALUES (0, 0);
+
+CREATE TRIGGER IF NOT EXISTS folders_inserted AFTER INSERT ON folders BEGIN
+    UPDATE change_counter SET value = value + 1;
+    UPDATE folders SET changed = (SELECT value FROM change_counter) WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS folders_updated AFTER UPDATE OF parent_id, data ON folders BEGIN
+    UPDATE change_counter SET value = value + 1;
+    UPDATE folders SET changed = (SELECT value FROM change_counter) WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS files_inserted AFTER INSERT ON files BEGIN
+    UPDATE change_counter SET value = value + 1;
+    UPDATE files SET changed = (SELECT value FROM change_counter) WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS files_updated AFTER UPDATE OF folder_id, message_id, data ON files BEGIN
+    UPDATE change_counter SET value = value + 1;
+    UPDATE files SET changed = (SELECT value FROM change_counter) WHERE id = NEW.id;
+END;
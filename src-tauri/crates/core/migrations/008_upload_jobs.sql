@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS upload_jobs (
+    id TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL
+);
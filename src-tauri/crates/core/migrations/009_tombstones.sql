@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS tombstones (
+    id TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL
+);
@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::db::{Database, DbError, FileMetadata, Folder};
+use crate::storage::{RemoteBlob, StorageBackend, StorageError};
+
+/// Caption tag of metadata backups. They live in Saved Messages without a file
+/// referring to them, and are not orphans.
+pub const METADATA_BACKUP_TAG: &str = "#paperfold_metadata_backup";
+const BACKUP_POLICY_SETTING: &str = "backup_policy";
+const LAST_BACKUP_SETTING: &str = "last_backup_at";
+
+#[derive(Debug)]
+pub enum BackupError {
+    Db(DbError),
+    Storage(StorageError),
+    NotFound,
+}
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupError::Db(e) => write!(f, "{}", e),
+            BackupError::Storage(e) => write!(f, "{}", e),
+            BackupError::NotFound => write!(f, "No backup found in Saved Messages."),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<DbError> for BackupError {
+    fn from(e: DbError) -> Self {
+        BackupError::Db(e)
+    }
+}
+
+impl From<StorageError> for BackupError {
+    fn from(e: StorageError) -> Self {
+        BackupError::Storage(e)
+    }
+}
+
+impl From<BackupError> for String {
+    fn from(e: BackupError) -> Self {
+        e.to_string()
+    }
+}
+
+/// A metadata backup in Saved Messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackupInfo {
+    pub message_id: i32,
+    /// When it was taken, in Unix seconds.
+    pub created_at: i64,
+    /// Unknown for backups taken before the counts went into the caption.
+    pub files: Option<usize>,
+    pub folders: Option<usize>,
+    pub size: i64,
+}
+
+impl BackupInfo {
+    /// Reads a backup's caption, or `None` if `blob` is not a backup.
+    pub fn parse(blob: &RemoteBlob) -> Option<Self> {
+        if !blob.caption.contains(METADATA_BACKUP_TAG) {
+            return None;
+        }
+        let field = |key: &str| {
+            blob.caption.lines().find_map(|line| {
+                line.strip_prefix(key)
+                    .and_then(|v| v.trim().parse::<i64>().ok())
+            })
+        };
+        Some(Self {
+            message_id: blob.message_id,
+            created_at: field("Timestamp:").unwrap_or(blob.date),
+            files: field("Files:").map(|n| n.max(0) as usize),
+            folders: field("Folders:").map(|n| n.max(0) as usize),
+            size: blob.size,
+        })
+    }
+
+    fn caption(&self) -> String {
+        format!(
+            "{}\nTimestamp: {}\nFiles: {}\nFolders: {}",
+            METADATA_BACKUP_TAG,
+            self.created_at,
+            self.files.unwrap_or(0),
+            self.folders.unwrap_or(0)
+        )
+    }
+}
+
+/// When backups are taken in the background and how many are kept. `None` turns
+/// scheduled backups off, or places no limit.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BackupPolicy {
+    pub interval_hours: Option<u32>,
+    pub keep_backups: Option<u32>,
+    pub keep_days: Option<u32>,
+}
+
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        Self {
+            interval_hours: Some(24),
+            keep_backups: Some(10),
+            keep_days: None,
+        }
+    }
+}
+
+impl BackupPolicy {
+    pub fn load(db: &Database) -> Result<Self, DbError> {
+        Ok(db.setting(BACKUP_POLICY_SETTING)?.unwrap_or_default())
+    }
+
+    pub fn save(&self, db: &Database) -> Result<(), DbError> {
+        db.set_setting(BACKUP_POLICY_SETTING, self)
+    }
+
+    /// Whether a scheduled backup is due at `now`, given when the last one was taken
+    /// from this device.
+    pub fn is_due(&self, last: Option<i64>, now: i64) -> bool {
+        match (self.interval_hours, last) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(hours), Some(last)) => now - last >= i64::from(hours) * 60 * 60,
+        }
+    }
+
+    /// Backups among `backups` the policy no longer keeps. The newest one is always
+    /// kept.
+    pub fn expired(&self, backups: &[BackupInfo], now: i64) -> Vec<i32> {
+        let limit = self
+            .keep_days
+            .map(|days| now - i64::from(days) * 24 * 60 * 60);
+        let keep = self
+            .keep_backups
+            .map_or(usize::MAX, |n| (n as usize).max(1));
+        let mut sorted: Vec<&BackupInfo> = backups.iter().collect();
+        sorted.sort_by_key(|b| std::cmp::Reverse((b.created_at, b.message_id)));
+        sorted
+            .iter()
+            .enumerate()
+            .filter(|(i, b)| *i > 0 && (*i >= keep || limit.is_some_and(|l| b.created_at < l)))
+            .map(|(_, b)| b.message_id)
+            .collect()
+    }
+}
+
+/// When this device last took a backup, in Unix seconds.
+pub fn last_backup_at(db: &Database) -> Result<Option<i64>, DbError> {
+    db.setting(LAST_BACKUP_SETTING)
+}
+
+/// Every backup in `remote`, newest first.
+pub fn backups_in(remote: &[RemoteBlob]) -> Vec<BackupInfo> {
+    let mut backups: Vec<BackupInfo> = remote.iter().filter_map(BackupInfo::parse).collect();
+    backups.sort_by_key(|b| std::cmp::Reverse((b.created_at, b.message_id)));
+    backups
+}
+
+/// Every backup in Saved Messages, newest first.
+pub async fn list_backups(storage: &dyn StorageBackend) -> Result<Vec<BackupInfo>, BackupError> {
+    Ok(backups_in(&storage.list(usize::MAX).await?))
+}
+
+/// Uploads a snapshot of the metadata as a new backup. Sealed when the metadata is.
+pub async fn create_backup(
+    db: &Database,
+    storage: &dyn StorageBackend,
+) -> Result<BackupInfo, BackupError> {
+    let path = std::env::temp_dir().join(format!("paperfold_backup_{}.json", Uuid::new_v4()));
+    db.export_snapshot(&path)?;
+    let mut info = BackupInfo {
+        message_id: 0,
+        created_at: chrono::Utc::now().timestamp(),
+        files: Some(db.get_all_files()?.len()),
+        folders: Some(db.get_all_folders()?.len()),
+        size: std::fs::metadata(&path).map_err(DbError::from)?.len() as i64,
+    };
+
+    let result = storage
+        .put_file(
+            &path,
+            "metadata.json",
+            "application/json",
+            &info.caption(),
+            None,
+        )
+        .await;
+    let _ = std::fs::remove_file(&path);
+    info.message_id = result?;
+
+    db.set_setting(LAST_BACKUP_SETTING, &info.created_at)?;
+    Ok(info)
+}
+
+/// Deletes the backups the policy no longer keeps. Returns how many went.
+pub async fn prune_backups(
+    db: &Database,
+    storage: &dyn StorageBackend,
+) -> Result<usize, BackupError> {
+    let policy = BackupPolicy::load(db)?;
+    let backups = list_backups(storage).await?;
+    let expired = policy.expired(&backups, chrono::Utc::now().timestamp());
+    if !expired.is_empty() {
+        storage.delete(&expired).await?;
+    }
+    Ok(expired.len())
+}
+
+/// The folders and files in the backup held by `message_id`. Sealed backups need
+/// the metadata to be unlocked with the key they were sealed under.
+pub async fn load_backup(
+    db: &Database,
+    storage: &dyn StorageBackend,
+    message_id: i32,
+) -> Result<(Vec<Folder>, Vec<FileMetadata>), BackupError> {
+    let path = std::env::temp_dir().join(format!("paperfold_restore_{}.json", Uuid::new_v4()));
+    let result = match storage.download_to(message_id, &path, None).await {
+        Ok(()) => db.load_snapshot(&path).map_err(BackupError::from),
+        Err(e) => Err(e.into()),
+    };
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+/// An item that differs between a backup and the current metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiffEntry {
+    pub id: String,
+    pub name: String,
+    pub is_folder: bool,
+}
+
+/// What restoring a backup would change.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackupDiff {
+    /// Only in the backup: restoring or merging brings them back.
+    pub added: Vec<DiffEntry>,
+    /// Only in the current metadata: restoring drops them, merging keeps them.
+    pub removed: Vec<DiffEntry>,
+    /// In both, but renamed, moved, trashed or with other contents since. Shown
+    /// under their name in the backup.
+    pub changed: Vec<DiffEntry>,
+}
+
+/// Compares a backup with the current metadata, item by item.
+pub fn diff(
+    backup: (&[Folder], &[FileMetadata]),
+    current: (&[Folder], &[FileMetadata]),
+) -> BackupDiff {
+    let mut result = BackupDiff::default();
+
+    let folders: HashMap<&str, &Folder> = current.0.iter().map(|f| (f.id.as_str(), f)).collect();
+    for folder in backup.0 {
+        let entry = DiffEntry {
+            id: folder.id.clone(),
+            name: folder.name.clone(),
+            is_folder: true,
+        };
+        match folders.get(folder.id.as_str()) {
+            None => result.added.push(entry),
+            Some(now) => {
+                if now.name != folder.name
+                    || now.parent_id != folder.parent_id
+                    || now.trashed != folder.trashed
+                {
+                    result.changed.push(entry);
+                }
+            }
+        }
+    }
+    let kept: HashMap<&str, &Folder> = backup.0.iter().map(|f| (f.id.as_str(), f)).collect();
+    result.removed.extend(
+        current
+            .0
+            .iter()
+            .filter(|f| !kept.contains_key(f.id.as_str()))
+            .map(|f| DiffEntry {
+                id: f.id.clone(),
+                name: f.name.clone(),
+                is_folder: true,
+            }),
+    );
+
+    let files: HashMap<&str, &FileMetadata> =
+        current.1.iter().map(|f| (f.id.as_str(), f)).collect();
+    for file in backup.1 {
+        let entry = DiffEntry {
+            id: file.id.clone(),
+            name: file.name.clone(),
+            is_folder: false,
+        };
+        match files.get(file.id.as_str()) {
+            None => result.added.push(entry),
+            Some(now) => {
+                if now.name != file.name
+                    || now.folder_id != file.folder_id
+                    || now.trashed != file.trashed
+                    || now.message_id != file.message_id
+                    || now.segments != file.segments
+                {
+                    result.changed.push(entry);
+                }
+            }
+        }
+    }
+    let kept: HashMap<&str, &FileMetadata> = backup.1.iter().map(|f| (f.id.as_str(), f)).collect();
+    result.removed.extend(
+        current
+            .1
+            .iter()
+            .filter(|f| !kept.contains_key(f.id.as_str()))
+            .map(|f| DiffEntry {
+                id: f.id.clone(),
+                name: f.name.clone(),
+                is_folder: false,
+            }),
+    );
+    result
+}
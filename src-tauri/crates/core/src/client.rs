@@ -1,13 +1,19 @@
+use crate::crypto::{CryptoError, Vault};
 use grammers_client::{Client, Config, InitParams};
 use grammers_session::Session;
 use std::path::Path;
 
+// Header of a session file sealed under the vault's session key.
+const SEALED_SESSION_MAGIC: &[u8] = b"PAPERFOLD-SESSION-1\n";
+const SESSION_AAD: &[u8] = b"telegram.session";
+
 pub mod utils {
     use base64::{engine::general_purpose, Engine as _};
     use grammers_client::types::{Downloadable, Media};
 
     pub async fn extract_thumbnail_base64(
         client: &grammers_client::Client,
+        budget: &crate::retry::Budget,
         message: &grammers_client::types::Message,
     ) -> Option<String> {
         let media = message.media()?;
@@ -34,6 +40,8 @@ pub mod utils {
         let temp_path = std::env::temp_dir().join(temp_name);
         let temp_path_str = temp_path.to_string_lossy().to_string();
 
+        // Best effort, so not retried, but it still counts against the request budget
+        crate::retry::Retry::new(budget).ready().await;
         match client
             .download_media(&Downloadable::PhotoSize(thumb.clone()), &temp_path_str)
             .await
@@ -54,17 +62,59 @@ pub mod utils {
     }
 }
 
-pub async fn connect(session_file: &Path, api_id: i32, api_hash: &str) -> Result<Client, String> {
+/// Reads the session file, or starts a fresh session if there is none. A sealed
+/// file needs the vault; a plain one is sealed in place once a vault is given.
+pub fn load_session(path: &Path, vault: Option<&Vault>) -> Result<Session, String> {
+    if !path.exists() {
+        return Ok(Session::new());
+    }
+    let data = std::fs::read(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+
+    if let Some(sealed) = data.strip_prefix(SEALED_SESSION_MAGIC) {
+        let vault = vault.ok_or(CryptoError::Locked)?;
+        let plain = vault.session_cipher().open(SESSION_AAD, sealed)?;
+        return Session::load(&plain).map_err(|e| e.to_string());
+    }
+
+    let session = Session::load(&data).map_err(|e| e.to_string())?;
+    if vault.is_some() {
+        save_session(path, &session, vault)?;
+    }
+    Ok(session)
+}
+
+/// Writes the session file, sealed when a vault is unlocked.
+pub fn save_session(path: &Path, session: &Session, vault: Option<&Vault>) -> Result<(), String> {
+    let mut data = session.save();
+    if let Some(vault) = vault {
+        let sealed = vault.session_cipher().seal(SESSION_AAD, &data);
+        data = [SEALED_SESSION_MAGIC, &sealed].concat();
+    }
+    // Replace the file in one step so a crash never leaves half a session behind
+    let tmp_path = path.with_extension("session.tmp");
+    std::fs::write(&tmp_path, data)
+        .and_then(|_| std::fs::rename(&tmp_path, path))
+        .map_err(|e| format!("Failed to write to {:?}: {}", path, e))
+}
+
+pub async fn connect(
+    session_file: &Path,
+    api_id: i32,
+    api_hash: &str,
+    vault: Option<&Vault>,
+) -> Result<Client, String> {
     if !session_file.exists() {
         return Err("Session file not found".to_string());
     }
 
-    let session = Session::load_file_or_create(session_file).map_err(|e| e.to_string())?;
+    let session = load_session(session_file, vault)?;
 
     let params = InitParams {
         device_model: "Paperfold Desktop".to_string(),
         app_version: "0.1.0".to_string(),
         system_version: "macOS".to_string(),
+        // Flood waits are handled by `retry`, which shares them with the other process
+        flood_sleep_threshold: 0,
         ..Default::default()
     };
 
@@ -0,0 +1,804 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const VAULT_FILENAME: &str = "vault.json";
+const KEY_FILENAME: &str = "vault.key";
+
+pub const CIPHER: &str = "aes-256-gcm";
+pub const KDF: &str = "pbkdf2-sha256";
+pub const KDF_ITERATIONS: u32 = 600_000;
+
+const TAG_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 8;
+/// Size of one sealed chunk. Equal to the upload part size, so every part that
+/// reaches Telegram holds exactly one chunk.
+pub const SEALED_CHUNK_SIZE: usize = 512 * 1024;
+/// Plaintext bytes per chunk, leaving room for the GCM tag.
+pub const PLAIN_CHUNK_SIZE: usize = SEALED_CHUNK_SIZE - TAG_SIZE;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// Encrypted content was requested but no passphrase has been entered.
+    Locked,
+    /// No vault has been set up yet.
+    NotSetUp,
+    WrongPassphrase,
+    /// The file was sealed under a different vault key than the unlocked one.
+    KeyMismatch,
+    /// Sealed data failed authentication or does not match its parameters.
+    Corrupt(String),
+    Io(String),
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::Locked => write!(
+                f,
+                "Encrypted files are locked. Enter your passphrase first."
+            ),
+            CryptoError::NotSetUp => write!(f, "No passphrase has been set up"),
+            CryptoError::WrongPassphrase => write!(f, "Wrong passphrase"),
+            CryptoError::KeyMismatch => {
+                write!(f, "This file was encrypted with a different passphrase")
+            }
+            CryptoError::Corrupt(e) => write!(f, "Encrypted data is damaged: {}", e),
+            CryptoError::Io(e) => write!(f, "Encryption I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+impl From<std::io::Error> for CryptoError {
+    fn from(e: std::io::Error) -> Self {
+        CryptoError::Io(e.to_string())
+    }
+}
+
+impl From<CryptoError> for String {
+    fn from(e: CryptoError) -> Self {
+        e.to_string()
+    }
+}
+
+/// How a file's contents were sealed. Stored with the file's metadata; everything
+/// except the vault key itself is here.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionInfo {
+    pub cipher: String,
+    /// Id of the vault key the file was sealed under.
+    pub key_id: String,
+    /// Per-file salt (base64) the file key is derived with from the vault key.
+    pub file_salt: String,
+    /// Random leading nonce bytes (base64); the chunk index makes up the rest.
+    pub nonce_prefix: String,
+    /// Plaintext bytes per chunk. Each sealed chunk is this plus the 16-byte tag.
+    pub chunk_size: u32,
+    pub plain_size: u64,
+}
+
+impl EncryptionInfo {
+    /// Number of sealed chunks. An empty file still gets one, so it cannot be
+    /// truncated unnoticed.
+    pub fn chunk_count(&self) -> u64 {
+        chunk_count(self.plain_size, self.chunk_size as usize)
+    }
+
+    /// Size of the sealed blob as uploaded.
+    pub fn sealed_size(&self) -> u64 {
+        self.plain_size + self.chunk_count() * TAG_SIZE as u64
+    }
+
+    /// Where sealed chunk `index` starts in the blob, and how long it is.
+    pub fn sealed_range(&self, index: u64) -> (u64, usize) {
+        let start = index * (self.chunk_size as u64 + TAG_SIZE as u64);
+        (start, chunk_len(self, index) + TAG_SIZE)
+    }
+}
+
+fn chunk_count(plain_size: u64, chunk_size: usize) -> u64 {
+    plain_size.div_ceil(chunk_size as u64).max(1)
+}
+
+/// How the vault key is protected on disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum KeyProtection {
+    /// Wrapped under a key derived from the user's passphrase.
+    Passphrase {
+        kdf: String,
+        iterations: u32,
+        salt: String,
+    },
+    /// Wrapped under a random key kept in `vault.key` next to the vault, so it
+    /// unlocks without asking.
+    KeyFile,
+}
+
+/// What is kept on disk about the vault: the vault key sealed under the passphrase
+/// or key file, never the key itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultParams {
+    /// Random id of the vault key. Everything sealed under it records this id.
+    pub key_id: String,
+    pub protection: KeyProtection,
+    /// The vault key sealed with AES-GCM (base64 nonce and ciphertext).
+    pub wrapped_key: String,
+    /// HMAC of a fixed label under the vault key, to check keys handed over by the app.
+    pub check: String,
+}
+
+/// What the user unlocks the vault with.
+#[derive(Clone)]
+pub enum Credential {
+    Passphrase(String),
+    KeyFile,
+}
+
+/// The unlocked vault key. Every other key (per file, metadata, session) is derived
+/// from it, so changing the passphrase only re-wraps this one key.
+#[derive(Clone)]
+pub struct Vault {
+    params: VaultParams,
+    key: [u8; 32],
+}
+
+impl std::fmt::Debug for Vault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vault")
+            .field("key_id", &self.params.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Vault {
+    /// Sets up a vault with a fresh random key, protected by `credential`.
+    pub fn create(app_dir: &Path, credential: &Credential) -> Result<Self, CryptoError> {
+        let mut key = [0u8; 32];
+        let mut key_id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut key);
+        rand::thread_rng().fill_bytes(&mut key_id);
+        let key_id = BASE64.encode(key_id);
+        let check = BASE64.encode(check_tag(&key));
+
+        let (protection, wrapping_key) = new_protection(app_dir, credential)?;
+        let params = VaultParams {
+            wrapped_key: wrap_key(&wrapping_key, &key_id, &key),
+            key_id,
+            protection,
+            check,
+        };
+        let vault = Vault { params, key };
+        vault.save_params(app_dir)?;
+        Ok(vault)
+    }
+
+    /// Unlocks the vault kept in `app_dir`.
+    pub fn unlock(app_dir: &Path, credential: &Credential) -> Result<Self, CryptoError> {
+        let params = Vault::load_params(app_dir)?.ok_or(CryptoError::NotSetUp)?;
+        let wrapping_key = match (&params.protection, credential) {
+            (
+                KeyProtection::Passphrase {
+                    kdf,
+                    iterations,
+                    salt,
+                },
+                Credential::Passphrase(passphrase),
+            ) => {
+                if kdf != KDF {
+                    return Err(CryptoError::Corrupt(format!("unknown KDF {}", kdf)));
+                }
+                derive_key(passphrase, &decode(salt)?, *iterations)
+            }
+            (KeyProtection::KeyFile, _) => read_key_file(app_dir)?,
+            (KeyProtection::Passphrase { .. }, Credential::KeyFile) => {
+                return Err(CryptoError::Locked)
+            }
+        };
+        let key = unwrap_key(&wrapping_key, &params).map_err(|e| match params.protection {
+            KeyProtection::Passphrase { .. } => CryptoError::WrongPassphrase,
+            KeyProtection::KeyFile => e,
+        })?;
+        Vault::from_key(&params, key)
+    }
+
+    /// Unlocks the vault kept in `app_dir`, creating it with this credential the
+    /// first time.
+    pub fn open_or_create(app_dir: &Path, credential: &Credential) -> Result<Self, CryptoError> {
+        if Vault::load_params(app_dir)?.is_some() {
+            return Vault::unlock(app_dir, credential);
+        }
+        Vault::create(app_dir, credential)
+    }
+
+    /// Re-wraps the vault key under a new passphrase or the key file. Everything
+    /// sealed so far stays readable.
+    pub fn protect(&mut self, app_dir: &Path, credential: &Credential) -> Result<(), CryptoError> {
+        let (protection, wrapping_key) = new_protection(app_dir, credential)?;
+        self.params.wrapped_key = wrap_key(&wrapping_key, &self.params.key_id, &self.key);
+        self.params.protection = protection;
+        self.save_params(app_dir)?;
+        if matches!(credential, Credential::Passphrase(_)) {
+            // The key file would otherwise still unlock the old wrapping
+            let _ = std::fs::remove_file(app_dir.join(KEY_FILENAME));
+        }
+        Ok(())
+    }
+
+    /// Rebuilds an unlocked vault from a key produced by `export_key`, as handed over
+    /// to the WebDAV daemon.
+    pub fn import_key(params: &VaultParams, key: &str) -> Result<Self, CryptoError> {
+        let key: [u8; 32] = decode(key.trim())?
+            .try_into()
+            .map_err(|_| CryptoError::Corrupt("vault key has the wrong length".to_string()))?;
+        Vault::from_key(params, key)
+    }
+
+    fn from_key(params: &VaultParams, key: [u8; 32]) -> Result<Self, CryptoError> {
+        let check = decode(&params.check)?;
+        let mut mac = keyed_mac(&key);
+        mac.update(CHECK_LABEL);
+        mac.verify_slice(&check)
+            .map_err(|_| CryptoError::KeyMismatch)?;
+        Ok(Vault {
+            params: params.clone(),
+            key,
+        })
+    }
+
+    pub fn load_params(app_dir: &Path) -> Result<Option<VaultParams>, CryptoError> {
+        let path = app_dir.join(VAULT_FILENAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(&path)?;
+        serde_json::from_reader(BufReader::new(file))
+            .map(Some)
+            .map_err(|e| CryptoError::Corrupt(e.to_string()))
+    }
+
+    fn save_params(&self, app_dir: &Path) -> Result<(), CryptoError> {
+        let path = app_dir.join(VAULT_FILENAME);
+        let tmp_path = path.with_extension("json.tmp");
+        let data =
+            serde_json::to_vec_pretty(&self.params).map_err(|e| CryptoError::Io(e.to_string()))?;
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    pub fn params(&self) -> &VaultParams {
+        &self.params
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.params.key_id
+    }
+
+    /// The raw key, base64-encoded, for handing to the WebDAV daemon.
+    pub fn export_key(&self) -> String {
+        BASE64.encode(self.key)
+    }
+
+    /// Cipher for metadata rows and snapshots.
+    pub fn metadata_cipher(&self) -> RecordCipher {
+        self.record_cipher(METADATA_KEY_LABEL)
+    }
+
+    /// Cipher for the Telegram session file.
+    pub fn session_cipher(&self) -> RecordCipher {
+        self.record_cipher(SESSION_KEY_LABEL)
+    }
+
+    fn record_cipher(&self, label: &[u8]) -> RecordCipher {
+        let mut mac = keyed_mac(&self.key);
+        mac.update(label);
+        RecordCipher {
+            aead: Aes256Gcm::new(&mac.finalize().into_bytes()),
+            key_id: self.params.key_id.clone(),
+        }
+    }
+
+    /// Encrypts `src` into `dest` chunk by chunk.
+    pub fn seal_file(&self, src: &Path, dest: &Path) -> Result<EncryptionInfo, CryptoError> {
+        let mut file_salt = [0u8; 16];
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        rand::thread_rng().fill_bytes(&mut file_salt);
+        rand::thread_rng().fill_bytes(&mut nonce_prefix);
+
+        let info = EncryptionInfo {
+            cipher: CIPHER.to_string(),
+            key_id: self.params.key_id.clone(),
+            file_salt: BASE64.encode(file_salt),
+            nonce_prefix: BASE64.encode(nonce_prefix),
+            chunk_size: PLAIN_CHUNK_SIZE as u32,
+            plain_size: std::fs::metadata(src)?.len(),
+        };
+        let cipher = self.file_cipher(&info)?;
+
+        let mut reader = BufReader::new(File::open(src)?);
+        let mut writer = BufWriter::new(File::create(dest)?);
+        let mut buf = vec![0u8; PLAIN_CHUNK_SIZE];
+        let count = info.chunk_count();
+        for index in 0..count {
+            let n = read_full(&mut reader, &mut buf)?;
+            let expected = chunk_len(&info, index);
+            if n != expected {
+                return Err(CryptoError::Io(
+                    "file changed while it was being encrypted".to_string(),
+                ));
+            }
+            let sealed = cipher.seal(index, count, &buf[..n])?;
+            writer.write_all(&sealed)?;
+        }
+        if read_full(&mut reader, &mut buf[..1])? != 0 {
+            return Err(CryptoError::Io(
+                "file changed while it was being encrypted".to_string(),
+            ));
+        }
+        writer.flush()?;
+        Ok(info)
+    }
+
+    /// Decrypts a whole sealed blob from `src` into `dest`, verifying every chunk.
+    pub fn open_file(
+        &self,
+        info: &EncryptionInfo,
+        src: &Path,
+        dest: &Path,
+    ) -> Result<(), CryptoError> {
+        let cipher = self.file_cipher(info)?;
+        let mut reader = BufReader::new(File::open(src)?);
+        let mut writer = BufWriter::new(File::create(dest)?);
+        let mut buf = vec![0u8; info.chunk_size as usize + TAG_SIZE];
+        let count = info.chunk_count();
+        for index in 0..count {
+            let n = read_full(&mut reader, &mut buf)?;
+            if n != chunk_len(info, index) + TAG_SIZE {
+                return Err(CryptoError::Corrupt(format!(
+                    "chunk {} is truncated",
+                    index
+                )));
+            }
+            writer.write_all(&cipher.open(index, count, &buf[..n])?)?;
+        }
+        if read_full(&mut reader, &mut buf[..1])? != 0 {
+            return Err(CryptoError::Corrupt(
+                "unexpected data after the last chunk".to_string(),
+            ));
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Decrypts chunk `index` of a file, for reads that fetch single chunks.
+    pub fn open_chunk(
+        &self,
+        info: &EncryptionInfo,
+        index: u64,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let count = info.chunk_count();
+        if index >= count || sealed.len() != chunk_len(info, index) + TAG_SIZE {
+            return Err(CryptoError::Corrupt(format!(
+                "chunk {} is truncated",
+                index
+            )));
+        }
+        self.file_cipher(info)?.open(index, count, sealed)
+    }
+
+    fn file_cipher(&self, info: &EncryptionInfo) -> Result<FileCipher, CryptoError> {
+        if info.cipher != CIPHER {
+            return Err(CryptoError::Corrupt(format!(
+                "unknown cipher {}",
+                info.cipher
+            )));
+        }
+        if info.key_id != self.params.key_id {
+            return Err(CryptoError::KeyMismatch);
+        }
+        if info.chunk_size == 0 {
+            return Err(CryptoError::Corrupt("chunk size is zero".to_string()));
+        }
+        let file_salt = decode(&info.file_salt)?;
+        let nonce_prefix: [u8; NONCE_PREFIX_SIZE] = decode(&info.nonce_prefix)?
+            .try_into()
+            .map_err(|_| CryptoError::Corrupt("nonce prefix has the wrong length".to_string()))?;
+
+        let mut mac = keyed_mac(&self.key);
+        mac.update(FILE_KEY_LABEL);
+        mac.update(&file_salt);
+        let file_key = mac.finalize().into_bytes();
+        Ok(FileCipher {
+            aead: Aes256Gcm::new(&file_key),
+            nonce_prefix,
+        })
+    }
+}
+
+const CHECK_LABEL: &[u8] = b"paperfold vault check";
+const FILE_KEY_LABEL: &[u8] = b"paperfold file key";
+const METADATA_KEY_LABEL: &[u8] = b"paperfold metadata key";
+const SESSION_KEY_LABEL: &[u8] = b"paperfold session key";
+const NONCE_SIZE: usize = 12;
+
+/// Seals small records (metadata rows, snapshots, the session) under a key derived
+/// from the vault key. Each record gets a random nonce, stored in front of it.
+#[derive(Clone)]
+pub struct RecordCipher {
+    aead: Aes256Gcm,
+    key_id: String,
+}
+
+impl std::fmt::Debug for RecordCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RecordCipher")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RecordCipher {
+    /// Id of the vault key this cipher derives from.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// `aad` binds the record to where it is stored; opening needs the same value.
+    pub fn seal(&self, aad: &[u8], plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let sealed = self
+            .aead
+            .encrypt(&nonce.into(), Payload { msg: plain, aad })
+            .expect("AES-GCM sealing does not fail for in-memory records");
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&sealed);
+        out
+    }
+
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(CryptoError::Corrupt("record is truncated".to_string()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.aead
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| CryptoError::Corrupt("record failed authentication".to_string()))
+    }
+}
+
+// Picks up a new wrapping for the vault key: a fresh salt for passphrases, or the
+// key file (created on first use).
+fn new_protection(
+    app_dir: &Path,
+    credential: &Credential,
+) -> Result<(KeyProtection, [u8; 32]), CryptoError> {
+    match credential {
+        Credential::Passphrase(passphrase) => {
+            let mut salt = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut salt);
+            let protection = KeyProtection::Passphrase {
+                kdf: KDF.to_string(),
+                iterations: KDF_ITERATIONS,
+                salt: BASE64.encode(salt),
+            };
+            Ok((protection, derive_key(passphrase, &salt, KDF_ITERATIONS)))
+        }
+        Credential::KeyFile => {
+            let path = app_dir.join(KEY_FILENAME);
+            if !path.exists() {
+                let mut key = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                write_private(&path, BASE64.encode(key).as_bytes())?;
+            }
+            Ok((KeyProtection::KeyFile, read_key_file(app_dir)?))
+        }
+    }
+}
+
+fn read_key_file(app_dir: &Path) -> Result<[u8; 32], CryptoError> {
+    let path = app_dir.join(KEY_FILENAME);
+    let data = std::fs::read_to_string(&path)?;
+    decode(data.trim())?
+        .try_into()
+        .map_err(|_| CryptoError::Corrupt("key file has the wrong length".to_string()))
+}
+
+fn wrap_key(wrapping_key: &[u8; 32], key_id: &str, key: &[u8; 32]) -> String {
+    let cipher = RecordCipher {
+        aead: Aes256Gcm::new(wrapping_key.into()),
+        key_id: key_id.to_string(),
+    };
+    BASE64.encode(cipher.seal(key_id.as_bytes(), key))
+}
+
+fn unwrap_key(wrapping_key: &[u8; 32], params: &VaultParams) -> Result<[u8; 32], CryptoError> {
+    let cipher = RecordCipher {
+        aead: Aes256Gcm::new(wrapping_key.into()),
+        key_id: params.key_id.clone(),
+    };
+    cipher
+        .open(params.key_id.as_bytes(), &decode(&params.wrapped_key)?)?
+        .try_into()
+        .map_err(|_| CryptoError::Corrupt("vault key has the wrong length".to_string()))
+}
+
+// Creates `path` readable by the owner only, where the platform supports it.
+fn write_private(path: &Path, data: &[u8]) -> Result<(), CryptoError> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)?;
+    Ok(())
+}
+
+struct FileCipher {
+    aead: Aes256Gcm,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+}
+
+impl FileCipher {
+    // The nonce carries the chunk index and the associated data binds it together with
+    // the chunk count, so chunks cannot be reordered, repeated or dropped.
+    fn seal(&self, index: u64, count: u64, plain: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let aad = chunk_aad(index, count);
+        self.aead
+            .encrypt(
+                &self.nonce(index)?,
+                Payload {
+                    msg: plain,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| CryptoError::Corrupt(format!("failed to seal chunk {}", index)))
+    }
+
+    fn open(&self, index: u64, count: u64, sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let aad = chunk_aad(index, count);
+        self.aead
+            .decrypt(
+                &self.nonce(index)?,
+                Payload {
+                    msg: sealed,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| CryptoError::Corrupt(format!("chunk {} failed authentication", index)))
+    }
+
+    fn nonce(&self, index: u64) -> Result<Nonce<aes_gcm::aead::consts::U12>, CryptoError> {
+        let index = u32::try_from(index)
+            .map_err(|_| CryptoError::Corrupt("file has too many chunks".to_string()))?;
+        let mut nonce = [0u8; 12];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..].copy_from_slice(&index.to_be_bytes());
+        Ok(nonce.into())
+    }
+}
+
+fn chunk_aad(index: u64, count: u64) -> [u8; 16] {
+    let mut aad = [0u8; 16];
+    aad[..8].copy_from_slice(&index.to_be_bytes());
+    aad[8..].copy_from_slice(&count.to_be_bytes());
+    aad
+}
+
+// Plaintext length of chunk `index`: full chunks, then whatever is left.
+fn chunk_len(info: &EncryptionInfo, index: u64) -> usize {
+    let chunk_size = info.chunk_size as u64;
+    let start = index * chunk_size;
+    info.plain_size.saturating_sub(start).min(chunk_size) as usize
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+fn check_tag(key: &[u8; 32]) -> Vec<u8> {
+    let mut mac = keyed_mac(key);
+    mac.update(CHECK_LABEL);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn keyed_mac(key: &[u8]) -> HmacSha256 {
+    <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any size")
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, CryptoError> {
+    BASE64
+        .decode(value)
+        .map_err(|e| CryptoError::Corrupt(e.to_string()))
+}
+
+// Fills `buf` unless the reader runs out first; returns how much was read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("paperfold_crypto_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Seals a file spanning three chunks, returning its plaintext, how it was
+    // sealed and the sealed blob.
+    fn seal(vault: &Vault, dir: &Path) -> (Vec<u8>, EncryptionInfo, Vec<u8>) {
+        let plain: Vec<u8> = (0..2 * PLAIN_CHUNK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let src = dir.join("plain");
+        let dest = dir.join("sealed");
+        std::fs::write(&src, &plain).unwrap();
+        let info = vault.seal_file(&src, &dest).unwrap();
+        (plain, info, std::fs::read(dest).unwrap())
+    }
+
+    fn open(
+        vault: &Vault,
+        info: &EncryptionInfo,
+        dir: &Path,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let src = dir.join("tampered");
+        let dest = dir.join("opened");
+        std::fs::write(&src, sealed).unwrap();
+        vault.open_file(info, &src, &dest)?;
+        Ok(std::fs::read(dest).unwrap())
+    }
+
+    #[test]
+    fn sealed_files_open_to_the_same_bytes() {
+        let dir = temp_dir();
+        let vault = Vault::create(&dir, &Credential::KeyFile).unwrap();
+        let (plain, info, sealed) = seal(&vault, &dir);
+        assert_eq!(info.chunk_count(), 3);
+        assert_eq!(sealed.len() as u64, info.sealed_size());
+        assert_eq!(open(&vault, &info, &dir, &sealed).unwrap(), plain);
+
+        let (start, len) = info.sealed_range(2);
+        let chunk = &sealed[start as usize..start as usize + len];
+        assert_eq!(
+            vault.open_chunk(&info, 2, chunk).unwrap(),
+            plain[2 * PLAIN_CHUNK_SIZE..]
+        );
+
+        let unlocked = Vault::unlock(&dir, &Credential::KeyFile).unwrap();
+        assert_eq!(open(&unlocked, &info, &dir, &sealed).unwrap(), plain);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn wrong_credentials_are_rejected() {
+        let dir = temp_dir();
+        Vault::create(&dir, &Credential::Passphrase("right".into())).unwrap();
+        assert!(matches!(
+            Vault::unlock(&dir, &Credential::Passphrase("wrong".into())),
+            Err(CryptoError::WrongPassphrase)
+        ));
+        assert!(matches!(
+            Vault::unlock(&dir, &Credential::KeyFile),
+            Err(CryptoError::Locked)
+        ));
+
+        // A key file from another vault does not unwrap this one
+        let keyed = temp_dir();
+        let other = temp_dir();
+        Vault::create(&keyed, &Credential::KeyFile).unwrap();
+        Vault::create(&other, &Credential::KeyFile).unwrap();
+        std::fs::copy(other.join(KEY_FILENAME), keyed.join(KEY_FILENAME)).unwrap();
+        assert!(matches!(
+            Vault::unlock(&keyed, &Credential::KeyFile),
+            Err(CryptoError::Corrupt(_))
+        ));
+
+        // Nor does another vault's key open files sealed under this one
+        let vault = Vault::unlock(&other, &Credential::KeyFile).unwrap();
+        let stranger = Vault::create(&keyed, &Credential::KeyFile).unwrap();
+        let (_, info, sealed) = seal(&vault, &other);
+        assert!(matches!(
+            open(&stranger, &info, &other, &sealed),
+            Err(CryptoError::KeyMismatch)
+        ));
+        for dir in [dir, keyed, other] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn swapped_or_dropped_chunks_fail() {
+        let dir = temp_dir();
+        let vault = Vault::create(&dir, &Credential::KeyFile).unwrap();
+        let (_, info, sealed) = seal(&vault, &dir);
+        let (first, len) = info.sealed_range(0);
+        let (second, _) = info.sealed_range(1);
+        let (last, _) = info.sealed_range(2);
+        let (first, second, last) = (first as usize, second as usize, last as usize);
+
+        let mut swapped = sealed[second..second + len].to_vec();
+        swapped.extend_from_slice(&sealed[first..first + len]);
+        swapped.extend_from_slice(&sealed[last..]);
+        assert!(matches!(
+            open(&vault, &info, &dir, &swapped),
+            Err(CryptoError::Corrupt(_))
+        ));
+        assert!(vault.open_chunk(&info, 0, &swapped[..len]).is_err());
+
+        let mut dropped = sealed[..second].to_vec();
+        dropped.extend_from_slice(&sealed[last..]);
+        assert!(matches!(
+            open(&vault, &info, &dir, &dropped),
+            Err(CryptoError::Corrupt(_))
+        ));
+        assert!(matches!(
+            open(&vault, &info, &dir, &sealed[..last]),
+            Err(CryptoError::Corrupt(_))
+        ));
+
+        // Dropping the tail and claiming fewer chunks fails too, as each chunk is bound to the count
+        let shortened = EncryptionInfo {
+            plain_size: 2 * PLAIN_CHUNK_SIZE as u64,
+            ..info.clone()
+        };
+        assert!(matches!(
+            open(&vault, &shortened, &dir, &sealed[..last]),
+            Err(CryptoError::Corrupt(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn protecting_again_keeps_sealed_files_readable() {
+        let dir = temp_dir();
+        let mut vault = Vault::create(&dir, &Credential::KeyFile).unwrap();
+        let (plain, info, sealed) = seal(&vault, &dir);
+        let key_id = vault.key_id().to_string();
+
+        vault
+            .protect(&dir, &Credential::Passphrase("new".into()))
+            .unwrap();
+        assert!(!dir.join(KEY_FILENAME).exists());
+        let unlocked = Vault::unlock(&dir, &Credential::Passphrase("new".into())).unwrap();
+        assert_eq!(unlocked.key_id(), key_id);
+        assert_eq!(info.key_id, key_id);
+        assert_eq!(open(&unlocked, &info, &dir, &sealed).unwrap(), plain);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,10 +1,47 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use std::sync::RwLock;
+use std::io::{BufWriter, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use uuid::Uuid;
 
+use crate::crypto::{EncryptionInfo, RecordCipher};
+use crate::storage::Segment;
+use crate::sync::{Clock, Delta, Stamp, SyncConflict, SyncStamp, Tombstone};
+use crate::targets::MessageRef;
+use crate::uploads::UploadJob;
+
+const DB_FILENAME: &str = "metadata.db";
+const LEGACY_FILENAME: &str = "metadata.json";
+const INITIAL_SCHEMA: &str = include_str!("../migrations/001_initial.sql");
+const SETTINGS_SCHEMA: &str = include_str!("../migrations/004_settings.sql");
+const CHANGE_COUNTER_SCHEMA: &str = include_str!("../migrations/005_change_counter.sql");
+const UPLOAD_JOBS_SCHEMA: &str = include_str!("../migrations/008_upload_jobs.sql");
+const TOMBSTONES_SCHEMA: &str = include_str!("../migrations/009_tombstones.sql");
+// Prefix of row payloads and snapshots sealed under the metadata key.
+const SEALED_PREFIX: &str = "sealed:";
+const SNAPSHOT_AAD: &[u8] = b"snapshot";
+const DELTA_AAD: &[u8] = b"delta";
+const METADATA_KEY_SETTING: &str = "metadata_key_id";
+const VERSION_RETENTION_SETTING: &str = "version_retention";
+const CONFLICT_POLICY_SETTING: &str = "move_conflict_policy";
+const UPLOAD_JOBS_TABLE: &str = "upload_jobs";
+const TOMBSTONES_TABLE: &str = "tombstones";
+const DEVICE_ID_SETTING: &str = "device_id";
+// Latest stamp issued by either process sharing the database, and so the device id
+const CLOCK_SETTING: &str = "sync_clock";
+
+/// Version of the persisted metadata format (SQLite `user_version` and the
+/// `schema_version` field of JSON snapshots). Bump it together with a new step in
+/// `apply_migration`.
+pub const SCHEMA_VERSION: u32 = 9;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Folder {
     pub id: String,
@@ -15,6 +52,9 @@ pub struct Folder {
     pub trashed: bool,
     #[serde(default)]
     pub trashed_at: Option<i64>,
+    // Shared by everything trashed in one action, so it can be restored together.
+    #[serde(default)]
+    pub trash_batch: Option<String>,
     #[serde(default)]
     pub is_starred: bool,
     #[serde(default)]
@@ -39,6 +79,16 @@ pub struct Folder {
     pub view_mode: Option<String>, // 'grid' | 'list'
     #[serde(default)]
     pub last_modified: i64,
+    // Files uploaded into this folder or any folder below it are encrypted.
+    #[serde(default)]
+    pub encrypted: bool,
+    // Copies kept of each file uploaded into this folder or below it, each in a
+    // different storage chat. `None` uses the setting of the parent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replicas: Option<u32>,
+    // Set on every change, for merging with other devices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<SyncStamp>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,666 +99,4314 @@ pub struct FileMetadata {
     pub size: i64,
     pub mime_type: String,
     pub message_id: i32,
+    // Channel the blob is stored in, `None` for Saved Messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<i64>,
     pub created_at: i64,
     #[serde(default)]
     pub trashed: bool,
     #[serde(default)]
     pub trashed_at: Option<i64>,
     #[serde(default)]
+    pub trash_batch: Option<String>,
+    #[serde(default)]
     pub is_starred: bool,
     pub thumbnail: Option<String>,
+    // Set when the stored blob is sealed; `size` is still the plaintext size.
+    #[serde(default)]
+    pub encryption: Option<EncryptionInfo>,
+    // Messages holding the blob, in order, when it is too large for one.
+    // `message_id` is then the first of them. Empty for single-message files.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<Segment>,
+    // Hex SHA-256 of the plaintext, checked after downloads. Unknown for files
+    // stored before it was recorded and for files synced from Saved Messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    // Copies of the current contents in other chats, besides the one above.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<Replica>,
+    // Earlier contents replaced by an overwrite, newest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub versions: Vec<FileVersion>,
+    // Set on every change, for merging with other devices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<SyncStamp>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-struct DataStore {
-    folders: Vec<Folder>,
-    files: Vec<FileMetadata>,
-}
-
-pub struct Database {
-    db_path: PathBuf,
-    store: RwLock<DataStore>,
-}
-
-impl Database {
-    pub fn new(app_dir: &str) -> Self {
-        let db_path = std::path::Path::new(app_dir).join("metadata.json");
-        let store = if db_path.exists() {
-            let file = File::open(&db_path).unwrap();
-            let reader = BufReader::new(file);
-            serde_json::from_reader(reader).unwrap_or_default()
-        } else {
-            DataStore::default()
-        };
+impl FileMetadata {
+    /// The messages holding the stored blob, in order.
+    pub fn blob_segments(&self) -> Vec<Segment> {
+        stored_segments(
+            self.message_id,
+            &self.segments,
+            self.size,
+            self.encryption.as_ref(),
+        )
+    }
 
-        Database {
-            db_path,
-            store: RwLock::new(store),
+    pub fn message_ids(&self) -> Vec<i32> {
+        if self.segments.is_empty() {
+            return vec![self.message_id];
         }
+        self.segments.iter().map(|s| s.message_id).collect()
     }
 
-    fn save(&self) {
-        let file = File::create(&self.db_path).unwrap();
-        let writer = BufWriter::new(file);
-        serde_json::to_writer(writer, &*self.store.read().unwrap()).unwrap();
+    /// The messages holding the stored blob, with the chat they are in.
+    pub fn messages(&self) -> Vec<MessageRef> {
+        message_refs(self.chat_id, self.message_ids())
     }
 
-    // Helper to get a unique name (e.g. "Folder (1)")
-    // This needs to be called inside a lock, or we assume single-threaded access to store.
-    // Since we lock in public methods, we should make this a private method taking &store.
-    fn get_unique_name(
-        &self,
-        store: &DataStore,
-        parent_id: Option<&String>,
-        base_name: &str,
-        is_folder: bool,
-    ) -> String {
-        // Base case: check if it exists
-        let exists = if is_folder {
-            store
-                .folders
-                .iter()
-                .any(|f| f.parent_id.as_ref() == parent_id && f.name == base_name && !f.trashed)
-        } else {
-            store
-                .files
-                .iter()
-                .any(|f| f.folder_id.as_ref() == parent_id && f.name == base_name && !f.trashed)
+    /// Every stored copy of the current contents, the primary one first.
+    pub fn copies(&self) -> Vec<Replica> {
+        let primary = Replica {
+            chat_id: self.chat_id,
+            message_id: self.message_id,
+            segments: self.segments.clone(),
         };
+        std::iter::once(primary)
+            .chain(self.replicas.iter().cloned())
+            .collect()
+    }
 
-        if !exists {
-            return base_name.to_string();
-        }
-
-        // It exists, try (1), (2), etc.
-        let mut i = 1;
-        loop {
-            let candidate = format!("{} ({})", base_name, i);
-            let exists =
-                if is_folder {
-                    store.folders.iter().any(|f| {
-                        f.parent_id.as_ref() == parent_id && f.name == candidate && !f.trashed
-                    })
-                } else {
-                    store.files.iter().any(|f| {
-                        f.folder_id.as_ref() == parent_id && f.name == candidate && !f.trashed
-                    })
-                };
+    /// The messages holding `copy`, one of `copies`, in order.
+    pub fn copy_segments(&self, copy: &Replica) -> Vec<Segment> {
+        stored_segments(
+            copy.message_id,
+            &copy.segments,
+            self.size,
+            self.encryption.as_ref(),
+        )
+    }
 
-            if !exists {
-                return candidate;
-            }
-            i += 1;
+    // Makes the first of `copies` the primary one and the rest replicas.
+    fn set_copies(&mut self, mut copies: Vec<Replica>) {
+        if copies.is_empty() {
+            return;
         }
+        let primary = copies.remove(0);
+        self.chat_id = primary.chat_id;
+        self.message_id = primary.message_id;
+        self.segments = primary.segments;
+        self.replicas = copies;
     }
 
-    pub fn create_folder(&self, name: &str, parent_id: Option<String>) -> String {
-        let mut store = self.store.write().unwrap();
+    /// Messages of every copy of the current contents and of every version kept.
+    pub fn referenced_messages(&self) -> Vec<MessageRef> {
+        let mut messages = self.messages();
+        for r in &self.replicas {
+            messages.extend(r.messages());
+        }
+        for v in &self.versions {
+            messages.extend(v.messages());
+        }
+        messages
+    }
 
-        // Ensure unique name
-        let final_name = self.get_unique_name(&store, parent_id.as_ref(), name, true);
+    // The current contents as a version replaced at `replaced_at`. They were stored
+    // when the previous version was replaced, or with the file if there is none.
+    fn as_version(&self, replaced_at: i64) -> FileVersion {
+        FileVersion {
+            id: Uuid::new_v4().to_string(),
+            size: self.size,
+            mime_type: self.mime_type.clone(),
+            message_id: self.message_id,
+            chat_id: self.chat_id,
+            segments: self.segments.clone(),
+            replicas: self.replicas.clone(),
+            encryption: self.encryption.clone(),
+            sha256: self.sha256.clone(),
+            thumbnail: self.thumbnail.clone(),
+            created_at: self
+                .versions
+                .first()
+                .map_or(self.created_at, |v| v.replaced_at),
+            replaced_at,
+        }
+    }
 
-        let id = Uuid::new_v4().to_string();
-        // timestamp
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+    fn take_contents(&mut self, v: FileVersion) {
+        self.size = v.size;
+        self.mime_type = v.mime_type;
+        self.message_id = v.message_id;
+        self.chat_id = v.chat_id;
+        self.segments = v.segments;
+        self.replicas = v.replicas;
+        self.encryption = v.encryption;
+        self.sha256 = v.sha256;
+        self.thumbnail = v.thumbnail;
+    }
+}
 
-        let folder = Folder {
-            id: id.clone(),
-            parent_id,
-            name: final_name,
-            created_at: now,
-            trashed: false,
-            trashed_at: None,
-            is_starred: false,
-            color: None,
-            icon: None,
-            gradient: None,
-            cover_image: None,
-            emoji: None,
-            pattern: None,
-            show_badges: false,
-            tags: None,
-            description: None,
-            view_mode: None,
-
-            last_modified: now,
-        };
+/// Earlier contents of a file, kept when it was overwritten.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileVersion {
+    pub id: String,
+    pub size: i64,
+    pub mime_type: String,
+    pub message_id: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<Segment>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<Replica>,
+    #[serde(default)]
+    pub encryption: Option<EncryptionInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub thumbnail: Option<String>,
+    /// When these contents were stored.
+    pub created_at: i64,
+    /// When they were overwritten.
+    pub replaced_at: i64,
+}
 
-        store.folders.push(folder);
-        drop(store);
-        self.save();
-        id
+impl FileVersion {
+    /// The messages holding the stored blob, in order.
+    pub fn blob_segments(&self) -> Vec<Segment> {
+        stored_segments(
+            self.message_id,
+            &self.segments,
+            self.size,
+            self.encryption.as_ref(),
+        )
     }
 
-    pub fn list_contents(&self, folder_id: Option<String>) -> (Vec<Folder>, Vec<FileMetadata>) {
-        let store = self.store.read().unwrap();
-        let folders = store
-            .folders
-            .iter()
-            .filter(|f| f.parent_id == folder_id && !f.trashed)
-            .cloned()
-            .collect();
-        let files = store
-            .files
-            .iter()
-            .filter(|f| f.folder_id == folder_id && !f.trashed)
-            .cloned()
-            .collect();
-        (folders, files)
+    pub fn message_ids(&self) -> Vec<i32> {
+        if self.segments.is_empty() {
+            return vec![self.message_id];
+        }
+        self.segments.iter().map(|s| s.message_id).collect()
     }
 
-    pub fn list_trash(&self) -> (Vec<Folder>, Vec<FileMetadata>) {
-        let store = self.store.read().unwrap();
-        let folders = store
-            .folders
-            .iter()
-            .filter(|f| f.trashed)
-            .cloned()
-            .collect();
-        let files = store.files.iter().filter(|f| f.trashed).cloned().collect();
-        (folders, files)
+    /// The messages of every copy of these contents.
+    pub fn messages(&self) -> Vec<MessageRef> {
+        let mut messages = message_refs(self.chat_id, self.message_ids());
+        for r in &self.replicas {
+            messages.extend(r.messages());
+        }
+        messages
     }
+}
 
-    pub fn get_file(&self, id: &str) -> Option<FileMetadata> {
-        let store = self.store.read().unwrap();
-        store.files.iter().find(|f| f.id == id).cloned()
-    }
+/// A copy of a file's stored blob in another chat than the primary one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Replica {
+    /// `None` for Saved Messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<i64>,
+    pub message_id: i32,
+    /// As on `FileMetadata`: empty unless the blob needs several messages.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<Segment>,
+}
 
-    pub fn lookup_folder_name(&self, id: &str) -> Option<String> {
-        let store = self.store.read().unwrap();
-        store
-            .folders
-            .iter()
-            .find(|f| f.id == id)
-            .map(|f| f.name.clone())
+impl Replica {
+    /// The copy held by `segments`, in order, in chat `chat_id`.
+    pub fn new(chat_id: Option<i64>, segments: Vec<Segment>) -> Self {
+        let message_id = segments.first().map_or(-1, |s| s.message_id);
+        let segments = if segments.len() > 1 {
+            segments
+        } else {
+            Vec::new()
+        };
+        Self {
+            chat_id,
+            message_id,
+            segments,
+        }
     }
 
-    pub fn get_folder_by_id(&self, id: &str) -> Option<Folder> {
-        let store = self.store.read().unwrap();
-        store.folders.iter().find(|f| f.id == id).cloned()
+    pub fn message_ids(&self) -> Vec<i32> {
+        if self.segments.is_empty() {
+            return vec![self.message_id];
+        }
+        self.segments.iter().map(|s| s.message_id).collect()
     }
 
-    pub fn add_file(
-        &self,
-        folder_id: Option<String>,
-        name: String,
-        size: i64,
-        mime_type: String,
-        message_id: i32,
-        thumbnail: Option<String>,
-    ) -> FileMetadata {
-        let mut store = self.store.write().unwrap();
-
-        // Ensure unique name
-        let final_name = self.get_unique_name(&store, folder_id.as_ref(), &name, false);
-
-        let id = Uuid::new_v4().to_string();
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+    pub fn messages(&self) -> Vec<MessageRef> {
+        message_refs(self.chat_id, self.message_ids())
+    }
+}
 
-        let file = FileMetadata {
-            id: id.clone(),
-            folder_id,
-            name: final_name,
-            size,
-            mime_type,
+fn message_refs(chat_id: Option<i64>, message_ids: Vec<i32>) -> Vec<MessageRef> {
+    message_ids
+        .into_iter()
+        .map(|message_id| MessageRef {
+            chat_id,
             message_id,
-            created_at: now,
-            trashed: false,
-            trashed_at: None,
-            is_starred: false,
-
-            thumbnail,
-        };
+        })
+        .collect()
+}
 
-        store.files.push(file.clone());
-        drop(store);
-        self.save();
-        file
+fn stored_segments(
+    message_id: i32,
+    segments: &[Segment],
+    size: i64,
+    encryption: Option<&EncryptionInfo>,
+) -> Vec<Segment> {
+    if !segments.is_empty() {
+        return segments.to_vec();
     }
+    let size = match encryption {
+        Some(info) => info.sealed_size(),
+        None => size.max(0) as u64,
+    };
+    vec![Segment { message_id, size }]
+}
 
-    // Soft delete
-    pub fn trash_item(&self, id: &str, is_folder: bool) {
-        let mut store = self.store.write().unwrap();
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+/// How many overwritten versions of a file are kept, and for how long. `None`
+/// places no limit.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct VersionRetention {
+    pub keep_versions: Option<u32>,
+    pub keep_days: Option<u32>,
+}
 
-        if is_folder {
-            if let Some(f) = store.folders.iter_mut().find(|f| f.id == id) {
-                f.trashed = true;
-                f.trashed_at = Some(now);
+impl Default for VersionRetention {
+    fn default() -> Self {
+        Self {
+            keep_versions: Some(10),
+            keep_days: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    NotFound(&'static str),
+    NameConflict(String),
+    CycleDetected,
+    Io(String),
+    Corrupt(String),
+    SchemaVersion {
+        found: u32,
+        supported: u32,
+    },
+    /// The metadata is encrypted and the vault has not been unlocked.
+    Locked,
+}
 
-                // Also trash children recursively?
-                // For simplicity now, let's just trash the folder.
-                // But logically, children should be hidden too.
-                // If I trash a folder, list_contents won't show it.
-                // If I enter the folder by ID (if I had a link), list_contents(folder_id) would show children unless I filter them too?
-                // list_contents only filters items WHERE parent_id == folder_id AND !trashed.
-                // So children are effectively hidden because you can't navigate to the parent.
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::NotFound(kind) => write!(f, "{} not found", kind),
+            DbError::NameConflict(name) => {
+                write!(f, "An item named \"{}\" already exists here", name)
             }
-        } else {
-            if let Some(f) = store.files.iter_mut().find(|f| f.id == id) {
-                f.trashed = true;
-                f.trashed_at = Some(now);
+            DbError::CycleDetected => {
+                write!(f, "A folder cannot be moved into itself or one of its subfolders")
             }
+            DbError::Io(e) => write!(f, "Metadata storage error: {}", e),
+            DbError::Corrupt(e) => write!(f, "Metadata database is corrupt: {}", e),
+            DbError::SchemaVersion { found, supported } => write!(
+                f,
+                "Metadata was written by a newer version of Paperfold (schema {}, supported {}). Please update the app.",
+                found, supported
+            ),
+            DbError::Locked => write!(f, "Metadata is encrypted. Unlock it with your passphrase first."),
         }
-        drop(store);
-        self.save();
     }
+}
 
-    pub fn restore_item(&self, id: &str, is_folder: bool) {
-        let mut store = self.store.write().unwrap();
-        if is_folder {
-            if let Some(f) = store.folders.iter_mut().find(|f| f.id == id) {
-                f.trashed = false;
-                f.trashed_at = None;
-            }
-        } else {
-            if let Some(f) = store.files.iter_mut().find(|f| f.id == id) {
-                f.trashed = false;
-                f.trashed_at = None;
+impl std::error::Error for DbError {}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        use rusqlite::ErrorCode;
+        match &e {
+            rusqlite::Error::SqliteFailure(err, _)
+                if matches!(
+                    err.code,
+                    ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase
+                ) =>
+            {
+                DbError::Corrupt(e.to_string())
             }
+            rusqlite::Error::FromSqlConversionFailure(..) => DbError::Corrupt(e.to_string()),
+            _ => DbError::Io(e.to_string()),
         }
-        drop(store);
-        self.save();
     }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(e: std::io::Error) -> Self {
+        DbError::Io(e.to_string())
+    }
+}
 
-    // Hard delete (Permanent)
-    pub fn delete_file(&self, id: &str) -> bool {
-        let mut store = self.store.write().unwrap();
-        let len_before = store.files.len();
-        store.files.retain(|f| f.id != id);
-        let deleted = store.files.len() < len_before;
-        if deleted {
-            drop(store);
-            self.save();
+impl From<serde_json::Error> for DbError {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            DbError::Io(e.to_string())
+        } else {
+            DbError::Corrupt(e.to_string())
         }
-        deleted
     }
+}
 
-    pub fn delete_folder(&self, id: &str) -> Vec<FileMetadata> {
-        let mut store = self.store.write().unwrap();
+// Lets Tauri commands (which report errors as strings) use `?` directly.
+impl From<DbError> for String {
+    fn from(e: DbError) -> Self {
+        e.to_string()
+    }
+}
 
-        // 1. Find all files in this folder (recursive TODO later, for now flat)
-        let deleted_files: Vec<FileMetadata> = store
-            .files
-            .iter()
-            .filter(|f| f.folder_id.as_deref() == Some(id))
-            .cloned()
-            .collect();
+/// What a move does when the destination folder already holds an item with the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Refuse the move with `DbError::NameConflict`.
+    #[default]
+    Fail,
+    /// Move under the next free name ("name (1)", "name (2)", ...).
+    AutoRename,
+    /// Move the existing item to the trash and take its name.
+    Overwrite,
+}
 
-        // 2. Remove files
-        store.files.retain(|f| f.folder_id.as_deref() != Some(id));
+/// Items below a folder by their path relative to it, as listed by `Database::subtree`.
+#[derive(Debug, Clone, Default)]
+pub struct Subtree {
+    pub folders: Vec<(String, Folder)>,
+    pub files: Vec<(String, FileMetadata)>,
+}
 
-        // 3. Remove folder
-        store.folders.retain(|f| f.id != id);
+/// What a path resolves to.
+#[derive(Debug, Clone)]
+pub enum Entry {
+    Root,
+    Folder(Folder),
+    File(FileMetadata),
+}
 
-        drop(store);
-        self.save();
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct DataStore {
+    // Missing in files written before versioning, which are version 1 records.
+    #[serde(default)]
+    schema_version: u32,
+    folders: Vec<Folder>,
+    files: Vec<FileMetadata>,
+    #[serde(skip)]
+    index: StoreIndex,
+}
 
-        deleted_files
-    }
+// (parent folder, name) of an item in the path index.
+type NameKey = (Option<String>, String);
 
-    pub fn rename_file(&self, id: &str, new_name: &str) -> bool {
-        let mut store = self.store.write().unwrap();
-        if let Some(file) = store.files.iter_mut().find(|f| f.id == id) {
-            file.name = new_name.to_string();
-            drop(store); // release lock before save
-            self.save();
-            true
-        } else {
-            false
+// Lookup tables over the store. Built on load and patched by `persist` with the rows
+// each mutation touched, so they never need a full rebuild on the write path unless
+// rows were removed.
+#[derive(Debug, Default)]
+struct StoreIndex {
+    folder_pos: HashMap<String, usize>,
+    file_pos: HashMap<String, usize>,
+    // Non-trashed items by parent and name. Duplicates from older data are kept so
+    // lookups can pick the first one in store order, as a linear scan would.
+    folder_names: HashMap<NameKey, Vec<String>>,
+    file_names: HashMap<NameKey, Vec<String>>,
+    // Where each id is filed in the name maps, to unfile it when it changes.
+    folder_keys: HashMap<String, NameKey>,
+    file_keys: HashMap<String, NameKey>,
+    // Names in use below each parent, to walk a subtree without a full scan.
+    folder_children: HashMap<Option<String>, HashSet<String>>,
+    file_children: HashMap<Option<String>, HashSet<String>>,
+    // How many files and versions point at each message, and what each file added,
+    // so a message can be released once nothing points at it.
+    message_refs: HashMap<MessageRef, usize>,
+    file_messages: HashMap<String, Vec<MessageRef>>,
+}
+
+impl DataStore {
+    fn reindex(&mut self) {
+        self.index = StoreIndex::default();
+        self.index_positions();
+        for f in &self.folders {
+            if !f.trashed {
+                file_name(
+                    &mut self.index.folder_names,
+                    &mut self.index.folder_keys,
+                    &mut self.index.folder_children,
+                    &f.id,
+                    (f.parent_id.clone(), f.name.clone()),
+                );
+            }
+        }
+        for f in &self.files {
+            if !f.trashed {
+                file_name(
+                    &mut self.index.file_names,
+                    &mut self.index.file_keys,
+                    &mut self.index.file_children,
+                    &f.id,
+                    (f.folder_id.clone(), f.name.clone()),
+                );
+            }
+            count_messages(&mut self.index, f);
         }
     }
 
-    pub fn move_file(&self, id: &str, target_folder_id: Option<String>, new_name: &str) -> bool {
-        let mut store = self.store.write().unwrap();
-        if let Some(file) = store.files.iter_mut().find(|f| f.id == id) {
-            // Basic unique name check could be added here similar to add_file
-            // For now, we assume caller (WebDAV) handles name collisions or we just overwrite
-            file.folder_id = target_folder_id;
-            file.name = new_name.to_string();
-            drop(store);
-            self.save();
-            true
-        } else {
-            false
-        }
+    fn index_positions(&mut self) {
+        self.index.folder_pos = self
+            .folders
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.id.clone(), i))
+            .collect();
+        self.index.file_pos = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.id.clone(), i))
+            .collect();
     }
 
-    pub fn move_folder(&self, id: &str, target_parent_id: Option<String>, new_name: &str) -> bool {
-        let mut store = self.store.write().unwrap();
-        // Prevent moving folder into itself (simple check)
-        if let Some(target) = &target_parent_id {
-            if target == id {
-                return false;
-            }
-            // Deep cycle check omitted for brevity, but should be considered for prod
+    // Brings the index in line with the rows listed in `changes`.
+    fn update_index(&mut self, changes: &Changes) {
+        if !changes.removed_folders.is_empty()
+            || !changes.removed_files.is_empty()
+            || self.folders.len() != self.index.folder_pos.len()
+            || self.files.len() != self.index.file_pos.len()
+        {
+            self.index_positions();
         }
 
-        if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
-            folder.parent_id = target_parent_id;
-            folder.name = new_name.to_string();
-            drop(store);
-            self.save();
-            true
-        } else {
-            false
+        let index = &mut self.index;
+        for id in &changes.removed_folders {
+            unfile_name(
+                &mut index.folder_names,
+                &mut index.folder_keys,
+                &mut index.folder_children,
+                id,
+            );
+        }
+        for id in &changes.removed_files {
+            unfile_name(
+                &mut index.file_names,
+                &mut index.file_keys,
+                &mut index.file_children,
+                id,
+            );
+            uncount_messages(index, id);
+        }
+        for id in &changes.folders {
+            unfile_name(
+                &mut index.folder_names,
+                &mut index.folder_keys,
+                &mut index.folder_children,
+                id,
+            );
+            if let Some(f) = index.folder_pos.get(id).map(|&i| &self.folders[i]) {
+                if !f.trashed {
+                    file_name(
+                        &mut index.folder_names,
+                        &mut index.folder_keys,
+                        &mut index.folder_children,
+                        id,
+                        (f.parent_id.clone(), f.name.clone()),
+                    );
+                }
+            }
+        }
+        for id in &changes.files {
+            unfile_name(
+                &mut index.file_names,
+                &mut index.file_keys,
+                &mut index.file_children,
+                id,
+            );
+            uncount_messages(index, id);
+            if let Some(f) = index.file_pos.get(id).map(|&i| &self.files[i]) {
+                if !f.trashed {
+                    file_name(
+                        &mut index.file_names,
+                        &mut index.file_keys,
+                        &mut index.file_children,
+                        id,
+                        (f.folder_id.clone(), f.name.clone()),
+                    );
+                }
+                count_messages(index, f);
+            }
         }
     }
 
-    pub fn rename_folder(&self, id: &str, new_name: &str) -> bool {
-        let mut store = self.store.write().unwrap();
-        if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
-            folder.name = new_name.to_string();
-            drop(store);
-            self.save();
-            true
-        } else {
-            false
-        }
+    // Of `removed`, the messages no file or version points at any more.
+    fn released(&self, removed: &[MessageRef]) -> Vec<MessageRef> {
+        let mut messages: Vec<MessageRef> = removed
+            .iter()
+            .copied()
+            .filter(|m| m.message_id > 0 && !self.index.message_refs.contains_key(m))
+            .collect();
+        messages.sort_unstable();
+        messages.dedup();
+        messages
     }
 
-    pub fn get_folder_stats(&self, folder_id: &str) -> (i64, i32) {
-        let store = self.store.read().unwrap();
-        self.calculate_stats_recursive(&store, folder_id)
+    fn folder(&self, id: &str) -> Option<&Folder> {
+        self.index.folder_pos.get(id).map(|&i| &self.folders[i])
     }
 
-    fn calculate_stats_recursive(&self, store: &DataStore, folder_id: &str) -> (i64, i32) {
-        let mut total_size: i64 = 0;
-        let mut total_count: i32 = 0;
+    fn file(&self, id: &str) -> Option<&FileMetadata> {
+        self.index.file_pos.get(id).map(|&i| &self.files[i])
+    }
 
-        // Count files in this folder
-        let files_in_folder: Vec<&FileMetadata> = store
-            .files
-            .iter()
-            .filter(|f| f.folder_id.as_deref() == Some(folder_id) && !f.trashed)
-            .collect();
+    // First non-trashed child folder of `parent` called `name`.
+    fn child_folder(&self, parent: Option<&String>, name: &str) -> Option<&Folder> {
+        let ids = self
+            .index
+            .folder_names
+            .get(&(parent.cloned(), name.to_string()))?;
+        ids.iter()
+            .filter_map(|id| self.index.folder_pos.get(id))
+            .min()
+            .map(|&i| &self.folders[i])
+    }
 
-        for file in files_in_folder {
-            total_size += file.size;
-            total_count += 1;
-        }
+    // First non-trashed file in `parent` called `name`.
+    fn child_file(&self, parent: Option<&String>, name: &str) -> Option<&FileMetadata> {
+        let ids = self
+            .index
+            .file_names
+            .get(&(parent.cloned(), name.to_string()))?;
+        ids.iter()
+            .filter_map(|id| self.index.file_pos.get(id))
+            .min()
+            .map(|&i| &self.files[i])
+    }
+}
 
-        // Recursively count subfolders
-        let subfolders: Vec<&Folder> = store
-            .folders
-            .iter()
-            .filter(|f| f.parent_id.as_deref() == Some(folder_id) && !f.trashed)
-            .collect();
-
-        for folder in subfolders {
-            let (child_size, child_count) = self.calculate_stats_recursive(store, &folder.id);
-            total_size += child_size;
-            total_count += child_count; // Should we count the folder itself as 1 item?
-                                        // Usually "5 items" includes subfolders as items.
-            total_count += 1;
-        }
+fn file_name(
+    names: &mut HashMap<NameKey, Vec<String>>,
+    keys: &mut HashMap<String, NameKey>,
+    children: &mut HashMap<Option<String>, HashSet<String>>,
+    id: &str,
+    key: NameKey,
+) {
+    children
+        .entry(key.0.clone())
+        .or_default()
+        .insert(key.1.clone());
+    names.entry(key.clone()).or_default().push(id.to_string());
+    keys.insert(id.to_string(), key);
+}
 
-        (total_size, total_count)
+fn count_messages(index: &mut StoreIndex, file: &FileMetadata) {
+    let messages = file.referenced_messages();
+    for m in &messages {
+        *index.message_refs.entry(*m).or_default() += 1;
     }
+    index.file_messages.insert(file.id.clone(), messages);
+}
 
-    pub fn update_folder_metadata(
-        &self,
-        id: &str,
-        color: Option<String>,
-        icon: Option<String>,
-        gradient: Option<String>,
-        cover_image: Option<String>,
-        emoji: Option<String>,
-        pattern: Option<String>,
-        show_badges: Option<bool>,
-        tags: Option<Vec<String>>,
-        description: Option<String>,
-        // view_mode update logic or separate? Let's add it.
-        view_mode: Option<String>,
-    ) -> bool {
-        let mut store = self.store.write().unwrap();
-        if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
-            if let Some(c) = color {
-                folder.color = if c.is_empty() { None } else { Some(c) };
+fn uncount_messages(index: &mut StoreIndex, file_id: &str) {
+    for m in index.file_messages.remove(file_id).unwrap_or_default() {
+        if let Some(count) = index.message_refs.get_mut(&m) {
+            *count -= 1;
+            if *count == 0 {
+                index.message_refs.remove(&m);
             }
-            if let Some(i) = icon {
-                folder.icon = if i.is_empty() { None } else { Some(i) };
-            }
-            if let Some(g) = gradient {
-                folder.gradient = if g.is_empty() { None } else { Some(g) };
-            }
-            if let Some(c) = cover_image {
-                folder.cover_image = if c.is_empty() { None } else { Some(c) };
-            }
-            if let Some(e) = emoji {
-                folder.emoji = if e.is_empty() { None } else { Some(e) };
-            }
-            if let Some(p) = pattern {
-                folder.pattern = if p.is_empty() { None } else { Some(p) };
-            }
-            if let Some(s) = show_badges {
-                folder.show_badges = s;
-            }
-            if let Some(t) = tags {
-                folder.tags = Some(t);
-            }
-            if let Some(d) = description {
-                folder.description = Some(d);
-            }
-            if let Some(v) = view_mode {
-                folder.view_mode = Some(v);
+        }
+    }
+}
+
+fn unfile_name(
+    names: &mut HashMap<NameKey, Vec<String>>,
+    keys: &mut HashMap<String, NameKey>,
+    children: &mut HashMap<Option<String>, HashSet<String>>,
+    id: &str,
+) {
+    if let Some(key) = keys.remove(id) {
+        if let Some(ids) = names.get_mut(&key) {
+            ids.retain(|other| other != id);
+            if ids.is_empty() {
+                names.remove(&key);
+                if let Some(in_use) = children.get_mut(&key.0) {
+                    in_use.remove(&key.1);
+                    if in_use.is_empty() {
+                        children.remove(&key.0);
+                    }
+                }
             }
+        }
+    }
+}
 
-            folder.last_modified = chrono::Utc::now().timestamp();
+// Rows touched by a mutation. Only these are written back, inside a single transaction.
+#[derive(Default)]
+struct Changes {
+    folders: Vec<String>,
+    files: Vec<String>,
+    removed_folders: Vec<String>,
+    removed_files: Vec<String>,
+    removed_messages: Vec<MessageRef>,
+    // Written by `persist` for the removed folders and files.
+    tombstones: Vec<Tombstone>,
+}
 
-            drop(store);
-            self.save();
-            true
-        } else {
-            false
+// Ids of `root` and every folder below it.
+fn subtree_folder_ids(store: &DataStore, root: &str) -> HashSet<String> {
+    let mut ids = HashSet::new();
+    let mut queue = vec![root.to_string()];
+    while let Some(id) = queue.pop() {
+        if !ids.insert(id.clone()) {
+            continue;
         }
+        queue.extend(
+            store
+                .folders
+                .iter()
+                .filter(|f| f.parent_id.as_deref() == Some(id.as_str()))
+                .map(|f| f.id.clone()),
+        );
     }
+    ids
+}
 
-    pub fn cleanup_trash(&self, days: i64) -> Vec<FileMetadata> {
-        let mut store = self.store.write().unwrap();
-        let now = std::time::SystemTime::now()
+/// A fresh, untrashed folder record under a new id.
+pub fn new_folder(parent_id: Option<String>, name: String) -> Folder {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    Folder {
+        id: Uuid::new_v4().to_string(),
+        parent_id,
+        name,
+        created_at: now,
+        trashed: false,
+        trashed_at: None,
+        trash_batch: None,
+        is_starred: false,
+        color: None,
+        icon: None,
+        gradient: None,
+        cover_image: None,
+        emoji: None,
+        pattern: None,
+        show_badges: false,
+        tags: None,
+        description: None,
+        view_mode: None,
+
+        last_modified: now,
+        encrypted: false,
+        replicas: None,
+        stamp: None,
+    }
+}
+
+/// A fresh, untrashed file record under a new id. The name is made unique on insert.
+pub fn new_file(
+    folder_id: Option<String>,
+    name: String,
+    size: i64,
+    mime_type: String,
+    segments: Vec<Segment>,
+) -> FileMetadata {
+    let message_id = segments.first().map_or(-1, |s| s.message_id);
+    let segments = if segments.len() > 1 {
+        segments
+    } else {
+        Vec::new()
+    };
+    FileMetadata {
+        id: Uuid::new_v4().to_string(),
+        folder_id,
+        name,
+        size,
+        mime_type,
+        message_id,
+        chat_id: None,
+        created_at: std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
-            .as_secs() as i64;
+            .as_secs() as i64,
+        trashed: false,
+        trashed_at: None,
+        trash_batch: None,
+        is_starred: false,
+        thumbnail: None,
+        encryption: None,
+        segments,
+        sha256: None,
+        replicas: Vec::new(),
+        versions: Vec::new(),
+        stamp: None,
+    }
+}
 
-        let limit = now - (days * 24 * 60 * 60);
+// True if `folder` is `ancestor` or lies anywhere below it. Stops on a parent chain
+// that loops, so a store that is already inconsistent cannot hang the check.
+fn is_within(store: &DataStore, folder: &str, ancestor: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut current = Some(folder.to_string());
+    while let Some(id) = current {
+        if id == ancestor {
+            return true;
+        }
+        if !seen.insert(id.clone()) {
+            return false;
+        }
+        current = store.folder(&id).and_then(|f| f.parent_id.clone());
+    }
+    false
+}
 
-        // Collect files to be deleted (for telegram deletion)
-        let mut deleted_files = Vec::new();
+// Non-trashed folders and files named `name` directly inside `parent`, except `exclude`.
+// Folders and files share one namespace, as paths do not tell them apart.
+fn sibling_conflicts(
+    store: &DataStore,
+    parent: Option<&String>,
+    name: &str,
+    exclude: &str,
+) -> (Vec<String>, Vec<String>) {
+    let key = (parent.cloned(), name.to_string());
+    let named = |names: &HashMap<NameKey, Vec<String>>| -> Vec<String> {
+        names
+            .get(&key)
+            .into_iter()
+            .flatten()
+            .filter(|id| *id != exclude)
+            .cloned()
+            .collect()
+    };
+    (
+        named(&store.index.folder_names),
+        named(&store.index.file_names),
+    )
+}
 
-        // 1. Identify valid trash items to remove
-        // Note: If we remove a folder, we need to return its files too?
-        // This is getting complex for auto-cleanup.
-        // Let's just implement individual item cleanup for now.
+// Flags the subtrees of `folder_ids` and the files in `file_ids` as trashed under one
+// batch. Items that were already in the trash keep their own batch.
+fn mark_trashed(
+    store: &mut DataStore,
+    folder_ids: &[String],
+    file_ids: &[String],
+    changes: &mut Changes,
+) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let batch = Uuid::new_v4().to_string();
 
-        // Files older than limit
-        let files_to_remove: Vec<String> = store
-            .files
-            .iter()
-            .filter(|f| f.trashed && f.trashed_at.unwrap_or(0) < limit)
-            .map(|f| f.id.clone())
-            .collect();
+    let mut subtree = HashSet::new();
+    for id in folder_ids {
+        subtree.extend(subtree_folder_ids(store, id));
+    }
+    for f in store
+        .folders
+        .iter_mut()
+        .filter(|f| !f.trashed && subtree.contains(&f.id))
+    {
+        f.trashed = true;
+        f.trashed_at = Some(now);
+        f.trash_batch = Some(batch.clone());
+        changes.folders.push(f.id.clone());
+    }
+    for f in store.files.iter_mut().filter(|f| {
+        !f.trashed
+            && (file_ids.contains(&f.id)
+                || f.folder_id
+                    .as_ref()
+                    .is_some_and(|pid| subtree.contains(pid)))
+    }) {
+        f.trashed = true;
+        f.trashed_at = Some(now);
+        f.trash_batch = Some(batch.clone());
+        changes.files.push(f.id.clone());
+    }
+}
 
-        for id in &files_to_remove {
-            if let Some(f) = store.files.iter().find(|f| f.id == *id) {
-                deleted_files.push(f.clone());
-            }
+// Removes the given folders, every file inside them and the given files.
+fn remove_items(
+    store: &mut DataStore,
+    folder_ids: &HashSet<String>,
+    file_ids: &HashSet<String>,
+) -> Changes {
+    let mut changes = Changes::default();
+    store.files.retain(|f| {
+        let remove = file_ids.contains(&f.id)
+            || f.folder_id
+                .as_ref()
+                .is_some_and(|pid| folder_ids.contains(pid));
+        if remove {
+            changes.removed_files.push(f.id.clone());
+            changes.removed_messages.extend(f.referenced_messages());
+        }
+        !remove
+    });
+    store.folders.retain(|f| {
+        let remove = folder_ids.contains(&f.id);
+        if remove {
+            changes.removed_folders.push(f.id.clone());
         }
-        store.files.retain(|f| !files_to_remove.contains(&f.id));
+        !remove
+    });
+    changes
+}
 
-        // Folders older than limit
-        let folders_to_remove: Vec<String> = store
-            .folders
-            .iter()
-            .filter(|f| f.trashed && f.trashed_at.unwrap_or(0) < limit)
-            .map(|f| f.id.clone())
-            .collect();
+// The app and the WebDAV daemon each open the same database. Every mutation runs in
+// an IMMEDIATE transaction, which holds SQLite's single write lock, and first reloads
+// the in-memory store if the other process committed since we last looked. Readers
+// do the same check, so neither process works from, or writes back, a stale view.
+pub struct Database {
+    db_path: PathBuf,
+    conn: Mutex<Connection>,
+    store: RwLock<DataStore>,
+    // `PRAGMA data_version` when the store was last loaded. It only moves when another
+    // connection commits. Updated with the connection locked.
+    data_version: AtomicI64,
+    // The change counter the store is up to date with; rows changed after it are
+    // what the other process wrote since.
+    seen_change: AtomicI64,
+    // Key for sealed rows, once unlocked. Rows are plain JSON until the first unlock.
+    cipher: RwLock<Option<RecordCipher>>,
+    // Set while the rows are sealed and `cipher` cannot open them. The store is empty
+    // then and every read or write fails with `DbError::Locked`.
+    locked: AtomicBool,
+    // Stamps changes for sync, see `crate::sync`.
+    clock: Mutex<Clock>,
+    device: String,
+}
 
-        // If folder is removed, its children must be removed too
-        // But children might not be marked trashed?
-        // For now, let's assume if you trash a folder, we don't necessarily trash children in DB
-        // but they become inaccessible.
-        // When cleaning up a folder, we should delete its children.
+// The store together with an open IMMEDIATE transaction. Dropping it without
+// `commit` rolls the transaction back.
+struct WriteGuard<'a> {
+    conn: MutexGuard<'a, Connection>,
+    store: RwLockWriteGuard<'a, DataStore>,
+    open: bool,
+}
 
-        for fid in folders_to_remove.clone() {
-            let children_files: Vec<FileMetadata> = store
-                .files
-                .iter()
-                .filter(|f| f.folder_id.as_deref() == Some(&fid))
-                .cloned()
-                .collect();
-            deleted_files.extend(children_files);
-            store.files.retain(|f| f.folder_id.as_deref() != Some(&fid));
+impl WriteGuard<'_> {
+    fn commit(&mut self) -> rusqlite::Result<()> {
+        self.conn.execute_batch("COMMIT")?;
+        self.open = false;
+        Ok(())
+    }
+
+    fn rollback(&mut self) {
+        if let Err(e) = self.conn.execute_batch("ROLLBACK") {
+            eprintln!("Failed to roll back metadata transaction: {}", e);
         }
+        self.open = false;
+    }
+}
 
-        store.folders.retain(|f| !folders_to_remove.contains(&f.id));
+impl Deref for WriteGuard<'_> {
+    type Target = DataStore;
 
-        drop(store);
-        self.save();
+    fn deref(&self) -> &DataStore {
+        &self.store
+    }
+}
 
-        deleted_files
+impl DerefMut for WriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut DataStore {
+        &mut self.store
     }
+}
 
-    pub fn toggle_star(&self, id: &str, is_folder: bool) -> bool {
-        let mut store = self.store.write().unwrap();
-        let mut found = false;
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        if self.open {
+            self.rollback();
+        }
+    }
+}
 
-        if is_folder {
-            if let Some(f) = store.folders.iter_mut().find(|f| f.id == id) {
-                f.is_starred = !f.is_starred;
-                found = true;
+impl Database {
+    pub fn new(app_dir: &str) -> Result<Self, DbError> {
+        let app_dir = Path::new(app_dir);
+        let db_path = app_dir.join(DB_FILENAME);
+        let mut conn = open_connection(&db_path)?;
+
+        // Sealed metadata stays locked (and unmigrated) until `unlock` brings the key
+        let sealed = sealed_key_id(&conn)?.is_some();
+        let mut store = DataStore::default();
+        if !sealed {
+            migrate(&mut conn, &db_path, None)?;
+            store = load_store(&conn, None)?;
+        }
+
+        // One-time import of the old whole-file JSON store. A file we cannot parse is
+        // reported instead of being replaced by an empty store.
+        let legacy_path = app_dir.join(LEGACY_FILENAME);
+        if !sealed && store.folders.is_empty() && store.files.is_empty() && legacy_path.exists() {
+            let legacy = read_snapshot(&legacy_path, None)?;
+            replace_all(&mut conn, &legacy)?;
+            std::fs::rename(&legacy_path, app_dir.join("metadata.json.migrated"))?;
+            println!(
+                "Migrated {} folders and {} files from metadata.json",
+                legacy.folders.len(),
+                legacy.files.len()
+            );
+            store = legacy;
+        }
+
+        let device = device_id(&conn)?;
+        let version = data_version(&conn)?;
+        let seen_change = if sealed { 0 } else { change_counter(&conn)? };
+        let db = Database {
+            db_path,
+            conn: Mutex::new(conn),
+            store: RwLock::new(store),
+            data_version: AtomicI64::new(version),
+            seen_change: AtomicI64::new(seen_change),
+            cipher: RwLock::new(None),
+            locked: AtomicBool::new(sealed),
+            clock: Mutex::new(Clock::default()),
+            device,
+        };
+        if !sealed {
+            db.stamp_unstamped()?;
+        }
+        Ok(db)
+    }
+
+    /// Whether the metadata is sealed and waiting for `unlock`.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Acquire)
+    }
+
+    /// Whether the rows are sealed under a vault key.
+    pub fn is_encrypted(&self) -> Result<bool, DbError> {
+        let conn = self.conn()?;
+        Ok(sealed_key_id(&conn)?.is_some())
+    }
+
+    /// Opens sealed metadata with the vault's key, or seals plain metadata under it
+    /// the first time a vault is unlocked. Either way the rows are encrypted at rest
+    /// from here on.
+    pub fn unlock(&self, cipher: RecordCipher) -> Result<(), DbError> {
+        let mut conn = self.conn()?;
+        match sealed_key_id(&conn)? {
+            Some(key_id) if key_id != cipher.key_id() => {
+                return Err(DbError::Corrupt(
+                    "metadata is encrypted with a different key".to_string(),
+                ))
             }
-        } else {
-            if let Some(f) = store.files.iter_mut().find(|f| f.id == id) {
-                f.is_starred = !f.is_starred;
-                found = true;
+            Some(_) => migrate(&mut conn, &self.db_path, Some(&cipher))?,
+            None => {
+                migrate(&mut conn, &self.db_path, None)?;
+                seal_rows(&mut conn, &cipher)?;
+                remove_plaintext_copies(&self.db_path);
+                println!("Encrypted metadata at rest");
             }
         }
 
-        if found {
-            drop(store);
-            self.save();
+        *self.cipher_write()? = Some(cipher);
+        let mut store = self.store_write()?;
+        self.load_into(&conn, &mut store)?;
+        self.locked.store(false, Ordering::Release);
+        drop(store);
+        drop(conn);
+        self.stamp_unstamped()
+    }
+
+    // Stamps the records from before change tracking, so every record has a stamp
+    // by the time a delta is built and building one changes nothing.
+    fn stamp_unstamped(&self) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        let unstamped = Changes {
+            folders: store
+                .folders
+                .iter()
+                .filter(|f| f.stamp.is_none())
+                .map(|f| f.id.clone())
+                .collect(),
+            files: store
+                .files
+                .iter()
+                .filter(|f| f.stamp.is_none())
+                .map(|f| f.id.clone())
+                .collect(),
+            ..Default::default()
+        };
+        if unstamped.folders.is_empty() && unstamped.files.is_empty() {
+            return Ok(());
         }
-        found
+        self.persist(&mut store, unstamped)
     }
 
-    pub fn get_starred(&self) -> (Vec<Folder>, Vec<FileMetadata>) {
-        let store = self.store.read().unwrap();
-        let folders = store
-            .folders
-            .iter()
-            .filter(|f| f.is_starred && !f.trashed)
-            .cloned()
-            .collect();
-        let files = store
-            .files
-            .iter()
-            .filter(|f| f.is_starred && !f.trashed)
-            .cloned()
-            .collect();
-        (folders, files)
+    /// Forgets the key and the decrypted store until the next `unlock`.
+    pub fn lock(&self) -> Result<(), DbError> {
+        let _conn = self.conn()?;
+        *self.cipher_write()? = None;
+        *self.store_write()? = DataStore::default();
+        self.locked.store(true, Ordering::Release);
+        Ok(())
     }
 
-    pub fn search_items(&self, query: &str) -> (Vec<Folder>, Vec<FileMetadata>) {
-        let store = self.store.read().unwrap();
-        let query_lower = query.to_lowercase();
+    fn cipher(&self) -> Result<Option<RecordCipher>, DbError> {
+        self.cipher
+            .read()
+            .map(|cipher| cipher.clone())
+            .map_err(|_| DbError::Corrupt("metadata key lock poisoned".to_string()))
+    }
 
-        let folders = store
-            .folders
-            .iter()
-            .filter(|f| {
-                !f.trashed
-                    && (f.name.to_lowercase().contains(&query_lower)
-                        || f.tags.as_ref().map_or(false, |tags| {
-                            tags.iter().any(|t| t.to_lowercase().contains(&query_lower))
-                        }))
-            })
-            .cloned()
-            .collect();
+    fn cipher_write(&self) -> Result<RwLockWriteGuard<'_, Option<RecordCipher>>, DbError> {
+        self.cipher
+            .write()
+            .map_err(|_| DbError::Corrupt("metadata key lock poisoned".to_string()))
+    }
 
-        let files = store
-            .files
-            .iter()
-            .filter(|f| !f.trashed && f.name.to_lowercase().contains(&query_lower))
-            .cloned()
-            .collect();
+    // Locks are always taken connection first, then store.
+    fn read(&self) -> Result<RwLockReadGuard<'_, DataStore>, DbError> {
+        self.refresh()?;
+        self.store
+            .read()
+            .map_err(|_| DbError::Corrupt("metadata lock poisoned".to_string()))
+    }
 
-        (folders, files)
+    // Starts a write: takes SQLite's write lock (waiting out the other process up to
+    // the busy timeout) and brings the store up to date under it.
+    fn write(&self) -> Result<WriteGuard<'_>, DbError> {
+        let conn = self.conn()?;
+        if self.is_locked() {
+            return Err(DbError::Locked);
+        }
+        let store = self.store_write()?;
+        conn.execute_batch("BEGIN IMMEDIATE")?;
+        let mut guard = WriteGuard {
+            conn,
+            store,
+            open: true,
+        };
+        if self.is_stale(&guard.conn)? {
+            self.catch_up(&guard.conn, &mut guard.store)?;
+        }
+        Ok(guard)
     }
 
-    pub fn get_total_usage(&self) -> i64 {
-        let store = self.store.read().unwrap();
-        // Sum size of all NON-TRASHED files
-        store
-            .files
-            .iter()
-            .filter(|f| !f.trashed)
-            .map(|f| f.size)
-            .sum()
+    fn store_write(&self) -> Result<RwLockWriteGuard<'_, DataStore>, DbError> {
+        self.store
+            .write()
+            .map_err(|_| DbError::Corrupt("metadata lock poisoned".to_string()))
     }
-    pub fn get_all_files(&self) -> Vec<FileMetadata> {
-        let store = self.store.read().unwrap();
-        store.files.clone()
+
+    fn conn(&self) -> Result<MutexGuard<'_, Connection>, DbError> {
+        self.conn
+            .lock()
+            .map_err(|_| DbError::Corrupt("metadata connection poisoned".to_string()))
     }
 
-    pub fn get_all_folders(&self) -> Vec<Folder> {
-        let store = self.store.read().unwrap();
-        store.folders.clone()
+    fn is_stale(&self, conn: &Connection) -> Result<bool, DbError> {
+        Ok(data_version(conn)? != self.data_version.load(Ordering::Acquire))
     }
 
-    pub fn delete_files_by_ids(&self, ids: &[String]) {
-        {
-            let mut store = self.store.write().unwrap();
-            store.files.retain(|f| !ids.contains(&f.id));
-        }
-        self.save();
+    // Replaces the store with what SQLite holds.
+    fn load_into(&self, conn: &Connection, store: &mut DataStore) -> Result<(), DbError> {
+        let cipher = self.readable_cipher(conn, store)?;
+        let version = data_version(conn)?;
+        // Read first: a row changed while loading is only read again next time
+        let seen_change = change_counter(conn)?;
+        *store = load_store(conn, cipher.as_ref())?;
+        self.data_version.store(version, Ordering::Release);
+        self.seen_change.store(seen_change, Ordering::Release);
+        Ok(())
     }
 
-    pub fn get_existing_message_ids(&self) -> Vec<i32> {
-        let store = self.store.read().unwrap();
-        store.files.iter().map(|f| f.message_id).collect()
+    // Brings the store up to date with what the other process committed, decoding
+    // only the rows it changed. Removed rows leave nothing to read, but they show up
+    // as fewer rows than the store holds.
+    fn catch_up(&self, conn: &Connection, store: &mut DataStore) -> Result<(), DbError> {
+        let cipher = self.readable_cipher(conn, store)?;
+        let version = data_version(conn)?;
+        // Rows and counts must come from the same snapshot while the other process writes
+        let _snapshot = if conn.is_autocommit() {
+            Some(conn.unchecked_transaction()?)
+        } else {
+            None
+        };
+        let seen_change = change_counter(conn)?;
+        let since = self.seen_change.load(Ordering::Acquire);
+
+        let mut changes = Changes::default();
+        for value in read_rows_since(conn, "folders", since, cipher.as_ref())? {
+            let folder: Folder = from_value(value)?;
+            changes.folders.push(folder.id.clone());
+            match store.index.folder_pos.get(&folder.id) {
+                Some(&i) => store.folders[i] = folder,
+                None => store.folders.push(folder),
+            }
+        }
+        for value in read_rows_since(conn, "files", since, cipher.as_ref())? {
+            let file: FileMetadata = from_value(value)?;
+            changes.files.push(file.id.clone());
+            match store.index.file_pos.get(&file.id) {
+                Some(&i) => store.files[i] = file,
+                None => store.files.push(file),
+            }
+        }
+        // Every row is in the store now, so equal counts mean nothing was removed
+        if row_count(conn, "folders")? != store.folders.len() {
+            let ids = row_ids(conn, "folders")?;
+            store.folders.retain(|f| {
+                let keep = ids.contains(&f.id);
+                if !keep {
+                    changes.removed_folders.push(f.id.clone());
+                }
+                keep
+            });
+        }
+        if row_count(conn, "files")? != store.files.len() {
+            let ids = row_ids(conn, "files")?;
+            store.files.retain(|f| {
+                let keep = ids.contains(&f.id);
+                if !keep {
+                    changes.removed_files.push(f.id.clone());
+                }
+                keep
+            });
+        }
+        store.update_index(&changes);
+
+        self.data_version.store(version, Ordering::Release);
+        self.seen_change.store(seen_change, Ordering::Release);
+        Ok(())
     }
 
-    pub fn move_file_to_sync_folder(
+    // The key to read the rows with. Locks the database, emptying `store`, if the
+    // other process sealed it under a key we do not have.
+    fn readable_cipher(
         &self,
-        message_id: i32,
-        target_folder_id: &str,
-    ) -> Result<(), String> {
-        let mut store = self.store.write().unwrap();
-        if let Some(file) = store.files.iter_mut().find(|f| f.message_id == message_id) {
-            // Only move if currently in root (folder_id is None)
-            if file.folder_id.is_none() {
-                file.folder_id = Some(target_folder_id.to_string());
-                drop(store);
-                self.save();
+        conn: &Connection,
+        store: &mut DataStore,
+    ) -> Result<Option<RecordCipher>, DbError> {
+        let found = schema_version(conn)?;
+        if found > SCHEMA_VERSION {
+            return Err(DbError::SchemaVersion {
+                found,
+                supported: SCHEMA_VERSION,
+            });
+        }
+        let cipher = self.cipher()?;
+        if let Some(key_id) = sealed_key_id(conn)? {
+            if !matches!(&cipher, Some(c) if c.key_id() == key_id) {
+                *store = DataStore::default();
+                self.locked.store(true, Ordering::Release);
+                return Err(DbError::Locked);
             }
         }
+        Ok(cipher)
+    }
+
+    // Stamps the touched records as changed on this device and commits them.
+    fn persist(&self, store: &mut WriteGuard<'_>, mut changes: Changes) -> Result<(), DbError> {
+        self.stamp(store, &mut changes)?;
+        self.commit_changes(store, changes)
+    }
+
+    // Writes the touched rows and commits the transaction opened by `write`. If that
+    // fails nothing reaches the disk, so the in-memory view is rolled back to what
+    // SQLite holds.
+    fn commit_changes(&self, store: &mut WriteGuard<'_>, changes: Changes) -> Result<(), DbError> {
+        let cipher = self.cipher()?;
+        // The store already holds our own rows, so the next catch-up can skip them
+        let result = write_changes(&store.conn, &store.store, &changes, cipher.as_ref())
+            .and_then(|_| change_counter(&store.conn))
+            .and_then(|seen_change| {
+                store.commit()?;
+                self.seen_change.store(seen_change, Ordering::Release);
+                Ok(())
+            });
+        if let Err(e) = result {
+            eprintln!("Failed to commit metadata changes: {}", e);
+            store.rollback();
+            let WriteGuard { conn, store, .. } = store;
+            self.load_into(conn, store)?;
+            return Err(e.into());
+        }
+        store.update_index(&changes);
         Ok(())
     }
 
-    pub fn reload(&self) {
-        let mut store = self.store.write().unwrap();
-        if self.db_path.exists() {
-            if let Ok(file) = File::open(&self.db_path) {
-                let reader = BufReader::new(file);
-                if let Ok(new_store) = serde_json::from_reader(reader) {
-                    *store = new_store;
-                    println!("Database reloaded from disk.");
-                } else {
-                    eprintln!("Failed to parse metadata.json during reload.");
-                }
-            } else {
-                eprintln!("Failed to open metadata.json during reload.");
-            }
+    // Gives every touched record a new stamp, noting renames by the name it is still
+    // indexed under, and leaves a tombstone for every removed one.
+    fn stamp(&self, store: &mut WriteGuard<'_>, changes: &mut Changes) -> Result<(), DbError> {
+        let mut clock = self.clock(&store.conn)?;
+        let data: &mut DataStore = &mut store.store;
+        let folders: HashSet<&String> = changes.folders.iter().collect();
+        for folder in data.folders.iter_mut().filter(|f| folders.contains(&f.id)) {
+            let renamed = data
+                .index
+                .folder_keys
+                .get(&folder.id)
+                .is_some_and(|(_, name)| *name != folder.name);
+            folder.stamp = Some(next_stamp(
+                &mut clock,
+                &self.device,
+                folder.stamp.take(),
+                renamed,
+            ));
+        }
+        let files: HashSet<&String> = changes.files.iter().collect();
+        for file in data.files.iter_mut().filter(|f| files.contains(&f.id)) {
+            let renamed = data
+                .index
+                .file_keys
+                .get(&file.id)
+                .is_some_and(|(_, name)| *name != file.name);
+            file.stamp = Some(next_stamp(
+                &mut clock,
+                &self.device,
+                file.stamp.take(),
+                renamed,
+            ));
+        }
+        let removed = changes
+            .removed_folders
+            .iter()
+            .map(|id| (id, true))
+            .chain(changes.removed_files.iter().map(|id| (id, false)));
+        for (id, is_folder) in removed {
+            changes.tombstones.push(Tombstone {
+                id: id.clone(),
+                is_folder,
+                stamp: clock.tick(&self.device),
+            });
+        }
+        write_setting(&store.conn, CLOCK_SETTING, &clock.latest(&self.device))?;
+        Ok(())
+    }
+
+    // The clock, caught up with the stamps the other process issued. Both share the
+    // device id, so one must never issue a stamp the other already has: a delta
+    // would skip it. Call it inside the transaction opened by `write`.
+    fn clock(&self, conn: &Connection) -> Result<MutexGuard<'_, Clock>, DbError> {
+        let mut clock = self.clock.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(stamp) = read_setting::<Stamp>(conn, CLOCK_SETTING)? {
+            clock.observe(&stamp);
+        }
+        Ok(clock)
+    }
+
+    /// Reloads the store if the other process (app or WebDAV daemon) committed changes
+    /// since it was last loaded. Returns whether anything was reloaded. Reads and
+    /// writes call this on their own; it is public so callers can poll for changes.
+    pub fn refresh(&self) -> Result<bool, DbError> {
+        let conn = self.conn()?;
+        if self.is_locked() {
+            return Err(DbError::Locked);
+        }
+        if !self.is_stale(&conn)? {
+            return Ok(false);
         }
+        let mut store = self.store_write()?;
+        self.catch_up(&conn, &mut store)?;
+        Ok(true)
+    }
+
+    // Helper to get a unique name (e.g. "Folder (1)")
+    // This needs to be called inside a lock, or we assume single-threaded access to store.
+    // Since we lock in public methods, we should make this a private method taking &store.
+    fn get_unique_name(
+        &self,
+        store: &DataStore,
+        parent_id: Option<&String>,
+        base_name: &str,
+        is_folder: bool,
+    ) -> String {
+        // Base case: check if it exists
+        let exists = if is_folder {
+            store
+                .folders
+                .iter()
+                .any(|f| f.parent_id.as_ref() == parent_id && f.name == base_name && !f.trashed)
+        } else {
+            store
+                .files
+                .iter()
+                .any(|f| f.folder_id.as_ref() == parent_id && f.name == base_name && !f.trashed)
+        };
+
+        if !exists {
+            return base_name.to_string();
+        }
+
+        // It exists, try (1), (2), etc.
+        let mut i = 1;
+        loop {
+            let candidate = format!("{} ({})", base_name, i);
+            let exists =
+                if is_folder {
+                    store.folders.iter().any(|f| {
+                        f.parent_id.as_ref() == parent_id && f.name == candidate && !f.trashed
+                    })
+                } else {
+                    store.files.iter().any(|f| {
+                        f.folder_id.as_ref() == parent_id && f.name == candidate && !f.trashed
+                    })
+                };
+
+            if !exists {
+                return candidate;
+            }
+            i += 1;
+        }
+    }
+
+    pub fn create_folder(&self, name: &str, parent_id: Option<String>) -> Result<String, DbError> {
+        let mut store = self.write()?;
+
+        // Ensure unique name
+        let final_name = self.get_unique_name(&store, parent_id.as_ref(), name, true);
+        let folder = new_folder(parent_id, final_name);
+        let id = folder.id.clone();
+
+        store.folders.push(folder);
+        let changes = Changes {
+            folders: vec![id.clone()],
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)?;
+        Ok(id)
+    }
+
+    pub fn list_contents(
+        &self,
+        folder_id: Option<String>,
+    ) -> Result<(Vec<Folder>, Vec<FileMetadata>), DbError> {
+        let store = self.read()?;
+        let folders = store
+            .folders
+            .iter()
+            .filter(|f| f.parent_id == folder_id && !f.trashed)
+            .cloned()
+            .collect();
+        let files = store
+            .files
+            .iter()
+            .filter(|f| f.folder_id == folder_id && !f.trashed)
+            .cloned()
+            .collect();
+        Ok((folders, files))
+    }
+
+    pub fn list_trash(&self) -> Result<(Vec<Folder>, Vec<FileMetadata>), DbError> {
+        let store = self.read()?;
+        // Only the item that was trashed is listed, not everything that went with it.
+        let trashed_with_parent = |parent_id: &Option<String>, batch: &Option<String>| {
+            parent_id.as_ref().is_some_and(|pid| {
+                store
+                    .folders
+                    .iter()
+                    .any(|p| &p.id == pid && p.trashed && p.trash_batch == *batch)
+            })
+        };
+        let folders = store
+            .folders
+            .iter()
+            .filter(|f| f.trashed && !trashed_with_parent(&f.parent_id, &f.trash_batch))
+            .cloned()
+            .collect();
+        let files = store
+            .files
+            .iter()
+            .filter(|f| f.trashed && !trashed_with_parent(&f.folder_id, &f.trash_batch))
+            .cloned()
+            .collect();
+        Ok((folders, files))
+    }
+
+    pub fn get_file(&self, id: &str) -> Result<FileMetadata, DbError> {
+        let store = self.read()?;
+        store.file(id).cloned().ok_or(DbError::NotFound("File"))
+    }
+
+    pub fn lookup_folder_name(&self, id: &str) -> Result<String, DbError> {
+        let store = self.read()?;
+        store
+            .folder(id)
+            .map(|f| f.name.clone())
+            .ok_or(DbError::NotFound("Folder"))
+    }
+
+    pub fn get_folder_by_id(&self, id: &str) -> Result<Folder, DbError> {
+        let store = self.read()?;
+        store.folder(id).cloned().ok_or(DbError::NotFound("Folder"))
+    }
+
+    /// Resolves a `/`-separated path to the item it names, skipping trashed items.
+    /// Each component is looked up directly, so the cost grows with depth only. A
+    /// folder wins over a file of the same name. Returns `None` for unknown paths.
+    pub fn resolve_path(&self, path: &str) -> Result<Option<Entry>, DbError> {
+        let store = self.read()?;
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let Some((last, dirs)) = components.split_last() else {
+            return Ok(Some(Entry::Root));
+        };
+
+        let mut parent: Option<String> = None;
+        for name in dirs {
+            match store.child_folder(parent.as_ref(), name) {
+                Some(folder) => parent = Some(folder.id.clone()),
+                None => return Ok(None),
+            }
+        }
+
+        if let Some(folder) = store.child_folder(parent.as_ref(), last) {
+            return Ok(Some(Entry::Folder(folder.clone())));
+        }
+        Ok(store
+            .child_file(parent.as_ref(), last)
+            .map(|f| Entry::File(f.clone())))
+    }
+
+    /// Absolute path (`/A/B/name`) of a folder or file, the inverse of `resolve_path`
+    /// for items that are not in the trash.
+    pub fn path_of(&self, id: &str) -> Result<String, DbError> {
+        let store = self.read()?;
+        let (mut parts, mut parent) = if let Some(folder) = store.folder(id) {
+            (vec![folder.name.as_str()], folder.parent_id.as_ref())
+        } else if let Some(file) = store.file(id) {
+            (vec![file.name.as_str()], file.folder_id.as_ref())
+        } else {
+            return Err(DbError::NotFound("Item"));
+        };
+
+        let mut seen = HashSet::new();
+        while let Some(pid) = parent {
+            if !seen.insert(pid) {
+                return Err(DbError::CycleDetected);
+            }
+            // A parent that no longer exists leaves the item at the top level, as
+            // `restore_item` treats it.
+            let Some(folder) = store.folder(pid) else {
+                break;
+            };
+            parts.push(folder.name.as_str());
+            parent = folder.parent_id.as_ref();
+        }
+
+        parts.reverse();
+        Ok(format!("/{}", parts.join("/")))
+    }
+
+    /// Every folder and file below folder `id` that is not in the trash, with its
+    /// path relative to it (`B/C/name`). Walks the path index, so the cost grows with
+    /// the size of the subtree, not of the library. Parents come before their children.
+    pub fn subtree(&self, id: &str) -> Result<Subtree, DbError> {
+        let store = self.read()?;
+        if store.folder(id).is_none() {
+            return Err(DbError::NotFound("Folder"));
+        }
+        let mut subtree = Subtree::default();
+        let mut seen = HashSet::new();
+        let mut queue = vec![(id.to_string(), String::new())];
+        while let Some((parent, path)) = queue.pop() {
+            if !seen.insert(parent.clone()) {
+                continue;
+            }
+            let parent = Some(parent);
+            let join = |name: &str| {
+                if path.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}/{}", path, name)
+                }
+            };
+            for name in store
+                .index
+                .folder_children
+                .get(&parent)
+                .into_iter()
+                .flatten()
+            {
+                // Of same-named items only the one `resolve_path` finds is listed
+                if let Some(folder) = store.child_folder(parent.as_ref(), name) {
+                    queue.push((folder.id.clone(), join(name)));
+                    subtree.folders.push((join(name), folder.clone()));
+                }
+            }
+            for name in store.index.file_children.get(&parent).into_iter().flatten() {
+                if let Some(file) = store.child_file(parent.as_ref(), name) {
+                    subtree.files.push((join(name), file.clone()));
+                }
+            }
+        }
+        Ok(subtree)
+    }
+
+    pub fn add_file(
+        &self,
+        folder_id: Option<String>,
+        name: String,
+        size: i64,
+        mime_type: String,
+        segments: Vec<Segment>,
+        thumbnail: Option<String>,
+    ) -> Result<FileMetadata, DbError> {
+        self.insert_file(FileMetadata {
+            thumbnail,
+            ..new_file(folder_id, name, size, mime_type, segments)
+        })
+    }
+
+    // Encrypted blobs have no usable thumbnail, so there is none to pass.
+    pub fn add_encrypted_file(
+        &self,
+        folder_id: Option<String>,
+        name: String,
+        mime_type: String,
+        segments: Vec<Segment>,
+        encryption: EncryptionInfo,
+    ) -> Result<FileMetadata, DbError> {
+        let size = encryption.plain_size as i64;
+        self.insert_file(FileMetadata {
+            encryption: Some(encryption),
+            ..new_file(folder_id, name, size, mime_type, segments)
+        })
+    }
+
+    /// Records the SHA-256 of a file's contents, once they are stored.
+    pub fn set_file_sha256(&self, id: &str, sha256: String) -> Result<FileMetadata, DbError> {
+        let mut store = self.write()?;
+        let file = store
+            .files
+            .iter_mut()
+            .find(|f| f.id == id)
+            .ok_or(DbError::NotFound("File"))?;
+        file.sha256 = Some(sha256);
+        let file = file.clone();
+        let changes = Changes {
+            files: vec![file.id.clone()],
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)?;
+        Ok(file)
+    }
+
+    /// Adds a file that shares the stored blob of a file with the same contents, if
+    /// there is one. Only a blob that is sealed, or plain, as asked for is shared.
+    /// Its messages stay until no file refers to them, see `delete_file`.
+    pub fn add_duplicate(
+        &self,
+        folder_id: Option<String>,
+        name: String,
+        mime_type: String,
+        sha256: &str,
+        encrypted: bool,
+    ) -> Result<Option<FileMetadata>, DbError> {
+        let mut store = self.write()?;
+        let Some(original) = store
+            .files
+            .iter()
+            .find(|f| {
+                f.message_id > 0
+                    && f.encryption.is_some() == encrypted
+                    && f.sha256
+                        .as_deref()
+                        .is_some_and(|h| h.eq_ignore_ascii_case(sha256))
+            })
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        let file = FileMetadata {
+            chat_id: original.chat_id,
+            replicas: original.replicas.clone(),
+            thumbnail: original.thumbnail.clone(),
+            encryption: original.encryption.clone(),
+            sha256: original.sha256.clone(),
+            ..new_file(
+                folder_id,
+                name,
+                original.size,
+                mime_type,
+                original.blob_segments(),
+            )
+        };
+        self.insert_into(&mut store, file).map(Some)
+    }
+
+    /// The file outside the trash called `name` in `folder_id`, if there is one.
+    pub fn file_named(
+        &self,
+        folder_id: Option<&str>,
+        name: &str,
+    ) -> Result<Option<FileMetadata>, DbError> {
+        let store = self.read()?;
+        Ok(store
+            .files
+            .iter()
+            .find(|f| !f.trashed && f.folder_id.as_deref() == folder_id && f.name == name)
+            .cloned())
+    }
+
+    /// Makes the contents of file `by` the new contents of file `id`, keeping the
+    /// old ones as its newest version. `by` is removed; `id` keeps its name, place
+    /// and star. Nothing is deleted from Telegram, see `prune_versions`.
+    pub fn supersede(&self, id: &str, by: &str) -> Result<FileMetadata, DbError> {
+        let mut store = self.write()?;
+        if id == by {
+            return store.file(id).cloned().ok_or(DbError::NotFound("File"));
+        }
+        let newer = store.file(by).cloned().ok_or(DbError::NotFound("File"))?;
+        let file = store
+            .files
+            .iter_mut()
+            .find(|f| f.id == id)
+            .ok_or(DbError::NotFound("File"))?;
+        let old = file.as_version(newer.created_at);
+        file.take_contents(newer.as_version(newer.created_at));
+        file.versions.insert(0, old);
+        // Older versions of `by` itself, if it had any, follow
+        file.versions.extend(newer.versions);
+        let file = file.clone();
+
+        store.files.retain(|f| f.id != by);
+        let changes = Changes {
+            files: vec![file.id.clone()],
+            removed_files: vec![by.to_string()],
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)?;
+        Ok(file)
+    }
+
+    /// Brings back the contents of one of a file's versions. The contents it had
+    /// until now become its newest version, so a restore can be undone.
+    pub fn restore_version(&self, id: &str, version_id: &str) -> Result<FileMetadata, DbError> {
+        let mut store = self.write()?;
+        let now = chrono::Utc::now().timestamp();
+        let file = store
+            .files
+            .iter_mut()
+            .find(|f| f.id == id)
+            .ok_or(DbError::NotFound("File"))?;
+        let pos = file
+            .versions
+            .iter()
+            .position(|v| v.id == version_id)
+            .ok_or(DbError::NotFound("Version"))?;
+        let current = file.as_version(now);
+        let version = file.versions.remove(pos);
+        file.take_contents(version);
+        file.versions.insert(0, current);
+        let file = file.clone();
+
+        let changes = Changes {
+            files: vec![file.id.clone()],
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)?;
+        Ok(file)
+    }
+
+    pub fn version_retention(&self) -> Result<VersionRetention, DbError> {
+        Ok(self.setting(VERSION_RETENTION_SETTING)?.unwrap_or_default())
+    }
+
+    /// Takes effect at the next `prune_versions`.
+    pub fn set_version_retention(&self, retention: &VersionRetention) -> Result<(), DbError> {
+        self.set_setting(VERSION_RETENTION_SETTING, retention)
+    }
+
+    // Drops the versions the retention policy no longer keeps. Returns the Telegram
+    // messages that are no longer referenced.
+    pub fn prune_versions(&self) -> Result<Vec<MessageRef>, DbError> {
+        let mut store = self.write()?;
+        let retention: VersionRetention =
+            read_setting(&store.conn, VERSION_RETENTION_SETTING)?.unwrap_or_default();
+        let limit = retention
+            .keep_days
+            .map(|days| chrono::Utc::now().timestamp() - i64::from(days) * 24 * 60 * 60);
+        let keep = retention.keep_versions.map_or(usize::MAX, |n| n as usize);
+
+        let mut changes = Changes::default();
+        for file in store.files.iter_mut() {
+            let before = file.versions.len();
+            let mut kept = 0;
+            file.versions.retain(|v| {
+                let expired = kept >= keep || limit.is_some_and(|l| v.replaced_at < l);
+                if expired {
+                    changes.removed_messages.extend(v.messages());
+                } else {
+                    kept += 1;
+                }
+                !expired
+            });
+            if file.versions.len() != before {
+                changes.files.push(file.id.clone());
+            }
+        }
+        if changes.files.is_empty() {
+            return Ok(Vec::new());
+        }
+        let removed = changes.removed_messages.clone();
+        self.persist(&mut store, changes)?;
+        Ok(store.released(&removed))
+    }
+
+    /// Adds a file record built with `new_file`, for callers that need to pick its id
+    /// or set more of it than `add_file` does.
+    pub fn insert_file(&self, file: FileMetadata) -> Result<FileMetadata, DbError> {
+        let mut store = self.write()?;
+        self.insert_into(&mut store, file)
+    }
+
+    fn insert_into(
+        &self,
+        store: &mut WriteGuard<'_>,
+        mut file: FileMetadata,
+    ) -> Result<FileMetadata, DbError> {
+        // Ensure unique name
+        file.name = self.get_unique_name(store, file.folder_id.as_ref(), &file.name, false);
+
+        store.files.push(file.clone());
+        let changes = Changes {
+            files: vec![file.id.clone()],
+            ..Default::default()
+        };
+        self.persist(store, changes)?;
+        Ok(file)
+    }
+
+    // Whether uploads into `folder_id` should be encrypted: true when it or any
+    // folder above it has encryption turned on.
+    pub fn encrypts_uploads(&self, folder_id: Option<&str>) -> Result<bool, DbError> {
+        let store = self.read()?;
+        let mut seen = HashSet::new();
+        let mut current = folder_id.map(str::to_string);
+        while let Some(id) = current {
+            if !seen.insert(id.clone()) {
+                return Err(DbError::CycleDetected);
+            }
+            let folder = store.folder(&id).ok_or(DbError::NotFound("Folder"))?;
+            if folder.encrypted {
+                return Ok(true);
+            }
+            current = folder.parent_id.clone();
+        }
+        Ok(false)
+    }
+
+    // Only affects later uploads; files already stored keep their form.
+    pub fn set_folder_encrypted(&self, id: &str, encrypted: bool) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        let folder = store
+            .folders
+            .iter_mut()
+            .find(|f| f.id == id)
+            .ok_or(DbError::NotFound("Folder"))?;
+        folder.encrypted = encrypted;
+        folder.last_modified = chrono::Utc::now().timestamp();
+        let changes = Changes {
+            folders: vec![id.to_string()],
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)
+    }
+
+    // How many copies to keep of files in `folder_id`: the setting of the nearest
+    // folder that has one, or a single copy.
+    pub fn replica_count(&self, folder_id: Option<&str>) -> Result<u32, DbError> {
+        let store = self.read()?;
+        let mut seen = HashSet::new();
+        let mut current = folder_id.map(str::to_string);
+        while let Some(id) = current {
+            if !seen.insert(id.clone()) {
+                return Err(DbError::CycleDetected);
+            }
+            let folder = store.folder(&id).ok_or(DbError::NotFound("Folder"))?;
+            if let Some(n) = folder.replicas {
+                return Ok(n.max(1));
+            }
+            current = folder.parent_id.clone();
+        }
+        Ok(1)
+    }
+
+    // `None` inherits the parent's setting again. Files already stored get their
+    // copies at the next repair.
+    pub fn set_folder_replicas(&self, id: &str, replicas: Option<u32>) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        let folder = store
+            .folders
+            .iter_mut()
+            .find(|f| f.id == id)
+            .ok_or(DbError::NotFound("Folder"))?;
+        folder.replicas = replicas;
+        folder.last_modified = chrono::Utc::now().timestamp();
+        let changes = Changes {
+            folders: vec![id.to_string()],
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)
+    }
+
+    /// Replaces the stored copies of a file's current contents, the first becoming
+    /// the primary one, provided they are still `expected`. Returns `None` if the
+    /// file is gone or its contents changed in the meantime. Messages only `expected`
+    /// referred to are returned for deletion.
+    pub fn replace_copies(
+        &self,
+        id: &str,
+        expected: &[Replica],
+        copies: Vec<Replica>,
+    ) -> Result<Option<(FileMetadata, Vec<MessageRef>)>, DbError> {
+        let mut store = self.write()?;
+        let Some(file) = store.files.iter_mut().find(|f| f.id == id) else {
+            return Ok(None);
+        };
+        if file.copies() != expected || copies.is_empty() {
+            return Ok(None);
+        }
+        let mut changes = Changes::default();
+        for old in expected {
+            if !copies.contains(old) {
+                changes.removed_messages.extend(old.messages());
+            }
+        }
+        file.set_copies(copies);
+        let file = file.clone();
+        changes.files.push(file.id.clone());
+        let removed = changes.removed_messages.clone();
+        self.persist(&mut store, changes)?;
+        Ok(Some((file, store.released(&removed))))
+    }
+
+    // Soft delete. Trashing a folder trashes its whole subtree under one batch id.
+    pub fn trash_item(&self, id: &str, is_folder: bool) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        let mut changes = Changes::default();
+        if is_folder {
+            if !store.folders.iter().any(|f| f.id == id) {
+                return Err(DbError::NotFound("Folder"));
+            }
+            mark_trashed(&mut store, &[id.to_string()], &[], &mut changes);
+        } else {
+            if !store.files.iter().any(|f| f.id == id) {
+                return Err(DbError::NotFound("File"));
+            }
+            mark_trashed(&mut store, &[], &[id.to_string()], &mut changes);
+        }
+        self.persist(&mut store, changes)
+    }
+
+    /// Moves the files to the trash as one batch, so restoring one restores them all.
+    pub fn trash_files(&self, ids: &[String]) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        if ids.iter().any(|id| store.file(id).is_none()) {
+            return Err(DbError::NotFound("File"));
+        }
+        let mut changes = Changes::default();
+        mark_trashed(&mut store, &[], ids, &mut changes);
+        self.persist(&mut store, changes)
+    }
+
+    // Restores everything that was trashed together with the item. Items whose parent
+    // is gone or still in the trash come back at the top level, and restored items are
+    // renamed if something with the same name was created meanwhile.
+    pub fn restore_item(&self, id: &str, is_folder: bool) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        let batch = if is_folder {
+            store
+                .folders
+                .iter()
+                .find(|f| f.id == id)
+                .ok_or(DbError::NotFound("Folder"))?
+                .trash_batch
+                .clone()
+        } else {
+            store
+                .files
+                .iter()
+                .find(|f| f.id == id)
+                .ok_or(DbError::NotFound("File"))?
+                .trash_batch
+                .clone()
+        };
+        let in_batch = |item_id: &str, item_batch: &Option<String>| match &batch {
+            Some(b) => item_batch.as_ref() == Some(b),
+            None => item_id == id,
+        };
+
+        let folder_ids: HashSet<String> = store
+            .folders
+            .iter()
+            .filter(|f| f.trashed && in_batch(&f.id, &f.trash_batch))
+            .map(|f| f.id.clone())
+            .collect();
+        let file_ids: HashSet<String> = store
+            .files
+            .iter()
+            .filter(|f| f.trashed && in_batch(&f.id, &f.trash_batch))
+            .map(|f| f.id.clone())
+            .collect();
+
+        // Roots of the restored set: their parent is not being restored with them.
+        let target_parent = |store: &DataStore, parent_id: &Option<String>| {
+            parent_id
+                .clone()
+                .filter(|pid| store.folders.iter().any(|p| &p.id == pid && !p.trashed))
+        };
+        let mut placements: Vec<(String, bool, Option<String>, String)> = Vec::new();
+        for f in store.folders.iter().filter(|f| folder_ids.contains(&f.id)) {
+            if f.parent_id
+                .as_ref()
+                .is_none_or(|pid| !folder_ids.contains(pid))
+            {
+                let parent = target_parent(&store, &f.parent_id);
+                let name = self.get_unique_name(&store, parent.as_ref(), &f.name, true);
+                placements.push((f.id.clone(), true, parent, name));
+            }
+        }
+        for f in store.files.iter().filter(|f| file_ids.contains(&f.id)) {
+            if f.folder_id
+                .as_ref()
+                .is_none_or(|pid| !folder_ids.contains(pid))
+            {
+                let parent = target_parent(&store, &f.folder_id);
+                let name = self.get_unique_name(&store, parent.as_ref(), &f.name, false);
+                placements.push((f.id.clone(), false, parent, name));
+            }
+        }
+
+        for f in store
+            .folders
+            .iter_mut()
+            .filter(|f| folder_ids.contains(&f.id))
+        {
+            f.trashed = false;
+            f.trashed_at = None;
+            f.trash_batch = None;
+        }
+        for f in store.files.iter_mut().filter(|f| file_ids.contains(&f.id)) {
+            f.trashed = false;
+            f.trashed_at = None;
+            f.trash_batch = None;
+        }
+        for (item_id, is_folder, parent, name) in placements {
+            if is_folder {
+                if let Some(f) = store.folders.iter_mut().find(|f| f.id == item_id) {
+                    f.parent_id = parent;
+                    f.name = name;
+                }
+            } else if let Some(f) = store.files.iter_mut().find(|f| f.id == item_id) {
+                f.folder_id = parent;
+                f.name = name;
+            }
+        }
+
+        let changes = Changes {
+            folders: folder_ids.into_iter().collect(),
+            files: file_ids.into_iter().collect(),
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)
+    }
+
+    // Hard delete (Permanent). Returns the Telegram messages that are no longer
+    // referenced; a blob shared with other files stays until the last of them goes.
+    pub fn delete_file(&self, id: &str) -> Result<Vec<MessageRef>, DbError> {
+        let mut store = self.write()?;
+        if !store.files.iter().any(|f| f.id == id) {
+            return Err(DbError::NotFound("File"));
+        }
+        let file_ids = HashSet::from([id.to_string()]);
+        let changes = remove_items(&mut store, &HashSet::new(), &file_ids);
+        let removed = changes.removed_messages.clone();
+        self.persist(&mut store, changes)?;
+        Ok(store.released(&removed))
+    }
+
+    // Permanently deletes the folder and everything below it. Returns the Telegram
+    // messages that are no longer referenced.
+    pub fn delete_folder(&self, id: &str) -> Result<Vec<MessageRef>, DbError> {
+        let mut store = self.write()?;
+        if !store.folders.iter().any(|f| f.id == id) {
+            return Err(DbError::NotFound("Folder"));
+        }
+        let folder_ids = subtree_folder_ids(&store, id);
+        let changes = remove_items(&mut store, &folder_ids, &HashSet::new());
+        let removed = changes.removed_messages.clone();
+        self.persist(&mut store, changes)?;
+        Ok(store.released(&removed))
+    }
+
+    pub fn rename_file(&self, id: &str, new_name: &str) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        let folder_id = store
+            .files
+            .iter()
+            .find(|f| f.id == id)
+            .map(|f| f.folder_id.clone())
+            .ok_or(DbError::NotFound("File"))?;
+        let (folders, files) = sibling_conflicts(&store, folder_id.as_ref(), new_name, id);
+        if !folders.is_empty() || !files.is_empty() {
+            return Err(DbError::NameConflict(new_name.to_string()));
+        }
+        if let Some(file) = store.files.iter_mut().find(|f| f.id == id) {
+            file.name = new_name.to_string();
+        }
+        let changes = Changes {
+            files: vec![id.to_string()],
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)
+    }
+
+    // Returns the name the file ended up with, which differs from `new_name` only
+    // under `ConflictPolicy::AutoRename`.
+    pub fn move_file(
+        &self,
+        id: &str,
+        target_folder_id: Option<String>,
+        new_name: &str,
+        policy: ConflictPolicy,
+    ) -> Result<String, DbError> {
+        let mut store = self.write()?;
+        if !store.files.iter().any(|f| f.id == id) {
+            return Err(DbError::NotFound("File"));
+        }
+        if let Some(target) = &target_folder_id {
+            if !store.folders.iter().any(|f| &f.id == target) {
+                return Err(DbError::NotFound("Folder"));
+            }
+        }
+
+        let mut changes = Changes::default();
+        let final_name = self.settle_name_conflict(
+            &mut store,
+            id,
+            target_folder_id.as_ref(),
+            new_name,
+            policy,
+            &mut changes,
+        )?;
+        if let Some(file) = store.files.iter_mut().find(|f| f.id == id) {
+            file.folder_id = target_folder_id;
+            file.name = final_name.clone();
+        }
+        changes.files.push(id.to_string());
+        self.persist(&mut store, changes)?;
+        Ok(final_name)
+    }
+
+    // Same as `move_file`. Moving a folder into itself or anywhere below itself is
+    // refused, as it would detach the subtree from the root.
+    pub fn move_folder(
+        &self,
+        id: &str,
+        target_parent_id: Option<String>,
+        new_name: &str,
+        policy: ConflictPolicy,
+    ) -> Result<String, DbError> {
+        let mut store = self.write()?;
+        if !store.folders.iter().any(|f| f.id == id) {
+            return Err(DbError::NotFound("Folder"));
+        }
+        if let Some(target) = &target_parent_id {
+            if !store.folders.iter().any(|f| &f.id == target) {
+                return Err(DbError::NotFound("Folder"));
+            }
+            if is_within(&store, target, id) {
+                return Err(DbError::CycleDetected);
+            }
+        }
+
+        let mut changes = Changes::default();
+        let final_name = self.settle_name_conflict(
+            &mut store,
+            id,
+            target_parent_id.as_ref(),
+            new_name,
+            policy,
+            &mut changes,
+        )?;
+        if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
+            folder.parent_id = target_parent_id;
+            folder.name = final_name.clone();
+        }
+        changes.folders.push(id.to_string());
+        self.persist(&mut store, changes)?;
+        Ok(final_name)
+    }
+
+    // Applies `policy` to siblings named `name` in `parent` and returns the name the
+    // moved item `id` should take.
+    fn settle_name_conflict(
+        &self,
+        store: &mut DataStore,
+        id: &str,
+        parent: Option<&String>,
+        name: &str,
+        policy: ConflictPolicy,
+        changes: &mut Changes,
+    ) -> Result<String, DbError> {
+        let (folders, files) = sibling_conflicts(store, parent, name, id);
+        if folders.is_empty() && files.is_empty() {
+            return Ok(name.to_string());
+        }
+        match policy {
+            ConflictPolicy::Fail => Err(DbError::NameConflict(name.to_string())),
+            ConflictPolicy::AutoRename => {
+                let mut i = 1;
+                loop {
+                    let candidate = format!("{} ({})", name, i);
+                    let (folders, files) = sibling_conflicts(store, parent, &candidate, id);
+                    if folders.is_empty() && files.is_empty() {
+                        return Ok(candidate);
+                    }
+                    i += 1;
+                }
+            }
+            ConflictPolicy::Overwrite => {
+                // Trashing a folder that contains the item would take the item with it.
+                let container = match store.files.iter().find(|f| f.id == id) {
+                    Some(file) => file.folder_id.clone(),
+                    None => Some(id.to_string()),
+                };
+                if container.is_some_and(|c| folders.iter().any(|f| is_within(store, &c, f))) {
+                    return Err(DbError::NameConflict(name.to_string()));
+                }
+                mark_trashed(store, &folders, &files, changes);
+                Ok(name.to_string())
+            }
+        }
+    }
+
+    /// A value kept in the settings table, or `None` when it was never set or can no
+    /// longer be read.
+    pub fn setting<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, DbError> {
+        let conn = self.conn()?;
+        read_setting(&conn, key)
+    }
+
+    pub fn set_setting<T: Serialize>(&self, key: &str, value: &T) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        write_setting(&conn, key, value)
+    }
+
+    /// The policy moves use unless the caller picks one, in the app and over WebDAV.
+    pub fn conflict_policy(&self) -> Result<ConflictPolicy, DbError> {
+        Ok(self.setting(CONFLICT_POLICY_SETTING)?.unwrap_or_default())
+    }
+
+    pub fn set_conflict_policy(&self, policy: ConflictPolicy) -> Result<(), DbError> {
+        self.set_setting(CONFLICT_POLICY_SETTING, &policy)
+    }
+
+    pub fn rename_folder(&self, id: &str, new_name: &str) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        let parent_id = store
+            .folders
+            .iter()
+            .find(|f| f.id == id)
+            .map(|f| f.parent_id.clone())
+            .ok_or(DbError::NotFound("Folder"))?;
+        let (folders, files) = sibling_conflicts(&store, parent_id.as_ref(), new_name, id);
+        if !folders.is_empty() || !files.is_empty() {
+            return Err(DbError::NameConflict(new_name.to_string()));
+        }
+        if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
+            folder.name = new_name.to_string();
+        }
+        let changes = Changes {
+            folders: vec![id.to_string()],
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)
+    }
+
+    pub fn get_folder_stats(&self, folder_id: &str) -> Result<(i64, i32), DbError> {
+        let store = self.read()?;
+        if !store.folders.iter().any(|f| f.id == folder_id) {
+            return Err(DbError::NotFound("Folder"));
+        }
+        Ok(self.calculate_stats_recursive(&store, folder_id))
+    }
+
+    fn calculate_stats_recursive(&self, store: &DataStore, folder_id: &str) -> (i64, i32) {
+        let mut total_size: i64 = 0;
+        let mut total_count: i32 = 0;
+
+        // Count files in this folder
+        let files_in_folder: Vec<&FileMetadata> = store
+            .files
+            .iter()
+            .filter(|f| f.folder_id.as_deref() == Some(folder_id) && !f.trashed)
+            .collect();
+
+        for file in files_in_folder {
+            total_size += file.size;
+            total_count += 1;
+        }
+
+        // Recursively count subfolders
+        let subfolders: Vec<&Folder> = store
+            .folders
+            .iter()
+            .filter(|f| f.parent_id.as_deref() == Some(folder_id) && !f.trashed)
+            .collect();
+
+        for folder in subfolders {
+            let (child_size, child_count) = self.calculate_stats_recursive(store, &folder.id);
+            total_size += child_size;
+            total_count += child_count; // Should we count the folder itself as 1 item?
+                                        // Usually "5 items" includes subfolders as items.
+            total_count += 1;
+        }
+
+        (total_size, total_count)
+    }
+
+    pub fn update_folder_metadata(
+        &self,
+        id: &str,
+        color: Option<String>,
+        icon: Option<String>,
+        gradient: Option<String>,
+        cover_image: Option<String>,
+        emoji: Option<String>,
+        pattern: Option<String>,
+        show_badges: Option<bool>,
+        tags: Option<Vec<String>>,
+        description: Option<String>,
+        // view_mode update logic or separate? Let's add it.
+        view_mode: Option<String>,
+    ) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        if let Some(folder) = store.folders.iter_mut().find(|f| f.id == id) {
+            if let Some(c) = color {
+                folder.color = if c.is_empty() { None } else { Some(c) };
+            }
+            if let Some(i) = icon {
+                folder.icon = if i.is_empty() { None } else { Some(i) };
+            }
+            if let Some(g) = gradient {
+                folder.gradient = if g.is_empty() { None } else { Some(g) };
+            }
+            if let Some(c) = cover_image {
+                folder.cover_image = if c.is_empty() { None } else { Some(c) };
+            }
+            if let Some(e) = emoji {
+                folder.emoji = if e.is_empty() { None } else { Some(e) };
+            }
+            if let Some(p) = pattern {
+                folder.pattern = if p.is_empty() { None } else { Some(p) };
+            }
+            if let Some(s) = show_badges {
+                folder.show_badges = s;
+            }
+            if let Some(t) = tags {
+                folder.tags = Some(t);
+            }
+            if let Some(d) = description {
+                folder.description = Some(d);
+            }
+            if let Some(v) = view_mode {
+                folder.view_mode = Some(v);
+            }
+
+            folder.last_modified = chrono::Utc::now().timestamp();
+
+            let changes = Changes {
+                folders: vec![id.to_string()],
+                ..Default::default()
+            };
+            self.persist(&mut store, changes)
+        } else {
+            Err(DbError::NotFound("Folder"))
+        }
+    }
+
+    // Permanently deletes trash batches older than `days` (0 empties the trash),
+    // including every descendant of the folders in them. Returns the Telegram
+    // messages that are no longer referenced.
+    pub fn cleanup_trash(&self, days: i64) -> Result<Vec<MessageRef>, DbError> {
+        let mut store = self.write()?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let limit = now - (days * 24 * 60 * 60);
+        let expired = |trashed: bool, trashed_at: Option<i64>| {
+            trashed && (days == 0 || trashed_at.unwrap_or(0) < limit)
+        };
+
+        let mut folder_ids = HashSet::new();
+        for f in store
+            .folders
+            .iter()
+            .filter(|f| expired(f.trashed, f.trashed_at))
+        {
+            folder_ids.extend(subtree_folder_ids(&store, &f.id));
+        }
+        let file_ids: HashSet<String> = store
+            .files
+            .iter()
+            .filter(|f| expired(f.trashed, f.trashed_at))
+            .map(|f| f.id.clone())
+            .collect();
+
+        let changes = remove_items(&mut store, &folder_ids, &file_ids);
+        let removed = changes.removed_messages.clone();
+        self.persist(&mut store, changes)?;
+        let messages = store.released(&removed);
+
+        Ok(messages)
+    }
+
+    pub fn toggle_star(&self, id: &str, is_folder: bool) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        let mut found = false;
+        let mut changes = Changes::default();
+
+        if is_folder {
+            if let Some(f) = store.folders.iter_mut().find(|f| f.id == id) {
+                f.is_starred = !f.is_starred;
+                changes.folders.push(f.id.clone());
+                found = true;
+            }
+        } else {
+            if let Some(f) = store.files.iter_mut().find(|f| f.id == id) {
+                f.is_starred = !f.is_starred;
+                changes.files.push(f.id.clone());
+                found = true;
+            }
+        }
+
+        if !found {
+            return Err(DbError::NotFound("Item"));
+        }
+        self.persist(&mut store, changes)
+    }
+
+    pub fn get_starred(&self) -> Result<(Vec<Folder>, Vec<FileMetadata>), DbError> {
+        let store = self.read()?;
+        let folders = store
+            .folders
+            .iter()
+            .filter(|f| f.is_starred && !f.trashed)
+            .cloned()
+            .collect();
+        let files = store
+            .files
+            .iter()
+            .filter(|f| f.is_starred && !f.trashed)
+            .cloned()
+            .collect();
+        Ok((folders, files))
+    }
+
+    pub fn search_items(&self, query: &str) -> Result<(Vec<Folder>, Vec<FileMetadata>), DbError> {
+        let store = self.read()?;
+        let query_lower = query.to_lowercase();
+
+        let folders = store
+            .folders
+            .iter()
+            .filter(|f| {
+                !f.trashed
+                    && (f.name.to_lowercase().contains(&query_lower)
+                        || f.tags.as_ref().map_or(false, |tags| {
+                            tags.iter().any(|t| t.to_lowercase().contains(&query_lower))
+                        }))
+            })
+            .cloned()
+            .collect();
+
+        let files = store
+            .files
+            .iter()
+            .filter(|f| !f.trashed && f.name.to_lowercase().contains(&query_lower))
+            .cloned()
+            .collect();
+
+        Ok((folders, files))
+    }
+
+    pub fn get_total_usage(&self) -> Result<i64, DbError> {
+        let store = self.read()?;
+        // Sum size of all NON-TRASHED files
+        Ok(store
+            .files
+            .iter()
+            .filter(|f| !f.trashed)
+            .map(|f| f.size)
+            .sum())
+    }
+    pub fn get_all_files(&self) -> Result<Vec<FileMetadata>, DbError> {
+        let store = self.read()?;
+        Ok(store.files.clone())
+    }
+
+    pub fn get_all_folders(&self) -> Result<Vec<Folder>, DbError> {
+        let store = self.read()?;
+        Ok(store.folders.clone())
+    }
+
+    pub fn delete_files_by_ids(&self, ids: &[String]) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        store.files.retain(|f| !ids.contains(&f.id));
+        let changes = Changes {
+            removed_files: ids.to_vec(),
+            ..Default::default()
+        };
+        self.persist(&mut store, changes)
+    }
+
+    /// Every message a file or a kept version refers to.
+    pub fn referenced_messages(&self) -> Result<Vec<MessageRef>, DbError> {
+        let store = self.read()?;
+        Ok(store
+            .files
+            .iter()
+            .flat_map(|f| f.referenced_messages())
+            .collect())
+    }
+
+    pub fn reload(&self) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        let mut store = self.store_write()?;
+        self.load_into(&conn, &mut store)?;
+        println!("Database reloaded from {:?}.", self.db_path);
+        Ok(())
+    }
+
+    /// Writes the whole store as a JSON snapshot (the legacy `metadata.json` layout).
+    /// Used for Telegram backups.
+    /// Sealed under the metadata key when the metadata is encrypted.
+    pub fn export_snapshot(&self, path: &Path) -> Result<(), DbError> {
+        let store = self.read()?;
+        write_document(path, SNAPSHOT_AAD, &*store, self.cipher()?.as_ref())
+    }
+
+    /// Replaces the whole store with a JSON snapshot in one transaction.
+    pub fn import_snapshot(&self, path: &Path) -> Result<(), DbError> {
+        let cipher = self.cipher()?;
+        let snapshot = read_snapshot(path, cipher.as_ref())?;
+        self.replace_contents(snapshot.folders, snapshot.files)
+    }
+
+    /// The folders and files in a snapshot written by `export_snapshot`, without
+    /// touching the store.
+    pub fn load_snapshot(&self, path: &Path) -> Result<(Vec<Folder>, Vec<FileMetadata>), DbError> {
+        let cipher = self.cipher()?;
+        let snapshot = read_snapshot(path, cipher.as_ref())?;
+        Ok((snapshot.folders, snapshot.files))
+    }
+
+    /// Adds the folders and files that are not in the store yet, by id. Items that
+    /// are keep their current state. Names taken meanwhile get a numbered one, and
+    /// items whose folder is gone go to the top level. Returns how many folders and
+    /// files were added.
+    pub fn merge_contents(
+        &self,
+        folders: Vec<Folder>,
+        files: Vec<FileMetadata>,
+    ) -> Result<(usize, usize), DbError> {
+        let mut store = self.write()?;
+        let mut changes = Changes::default();
+
+        // Parents go in before their children
+        let mut added: HashSet<String> = HashSet::new();
+        let mut pending: Vec<Folder> = folders
+            .into_iter()
+            .filter(|f| store.folder(&f.id).is_none())
+            .collect();
+        while !pending.is_empty() {
+            let known = |id: &String| store.folder(id).is_some() || added.contains(id);
+            let (ready, rest): (Vec<Folder>, Vec<Folder>) = pending
+                .into_iter()
+                .partition(|f| f.parent_id.as_ref().is_none_or(known));
+            pending = rest;
+            if ready.is_empty() {
+                // Below a folder that is in neither, or in a cycle
+                pending[0].parent_id = None;
+                continue;
+            }
+            for mut folder in ready {
+                folder.name =
+                    self.get_unique_name(&store, folder.parent_id.as_ref(), &folder.name, true);
+                added.insert(folder.id.clone());
+                changes.folders.push(folder.id.clone());
+                store.folders.push(folder);
+            }
+        }
+
+        for mut file in files {
+            if store.file(&file.id).is_some() {
+                continue;
+            }
+            if file
+                .folder_id
+                .as_ref()
+                .is_some_and(|id| store.folder(id).is_none() && !added.contains(id))
+            {
+                file.folder_id = None;
+            }
+            file.name = self.get_unique_name(&store, file.folder_id.as_ref(), &file.name, false);
+            changes.files.push(file.id.clone());
+            store.files.push(file);
+        }
+
+        let added = (changes.folders.len(), changes.files.len());
+        if added != (0, 0) {
+            self.persist(&mut store, changes)?;
+        }
+        Ok(added)
+    }
+
+    /// This installation's id in sync stamps.
+    pub fn device_id(&self) -> &str {
+        &self.device
+    }
+
+    /// What changed on this device after `since`, or everything it changed if
+    /// `None`. Only reads: records from before change tracking were stamped when the
+    /// store was opened.
+    pub fn changes_since(&self, since: Option<&Stamp>) -> Result<Delta, DbError> {
+        let conn = self.conn()?;
+        if self.is_locked() {
+            return Err(DbError::Locked);
+        }
+        let cipher = self.cipher()?;
+        // Tombstones and records from one snapshot, so a delta never carries a
+        // change without the ones stamped before it
+        let snapshot = conn.unchecked_transaction()?;
+        let tombstones = read_tombstones(&snapshot, cipher.as_ref())?;
+        if self.is_stale(&snapshot)? {
+            let mut store = self.store_write()?;
+            self.catch_up(&snapshot, &mut store)?;
+        }
+        drop(snapshot);
+        let store = self
+            .store
+            .read()
+            .map_err(|_| DbError::Corrupt("metadata lock poisoned".to_string()))?;
+
+        let newer =
+            |stamp: &Stamp| stamp.device == self.device && since.is_none_or(|since| stamp > since);
+        Ok(Delta {
+            device: self.device.clone(),
+            folders: store
+                .folders
+                .iter()
+                .filter(|f| f.stamp.as_ref().is_some_and(|s| newer(&s.changed)))
+                .cloned()
+                .collect(),
+            files: store
+                .files
+                .iter()
+                .filter(|f| f.stamp.as_ref().is_some_and(|s| newer(&s.changed)))
+                .cloned()
+                .collect(),
+            removed: tombstones.into_iter().filter(|t| newer(&t.stamp)).collect(),
+        })
+    }
+
+    /// Merges another device's changes, record by record. The later change wins,
+    /// except for names: a rename made after seeing the other one wins, and of two
+    /// renames made without seeing each other the later one wins and both are
+    /// returned as a conflict. Every device merges the same changes the same way.
+    pub fn apply_delta(&self, delta: &Delta) -> Result<Vec<SyncConflict>, DbError> {
+        let mut store = self.write()?;
+        let cipher = self.cipher()?;
+        let mut tombstones: HashMap<String, Stamp> = read_tombstones(&store.conn, cipher.as_ref())?
+            .into_iter()
+            .map(|t| (t.id, t.stamp))
+            .collect();
+        let mut clock = self.clock(&store.conn)?;
+        let mut changes = Changes::default();
+        let mut conflicts = Vec::new();
+
+        for folder in &delta.folders {
+            if let Some(merged) = merge_record(
+                &mut store.folders,
+                folder,
+                &tombstones,
+                &mut clock,
+                &self.device,
+            ) {
+                changes.folders.push(folder.id.clone());
+                conflicts.extend(merged.conflict(&folder.id, true));
+            }
+        }
+        for file in &delta.files {
+            if let Some(merged) = merge_record(
+                &mut store.files,
+                file,
+                &tombstones,
+                &mut clock,
+                &self.device,
+            ) {
+                changes.files.push(file.id.clone());
+                conflicts.extend(merged.conflict(&file.id, false));
+            }
+        }
+
+        for tombstone in &delta.removed {
+            clock.observe(&tombstone.stamp);
+            if tombstones
+                .get(&tombstone.id)
+                .is_some_and(|t| *t >= tombstone.stamp)
+            {
+                continue;
+            }
+            // Unless it changed here after it was deleted there
+            let outdated = |stamp: &Option<SyncStamp>| {
+                stamp.as_ref().is_none_or(|s| s.changed <= tombstone.stamp)
+            };
+            if tombstone.is_folder {
+                let before = store.folders.len();
+                store
+                    .folders
+                    .retain(|f| f.id != tombstone.id || !outdated(&f.stamp));
+                if store.folders.len() != before {
+                    changes.removed_folders.push(tombstone.id.clone());
+                }
+            } else {
+                let before = store.files.len();
+                store
+                    .files
+                    .retain(|f| f.id != tombstone.id || !outdated(&f.stamp));
+                if store.files.len() != before {
+                    changes.removed_files.push(tombstone.id.clone());
+                }
+            }
+            tombstones.insert(tombstone.id.clone(), tombstone.stamp.clone());
+            changes.tombstones.push(tombstone.clone());
+        }
+
+        // Items added here into a folder deleted there go to the top level
+        let folder_ids: HashSet<String> = store.folders.iter().map(|f| f.id.clone()).collect();
+        for folder in store.folders.iter_mut() {
+            if folder
+                .parent_id
+                .as_ref()
+                .is_some_and(|p| !folder_ids.contains(p))
+            {
+                folder.parent_id = None;
+                folder.stamp = Some(next_stamp(
+                    &mut clock,
+                    &self.device,
+                    folder.stamp.take(),
+                    false,
+                ));
+                changes.folders.push(folder.id.clone());
+            }
+        }
+        for file in store.files.iter_mut() {
+            if file
+                .folder_id
+                .as_ref()
+                .is_some_and(|p| !folder_ids.contains(p))
+            {
+                file.folder_id = None;
+                file.stamp = Some(next_stamp(
+                    &mut clock,
+                    &self.device,
+                    file.stamp.take(),
+                    false,
+                ));
+                changes.files.push(file.id.clone());
+            }
+        }
+
+        // Moves and renames that were fine on each side can clash once merged
+        break_cycles(&mut store, &mut changes, &mut clock, &self.device);
+        let folder_ids = changes.folders.clone();
+        conflicts.extend(settle_names(
+            &mut store.folders,
+            &folder_ids,
+            true,
+            &mut changes.folders,
+            &mut clock,
+            &self.device,
+        ));
+        let file_ids = changes.files.clone();
+        conflicts.extend(settle_names(
+            &mut store.files,
+            &file_ids,
+            false,
+            &mut changes.files,
+            &mut clock,
+            &self.device,
+        ));
+        write_setting(&store.conn, CLOCK_SETTING, &clock.latest(&self.device))?;
+        drop(clock);
+
+        let conflicts = conflicts
+            .into_iter()
+            .map(|(id, is_folder, other_name)| SyncConflict {
+                kept_name: if is_folder {
+                    store
+                        .folders
+                        .iter()
+                        .find(|f| f.id == id)
+                        .map(|f| f.name.clone())
+                } else {
+                    store
+                        .files
+                        .iter()
+                        .find(|f| f.id == id)
+                        .map(|f| f.name.clone())
+                }
+                .unwrap_or_default(),
+                id,
+                is_folder,
+                other_name,
+                detected_at: chrono::Utc::now().timestamp(),
+            })
+            .collect();
+        self.commit_changes(&mut store, changes)?;
+        Ok(conflicts)
+    }
+
+    /// Writes a delta for sending, sealed like a snapshot.
+    pub fn export_delta(&self, delta: &Delta, path: &Path) -> Result<(), DbError> {
+        write_document(path, DELTA_AAD, delta, self.cipher()?.as_ref())
+    }
+
+    pub fn read_delta(&self, path: &Path) -> Result<Delta, DbError> {
+        let value = read_document(path, DELTA_AAD, self.cipher()?.as_ref())?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Replaces every folder and file with the given ones in one transaction. Like
+    /// any other change it reaches the other devices: every record given is stamped
+    /// anew and every one it drops leaves a tombstone.
+    pub fn replace_contents(
+        &self,
+        folders: Vec<Folder>,
+        files: Vec<FileMetadata>,
+    ) -> Result<(), DbError> {
+        let mut store = self.write()?;
+        let kept_folders: HashSet<&String> = folders.iter().map(|f| &f.id).collect();
+        let kept_files: HashSet<&String> = files.iter().map(|f| &f.id).collect();
+        let changes = Changes {
+            folders: folders.iter().map(|f| f.id.clone()).collect(),
+            files: files.iter().map(|f| f.id.clone()).collect(),
+            removed_folders: store
+                .folders
+                .iter()
+                .filter(|f| !kept_folders.contains(&f.id))
+                .map(|f| f.id.clone())
+                .collect(),
+            removed_files: store
+                .files
+                .iter()
+                .filter(|f| !kept_files.contains(&f.id))
+                .map(|f| f.id.clone())
+                .collect(),
+            ..Default::default()
+        };
+        store.folders = folders;
+        store.files = files;
+        // Ids may have kept their count but not their places
+        store.index_positions();
+        self.persist(&mut store, changes)
+    }
+
+    /// Every job in the upload queue, in the order they were added. Jobs live next
+    /// to the metadata but outside the store, so progress updates do not touch it.
+    pub fn upload_jobs(&self) -> Result<Vec<UploadJob>, DbError> {
+        let conn = self.conn()?;
+        if self.is_locked() {
+            return Err(DbError::Locked);
+        }
+        let cipher = self.cipher()?;
+        let jobs = read_rows(&conn, UPLOAD_JOBS_TABLE, cipher.as_ref())?
+            .into_iter()
+            .map(from_value)
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(jobs)
+    }
+
+    pub fn upload_job(&self, id: &str) -> Result<Option<UploadJob>, DbError> {
+        let conn = self.conn()?;
+        if self.is_locked() {
+            return Err(DbError::Locked);
+        }
+        let cipher = self.cipher()?;
+        let data: Option<String> = conn
+            .query_row(
+                "SELECT data FROM upload_jobs WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let job = data
+            .map(|data| decode_row(UPLOAD_JOBS_TABLE, id, &data, cipher.as_ref()))
+            .transpose()?
+            .map(from_value)
+            .transpose()?;
+        Ok(job)
+    }
+
+    /// Inserts the job or replaces the stored copy.
+    pub fn save_upload_job(&self, job: &UploadJob) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        if self.is_locked() {
+            return Err(DbError::Locked);
+        }
+        let cipher = self.cipher()?;
+        conn.execute(
+            "INSERT INTO upload_jobs (id, data) VALUES (?1, ?2)
+             ON CONFLICT(id) DO UPDATE SET data = excluded.data",
+            params![
+                job.id,
+                encode_row(UPLOAD_JOBS_TABLE, &job.id, job, cipher.as_ref())?
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_upload_job(&self, id: &str) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        if self.is_locked() {
+            return Err(DbError::Locked);
+        }
+        conn.execute("DELETE FROM upload_jobs WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+}
+
+fn open_connection(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "FULL")?;
+    // Overwrite deleted content, so plaintext does not linger in free pages
+    conn.pragma_update(None, "secure_delete", true)?;
+    Ok(conn)
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+fn data_version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.pragma_query_value(None, "data_version", |row| row.get(0))
+}
+
+// Number of the last change to a folder or file row, see 005_change_counter.sql.
+fn change_counter(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT value FROM change_counter", [], |row| row.get(0))
+}
+
+fn row_count(conn: &Connection, table: &str) -> rusqlite::Result<usize> {
+    conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+        row.get(0)
+    })
+}
+
+fn row_ids(conn: &Connection, table: &str) -> rusqlite::Result<HashSet<String>> {
+    let mut stmt = conn.prepare(&format!("SELECT id FROM {}", table))?;
+    let ids = stmt.query_map([], |row| row.get(0))?;
+    ids.collect()
+}
+
+// Upgrades the database one version at a time, each step in its own transaction.
+// Databases from a newer release are refused rather than rewritten, and anything
+// that already holds data is copied aside before the first step runs.
+fn migrate(
+    conn: &mut Connection,
+    db_path: &Path,
+    cipher: Option<&RecordCipher>,
+) -> Result<(), DbError> {
+    let found = schema_version(conn)?;
+    if found > SCHEMA_VERSION {
+        return Err(DbError::SchemaVersion {
+            found,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    if found == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    // Databases created before versioning report 0 but already have tables.
+    let has_tables: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'folders')",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_tables {
+        let backup_path = db_path.with_extension(format!("db.v{}.bak", found));
+        let _ = std::fs::remove_file(&backup_path);
+        conn.execute("VACUUM INTO ?1", params![backup_path.to_string_lossy()])?;
+        println!("Backed up metadata to {:?} before migrating", backup_path);
+    }
+
+    for version in (found + 1)..=SCHEMA_VERSION {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        apply_migration(&tx, version, cipher)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        println!("Migrated metadata schema to version {}", version);
+    }
+    Ok(())
+}
+
+// Upgrades the database from `version - 1` to `version`.
+fn apply_migration(
+    tx: &rusqlite::Transaction,
+    version: u32,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<()> {
+    match version {
+        1 => return tx.execute_batch(INITIAL_SCHEMA),
+        4 => return tx.execute_batch(SETTINGS_SCHEMA),
+        5 => return tx.execute_batch(CHANGE_COUNTER_SCHEMA),
+        8 => return tx.execute_batch(UPLOAD_JOBS_SCHEMA),
+        9 => return tx.execute_batch(TOMBSTONES_SCHEMA),
+        _ => {}
+    }
+
+    let mut snapshot = serde_json::json!({
+        "folders": read_rows(tx, "folders", cipher)?,
+        "files": read_rows(tx, "files", cipher)?,
+    });
+    upgrade_snapshot(&mut snapshot, version);
+
+    if let Some(folders) = snapshot["folders"].as_array() {
+        for folder in folders {
+            tx.execute(
+                "UPDATE folders SET parent_id = ?1, data = ?2 WHERE id = ?3",
+                params![
+                    folder["parent_id"].as_str(),
+                    encode_row(
+                        "folders",
+                        folder["id"].as_str().unwrap_or_default(),
+                        folder,
+                        cipher
+                    )?,
+                    folder["id"].as_str()
+                ],
+            )?;
+        }
+    }
+    if let Some(files) = snapshot["files"].as_array() {
+        for file in files {
+            tx.execute(
+                "UPDATE files SET folder_id = ?1, data = ?2 WHERE id = ?3",
+                params![
+                    file["folder_id"].as_str(),
+                    encode_row(
+                        "files",
+                        file["id"].as_str().unwrap_or_default(),
+                        file,
+                        cipher
+                    )?,
+                    file["id"].as_str()
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn read_rows(
+    conn: &Connection,
+    table: &str,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<Vec<serde_json::Value>> {
+    let mut stmt = conn.prepare(&format!("SELECT id, data FROM {} ORDER BY rowid", table))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    decode_rows(table, rows, cipher)
+}
+
+// Rows of `table` changed after change `since`.
+fn read_rows_since(
+    conn: &Connection,
+    table: &str,
+    since: i64,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<Vec<serde_json::Value>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT id, data FROM {} WHERE changed > ?1 ORDER BY rowid",
+        table
+    ))?;
+    let rows = stmt.query_map(params![since], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    decode_rows(table, rows, cipher)
+}
+
+fn decode_rows(
+    table: &str,
+    rows: impl Iterator<Item = rusqlite::Result<(String, String)>>,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<Vec<serde_json::Value>> {
+    let mut values = Vec::new();
+    for row in rows {
+        let (id, data) = row?;
+        values.push(decode_row(table, &id, &data, cipher)?);
+    }
+    Ok(values)
+}
+
+// Upgrades a `{ "folders": [...], "files": [...] }` document from `version - 1` to
+// `version`. Shared by database migrations and snapshot imports, so it works on raw
+// JSON rather than on the current structs.
+fn upgrade_snapshot(snapshot: &mut serde_json::Value, version: u32) {
+    match version {
+        2 => {
+            // `last_modified` used to default to 0 for folders that were never customised.
+            for folder in snapshot["folders"].as_array_mut().into_iter().flatten() {
+                if folder["last_modified"].as_i64().unwrap_or(0) == 0 {
+                    folder["last_modified"] = folder["created_at"].clone();
+                }
+            }
+        }
+        3 => cascade_legacy_trash(snapshot),
+        // 4 added the settings table and 5 the change counter, which snapshots do not
+        // carry.
+        // 6 added `FileMetadata::encryption` and `Folder::encrypted`. Both default to
+        // off, so nothing is rewritten; the bump keeps older releases from serving
+        // sealed blobs as plain files.
+        // 7 added `FileMetadata::segments`, empty for every existing file. Older
+        // releases would serve only the first segment of a split file.
+        _ => {}
+    }
+}
+
+// Trashing a folder used to flag only the folder itself. Give every such folder a
+// trash batch and push it down to the descendants that were left behind.
+fn cascade_legacy_trash(snapshot: &mut serde_json::Value) {
+    use serde_json::Value;
+
+    let folders = snapshot["folders"].as_array().cloned().unwrap_or_default();
+    let mut children: std::collections::HashMap<String, Vec<String>> =
+        std::collections::HashMap::new();
+    for folder in &folders {
+        if let (Some(id), Some(parent)) = (folder["id"].as_str(), folder["parent_id"].as_str()) {
+            children
+                .entry(parent.to_string())
+                .or_default()
+                .push(id.to_string());
+        }
+    }
+
+    // folder id -> (trashed_at, batch) it inherits
+    let mut inherited: std::collections::HashMap<String, (Value, String)> =
+        std::collections::HashMap::new();
+    for folder in &folders {
+        if folder["trashed"].as_bool() != Some(true) || !folder["trash_batch"].is_null() {
+            continue;
+        }
+        let Some(root) = folder["id"].as_str() else {
+            continue;
+        };
+        let batch = Uuid::new_v4().to_string();
+        let mut queue = vec![root.to_string()];
+        while let Some(id) = queue.pop() {
+            if inherited.contains_key(&id) {
+                continue;
+            }
+            inherited.insert(id.clone(), (folder["trashed_at"].clone(), batch.clone()));
+            queue.extend(children.get(&id).cloned().unwrap_or_default());
+        }
+    }
+
+    for folder in snapshot["folders"].as_array_mut().into_iter().flatten() {
+        let Some((trashed_at, batch)) = folder["id"].as_str().and_then(|id| inherited.get(id))
+        else {
+            continue;
+        };
+        if folder["trashed"].as_bool() != Some(true) || folder["trash_batch"].is_null() {
+            folder["trashed"] = Value::Bool(true);
+            folder["trashed_at"] = trashed_at.clone();
+            folder["trash_batch"] = Value::String(batch.clone());
+        }
+    }
+    for file in snapshot["files"].as_array_mut().into_iter().flatten() {
+        if file["trashed"].as_bool() == Some(true) {
+            if file["trash_batch"].is_null() {
+                file["trash_batch"] = Value::String(Uuid::new_v4().to_string());
+            }
+            continue;
+        }
+        let Some((trashed_at, batch)) = file["folder_id"].as_str().and_then(|id| inherited.get(id))
+        else {
+            continue;
+        };
+        file["trashed"] = Value::Bool(true);
+        file["trashed_at"] = trashed_at.clone();
+        file["trash_batch"] = Value::String(batch.clone());
+    }
+}
+
+fn load_store(conn: &Connection, cipher: Option<&RecordCipher>) -> rusqlite::Result<DataStore> {
+    // Both tables must come from the same snapshot while the other process writes.
+    let _snapshot = if conn.is_autocommit() {
+        Some(conn.unchecked_transaction()?)
+    } else {
+        None
+    };
+
+    let mut store = DataStore {
+        schema_version: SCHEMA_VERSION,
+        ..Default::default()
+    };
+
+    for value in read_rows(conn, "folders", cipher)? {
+        store.folders.push(from_value(value)?);
+    }
+    for value in read_rows(conn, "files", cipher)? {
+        store.files.push(from_value(value)?);
+    }
+
+    store.reindex();
+    Ok(store)
+}
+
+// Runs inside the transaction opened by `Database::write`.
+fn write_changes(
+    conn: &Connection,
+    store: &DataStore,
+    changes: &Changes,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<()> {
+    let mut delete_folder = conn.prepare_cached("DELETE FROM folders WHERE id = ?1")?;
+    for id in &changes.removed_folders {
+        delete_folder.execute(params![id])?;
+    }
+    let mut delete_file = conn.prepare_cached("DELETE FROM files WHERE id = ?1")?;
+    for id in &changes.removed_files {
+        delete_file.execute(params![id])?;
+    }
+    let folders: HashSet<&String> = changes.folders.iter().collect();
+    for folder in store.folders.iter().filter(|f| folders.contains(&f.id)) {
+        upsert_folder(conn, folder, cipher)?;
+    }
+    let files: HashSet<&String> = changes.files.iter().collect();
+    for file in store.files.iter().filter(|f| files.contains(&f.id)) {
+        upsert_file(conn, file, cipher)?;
+    }
+    for tombstone in &changes.tombstones {
+        conn.prepare_cached(
+            "INSERT INTO tombstones (id, data) VALUES (?1, ?2)
+             ON CONFLICT(id) DO UPDATE SET data = excluded.data",
+        )?
+        .execute(params![
+            tombstone.id,
+            encode_row(TOMBSTONES_TABLE, &tombstone.id, tombstone, cipher)?
+        ])?;
+    }
+    Ok(())
+}
+
+// This installation's id in sync stamps, created on first use.
+fn device_id(conn: &Connection) -> Result<String, DbError> {
+    if let Some(id) = read_setting(conn, DEVICE_ID_SETTING)? {
+        return Ok(id);
+    }
+    conn.execute(
+        "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+        params![
+            DEVICE_ID_SETTING,
+            serde_json::to_string(&Uuid::new_v4().to_string())?
+        ],
+    )?;
+    // The other process may have got there first
+    read_setting(conn, DEVICE_ID_SETTING)?.ok_or(DbError::NotFound("Device id"))
+}
+
+fn read_tombstones(
+    conn: &Connection,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<Vec<Tombstone>> {
+    read_rows(conn, TOMBSTONES_TABLE, cipher)?
+        .into_iter()
+        .map(from_value)
+        .collect()
+}
+
+// The stamp of a record changed on `device` now. The clock first observes the one it
+// had, so a record's stamps only grow even after a change from a clock ahead of ours.
+fn next_stamp(
+    clock: &mut Clock,
+    device: &str,
+    previous: Option<SyncStamp>,
+    renamed: bool,
+) -> SyncStamp {
+    if let Some(previous) = &previous {
+        clock.observe(&previous.changed);
+    }
+    let changed = clock.tick(device);
+    let (last_rename, renamed_over) = previous
+        .map(|p| (p.renamed, p.renamed_over))
+        .unwrap_or_default();
+    if renamed {
+        SyncStamp {
+            renamed: Some(changed.clone()),
+            renamed_over: last_rename,
+            changed,
+        }
+    } else {
+        SyncStamp {
+            changed,
+            renamed: last_rename,
+            renamed_over,
+        }
+    }
+}
+
+// Folders and files, as far as merging them is concerned.
+trait SyncedRecord: Clone {
+    fn id(&self) -> &str;
+    fn parent(&self) -> Option<&String>;
+    fn is_trashed(&self) -> bool;
+    fn name(&self) -> &str;
+    fn set_name(&mut self, name: String);
+    fn stamp(&self) -> Option<&SyncStamp>;
+    fn stamp_mut(&mut self) -> &mut Option<SyncStamp>;
+}
+
+impl SyncedRecord for Folder {
+    fn id(&self) -> &str {
+        &self.id
+    }
+    fn parent(&self) -> Option<&String> {
+        self.parent_id.as_ref()
+    }
+    fn is_trashed(&self) -> bool {
+        self.trashed
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+    fn stamp(&self) -> Option<&SyncStamp> {
+        self.stamp.as_ref()
+    }
+    fn stamp_mut(&mut self) -> &mut Option<SyncStamp> {
+        &mut self.stamp
+    }
+}
+
+impl SyncedRecord for FileMetadata {
+    fn id(&self) -> &str {
+        &self.id
+    }
+    fn parent(&self) -> Option<&String> {
+        self.folder_id.as_ref()
+    }
+    fn is_trashed(&self) -> bool {
+        self.trashed
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+    fn stamp(&self) -> Option<&SyncStamp> {
+        self.stamp.as_ref()
+    }
+    fn stamp_mut(&mut self) -> &mut Option<SyncStamp> {
+        &mut self.stamp
+    }
+}
+
+// A record that changed in a merge, with the name it lost to a concurrent rename.
+struct Merged {
+    lost_name: Option<String>,
+}
+
+impl Merged {
+    fn conflict(self, id: &str, is_folder: bool) -> Option<(String, bool, String)> {
+        self.lost_name.map(|name| (id.to_string(), is_folder, name))
+    }
+}
+
+// Merges an incoming copy of a record into `records`. Returns `None` when the local
+// copy stays as it was and there is no conflict to report.
+fn merge_record<T: SyncedRecord>(
+    records: &mut Vec<T>,
+    incoming: &T,
+    tombstones: &HashMap<String, Stamp>,
+    clock: &mut Clock,
+    device: &str,
+) -> Option<Merged> {
+    let remote = incoming.stamp()?;
+    clock.observe(&remote.changed);
+    if tombstones
+        .get(incoming.id())
+        .is_some_and(|t| *t >= remote.changed)
+    {
+        return None;
+    }
+    let Some(local) = records.iter_mut().find(|r| r.id() == incoming.id()) else {
+        records.push(incoming.clone());
+        return Some(Merged { lost_name: None });
+    };
+
+    let local_stamp = local.stamp().cloned();
+    let take_remote = local_stamp
+        .as_ref()
+        .is_none_or(|l| remote.changed > l.changed);
+    let (local_rename, local_over) = local_stamp
+        .as_ref()
+        .map(|l| (l.renamed.clone(), l.renamed_over.clone()))
+        .unwrap_or_default();
+
+    let mut lost_name = None;
+    let name_from_remote = if local.name() == incoming.name() || remote.renamed == local_rename {
+        take_remote
+    } else if remote.renamed_over == local_rename {
+        // Renamed there after our rename arrived
+        true
+    } else if local_over == remote.renamed {
+        false
+    } else {
+        let remote_wins = remote.renamed > local_rename;
+        lost_name = Some(if remote_wins {
+            local.name().to_string()
+        } else {
+            incoming.name().to_string()
+        });
+        remote_wins
+    };
+    if !take_remote && !name_from_remote {
+        // Ours stays as it is, but the lost rename is still reported
+        return lost_name.map(|name| Merged {
+            lost_name: Some(name),
+        });
+    }
+
+    let mut merged = if take_remote {
+        incoming.clone()
+    } else {
+        local.clone()
+    };
+    let named = if name_from_remote { incoming } else { &*local };
+    let (renamed, renamed_over) = named
+        .stamp()
+        .map(|s| (s.renamed.clone(), s.renamed_over.clone()))
+        .unwrap_or_default();
+    merged.set_name(named.name().to_string());
+    if let Some(stamp) = merged.stamp_mut() {
+        stamp.renamed = renamed;
+        stamp.renamed_over = renamed_over;
+    }
+    // Keeping our name on their record makes it a change the others have not seen
+    if take_remote && !name_from_remote {
+        if let Some(stamp) = merged.stamp_mut() {
+            clock.observe(&stamp.changed);
+            stamp.changed = clock.tick(device);
+        }
+    }
+    *local = merged;
+    Some(Merged { lost_name })
+}
+
+// Two devices can each move a folder into the other one. Once merged, the folders in
+// such a loop would be cut off from the top level, so of each loop the one changed
+// last moves there. Every device merging the same changes picks the same one.
+fn break_cycles(store: &mut DataStore, changes: &mut Changes, clock: &mut Clock, device: &str) {
+    let mut parents: HashMap<String, Option<String>> = store
+        .folders
+        .iter()
+        .map(|f| (f.id.clone(), f.parent_id.clone()))
+        .collect();
+    for id in changes.folders.clone() {
+        loop {
+            let mut path: Vec<String> = Vec::new();
+            let mut current = Some(id.clone());
+            let mut cycle = None;
+            while let Some(folder) = current {
+                if let Some(start) = path.iter().position(|p| *p == folder) {
+                    cycle = Some(path.split_off(start));
+                    break;
+                }
+                current = parents.get(&folder).cloned().flatten();
+                path.push(folder);
+            }
+            let Some(cycle) = cycle else {
+                break;
+            };
+            let Some(folder) = store
+                .folders
+                .iter_mut()
+                .filter(|f| cycle.contains(&f.id))
+                .max_by(|a, b| {
+                    let changed = |f: &Folder| f.stamp.as_ref().map(|s| s.changed.clone());
+                    changed(a).cmp(&changed(b))
+                })
+            else {
+                break;
+            };
+            folder.parent_id = None;
+            folder.stamp = Some(next_stamp(clock, device, folder.stamp.take(), false));
+            parents.insert(folder.id.clone(), None);
+            changes.folders.push(folder.id.clone());
+        }
+    }
+}
+
+// Merged records in `ids` that now share their name with a sibling. Of each pair the
+// one changed last gets a numbered name, as a new rename that reaches the others,
+// and is returned as a conflict over the name it had.
+fn settle_names<T: SyncedRecord>(
+    records: &mut [T],
+    ids: &[String],
+    is_folder: bool,
+    changed: &mut Vec<String>,
+    clock: &mut Clock,
+    device: &str,
+) -> Vec<(String, bool, String)> {
+    let mut conflicts = Vec::new();
+    for id in ids {
+        let Some(i) = records.iter().position(|r| r.id() == id) else {
+            continue;
+        };
+        if records[i].is_trashed() {
+            continue;
+        }
+        let clashes = |r: &T| {
+            r.id() != id
+                && !r.is_trashed()
+                && r.parent() == records[i].parent()
+                && r.name() == records[i].name()
+        };
+        let Some(j) = records.iter().position(clashes) else {
+            continue;
+        };
+        let stamp = |r: &T| r.stamp().map(|s| s.changed.clone());
+        let loser = if stamp(&records[i]) > stamp(&records[j]) {
+            i
+        } else {
+            j
+        };
+
+        let name = records[loser].name().to_string();
+        let parent = records[loser].parent().cloned();
+        let taken = |candidate: &str| {
+            records
+                .iter()
+                .any(|r| !r.is_trashed() && r.parent() == parent.as_ref() && r.name() == candidate)
+        };
+        let mut n = 1;
+        let mut unique = format!("{} ({})", name, n);
+        while taken(&unique) {
+            n += 1;
+            unique = format!("{} ({})", name, n);
+        }
+
+        let record = &mut records[loser];
+        record.set_name(unique);
+        let stamp = record.stamp_mut().take();
+        *record.stamp_mut() = Some(next_stamp(clock, device, stamp, true));
+        changed.push(record.id().to_string());
+        conflicts.push((record.id().to_string(), is_folder, name));
+    }
+    conflicts
+}
+
+fn replace_all(conn: &mut Connection, store: &DataStore) -> rusqlite::Result<()> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    replace_rows(&tx, store, None)?;
+    tx.commit()
+}
+
+fn replace_rows(
+    conn: &Connection,
+    store: &DataStore,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM folders", [])?;
+    conn.execute("DELETE FROM files", [])?;
+    for folder in &store.folders {
+        upsert_folder(conn, folder, cipher)?;
+    }
+    for file in &store.files {
+        upsert_file(conn, file, cipher)?;
+    }
+    Ok(())
+}
+
+// Rewrites every row sealed under `cipher` and records its key, in one transaction.
+// Unless the other process got there first.
+fn seal_rows(conn: &mut Connection, cipher: &RecordCipher) -> Result<(), DbError> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    if sealed_key_id(&tx)?.is_none() {
+        let store = load_store(&tx, None)?;
+        replace_rows(&tx, &store, Some(cipher))?;
+        for table in [UPLOAD_JOBS_TABLE, TOMBSTONES_TABLE] {
+            for row in read_rows(&tx, table, None)? {
+                let id = row["id"].as_str().unwrap_or_default();
+                tx.execute(
+                    &format!("UPDATE {} SET data = ?1 WHERE id = ?2", table),
+                    params![encode_row(table, id, &row, Some(cipher))?, id],
+                )?;
+            }
+        }
+        tx.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![METADATA_KEY_SETTING, cipher.key_id()],
+        )?;
+    }
+    tx.commit()?;
+
+    // Old plaintext pages may still sit in the WAL and the free list
+    let compact = conn
+        .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+        .and_then(|_| conn.execute_batch("VACUUM"));
+    if let Err(e) = compact {
+        eprintln!("Failed to compact metadata after encrypting it: {}", e);
+    }
+    Ok(())
+}
+
+// Pre-migration backups and the imported metadata.json hold the same records in the
+// clear. Once the rows are sealed they have to go.
+fn remove_plaintext_copies(db_path: &Path) {
+    let Some(app_dir) = db_path.parent() else {
+        return;
+    };
+    let Ok(entries) = std::fs::read_dir(app_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_backup = name.starts_with("metadata.db.v") && name.ends_with(".bak");
+        if is_backup || name == "metadata.json.migrated" {
+            if let Err(e) = std::fs::remove_file(entry.path()) {
+                eprintln!("Failed to remove plaintext copy {}: {}", name, e);
+            }
+        }
+    }
+}
+
+// Key id the rows are sealed under, if they are.
+fn sealed_key_id(conn: &Connection) -> rusqlite::Result<Option<String>> {
+    let has_settings: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'settings')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_settings {
+        return Ok(None);
+    }
+    conn.query_row(
+        "SELECT value FROM settings WHERE key = ?1",
+        params![METADATA_KEY_SETTING],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+fn upsert_folder(
+    conn: &Connection,
+    folder: &Folder,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO folders (id, parent_id, data) VALUES (?1, ?2, ?3)
+         ON CONFLICT(id) DO UPDATE SET parent_id = excluded.parent_id, data = excluded.data",
+    )?
+    .execute(params![
+        folder.id,
+        folder.parent_id,
+        encode_row("folders", &folder.id, folder, cipher)?
+    ])?;
+    Ok(())
+}
+
+fn upsert_file(
+    conn: &Connection,
+    file: &FileMetadata,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO files (id, folder_id, message_id, data) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(id) DO UPDATE SET folder_id = excluded.folder_id,
+             message_id = excluded.message_id, data = excluded.data",
+    )?
+    .execute(params![
+        file.id,
+        file.folder_id,
+        file.message_id,
+        encode_row("files", &file.id, file, cipher)?
+    ])?;
+    Ok(())
+}
+
+// Writes `value` as JSON, sealed under the metadata key when there is one.
+fn write_document<T: Serialize>(
+    path: &Path,
+    aad: &[u8],
+    value: &T,
+    cipher: Option<&RecordCipher>,
+) -> Result<(), DbError> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    match cipher {
+        Some(cipher) => {
+            let json = serde_json::to_vec(value)?;
+            writer.write_all(SEALED_PREFIX.as_bytes())?;
+            writer.write_all(BASE64.encode(cipher.seal(aad, &json)).as_bytes())?;
+        }
+        None => serde_json::to_writer(&mut writer, value)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_document(
+    path: &Path,
+    aad: &[u8],
+    cipher: Option<&RecordCipher>,
+) -> Result<serde_json::Value, DbError> {
+    let data = std::fs::read(path)?;
+    Ok(match data.strip_prefix(SEALED_PREFIX.as_bytes()) {
+        Some(sealed) => {
+            let cipher = cipher.ok_or(DbError::Locked)?;
+            let sealed = BASE64
+                .decode(sealed)
+                .map_err(|e| DbError::Corrupt(e.to_string()))?;
+            let json = cipher
+                .open(aad, &sealed)
+                .map_err(|e| DbError::Corrupt(e.to_string()))?;
+            serde_json::from_slice(&json)?
+        }
+        None => serde_json::from_slice(&data)?,
+    })
+}
+
+// Reads a JSON snapshot (legacy `metadata.json` or a backup) and upgrades its
+// records to the current schema.
+fn read_snapshot(path: &Path, cipher: Option<&RecordCipher>) -> Result<DataStore, DbError> {
+    let mut value = read_document(path, SNAPSHOT_AAD, cipher)?;
+
+    let found = value
+        .get("schema_version")
+        .and_then(|v| v.as_u64())
+        .map(|v| v as u32)
+        .unwrap_or(1)
+        .max(1);
+    if found > SCHEMA_VERSION {
+        return Err(DbError::SchemaVersion {
+            found,
+            supported: SCHEMA_VERSION,
+        });
+    }
+
+    for version in (found + 1)..=SCHEMA_VERSION {
+        upgrade_snapshot(&mut value, version);
+    }
+
+    let mut store: DataStore = serde_json::from_value(value)?;
+    store.schema_version = SCHEMA_VERSION;
+    store.reindex();
+    Ok(store)
+}
+
+fn write_setting<T: Serialize>(conn: &Connection, key: &str, value: &T) -> Result<(), DbError> {
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, serde_json::to_string(value)?],
+    )?;
+    Ok(())
+}
+
+fn read_setting<T: DeserializeOwned>(conn: &Connection, key: &str) -> Result<Option<T>, DbError> {
+    let value: Option<String> = conn
+        .query_row(
+            "SELECT value FROM settings WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+}
+
+// Row payloads are plain JSON, or JSON sealed under the metadata key. A sealed row is
+// bound to its table and id, so rows cannot be swapped around on disk.
+fn encode_row<T: Serialize>(
+    table: &str,
+    id: &str,
+    value: &T,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<String> {
+    let json = to_json(value)?;
+    Ok(match cipher {
+        Some(cipher) => {
+            let sealed = cipher.seal(row_aad(table, id).as_bytes(), json.as_bytes());
+            format!("{}{}", SEALED_PREFIX, BASE64.encode(sealed))
+        }
+        None => json,
+    })
+}
+
+fn decode_row(
+    table: &str,
+    id: &str,
+    data: &str,
+    cipher: Option<&RecordCipher>,
+) -> rusqlite::Result<serde_json::Value> {
+    let Some(sealed) = data.strip_prefix(SEALED_PREFIX) else {
+        return from_json(data);
+    };
+    let conversion_error = |e: String| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+    };
+    let cipher = cipher.ok_or_else(|| conversion_error(DbError::Locked.to_string()))?;
+    let sealed = BASE64
+        .decode(sealed)
+        .map_err(|e| conversion_error(e.to_string()))?;
+    let json = cipher
+        .open(row_aad(table, id).as_bytes(), &sealed)
+        .map_err(|e| conversion_error(format!("{} row {}: {}", table, id, e)))?;
+    serde_json::from_slice(&json).map_err(|e| conversion_error(e.to_string()))
+}
+
+fn row_aad(table: &str, id: &str) -> String {
+    format!("{}/{}", table, id)
+}
+
+fn from_value<T: serde::de::DeserializeOwned>(value: serde_json::Value) -> rusqlite::Result<T> {
+    serde_json::from_value(value).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+fn from_json<T: DeserializeOwned>(data: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(data).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("paperfold_db_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn open() -> (Database, PathBuf) {
+        let dir = temp_dir();
+        (Database::new(dir.to_str().unwrap()).unwrap(), dir)
+    }
+
+    fn file(db: &Database, folder_id: Option<&String>, name: &str, message_id: i32) -> String {
+        let segments = vec![Segment {
+            message_id,
+            size: 1,
+        }];
+        db.add_file(
+            folder_id.cloned(),
+            name.into(),
+            1,
+            "text/plain".into(),
+            segments,
+            None,
+        )
+        .unwrap()
+        .id
+    }
+
+    // A/B/C/D/E below the root, returning their ids from the top.
+    fn chain(db: &Database) -> Vec<String> {
+        let mut ids: Vec<String> = Vec::new();
+        for name in ["A", "B", "C", "D", "E"] {
+            ids.push(db.create_folder(name, ids.last().cloned()).unwrap());
+        }
+        ids
+    }
+
+    #[test]
+    fn moving_a_folder_below_itself_is_refused() {
+        let (db, dir) = open();
+        let ids = chain(&db);
+        for target in &ids {
+            let moved = db.move_folder(&ids[0], Some(target.clone()), "A", ConflictPolicy::Fail);
+            assert!(matches!(moved, Err(DbError::CycleDetected)));
+        }
+        let moved = db.move_folder(&ids[1], Some(ids[4].clone()), "B", ConflictPolicy::Fail);
+        assert!(matches!(moved, Err(DbError::CycleDetected)));
+        assert_eq!(db.path_of(&ids[4]).unwrap(), "/A/B/C/D/E");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn moving_into_a_deep_unrelated_folder() {
+        let (db, dir) = open();
+        let ids = chain(&db);
+        let other = db.create_folder("Other", None).unwrap();
+        let inner = db.create_folder("Inner", Some(other.clone())).unwrap();
+        db.move_folder(&other, Some(ids[4].clone()), "Other", ConflictPolicy::Fail)
+            .unwrap();
+        assert_eq!(db.path_of(&inner).unwrap(), "/A/B/C/D/E/Other/Inner");
+        // Moving a deep folder back up to the root is fine too
+        db.move_folder(&ids[3], None, "D", ConflictPolicy::Fail)
+            .unwrap();
+        assert_eq!(db.path_of(&inner).unwrap(), "/D/E/Other/Inner");
+        assert!(matches!(
+            db.resolve_path("/D/E/Other/Inner").unwrap(),
+            Some(Entry::Folder(f)) if f.id == inner
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn conflict_policies_on_move_file() {
+        let (db, dir) = open();
+        let target = db.create_folder("Target", None).unwrap();
+        let existing = file(&db, Some(&target), "a.txt", 1);
+        let moved = file(&db, None, "a.txt", 2);
+
+        let result = db.move_file(&moved, Some(target.clone()), "a.txt", ConflictPolicy::Fail);
+        assert!(matches!(result, Err(DbError::NameConflict(_))));
+        assert_eq!(db.get_file(&moved).unwrap().folder_id, None);
+
+        let name = db
+            .move_file(
+                &moved,
+                Some(target.clone()),
+                "a.txt",
+                ConflictPolicy::AutoRename,
+            )
+            .unwrap();
+        assert_eq!(name, "a.txt (1)");
+        assert_eq!(db.get_file(&moved).unwrap().folder_id, Some(target.clone()));
+        assert!(!db.get_file(&existing).unwrap().trashed);
+
+        let name = db
+            .move_file(
+                &moved,
+                Some(target.clone()),
+                "a.txt",
+                ConflictPolicy::Overwrite,
+            )
+            .unwrap();
+        assert_eq!(name, "a.txt");
+        assert!(db.get_file(&existing).unwrap().trashed);
+        assert!(matches!(
+            db.resolve_path("/Target/a.txt").unwrap(),
+            Some(Entry::File(f)) if f.id == moved
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renames_refuse_names_taken_by_a_file_or_folder() {
+        let (db, dir) = open();
+        let folder = db.create_folder("Docs", None).unwrap();
+        let other = db.create_folder("Other", None).unwrap();
+        let a = file(&db, None, "a.txt", 1);
+
+        let result = db.rename_file(&a, "Docs");
+        assert!(matches!(result, Err(DbError::NameConflict(_))));
+        let result = db.rename_folder(&other, "a.txt");
+        assert!(matches!(result, Err(DbError::NameConflict(_))));
+        let result = db.rename_folder(&other, "Docs");
+        assert!(matches!(result, Err(DbError::NameConflict(_))));
+
+        db.rename_folder(&folder, "Docs").unwrap();
+        db.rename_file(&a, "b.txt").unwrap();
+        assert_eq!(db.get_file(&a).unwrap().name, "b.txt");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn conflict_policies_on_move_folder() {
+        let (db, dir) = open();
+        let target = db.create_folder("Target", None).unwrap();
+        let existing = db.create_folder("Docs", Some(target.clone())).unwrap();
+        let moved = db.create_folder("Docs", None).unwrap();
+
+        let result = db.move_folder(&moved, Some(target.clone()), "Docs", ConflictPolicy::Fail);
+        assert!(matches!(result, Err(DbError::NameConflict(_))));
+
+        let name = db
+            .move_folder(
+                &moved,
+                Some(target.clone()),
+                "Docs",
+                ConflictPolicy::AutoRename,
+            )
+            .unwrap();
+        assert_eq!(name, "Docs (1)");
+        assert!(!db.get_folder_by_id(&existing).unwrap().trashed);
+
+        let name = db
+            .move_folder(
+                &moved,
+                Some(target.clone()),
+                "Docs",
+                ConflictPolicy::Overwrite,
+            )
+            .unwrap();
+        assert_eq!(name, "Docs");
+        assert!(db.get_folder_by_id(&existing).unwrap().trashed);
+
+        // Overwriting the folder that holds the moved item would trash the item too
+        let outer = db.create_folder("Outer", None).unwrap();
+        let nested = db.create_folder("Outer", Some(outer.clone())).unwrap();
+        let result = db.move_folder(&nested, None, "Outer", ConflictPolicy::Overwrite);
+        assert!(matches!(result, Err(DbError::NameConflict(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trashing_a_folder_takes_its_subtree_along() {
+        let (db, dir) = open();
+        let a = db.create_folder("A", None).unwrap();
+        let b = db.create_folder("B", Some(a.clone())).unwrap();
+        let c = db.create_folder("C", Some(b.clone())).unwrap();
+        let deep = file(&db, Some(&c), "deep.txt", 3);
+        file(&db, Some(&a), "top.txt", 1);
+        file(&db, Some(&b), "mid.txt", 2);
+        db.toggle_star(&c, true).unwrap();
+        db.toggle_star(&deep, false).unwrap();
+
+        db.trash_item(&a, true).unwrap();
+        let (folders, files) = db.search_items("deep").unwrap();
+        assert!(folders.is_empty() && files.is_empty());
+        let (folders, files) = db.get_starred().unwrap();
+        assert!(folders.is_empty() && files.is_empty());
+        assert_eq!(db.get_total_usage().unwrap(), 0);
+        let (folders, files) = db.list_trash().unwrap();
+        assert_eq!(folders.iter().map(|f| &f.id).collect::<Vec<_>>(), [&a]);
+        assert!(files.is_empty());
+
+        // Restoring any item of the batch brings back all of it
+        db.restore_item(&deep, false).unwrap();
+        assert_eq!(db.search_items("deep").unwrap().1.len(), 1);
+        assert_eq!(db.get_starred().unwrap().0.len(), 1);
+        assert_eq!(db.get_total_usage().unwrap(), 3);
+        assert_eq!(db.path_of(&deep).unwrap(), "/A/B/C/deep.txt");
+
+        db.trash_item(&a, true).unwrap();
+        let mut released = db.delete_folder(&a).unwrap();
+        released.sort();
+        let messages: Vec<i32> = released.iter().map(|m| m.message_id).collect();
+        assert_eq!(messages, [1, 2, 3]);
+        assert!(db.get_all_folders().unwrap().is_empty());
+        assert!(db.get_all_files().unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn subtree_lists_items_below_a_folder() {
+        let (db, dir) = open();
+        let ids = chain(&db);
+        file(&db, Some(&ids[2]), "c.txt", 1);
+        file(&db, Some(&ids[4]), "e.txt", 2);
+        file(&db, None, "top.txt", 3);
+        let trashed = file(&db, Some(&ids[3]), "gone.txt", 4);
+        db.trash_item(&trashed, false).unwrap();
+
+        let subtree = db.subtree(&ids[1]).unwrap();
+        let folders: Vec<&str> = subtree.folders.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(folders, ["C", "C/D", "C/D/E"]);
+        let mut files: Vec<&str> = subtree.files.iter().map(|(p, _)| p.as_str()).collect();
+        files.sort_unstable();
+        assert_eq!(files, ["C/D/E/e.txt", "C/c.txt"]);
+
+        // Moves and renames are followed
+        db.move_folder(&ids[3], None, "D2", ConflictPolicy::Fail)
+            .unwrap();
+        let subtree = db.subtree(&ids[1]).unwrap();
+        assert_eq!(subtree.folders.len(), 1);
+        assert_eq!(subtree.files.len(), 1);
+        assert_eq!(db.subtree(&ids[3]).unwrap().files[0].0, "E/e.txt");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn picks_up_changes_of_another_connection() {
+        let (db, dir) = open();
+        let other = Database::new(dir.to_str().unwrap()).unwrap();
+        let docs = db.create_folder("Docs", None).unwrap();
+        let kept = file(&db, Some(&docs), "kept.txt", 1);
+        let gone = file(&db, Some(&docs), "gone.txt", 2);
+        assert!(other.refresh().unwrap());
+        assert_eq!(other.get_all_files().unwrap().len(), 2);
+
+        other.rename_file(&kept, "renamed.txt").unwrap();
+        other
+            .delete_files_by_ids(std::slice::from_ref(&gone))
+            .unwrap();
+        let added = file(&other, None, "added.txt", 3);
+        assert!(db.refresh().unwrap());
+        assert!(!db.refresh().unwrap());
+        let mut names: Vec<String> = db
+            .get_all_files()
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, ["added.txt", "renamed.txt"]);
+        assert!(matches!(
+            db.resolve_path("/Docs/renamed.txt").unwrap(),
+            Some(Entry::File(f)) if f.id == kept
+        ));
+        assert!(db.resolve_path("/Docs/gone.txt").unwrap().is_none());
+        assert_eq!(db.path_of(&added).unwrap(), "/added.txt");
+
+        // Writes start from the other connection's latest state too
+        db.rename_file(&added, "added twice.txt").unwrap();
+        assert_eq!(other.get_file(&added).unwrap().name, "added twice.txt");
+        assert_eq!(other.get_file(&kept).unwrap().name, "renamed.txt");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn conflict_policy_setting() {
+        let (db, dir) = open();
+        assert_eq!(db.conflict_policy().unwrap(), ConflictPolicy::Fail);
+        db.set_conflict_policy(ConflictPolicy::AutoRename).unwrap();
+        assert_eq!(db.conflict_policy().unwrap(), ConflictPolicy::AutoRename);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A stamp from another device, `ahead` milliseconds from now.
+    fn remote_stamp(ahead: i64) -> Option<SyncStamp> {
+        Some(SyncStamp {
+            changed: Stamp {
+                time: chrono::Utc::now().timestamp_millis() + ahead,
+                counter: 0,
+                device: "other".into(),
+            },
+            renamed: None,
+            renamed_over: None,
+        })
+    }
+
+    #[test]
+    fn replacing_the_contents_reaches_other_devices() {
+        let (db, dir) = open();
+        let docs = db.create_folder("Docs", None).unwrap();
+        let kept = file(&db, Some(&docs), "kept.txt", 1);
+        let gone = file(&db, Some(&docs), "gone.txt", 2);
+        let published = db.changes_since(None).unwrap().upto().unwrap();
+
+        let folders = db.get_all_folders().unwrap();
+        let files: Vec<FileMetadata> = db
+            .get_all_files()
+            .unwrap()
+            .into_iter()
+            .filter(|f| f.id == kept)
+            .collect();
+        db.replace_contents(folders, files).unwrap();
+
+        let delta = db.changes_since(Some(&published)).unwrap();
+        assert_eq!(delta.folders.len(), 1);
+        assert_eq!(delta.files.len(), 1);
+        assert_eq!(delta.files[0].id, kept);
+        assert_eq!(delta.removed.len(), 1);
+        assert_eq!(delta.removed[0].id, gone);
+        assert!(db.get_file(&gone).is_err());
+        assert_eq!(db.path_of(&kept).unwrap(), "/Docs/kept.txt");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn both_processes_issue_later_stamps() {
+        let (db, dir) = open();
+        let other = Database::new(dir.to_str().unwrap()).unwrap();
+        // A change from a device whose clock runs an hour ahead
+        let mut ahead = new_folder(None, "Ahead".into());
+        ahead.stamp = remote_stamp(3_600_000);
+        db.apply_delta(&Delta {
+            device: "other".into(),
+            folders: vec![ahead],
+            ..Default::default()
+        })
+        .unwrap();
+        db.create_folder("First", None).unwrap();
+        let published = db.changes_since(None).unwrap().upto().unwrap();
+
+        let second = other.create_folder("Second", None).unwrap();
+        let delta = other.changes_since(Some(&published)).unwrap();
+        assert_eq!(delta.folders.len(), 1);
+        assert_eq!(delta.folders[0].id, second);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_merged_cycle_moves_the_later_folder_to_the_top() {
+        let (db, dir) = open();
+        let a = db.create_folder("A", None).unwrap();
+        let b = db.create_folder("B", None).unwrap();
+        let inner = db.create_folder("Inner", Some(a.clone())).unwrap();
+        db.move_folder(&b, Some(a.clone()), "B", ConflictPolicy::Fail)
+            .unwrap();
+        // Meanwhile the other device moved A into B
+        let mut moved = db.get_folder_by_id(&a).unwrap();
+        moved.parent_id = Some(b.clone());
+        moved.stamp = remote_stamp(1_000);
+        db.apply_delta(&Delta {
+            device: "other".into(),
+            folders: vec![moved],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(db.path_of(&a).unwrap(), "/A");
+        assert_eq!(db.path_of(&b).unwrap(), "/A/B");
+        assert_eq!(db.path_of(&inner).unwrap(), "/A/Inner");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merged_names_taken_by_a_sibling_are_numbered() {
+        let (db, dir) = open();
+        let docs = db.create_folder("Docs", None).unwrap();
+        let local = file(&db, Some(&docs), "notes.txt", 1);
+        let mut theirs = new_file(
+            Some(docs.clone()),
+            "notes.txt".into(),
+            1,
+            "text/plain".into(),
+            vec![Segment {
+                message_id: 2,
+                size: 1,
+            }],
+        );
+        theirs.stamp = remote_stamp(1_000);
+        let conflicts = db
+            .apply_delta(&Delta {
+                device: "other".into(),
+                files: vec![theirs.clone()],
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(db.path_of(&local).unwrap(), "/Docs/notes.txt");
+        assert_eq!(db.path_of(&theirs.id).unwrap(), "/Docs/notes.txt (1)");
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].id, theirs.id);
+        assert_eq!(conflicts[0].other_name, "notes.txt");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Version 1 records: folders without `last_modified`, and a trashed folder
+    // whose contents were left untrashed.
+    fn v1_records() -> (serde_json::Value, serde_json::Value) {
+        let folders = serde_json::json!([
+            {"id": "old", "parent_id": null, "name": "Old", "created_at": 5, "trashed": true, "trashed_at": 7},
+            {"id": "inner", "parent_id": "old", "name": "Inner", "created_at": 6},
+        ]);
+        let files = serde_json::json!([
+            {"id": "doc", "folder_id": "inner", "name": "doc.txt", "size": 1,
+             "mime_type": "text/plain", "message_id": 3, "created_at": 6},
+        ]);
+        (folders, files)
+    }
+
+    fn assert_upgraded(db: &Database) {
+        let inner = db.get_folder_by_id("inner").unwrap();
+        assert_eq!(inner.last_modified, 6);
+        assert!(inner.trashed);
+        let batch = db.get_folder_by_id("old").unwrap().trash_batch;
+        assert!(batch.is_some());
+        assert_eq!(inner.trash_batch, batch);
+        assert_eq!(db.get_file("doc").unwrap().trash_batch, batch);
+        let conn = db.conn().unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn a_legacy_json_store_is_imported_at_the_current_schema() {
+        let dir = temp_dir();
+        let (folders, files) = v1_records();
+        let legacy = serde_json::json!({"folders": folders, "files": files});
+        std::fs::write(dir.join(LEGACY_FILENAME), legacy.to_string()).unwrap();
+
+        let db = Database::new(dir.to_str().unwrap()).unwrap();
+        assert_upgraded(&db);
+        assert!(!dir.join(LEGACY_FILENAME).exists());
+        assert!(dir.join("metadata.json.migrated").exists());
+        drop(db);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn an_old_database_is_backed_up_and_migrated() {
+        let dir = temp_dir();
+        let conn = Connection::open(dir.join(DB_FILENAME)).unwrap();
+        conn.execute_batch(INITIAL_SCHEMA).unwrap();
+        let (folders, files) = v1_records();
+        for folder in folders.as_array().unwrap() {
+            conn.execute(
+                "INSERT INTO folders (id, parent_id, data) VALUES (?1, ?2, ?3)",
+                params![
+                    folder["id"].as_str(),
+                    folder["parent_id"].as_str(),
+                    folder.to_string()
+                ],
+            )
+            .unwrap();
+        }
+        for file in files.as_array().unwrap() {
+            conn.execute(
+                "INSERT INTO files (id, folder_id, message_id, data) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    file["id"].as_str(),
+                    file["folder_id"].as_str(),
+                    file["message_id"].as_i64(),
+                    file.to_string()
+                ],
+            )
+            .unwrap();
+        }
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        drop(conn);
+
+        let db = Database::new(dir.to_str().unwrap()).unwrap();
+        assert_upgraded(&db);
+        let backup = Connection::open(dir.join("metadata.db.v1.bak")).unwrap();
+        assert_eq!(schema_version(&backup).unwrap(), 1);
+        let rows: i64 = backup
+            .query_row("SELECT COUNT(*) FROM folders", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 2);
+        drop((db, backup));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_database_from_a_newer_release_is_refused_untouched() {
+        let (db, dir) = open();
+        let docs = db.create_folder("Docs", None).unwrap();
+        drop(db);
+        let conn = Connection::open(dir.join(DB_FILENAME)).unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        drop(conn);
+
+        assert!(matches!(
+            Database::new(dir.to_str().unwrap()),
+            Err(DbError::SchemaVersion { found, supported })
+                if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+        let conn = Connection::open(dir.join(DB_FILENAME)).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION + 1);
+        let kept: bool = conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM folders WHERE id = ?1)",
+                params![docs],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(kept);
+        drop(conn);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
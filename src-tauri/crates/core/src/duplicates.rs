@@ -0,0 +1,154 @@
+use base64::{engine::general_purpose, Engine as _};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::db::FileMetadata;
+use crate::targets::MessageRef;
+
+// Thumbnails whose difference hashes differ in at most this many of their 64 bits
+// count as the same picture.
+const SIMILAR_DISTANCE: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKind {
+    /// Same SHA-256.
+    Content,
+    NameAndSize,
+    /// Images whose thumbnails look alike.
+    SimilarImage,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateGroup {
+    pub kind: MatchKind,
+    /// Newest first.
+    pub files: Vec<FileMetadata>,
+    /// Stored bytes freed by keeping only the newest file. Copies that already share
+    /// a blob free nothing.
+    pub reclaimable: u64,
+}
+
+/// Groups files outside the trash that look like copies of each other, most
+/// reclaimable first. A group matched by name and size or by thumbnail whose files
+/// all have the same contents is left out, as it is already reported by content.
+pub fn find_duplicates(files: &[FileMetadata]) -> Vec<DuplicateGroup> {
+    let files: Vec<&FileMetadata> = files.iter().filter(|f| !f.trashed).collect();
+
+    let mut by_content: HashMap<String, Vec<&FileMetadata>> = HashMap::new();
+    let mut by_name: HashMap<(&str, i64), Vec<&FileMetadata>> = HashMap::new();
+    for f in &files {
+        if let Some(sha256) = &f.sha256 {
+            by_content
+                .entry(sha256.to_ascii_lowercase())
+                .or_default()
+                .push(f);
+        }
+        // Sizes of synced photos are unknown and empty files are all alike
+        if f.size > 0 {
+            by_name.entry((&f.name, f.size)).or_default().push(f);
+        }
+    }
+
+    let mut groups = Vec::new();
+    groups.extend(
+        by_content
+            .into_values()
+            .filter_map(|files| group(MatchKind::Content, files)),
+    );
+    groups.extend(
+        by_name
+            .into_values()
+            .filter(|files| !same_contents(files))
+            .filter_map(|files| group(MatchKind::NameAndSize, files)),
+    );
+    groups.extend(
+        similar_images(&files)
+            .into_iter()
+            .filter(|files| !same_contents(files))
+            .filter_map(|files| group(MatchKind::SimilarImage, files)),
+    );
+    groups.sort_by_key(|g| std::cmp::Reverse(g.reclaimable));
+    groups
+}
+
+/// Orders files newest first, the order `find_duplicates` lists them in.
+pub fn sort_newest_first(files: &mut [FileMetadata]) {
+    files.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(a.id.cmp(&b.id)));
+}
+
+fn group(kind: MatchKind, files: Vec<&FileMetadata>) -> Option<DuplicateGroup> {
+    if files.len() < 2 {
+        return None;
+    }
+    let mut files: Vec<FileMetadata> = files.into_iter().cloned().collect();
+    sort_newest_first(&mut files);
+
+    let mut counted: HashSet<MessageRef> = files[0].messages().into_iter().collect();
+    let mut reclaimable = 0;
+    for f in &files[1..] {
+        let first = MessageRef {
+            chat_id: f.chat_id,
+            message_id: f.message_id,
+        };
+        if f.message_id > 0 && !counted.contains(&first) {
+            reclaimable += f.size.max(0) as u64;
+        }
+        counted.extend(f.messages());
+    }
+    Some(DuplicateGroup {
+        kind,
+        files,
+        reclaimable,
+    })
+}
+
+fn same_contents(files: &[&FileMetadata]) -> bool {
+    let hashes: HashSet<Option<String>> = files
+        .iter()
+        .map(|f| f.sha256.as_deref().map(str::to_ascii_lowercase))
+        .collect();
+    hashes.len() == 1 && !hashes.contains(&None)
+}
+
+// Images with a thumbnail. Each joins the first group whose first image is close
+// enough to it, or starts a group of its own. Comparing with that one image keeps a
+// chain of small differences from pulling unlike pictures into one group.
+fn similar_images<'a>(files: &[&'a FileMetadata]) -> Vec<Vec<&'a FileMetadata>> {
+    let hashed = files
+        .iter()
+        .filter(|f| f.mime_type.starts_with("image/"))
+        .filter_map(|f| Some((*f, thumbnail_dhash(f.thumbnail.as_deref()?)?)));
+
+    let mut groups: Vec<(u64, Vec<&FileMetadata>)> = Vec::new();
+    for (file, hash) in hashed {
+        match groups
+            .iter_mut()
+            .find(|(first, _)| (first ^ hash).count_ones() <= SIMILAR_DISTANCE)
+        {
+            Some((_, group)) => group.push(file),
+            None => groups.push((hash, vec![file])),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Difference hash of a base64 thumbnail: one bit per neighbouring pair of pixels in
+/// a 9x8 grayscale copy, set where brightness rises. Alike pictures differ in few bits.
+pub fn thumbnail_dhash(thumbnail: &str) -> Option<u64> {
+    let bytes = general_purpose::STANDARD_NO_PAD
+        .decode(thumbnail.trim_end_matches('='))
+        .ok()?;
+    let image = image::load_from_memory(&bytes).ok()?;
+    let small = image
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let rises = small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0];
+            hash = hash << 1 | rises as u64;
+        }
+    }
+    Some(hash)
+}
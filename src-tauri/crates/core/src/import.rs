@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::backups::METADATA_BACKUP_TAG;
+use crate::db::{Database, DbError};
+use crate::storage::{RemoteBlob, Segment, StorageBackend, StorageError};
+use crate::sync::METADATA_DELTA_TAG;
+
+/// Root folder imported messages go to.
+pub const IMPORT_FOLDER: &str = "Telegram Sync";
+const IMPORT_CURSOR_SETTING: &str = "import_cursor";
+// Messages read per request. Where the import stands is saved after every page.
+const PAGE_SIZE: usize = 100;
+
+#[derive(Debug)]
+pub enum ImportError {
+    Db(DbError),
+    Storage(StorageError),
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Db(e) => write!(f, "{}", e),
+            ImportError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<DbError> for ImportError {
+    fn from(e: DbError) -> Self {
+        ImportError::Db(e)
+    }
+}
+
+impl From<StorageError> for ImportError {
+    fn from(e: StorageError) -> Self {
+        ImportError::Storage(e)
+    }
+}
+
+impl From<ImportError> for String {
+    fn from(e: ImportError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Which messages to import. Fields left empty let everything through.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportFilter {
+    /// Sent at or after, in Unix seconds.
+    #[serde(default)]
+    pub since: Option<i64>,
+    /// Sent at or before, in Unix seconds.
+    #[serde(default)]
+    pub until: Option<i64>,
+    /// Exact types like "application/pdf", or whole families like "image/".
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+    #[serde(default)]
+    pub has_caption: bool,
+}
+
+impl ImportFilter {
+    fn matches(&self, blob: &RemoteBlob) -> bool {
+        self.since.is_none_or(|since| blob.date >= since)
+            && self.until.is_none_or(|until| blob.date <= until)
+            && (self.mime_types.is_empty()
+                || self.mime_types.iter().any(|m| match m.strip_suffix('/') {
+                    Some(family) => blob.mime_type.split('/').next() == Some(family),
+                    None => blob.mime_type.eq_ignore_ascii_case(m),
+                }))
+            && (!self.has_caption || !blob.caption.trim().is_empty())
+    }
+}
+
+// Where imports stand. A run reads the history newest first, down to the newest
+// message the last finished run saw, and can resume part way.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ImportCursor {
+    // Imports with another filter start over.
+    filter: ImportFilter,
+    // Every message up to this id was looked at.
+    scanned_to: i32,
+    // Newest message of the run in progress, and where it goes on from.
+    top: Option<i32>,
+    next_offset: Option<i32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportProgress {
+    /// Messages with a file looked at in this run.
+    pub scanned: usize,
+    pub imported: usize,
+    /// Date of the oldest message reached so far, in Unix seconds.
+    pub reached: Option<i64>,
+    pub done: bool,
+}
+
+/// Adds a file to `IMPORT_FOLDER` for every message in Saved Messages that carries
+/// one, matches `filter` and no file refers to yet. Files already imported stay
+/// wherever the user put them. An interrupted import resumes where it stopped, and
+/// a finished one only reads messages newer than it saw. `progress` is called after
+/// every page.
+pub async fn import_saved_messages(
+    db: &Database,
+    storage: &dyn StorageBackend,
+    filter: &ImportFilter,
+    progress: &(dyn Fn(&ImportProgress) + Send + Sync),
+) -> Result<ImportProgress, ImportError> {
+    let mut cursor = db
+        .setting::<ImportCursor>(IMPORT_CURSOR_SETTING)?
+        .filter(|c| c.filter == *filter)
+        .unwrap_or_else(|| ImportCursor {
+            filter: filter.clone(),
+            ..Default::default()
+        });
+
+    // Unfinished uploads have messages no file refers to yet
+    let mut referenced = db.referenced_messages()?;
+    for job in db.upload_jobs()? {
+        referenced.extend(job.sent_messages());
+    }
+    let mut known: HashSet<i32> = referenced
+        .into_iter()
+        .filter(|m| m.chat_id.is_none())
+        .map(|m| m.message_id)
+        .collect();
+    let folder_id = match db
+        .get_all_folders()?
+        .into_iter()
+        .find(|f| f.name == IMPORT_FOLDER && f.parent_id.is_none() && !f.trashed)
+    {
+        Some(f) => f.id,
+        None => db.create_folder(IMPORT_FOLDER, None)?,
+    };
+
+    let mut report = ImportProgress::default();
+    let mut offset = cursor.next_offset.unwrap_or(0);
+    loop {
+        let page = storage.history(offset, PAGE_SIZE).await?;
+        let mut finished = page.next_offset.is_none();
+        for blob in &page.blobs {
+            // Older messages were seen by an earlier run, or sent before `since`
+            if blob.message_id <= cursor.scanned_to
+                || filter.since.is_some_and(|since| blob.date < since)
+            {
+                finished = true;
+                break;
+            }
+            cursor.top.get_or_insert(blob.message_id);
+            report.scanned += 1;
+            report.reached = Some(blob.date);
+
+            if known.contains(&blob.message_id)
+                || blob.caption.contains(METADATA_BACKUP_TAG)
+                || blob.caption.contains(METADATA_DELTA_TAG)
+                || !filter.matches(blob)
+            {
+                continue;
+            }
+            let thumbnail = storage
+                .thumbnail(blob.message_id)
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to fetch thumbnail: {}", e);
+                    None
+                });
+            db.add_file(
+                Some(folder_id.clone()),
+                imported_name(blob),
+                blob.size,
+                blob.mime_type.clone(),
+                vec![Segment {
+                    message_id: blob.message_id,
+                    size: blob.size.max(0) as u64,
+                }],
+                thumbnail,
+            )?;
+            known.insert(blob.message_id);
+            report.imported += 1;
+        }
+
+        if finished {
+            cursor.scanned_to = cursor.top.take().unwrap_or(0).max(cursor.scanned_to);
+            cursor.next_offset = None;
+            db.set_setting(IMPORT_CURSOR_SETTING, &cursor)?;
+            report.done = true;
+            progress(&report);
+            return Ok(report);
+        }
+        cursor.next_offset = page.next_offset;
+        db.set_setting(IMPORT_CURSOR_SETTING, &cursor)?;
+        progress(&report);
+        offset = page.next_offset.unwrap_or(0);
+    }
+}
+
+// Photos and some documents come without a file name.
+fn imported_name(blob: &RemoteBlob) -> String {
+    if blob.is_photo {
+        return format!("Photo_{}.jpg", blob.message_id);
+    }
+    if !blob.name.is_empty() {
+        return blob.name.clone();
+    }
+    match mime_guess::get_mime_extensions_str(&blob.mime_type).and_then(|e| e.first()) {
+        Some(ext) => format!("File_{}.{}", blob.message_id, ext),
+        None => format!("File_{}", blob.message_id),
+    }
+}
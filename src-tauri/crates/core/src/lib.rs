@@ -1,4 +1,21 @@
+pub mod backups;
 pub mod client;
+pub mod crypto;
 pub mod db;
+pub mod duplicates;
+pub mod import;
+pub mod manifest;
+pub mod profiles;
+pub mod reconcile;
+pub mod replicas;
+pub mod retry;
+pub mod storage;
+pub mod sync;
+pub mod targets;
+pub mod uploads;
 
-pub use db::Database;
+pub use crypto::{Credential, CryptoError, EncryptionInfo, KeyProtection, RecordCipher, Vault};
+pub use db::{ConflictPolicy, Database, DbError, Entry};
+pub use storage::{MemoryBackend, StorageBackend, StorageError, TelegramBackend};
+pub use targets::{MessageRef, StorageTargets, Storages};
+pub use uploads::{JobState, UploadError, UploadJob, UploadQueue};
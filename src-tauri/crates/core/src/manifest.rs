@@ -0,0 +1,285 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::crypto::{EncryptionInfo, Vault};
+use crate::db::{self, Database, DbError, FileMetadata, FileVersion, Folder, Replica};
+use crate::storage::{RemoteBlob, Segment};
+
+// Caption tags of messages that describe the file they hold. Sealed manifests are
+// sealed under the metadata key, so they say nothing without the vault.
+const MANIFEST_TAG: &str = "#paperfold ";
+const SEALED_MANIFEST_TAG: &str = "#paperfold_sealed ";
+const MANIFEST_AAD: &[u8] = b"manifest";
+// Telegram refuses longer captions on regular accounts. It counts UTF-16 code units.
+const CAPTION_LIMIT: usize = 1024;
+
+/// What the messages of a stored file say about it, sent along as their caption.
+/// Enough to put the file back in the library without any local metadata. Folder
+/// and name are as they were at upload.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: String,
+    /// Path of the folder the file went into, "/" for the top level.
+    #[serde(rename = "p")]
+    pub folder_path: String,
+    #[serde(rename = "n")]
+    pub name: String,
+    #[serde(rename = "t")]
+    pub mime_type: String,
+    /// Plaintext size.
+    #[serde(rename = "s")]
+    pub size: i64,
+    #[serde(rename = "h", default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// When the file was uploaded, in Unix seconds.
+    #[serde(rename = "c")]
+    pub created_at: i64,
+    /// When the source was last modified, if it was a local file.
+    #[serde(rename = "m", default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<i64>,
+    #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionInfo>,
+}
+
+impl Manifest {
+    /// A manifest for a file about to be uploaded into `folder_id`, under a new id.
+    pub fn new(
+        db: &Database,
+        folder_id: Option<&str>,
+        name: &str,
+        mime_type: &str,
+        size: i64,
+    ) -> Result<Self, DbError> {
+        // A folder deleted meanwhile leaves the file at the top level
+        let folder_path = match folder_id.map(|id| db.path_of(id)) {
+            Some(Ok(path)) => path,
+            Some(Err(DbError::NotFound(_))) | None => "/".to_string(),
+            Some(Err(e)) => return Err(e),
+        };
+        Ok(Self {
+            id: Uuid::new_v4().to_string(),
+            folder_path,
+            name: name.to_string(),
+            mime_type: mime_type.to_string(),
+            size,
+            sha256: None,
+            created_at: chrono::Utc::now().timestamp(),
+            modified_at: None,
+            encryption: None,
+        })
+    }
+
+    /// The caption for every message of the file, sealed when a vault is given. The
+    /// folder is left out of a manifest too long for a caption, and a manifest that
+    /// is still too long is not sent at all.
+    pub fn caption(&self, vault: Option<&Vault>) -> String {
+        let encode = |m: &Manifest| {
+            let json = serde_json::to_vec(m).unwrap_or_default();
+            match vault {
+                Some(vault) => {
+                    let sealed = vault.metadata_cipher().seal(MANIFEST_AAD, &json);
+                    format!("{}{}", SEALED_MANIFEST_TAG, BASE64.encode(sealed))
+                }
+                None => format!("{}{}", MANIFEST_TAG, String::from_utf8_lossy(&json)),
+            }
+        };
+        let caption = encode(self);
+        if caption.encode_utf16().count() <= CAPTION_LIMIT {
+            return caption;
+        }
+        let caption = encode(&Manifest {
+            folder_path: "/".to_string(),
+            ..self.clone()
+        });
+        if caption.encode_utf16().count() <= CAPTION_LIMIT {
+            return caption;
+        }
+        String::new()
+    }
+
+    /// Whether a caption carries a manifest, readable here or not. Only Paperfold
+    /// writes them, so such a message is one of its uploads.
+    pub fn is_tagged(caption: &str) -> bool {
+        caption.starts_with(MANIFEST_TAG) || caption.starts_with(SEALED_MANIFEST_TAG)
+    }
+
+    /// The manifest in a caption, if it has one that can be read. Sealed ones need
+    /// the vault they were sealed with.
+    pub fn parse(caption: &str, vault: Option<&Vault>) -> Option<Self> {
+        if let Some(json) = caption.strip_prefix(MANIFEST_TAG) {
+            return serde_json::from_str(json).ok();
+        }
+        let sealed = BASE64
+            .decode(caption.strip_prefix(SEALED_MANIFEST_TAG)?.trim())
+            .ok()?;
+        let json = vault?.metadata_cipher().open(MANIFEST_AAD, &sealed).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    // Bytes its messages hold together once the whole blob is stored.
+    fn blob_size(&self) -> u64 {
+        match &self.encryption {
+            Some(info) => info.sealed_size(),
+            None => self.size.max(0) as u64,
+        }
+    }
+}
+
+// The manifest of an upload and the messages of one copy of it.
+type StoredCopy<'a> = (Manifest, Vec<&'a RemoteBlob>);
+
+/// A library put back together from the manifests in the storage chats.
+#[derive(Debug, Default)]
+pub struct Rebuilt {
+    pub folders: Vec<Folder>,
+    pub files: Vec<FileMetadata>,
+    /// Messages with a manifest that could not be used: sealed without the vault at
+    /// hand, or part of a file some of whose messages are gone.
+    pub skipped: usize,
+}
+
+/// Rebuilds folders and files from every message in the storage chats, given with
+/// the id of their chat (`None` for Saved Messages). Files uploaded again under the
+/// same name in the same folder come back as one file with the older uploads as
+/// its versions, under the id of the first. Copies of an upload in several chats
+/// come back as one file with replicas. Copies that shared another file's messages,
+/// trash, stars and folder settings are not in any manifest and are not restored.
+pub fn rebuild(chats: &[(Option<i64>, Vec<RemoteBlob>)], vault: Option<&Vault>) -> Rebuilt {
+    let mut rebuilt = Rebuilt::default();
+
+    // Every segment of one copy of an upload is in the same chat. Replicas carry
+    // the same manifest in other chats.
+    let mut blobs: HashMap<(String, Option<i64>), StoredCopy> = HashMap::new();
+    let remote = chats
+        .iter()
+        .flat_map(|(chat_id, blobs)| blobs.iter().map(move |b| (*chat_id, b)));
+    for (chat_id, blob) in remote {
+        let sealed = blob.caption.starts_with(SEALED_MANIFEST_TAG);
+        match Manifest::parse(&blob.caption, vault) {
+            Some(manifest) => blobs
+                .entry((manifest.id.clone(), chat_id))
+                .or_insert_with(|| (manifest, Vec::new()))
+                .1
+                .push(blob),
+            None if sealed => rebuilt.skipped += 1,
+            None => {}
+        }
+    }
+
+    // Folders are created as their paths come up
+    let mut folder_ids: HashMap<String, String> = HashMap::new();
+    let mut uploads: HashMap<(Option<String>, String), Vec<FileMetadata>> = HashMap::new();
+    // Complete copies of each upload, with when they were sent
+    let mut copies: HashMap<String, (Manifest, Vec<(i64, Replica)>)> = HashMap::new();
+    for ((id, chat_id), (manifest, mut messages)) in blobs {
+        // Segments are sent in order
+        messages.sort_by_key(|b| b.message_id);
+        let stored: u64 = messages.iter().map(|b| b.size.max(0) as u64).sum();
+        if stored != manifest.blob_size() {
+            rebuilt.skipped += messages.len();
+            continue;
+        }
+        let sent_at = messages[0].date;
+        let segments = messages
+            .iter()
+            .map(|b| Segment {
+                message_id: b.message_id,
+                size: b.size.max(0) as u64,
+            })
+            .collect();
+        copies
+            .entry(id)
+            .or_insert_with(|| (manifest, Vec::new()))
+            .1
+            .push((sent_at, Replica::new(chat_id, segments)));
+    }
+
+    for (manifest, mut found) in copies.into_values() {
+        // The copy sent first is the primary one
+        found.sort_by_key(|(sent_at, c)| (*sent_at, c.chat_id));
+        let mut found = found.into_iter().map(|(_, c)| c);
+        let Some(primary) = found.next() else {
+            continue;
+        };
+        let folder_id = folder_for(&manifest.folder_path, &mut folder_ids, &mut rebuilt.folders);
+        let file = FileMetadata {
+            id: manifest.id,
+            chat_id: primary.chat_id,
+            message_id: primary.message_id,
+            segments: primary.segments,
+            replicas: found.collect(),
+            created_at: manifest.created_at,
+            encryption: manifest.encryption,
+            sha256: manifest.sha256,
+            ..db::new_file(
+                folder_id.clone(),
+                manifest.name.clone(),
+                manifest.size,
+                manifest.mime_type,
+                Vec::new(),
+            )
+        };
+        uploads
+            .entry((folder_id, manifest.name))
+            .or_default()
+            .push(file);
+    }
+
+    for mut files in uploads.into_values() {
+        // Messages are numbered in the order they were sent
+        files.sort_by_key(|f| std::cmp::Reverse((f.created_at, f.message_id)));
+        let mut files = files.into_iter();
+        let Some(mut file) = files.next() else {
+            continue;
+        };
+        let mut replaced_at = file.created_at;
+        for older in files {
+            // The file keeps the id and date of its first upload, as `supersede` does
+            file.id = older.id.clone();
+            file.created_at = older.created_at;
+            file.versions.push(FileVersion {
+                id: Uuid::new_v4().to_string(),
+                size: older.size,
+                mime_type: older.mime_type,
+                message_id: older.message_id,
+                chat_id: older.chat_id,
+                segments: older.segments,
+                replicas: older.replicas,
+                encryption: older.encryption,
+                sha256: older.sha256,
+                thumbnail: None,
+                created_at: older.created_at,
+                replaced_at,
+            });
+            replaced_at = older.created_at;
+        }
+        rebuilt.files.push(file);
+    }
+    rebuilt
+}
+
+// Id of the folder at `path`, creating it and any folder above it that is missing.
+fn folder_for(
+    path: &str,
+    ids: &mut HashMap<String, String>,
+    folders: &mut Vec<Folder>,
+) -> Option<String> {
+    let mut parent: Option<String> = None;
+    let mut current = String::new();
+    for name in path.split('/').filter(|c| !c.is_empty()) {
+        current = format!("{}/{}", current, name);
+        let id = ids
+            .entry(current.clone())
+            .or_insert_with(|| {
+                let folder = db::new_folder(parent.clone(), name.to_string());
+                let id = folder.id.clone();
+                folders.push(folder);
+                id
+            })
+            .clone();
+        parent = Some(id);
+    }
+    parent
+}
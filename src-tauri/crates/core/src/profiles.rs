@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const PROFILES_FILENAME: &str = "profiles.json";
+// Profiles other than the default one live in a directory of their own below this.
+const PROFILES_DIR: &str = "profiles";
+/// Id of the profile that keeps the app directory itself, where the session,
+/// metadata and cache were before there were profiles.
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug)]
+pub enum ProfileError {
+    NotFound(String),
+    NameConflict(String),
+    InvalidName,
+    /// The active profile cannot be removed; switch to another one first.
+    Active,
+    /// The default profile holds the data from before profiles and is kept.
+    Default,
+    Io(String),
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfileError::NotFound(id) => write!(f, "Profile \"{}\" not found", id),
+            ProfileError::NameConflict(name) => {
+                write!(f, "A profile named \"{}\" already exists", name)
+            }
+            ProfileError::InvalidName => write!(f, "Profile name cannot be empty"),
+            ProfileError::Active => {
+                write!(f, "Switch to another profile before removing this one")
+            }
+            ProfileError::Default => write!(f, "The default profile cannot be removed"),
+            ProfileError::Io(e) => write!(f, "Profile storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+impl From<std::io::Error> for ProfileError {
+    fn from(e: std::io::Error) -> Self {
+        ProfileError::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ProfileError {
+    fn from(e: serde_json::Error) -> Self {
+        ProfileError::Io(e.to_string())
+    }
+}
+
+impl From<ProfileError> for String {
+    fn from(e: ProfileError) -> Self {
+        e.to_string()
+    }
+}
+
+/// One Telegram account with its own session, metadata, vault and cache, all kept
+/// in `profile_dir`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    /// Also the directory name and the WebDAV path of the profile.
+    pub id: String,
+    pub name: String,
+    pub created_at: i64,
+}
+
+/// Every profile on this computer and the one the app has open.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profiles {
+    pub profiles: Vec<Profile>,
+    pub active: String,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self {
+            profiles: vec![Profile {
+                id: DEFAULT_PROFILE.to_string(),
+                name: "Default".to_string(),
+                created_at: 0,
+            }],
+            active: DEFAULT_PROFILE.to_string(),
+        }
+    }
+}
+
+impl Profiles {
+    /// The profiles in `app_dir`, or only the default one if none were added yet.
+    pub fn load(app_dir: &Path) -> Result<Self, ProfileError> {
+        let path = app_dir.join(PROFILES_FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = std::fs::read(&path)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    pub fn save(&self, app_dir: &Path) -> Result<(), ProfileError> {
+        let path = app_dir.join(PROFILES_FILENAME);
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<&Profile, ProfileError> {
+        self.profiles
+            .iter()
+            .find(|p| p.id == id)
+            .ok_or_else(|| ProfileError::NotFound(id.to_string()))
+    }
+
+    /// Adds a profile called `name`. Its id is made from the name, so it reads well
+    /// in a WebDAV address.
+    pub fn add(&mut self, name: &str) -> Result<Profile, ProfileError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(ProfileError::InvalidName);
+        }
+        if self
+            .profiles
+            .iter()
+            .any(|p| p.name.eq_ignore_ascii_case(name))
+        {
+            return Err(ProfileError::NameConflict(name.to_string()));
+        }
+        let profile = Profile {
+            id: self.unique_id(name),
+            name: name.to_string(),
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        self.profiles.push(profile.clone());
+        Ok(profile)
+    }
+
+    /// Forgets profile `id`. Its files are left for `remove_profile_dir`.
+    pub fn remove(&mut self, id: &str) -> Result<Profile, ProfileError> {
+        if id == DEFAULT_PROFILE {
+            return Err(ProfileError::Default);
+        }
+        if id == self.active {
+            return Err(ProfileError::Active);
+        }
+        let pos = self
+            .profiles
+            .iter()
+            .position(|p| p.id == id)
+            .ok_or_else(|| ProfileError::NotFound(id.to_string()))?;
+        Ok(self.profiles.remove(pos))
+    }
+
+    pub fn set_active(&mut self, id: &str) -> Result<(), ProfileError> {
+        self.get(id)?;
+        self.active = id.to_string();
+        Ok(())
+    }
+
+    // Lowercase letters, digits and dashes from `name`, numbered if already taken.
+    fn unique_id(&self, name: &str) -> String {
+        let mut base = String::new();
+        for c in name.chars() {
+            if c.is_ascii_alphanumeric() {
+                base.push(c.to_ascii_lowercase());
+            } else if !base.is_empty() && !base.ends_with('-') {
+                base.push('-');
+            }
+        }
+        let base = match base.trim_end_matches('-') {
+            "" => "profile".to_string(),
+            base => base.to_string(),
+        };
+        let taken = |id: &str| self.profiles.iter().any(|p| p.id == id);
+        if !taken(&base) {
+            return base;
+        }
+        (2..)
+            .map(|n| format!("{}-{}", base, n))
+            .find(|id| !taken(id))
+            .unwrap()
+    }
+}
+
+/// Directory holding the session, metadata, vault and cache of profile `id`.
+pub fn profile_dir(app_dir: &Path, id: &str) -> PathBuf {
+    if id == DEFAULT_PROFILE {
+        app_dir.to_path_buf()
+    } else {
+        app_dir.join(PROFILES_DIR).join(id)
+    }
+}
+
+/// Deletes everything profile `id` kept on this computer. The Telegram account and
+/// the files stored in it are not touched.
+pub fn remove_profile_dir(app_dir: &Path, id: &str) -> Result<(), ProfileError> {
+    if id == DEFAULT_PROFILE {
+        return Err(ProfileError::Default);
+    }
+    let dir = profile_dir(app_dir, id);
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::backups::METADATA_BACKUP_TAG;
+use crate::db::FileMetadata;
+use crate::storage::{RemoteBlob, Segment};
+use crate::sync::METADATA_DELTA_TAG;
+use crate::targets::MessageRef;
+
+/// What to do with messages no file refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanAction {
+    /// Only report them.
+    Keep,
+    /// Add them as files to the "Recovered" folder.
+    Adopt,
+    /// Delete them from Telegram.
+    Delete,
+}
+
+/// Contents of a file, or of one of its versions, with messages that are gone.
+#[derive(Debug, Clone, Serialize)]
+pub struct MissingBlob {
+    pub file_id: String,
+    /// Set when only a kept version is affected.
+    pub version_id: Option<String>,
+    pub name: String,
+    pub size: i64,
+    pub missing_message_ids: Vec<i32>,
+}
+
+/// How the metadata and one storage chat differ, in both directions.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Reconciliation {
+    /// `None` for Saved Messages.
+    pub chat_id: Option<i64>,
+    /// Messages with a file that nothing refers to, oldest first.
+    pub orphans: Vec<RemoteBlob>,
+    /// Photo sizes are not known and count as nothing.
+    pub orphan_bytes: u64,
+    pub missing: Vec<MissingBlob>,
+    pub missing_bytes: u64,
+}
+
+/// Compares `files` with every message in `remote`, the history of chat `chat_id`.
+/// Contents stored in other chats are left out. Messages sent by uploads that have
+/// not finished, `pending`, are not orphans.
+pub fn reconcile(
+    chat_id: Option<i64>,
+    files: &[FileMetadata],
+    pending: &[MessageRef],
+    remote: &[RemoteBlob],
+) -> Reconciliation {
+    let stored: HashSet<i32> = remote.iter().map(|b| b.message_id).collect();
+    let referenced: HashSet<i32> = files
+        .iter()
+        .flat_map(|f| f.referenced_messages())
+        .chain(pending.iter().copied())
+        .filter(|m| m.chat_id == chat_id)
+        .map(|m| m.message_id)
+        .collect();
+    let mut report = Reconciliation {
+        chat_id,
+        ..Default::default()
+    };
+
+    for f in files {
+        // Every copy of the contents and of each version
+        let current = f
+            .copies()
+            .into_iter()
+            .map(|c| (None, c.chat_id, f.size, c.message_ids()));
+        let versions = f.versions.iter().flat_map(|v| {
+            std::iter::once((v.chat_id, v.message_ids()))
+                .chain(v.replicas.iter().map(|r| (r.chat_id, r.message_ids())))
+                .map(move |(chat_id, ids)| (Some(v.id.clone()), chat_id, v.size, ids))
+        });
+        let contents = current.chain(versions);
+        for (version_id, stored_in, size, message_ids) in contents {
+            if stored_in != chat_id {
+                continue;
+            }
+            // Empty files have no message
+            let missing: Vec<i32> = message_ids
+                .into_iter()
+                .filter(|id| *id > 0 && !stored.contains(id))
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            report.missing_bytes += size.max(0) as u64;
+            report.missing.push(MissingBlob {
+                file_id: f.id.clone(),
+                version_id,
+                name: f.name.clone(),
+                size,
+                missing_message_ids: missing,
+            });
+        }
+    }
+
+    let mut orphans: Vec<RemoteBlob> = remote
+        .iter()
+        .filter(|b| !referenced.contains(&b.message_id))
+        .filter(|b| {
+            !b.caption.contains(METADATA_BACKUP_TAG) && !b.caption.contains(METADATA_DELTA_TAG)
+        })
+        .cloned()
+        .collect();
+    orphans.sort_by_key(|b| b.message_id);
+    report.orphan_bytes = orphans.iter().map(|b| b.size.max(0) as u64).sum();
+    report.orphans = orphans;
+    report
+}
+
+/// A file to add for orphaned messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveredFile {
+    pub name: String,
+    pub size: i64,
+    pub mime_type: String,
+    pub segments: Vec<Segment>,
+}
+
+/// One file per orphan, except that the segments of a split file, `name.001`,
+/// `name.002`, ... sent one after the other, are put back together as `name`.
+/// Sealed blobs stay sealed: the key to open them was in the lost metadata.
+pub fn recovered_files(orphans: &[RemoteBlob]) -> Vec<RecoveredFile> {
+    let mut orphans: Vec<&RemoteBlob> = orphans.iter().collect();
+    orphans.sort_by_key(|b| b.message_id);
+
+    let mut files = Vec::new();
+    let mut rest = &orphans[..];
+    while let Some(first) = rest.first() {
+        let count = split_run(rest);
+        let (run, next) = rest.split_at(count);
+        let name = match first.name.strip_suffix(".001") {
+            Some(base) if count > 1 => base.to_string(),
+            _ if first.is_photo => format!("Photo_{}.jpg", first.message_id),
+            _ if first.name.is_empty() => format!("Message_{}", first.message_id),
+            _ => first.name.clone(),
+        };
+        files.push(RecoveredFile {
+            name,
+            size: run.iter().map(|b| b.size).sum(),
+            mime_type: first.mime_type.clone(),
+            segments: run
+                .iter()
+                .map(|b| Segment {
+                    message_id: b.message_id,
+                    size: b.size.max(0) as u64,
+                })
+                .collect(),
+        });
+        rest = next;
+    }
+    files
+}
+
+// How many of `blobs` in a row are the segments of one split file. 1 when the
+// first is not a first segment.
+fn split_run(blobs: &[&RemoteBlob]) -> usize {
+    let Some(base) = blobs[0].name.strip_suffix(".001") else {
+        return 1;
+    };
+    let mut count = 1;
+    while blobs
+        .get(count)
+        .is_some_and(|b| b.name == format!("{}.{:03}", base, count + 1))
+    {
+        count += 1;
+    }
+    count
+}
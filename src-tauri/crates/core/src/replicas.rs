@@ -0,0 +1,441 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use uuid::Uuid;
+
+use crate::db::{Database, DbError, FileMetadata, Replica};
+use crate::storage::{Segment, StorageBackend, StorageError};
+use crate::targets::{MessageRef, Storages};
+
+// Messages looked up per request when checking copies.
+const LOOKUP_BATCH: usize = 100;
+
+#[derive(Debug)]
+pub enum ReplicaError {
+    Db(DbError),
+    Storage(StorageError),
+}
+
+impl std::fmt::Display for ReplicaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplicaError::Db(e) => write!(f, "{}", e),
+            ReplicaError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReplicaError {}
+
+impl From<DbError> for ReplicaError {
+    fn from(e: DbError) -> Self {
+        ReplicaError::Db(e)
+    }
+}
+
+impl From<StorageError> for ReplicaError {
+    fn from(e: StorageError) -> Self {
+        ReplicaError::Storage(e)
+    }
+}
+
+impl From<ReplicaError> for String {
+    fn from(e: ReplicaError) -> Self {
+        e.to_string()
+    }
+}
+
+/// What `repair` found and did.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepairReport {
+    pub checked: usize,
+    /// Copies sent to replace lost ones or to reach a folder's replica count.
+    pub copies_made: usize,
+    /// Files whose primary copy was gone and a replica took its place.
+    pub promoted: Vec<String>,
+    /// Files with no complete copy left. Their entries are removed.
+    pub lost: Vec<String>,
+    /// Files that could not be fully repaired this time, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Gives a freshly stored file the extra copies its folder asks for, forwarded or
+/// sent again from its primary copy to other storage chats. There can only be as
+/// many copies as there are chats; files keep what they have when that is fewer.
+pub async fn replicate(
+    db: &Database,
+    storages: &Storages,
+    file: FileMetadata,
+) -> Result<FileMetadata, ReplicaError> {
+    let wanted = db.replica_count(file.folder_id.as_deref())? as usize;
+    let copies = file.copies();
+    if file.message_id <= 0 || copies.len() >= wanted {
+        return Ok(file);
+    }
+    let (made, failure) = extend(storages, &file, &copies[0], &copies, &[], wanted).await;
+    if made.is_empty() {
+        return match failure {
+            Some(e) => Err(e.into()),
+            None => Ok(file),
+        };
+    }
+    let updated = commit(db, storages, &file, &copies, copies.clone(), &made).await?;
+    match failure {
+        Some(e) => Err(e.into()),
+        None => Ok(updated.unwrap_or(file)),
+    }
+}
+
+/// Checks every copy of every file's current contents. A copy with messages gone
+/// is sent again from an intact one, into the same chat where possible; a lost
+/// primary copy is replaced by the first intact replica. Files below their folder's
+/// replica count get the missing copies. Only files with no intact copy left are
+/// removed. Copies in chats this device has not set up are kept unchecked, and
+/// kept versions are not looked at.
+pub async fn repair(db: &Database, storages: &Storages) -> Result<RepairReport, ReplicaError> {
+    let files = db.get_all_files()?;
+    let present = present_messages(storages, &files).await?;
+    let mut report = RepairReport {
+        checked: files.len(),
+        ..Default::default()
+    };
+
+    for file in files {
+        // Empty files have no message
+        if file.message_id <= 0 {
+            continue;
+        }
+        let copies = file.copies();
+        let mut kept = Vec::new();
+        let mut broken = Vec::new();
+        for copy in &copies {
+            let checked = storages.get(copy.chat_id).is_ok();
+            if !checked || copy.messages().iter().all(|m| present.contains(m)) {
+                kept.push(copy.clone());
+            } else {
+                broken.push(copy.chat_id);
+            }
+        }
+        if broken.is_empty() && kept.len() >= wanted_copies(db, &file) {
+            continue;
+        }
+        // A replica in an unchecked chat can stand in for nothing
+        let Some(source) = kept
+            .iter()
+            .find(|c| storages.get(c.chat_id).is_ok())
+            .cloned()
+        else {
+            if kept.is_empty() {
+                report.lost.push(file.id.clone());
+            }
+            continue;
+        };
+        if kept[0] != copies[0] {
+            report.promoted.push(file.id.clone());
+        }
+
+        let wanted = wanted_copies(db, &file).max(copies.len());
+        let (made, failure) = extend(storages, &file, &source, &kept, &broken, wanted).await;
+        report.copies_made += made.len();
+        if let Some(e) = failure {
+            report.failed.push((file.id.clone(), e.to_string()));
+        }
+        if kept != copies || !made.is_empty() {
+            commit(db, storages, &file, &copies, kept, &made).await?;
+        }
+    }
+
+    if !report.lost.is_empty() {
+        db.delete_files_by_ids(&report.lost)?;
+    }
+    Ok(report)
+}
+
+/// The first copy of `file` in a chat this device has set up, and the messages
+/// holding it. The primary copy comes first, then the replicas.
+pub fn readable_copy<'a>(
+    storages: &'a Storages,
+    file: &FileMetadata,
+) -> Result<(&'a dyn StorageBackend, Vec<Segment>), StorageError> {
+    for copy in file.copies() {
+        if let Ok(storage) = storages.get(copy.chat_id) {
+            return Ok((storage, file.copy_segments(&copy)));
+        }
+    }
+    Err(StorageError::UnknownChat(file.chat_id.unwrap_or_default()))
+}
+
+// A folder that no longer exists asks for no more than one copy.
+fn wanted_copies(db: &Database, file: &FileMetadata) -> usize {
+    db.replica_count(file.folder_id.as_deref()).unwrap_or(1) as usize
+}
+
+// Every message of the current contents of `files` that is still there, checked
+// chat by chat in the chats this device knows.
+async fn present_messages(
+    storages: &Storages,
+    files: &[FileMetadata],
+) -> Result<HashSet<MessageRef>, StorageError> {
+    let mut by_chat: BTreeMap<Option<i64>, Vec<i32>> = BTreeMap::new();
+    for file in files.iter().filter(|f| f.message_id > 0) {
+        for copy in file.copies() {
+            by_chat
+                .entry(copy.chat_id)
+                .or_default()
+                .extend(copy.message_ids());
+        }
+    }
+    let mut present = HashSet::new();
+    for (chat_id, mut message_ids) in by_chat {
+        let Ok(storage) = storages.get(chat_id) else {
+            continue;
+        };
+        message_ids.sort_unstable();
+        message_ids.dedup();
+        for batch in message_ids.chunks(LOOKUP_BATCH) {
+            // Our files MUST have media, so a message without it is as good as gone
+            let blobs = storage.lookup(batch).await?;
+            for (message_id, blob) in batch.iter().zip(blobs) {
+                if blob.is_some() {
+                    present.insert(MessageRef {
+                        chat_id,
+                        message_id: *message_id,
+                    });
+                }
+            }
+        }
+    }
+    Ok(present)
+}
+
+// Copies `source` to chats without a copy among `copies` until there are `wanted`,
+// trying the chats in `preferred` first. Returns what was made, and the error that
+// stopped it early if one did.
+async fn extend(
+    storages: &Storages,
+    file: &FileMetadata,
+    source: &Replica,
+    copies: &[Replica],
+    preferred: &[Option<i64>],
+    wanted: usize,
+) -> (Vec<Replica>, Option<StorageError>) {
+    let taken: HashSet<Option<i64>> = copies.iter().map(|c| c.chat_id).collect();
+    let mut targets: Vec<Option<i64>> = preferred.to_vec();
+    targets.extend(storages.chats().into_iter().map(|(chat_id, _)| chat_id));
+    let mut seen = HashSet::new();
+    targets.retain(|chat_id| !taken.contains(chat_id) && seen.insert(*chat_id));
+
+    let mut made = Vec::new();
+    for chat_id in targets
+        .into_iter()
+        .take(wanted.saturating_sub(copies.len()))
+    {
+        match copy_to(storages, file, source, chat_id).await {
+            Ok(copy) => made.push(copy),
+            Err(e) => return (made, Some(e)),
+        }
+    }
+    (made, None)
+}
+
+// Records `kept` and the new copies `made` as the copies of `file` in place of
+// `before`; messages of copies left out are deleted. If the file changed in the
+// meantime, the new copies are deleted instead.
+async fn commit(
+    db: &Database,
+    storages: &Storages,
+    file: &FileMetadata,
+    before: &[Replica],
+    mut kept: Vec<Replica>,
+    made: &[Replica],
+) -> Result<Option<FileMetadata>, ReplicaError> {
+    kept.extend(made.iter().cloned());
+    match db.replace_copies(&file.id, before, kept)? {
+        Some((updated, stale)) => {
+            if let Err(e) = storages.delete(&stale).await {
+                eprintln!("Failed to delete stale copies of {}: {}", file.name, e);
+            }
+            Ok(Some(updated))
+        }
+        None => {
+            let sent: Vec<MessageRef> = made.iter().flat_map(|c| c.messages()).collect();
+            if let Err(e) = storages.delete(&sent).await {
+                eprintln!("Failed to delete unused copies of {}: {}", file.name, e);
+            }
+            Ok(None)
+        }
+    }
+}
+
+// One more copy of `source` in chat `chat_id`, forwarded if the storage can,
+// otherwise downloaded and sent again message by message.
+async fn copy_to(
+    storages: &Storages,
+    file: &FileMetadata,
+    source: &Replica,
+    chat_id: Option<i64>,
+) -> Result<Replica, StorageError> {
+    let from = storages.get(source.chat_id)?;
+    let to = storages.get(chat_id)?;
+    let segments = file.copy_segments(source);
+    let message_ids: Vec<i32> = segments.iter().map(|s| s.message_id).collect();
+
+    let new_ids = match from.forward_to(&message_ids, to).await? {
+        Some(ids) => ids,
+        None => {
+            let blobs = from.lookup(&message_ids).await?;
+            let mut sent = Vec::new();
+            for (message_id, blob) in message_ids.iter().zip(blobs) {
+                let path =
+                    std::env::temp_dir().join(format!("paperfold_replica_{}", Uuid::new_v4()));
+                let result = match blob {
+                    Some(blob) => match from.download_to(*message_id, &path, None).await {
+                        Ok(()) => {
+                            to.put_file(&path, &blob.name, &blob.mime_type, &blob.caption, None)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    },
+                    None => Err(StorageError::NotFound(*message_id)),
+                };
+                let _ = tokio::fs::remove_file(&path).await;
+                match result {
+                    Ok(id) => sent.push(id),
+                    Err(e) => {
+                        if let Err(e) = to.delete(&sent).await {
+                            eprintln!("Failed to delete a partial copy: {}", e);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            sent
+        }
+    };
+
+    let segments = segments
+        .iter()
+        .zip(new_ids)
+        .map(|(s, message_id)| Segment {
+            message_id,
+            size: s.size,
+        })
+        .collect();
+    Ok(Replica::new(chat_id, segments))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    const CHANNEL: i64 = 7;
+
+    struct Setup {
+        db: Database,
+        storages: Storages,
+        saved: Arc<MemoryBackend>,
+        channel: Arc<MemoryBackend>,
+        dir: PathBuf,
+    }
+
+    // A file in Saved Messages with a replica in the channel, as a folder asking
+    // for two copies keeps it.
+    async fn replicated() -> (Setup, FileMetadata) {
+        let dir = std::env::temp_dir().join(format!("paperfold_replicas_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::new(dir.to_str().unwrap()).unwrap();
+        let saved = Arc::new(MemoryBackend::new());
+        let channel = Arc::new(MemoryBackend::new());
+        let storages = Storages::new(saved.clone()).with_channel(CHANNEL, channel.clone());
+
+        let folder = db.create_folder("Kept", None).unwrap();
+        db.set_folder_replicas(&folder, Some(2)).unwrap();
+        let message_id = saved.insert("a.txt", "text/plain", "", b"hello".to_vec());
+        let segments = vec![Segment {
+            message_id,
+            size: 5,
+        }];
+        let file = db
+            .add_file(
+                Some(folder),
+                "a.txt".into(),
+                5,
+                "text/plain".into(),
+                segments,
+                None,
+            )
+            .unwrap();
+        let file = replicate(&db, &storages, file).await.unwrap();
+        assert_eq!(file.replicas.len(), 1);
+        let setup = Setup {
+            db,
+            storages,
+            saved,
+            channel,
+            dir,
+        };
+        (setup, file)
+    }
+
+    #[tokio::test]
+    async fn a_lost_primary_is_replaced_by_its_replica() {
+        let (s, file) = replicated().await;
+        s.saved.delete(&[file.message_id]).await.unwrap();
+
+        let report = repair(&s.db, &s.storages).await.unwrap();
+        assert_eq!(report.promoted, std::slice::from_ref(&file.id));
+        assert_eq!(report.copies_made, 1);
+        assert!(report.lost.is_empty() && report.failed.is_empty());
+        let repaired = s.db.get_file(&file.id).unwrap();
+        assert_eq!(repaired.chat_id, Some(CHANNEL));
+        assert_eq!(repaired.message_id, file.replicas[0].message_id);
+        assert_eq!(repaired.replicas.len(), 1);
+        assert_eq!(repaired.replicas[0].chat_id, None);
+        let resent = repaired.replicas[0].message_id;
+        assert_eq!(s.saved.contents(resent).unwrap(), b"hello");
+        std::fs::remove_dir_all(s.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_missing_replica_is_sent_again() {
+        let (s, file) = replicated().await;
+        s.channel
+            .delete(&[file.replicas[0].message_id])
+            .await
+            .unwrap();
+
+        let report = repair(&s.db, &s.storages).await.unwrap();
+        assert!(report.promoted.is_empty());
+        assert_eq!(report.copies_made, 1);
+        let repaired = s.db.get_file(&file.id).unwrap();
+        assert_eq!(repaired.message_id, file.message_id);
+        assert_eq!(repaired.replicas.len(), 1);
+        assert_eq!(repaired.replicas[0].chat_id, Some(CHANNEL));
+        let resent = repaired.replicas[0].message_id;
+        assert_ne!(resent, file.replicas[0].message_id);
+        assert_eq!(s.channel.contents(resent).unwrap(), b"hello");
+
+        // Nothing is left to do the second time
+        let report = repair(&s.db, &s.storages).await.unwrap();
+        assert_eq!(report.copies_made, 0);
+        std::fs::remove_dir_all(s.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_file_with_no_copy_left_is_removed() {
+        let (s, file) = replicated().await;
+        s.saved.delete(&[file.message_id]).await.unwrap();
+        s.channel
+            .delete(&[file.replicas[0].message_id])
+            .await
+            .unwrap();
+
+        let report = repair(&s.db, &s.storages).await.unwrap();
+        assert_eq!(report.lost, std::slice::from_ref(&file.id));
+        assert_eq!(report.copies_made, 0);
+        assert!(matches!(s.db.get_file(&file.id), Err(DbError::NotFound(_))));
+        std::fs::remove_dir_all(s.dir).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use grammers_client::InvocationError;
+use rand::Rng;
+use rusqlite::{params, Connection, TransactionBehavior};
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BUDGET_FILENAME: &str = "requests.db";
+const BUDGET_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS budget (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        tokens REAL NOT NULL,
+        updated_at REAL NOT NULL,
+        blocked_until REAL NOT NULL
+    );
+    INSERT OR IGNORE INTO budget (id, tokens, updated_at, blocked_until) VALUES (1, 0, 0, 0);
+";
+
+// Requests per second the app and the daemon may make together, and how many may go
+// out at once after a quiet spell.
+const REQUESTS_PER_SECOND: f64 = 30.0;
+const BURST: f64 = 60.0;
+// A call is tried this many times before its error is passed on.
+const ATTEMPTS: u32 = 5;
+const BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+// Flood waits longer than this are reported instead of slept through.
+const MAX_FLOOD_WAIT: Duration = Duration::from_secs(300);
+
+/// What a failed Telegram call calls for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Telegram is rate limiting the account: no calls until this has passed.
+    FloodWait(Duration),
+    /// Network trouble or a server-side hiccup; worth trying again shortly.
+    Transient,
+    /// Trying again will not help.
+    Fatal,
+}
+
+pub fn classify(e: &InvocationError) -> Failure {
+    match e {
+        // FLOOD_WAIT_X, FLOOD_PREMIUM_WAIT_X, SLOWMODE_WAIT_X and friends
+        InvocationError::Rpc(rpc) if rpc.code == 420 || rpc.name.ends_with("_WAIT") => {
+            Failure::FloodWait(Duration::from_secs(rpc.value.unwrap_or(1).into()))
+        }
+        // Internal server errors and timeouts (-503)
+        InvocationError::Rpc(rpc) if rpc.code >= 500 || rpc.code < 0 => Failure::Transient,
+        InvocationError::Rpc(_) => Failure::Fatal,
+        // The connection broke or the request was dropped with it
+        InvocationError::Read(_) | InvocationError::Dropped => Failure::Transient,
+    }
+}
+
+/// The request budget of one Telegram account. Every process that opens it from the
+/// same profile directory shares it, as Telegram limits the account, not the process.
+#[derive(Clone)]
+pub struct Budget(Arc<RequestBudget>);
+
+impl std::fmt::Debug for Budget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Budget").finish()
+    }
+}
+
+impl Budget {
+    /// Opens the budget kept in `profile_dir`. If it cannot be, the process keeps one
+    /// of its own.
+    pub fn open(profile_dir: &Path) -> Self {
+        match RequestBudget::open(&profile_dir.join(BUDGET_FILENAME)) {
+            Ok(budget) => Self(Arc::new(budget)),
+            Err(e) => {
+                eprintln!("Failed to open the shared request budget: {}", e);
+                Self(Arc::new(
+                    RequestBudget::open(Path::new(":memory:")).expect("in-memory request budget"),
+                ))
+            }
+        }
+    }
+
+    // Waits for a free request slot, and for any flood wait either process hit to
+    // pass. A budget that cannot be read lets the request through. The file is read
+    // on the blocking pool, as the other process may hold its lock for a while.
+    async fn acquire(&self) {
+        loop {
+            let budget = self.0.clone();
+            match tokio::task::spawn_blocking(move || budget.take()).await {
+                Ok(Ok(None)) => return,
+                Ok(Ok(Some(wait))) => tokio::time::sleep(wait).await,
+                Ok(Err(e)) => {
+                    eprintln!("Failed to read the request budget: {}", e);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Failed to read the request budget: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Holds every request back until `wait` has passed.
+    async fn block_for(&self, wait: Duration) {
+        let budget = self.0.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || budget.block_for(wait)).await {
+            eprintln!("Failed to record flood wait: {}", e);
+        }
+    }
+}
+
+/// Runs `request` until it succeeds or fails in a way retrying will not fix. Every
+/// attempt waits its turn in `budget`.
+pub async fn call<T, F, Fut>(budget: &Budget, request: F) -> Result<T, InvocationError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, InvocationError>>,
+{
+    run(Retry::new(budget), request).await
+}
+
+/// Like `call`, for requests that must not reach Telegram twice, such as creating a
+/// channel. A broken connection may have delivered the request, so only flood waits,
+/// which Telegram answers without running it, are tried again.
+pub async fn call_once<T, F, Fut>(budget: &Budget, request: F) -> Result<T, InvocationError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, InvocationError>>,
+{
+    run(Retry::once(budget), request).await
+}
+
+async fn run<T, F, Fut>(mut retry: Retry, mut request: F) -> Result<T, InvocationError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, InvocationError>>,
+{
+    loop {
+        retry.ready().await;
+        match request().await {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                if !retry.after(&e).await {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Attempts at one call, for loops `call` does not fit, such as reading the next
+/// chunk of a download.
+#[derive(Debug)]
+pub struct Retry {
+    budget: Budget,
+    attempts: u32,
+    idempotent: bool,
+}
+
+impl Retry {
+    pub fn new(budget: &Budget) -> Self {
+        Self {
+            budget: budget.clone(),
+            attempts: 0,
+            idempotent: true,
+        }
+    }
+
+    /// Attempts at a call that must not be sent again once it may have arrived.
+    pub fn once(budget: &Budget) -> Self {
+        Self {
+            idempotent: false,
+            ..Self::new(budget)
+        }
+    }
+
+    /// Waits for the request budget. Call before every attempt.
+    pub async fn ready(&self) {
+        self.budget.acquire().await;
+    }
+
+    /// Sleeps as long as `e` calls for and returns whether to try again.
+    pub async fn after(&mut self, e: &InvocationError) -> bool {
+        self.attempts += 1;
+        if self.attempts >= ATTEMPTS {
+            return false;
+        }
+        match classify(e) {
+            Failure::FloodWait(wait) if wait <= MAX_FLOOD_WAIT => {
+                println!(
+                    "Telegram asked to wait {}s before the next request",
+                    wait.as_secs()
+                );
+                // The other process is on the same account, so it has to wait too
+                self.budget.block_for(wait).await;
+                tokio::time::sleep(wait).await;
+                true
+            }
+            Failure::Transient if self.idempotent => {
+                tokio::time::sleep(backoff(self.attempts)).await;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// Doubles with every attempt, at a random point in the upper half so callers that
+// failed together do not retry together.
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_DELAY);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+// Token bucket kept in SQLite, so processes sharing the file share the bucket.
+struct RequestBudget {
+    conn: Mutex<Connection>,
+}
+
+impl RequestBudget {
+    fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        // Losing the bucket in a crash costs nothing
+        conn.pragma_update(None, "synchronous", "OFF")?;
+        conn.execute_batch(BUDGET_SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    // Takes a token if one is free; otherwise returns how long until one is.
+    fn take(&self) -> rusqlite::Result<Option<Duration>> {
+        let mut conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let (tokens, updated_at, blocked_until): (f64, f64, f64) = tx.query_row(
+            "SELECT tokens, updated_at, blocked_until FROM budget WHERE id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        let now = now_secs();
+        if now < blocked_until {
+            return Ok(Some(Duration::from_secs_f64(blocked_until - now)));
+        }
+        let tokens = (tokens + (now - updated_at).max(0.0) * REQUESTS_PER_SECOND).min(BURST);
+        if tokens < 1.0 {
+            return Ok(Some(Duration::from_secs_f64(
+                (1.0 - tokens) / REQUESTS_PER_SECOND,
+            )));
+        }
+        tx.execute(
+            "UPDATE budget SET tokens = ?1, updated_at = ?2 WHERE id = 1",
+            params![tokens - 1.0, now],
+        )?;
+        tx.commit()?;
+        Ok(None)
+    }
+
+    fn block_for(&self, wait: Duration) {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let until = now_secs() + wait.as_secs_f64();
+        if let Err(e) = conn.execute(
+            "UPDATE budget SET blocked_until = MAX(blocked_until, ?1) WHERE id = 1",
+            params![until],
+        ) {
+            eprintln!("Failed to record flood wait: {}", e);
+        }
+    }
+}
+
+fn now_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
@@ -0,0 +1,1556 @@
+use futures::StreamExt;
+use grammers_client::types::{Chat, Downloadable, Media, Message};
+use grammers_client::Client;
+use grammers_session::{PackedChat, PackedType};
+use grammers_tl_types as tl;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+use crate::crypto::{CryptoError, EncryptionInfo, Vault};
+use crate::manifest::Manifest;
+use crate::retry::{self, Budget, Failure, Retry};
+use crate::targets::{StorageChannel, StorageTargets, Storages};
+
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, StorageError>> + Send + 'a>>;
+
+/// Called with the number of bytes transferred so far.
+pub type ProgressFn = Arc<dyn Fn(u64) + Send + Sync>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// The message does not exist or carries no file.
+    NotFound(i32),
+    Io(String),
+    /// The remote side (Telegram) refused or failed the request.
+    Transport(String),
+    /// Sealing or opening an encrypted blob failed.
+    Crypto(CryptoError),
+    /// The transfer was stopped through its `Transfer` handle.
+    Stopped,
+    /// Downloaded contents are not the size or checksum on record.
+    Integrity(String),
+    /// The channel is not among the storage targets of this device.
+    UnknownChat(i64),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound(id) => write!(f, "Message {} not found", id),
+            StorageError::Io(e) => write!(f, "Storage I/O error: {}", e),
+            StorageError::Transport(e) => write!(f, "Telegram error: {}", e),
+            StorageError::Crypto(e) => write!(f, "{}", e),
+            StorageError::Stopped => write!(f, "Transfer stopped"),
+            StorageError::Integrity(e) => write!(f, "Download failed verification: {}", e),
+            StorageError::UnknownChat(id) => {
+                write!(f, "Storage channel {} is not set up on this device", id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e.to_string())
+    }
+}
+
+impl From<grammers_client::InvocationError> for StorageError {
+    fn from(e: grammers_client::InvocationError) -> Self {
+        StorageError::Transport(e.to_string())
+    }
+}
+
+impl From<CryptoError> for StorageError {
+    fn from(e: CryptoError) -> Self {
+        StorageError::Crypto(e)
+    }
+}
+
+impl From<StorageError> for String {
+    fn from(e: StorageError) -> Self {
+        e.to_string()
+    }
+}
+
+/// A stored file as seen from the remote side.
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteBlob {
+    pub message_id: i32,
+    /// File name from the document attributes. Empty for photos.
+    pub name: String,
+    pub size: i64,
+    pub mime_type: String,
+    pub caption: String,
+    pub is_photo: bool,
+    /// When the message was sent, in Unix seconds.
+    pub date: i64,
+}
+
+/// A stretch of the message history, see `StorageBackend::history`.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryPage {
+    /// Files among the messages, newest first.
+    pub blobs: Vec<RemoteBlob>,
+    /// Where the next, older page starts. `None` once the history is exhausted.
+    pub next_offset: Option<i32>,
+}
+
+/// One message holding part of a file's stored contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub message_id: i32,
+    /// Bytes of the stored blob in this message.
+    pub size: u64,
+}
+
+/// Upload parts of one blob that already reached the remote side under `file_id`.
+/// Telegram keeps uploaded parts for a while before they become a message, so an
+/// interrupted upload can carry on under the same id instead of starting over.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartLog {
+    /// Zero until the first part is sent.
+    pub file_id: i64,
+    pub done: BTreeSet<u32>,
+    /// Bytes in the parts that are done.
+    pub bytes: u64,
+}
+
+/// Handle on one blob upload: which parts are done, a way to stop it, and where to
+/// report progress. Clones share the same state, so the log can be read (and saved)
+/// while the upload runs.
+#[derive(Clone)]
+pub struct Transfer {
+    parts: Arc<Mutex<PartLog>>,
+    stopped: Arc<AtomicBool>,
+    progress: Option<ProgressFn>,
+}
+
+impl std::fmt::Debug for Transfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transfer")
+            .field("parts", &self.parts())
+            .field("stopped", &self.is_stopped())
+            .finish()
+    }
+}
+
+impl Transfer {
+    pub fn new(progress: Option<ProgressFn>) -> Self {
+        Self::resume(PartLog::default(), progress)
+    }
+
+    /// Continues an upload that already sent the parts in `parts`.
+    pub fn resume(parts: PartLog, progress: Option<ProgressFn>) -> Self {
+        Self {
+            parts: Arc::new(Mutex::new(parts)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            progress,
+        }
+    }
+
+    /// Asks the upload to stop. Parts in flight finish, then `put_range` returns
+    /// `StorageError::Stopped`.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    pub fn parts(&self) -> PartLog {
+        self.log().clone()
+    }
+
+    fn log(&self) -> std::sync::MutexGuard<'_, PartLog> {
+        self.parts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The id parts go up under, picking one on first use.
+    fn file_id(&self) -> i64 {
+        let mut log = self.log();
+        if log.file_id == 0 {
+            log.file_id = rand::thread_rng().gen_range(1..i64::MAX);
+        }
+        log.file_id
+    }
+
+    // Forgets everything sent so far and starts over under a new id.
+    fn restart(&self) {
+        *self.log() = PartLog::default();
+    }
+
+    fn is_done(&self, part: u32) -> bool {
+        self.log().done.contains(&part)
+    }
+
+    fn mark_done(&self, part: u32, len: u64) {
+        let bytes = {
+            let mut log = self.log();
+            if log.done.insert(part) {
+                log.bytes += len;
+            }
+            log.bytes
+        };
+        if let Some(progress) = &self.progress {
+            progress(bytes);
+        }
+    }
+}
+
+/// Where file contents live. Blobs are addressed by message id; a file larger than
+/// one message can hold is stored as several, see `put_segmented`. Everything that
+/// talks to Telegram about file contents goes through this, which lets the app and
+/// the daemon run against `MemoryBackend`.
+pub trait StorageBackend: Send + Sync + std::fmt::Debug {
+    /// Uploads the bytes of the file at `path` in `range` as one message and returns
+    /// its id. The range must fit in `max_blob_size`. Parts `transfer` already has
+    /// are not sent again.
+    fn put_range<'a>(
+        &'a self,
+        path: &'a Path,
+        range: Range<u64>,
+        name: &'a str,
+        mime_type: &'a str,
+        caption: &'a str,
+        transfer: &'a Transfer,
+    ) -> StorageFuture<'a, i32>;
+
+    /// Uploads the file at `path` and returns the id of the new message.
+    fn put_file<'a>(
+        &'a self,
+        path: &'a Path,
+        name: &'a str,
+        mime_type: &'a str,
+        caption: &'a str,
+        progress: Option<ProgressFn>,
+    ) -> StorageFuture<'a, i32> {
+        Box::pin(async move {
+            let size = tokio::fs::metadata(path).await?.len();
+            let transfer = Transfer::new(progress);
+            self.put_range(path, 0..size, name, mime_type, caption, &transfer)
+                .await
+        })
+    }
+
+    /// Largest blob a single message can hold.
+    fn max_blob_size(&self) -> u64;
+
+    /// Appends the whole blob to `out` and returns the number of bytes written.
+    /// Progress counts bytes of this blob only.
+    fn append_to<'a>(
+        &'a self,
+        message_id: i32,
+        out: &'a mut tokio::fs::File,
+        progress: Option<ProgressFn>,
+    ) -> StorageFuture<'a, u64>;
+
+    /// Writes the whole blob to `dest`, replacing it.
+    fn download_to<'a>(
+        &'a self,
+        message_id: i32,
+        dest: &'a Path,
+        progress: Option<ProgressFn>,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let mut out = tokio::fs::File::create(dest).await?;
+            self.append_to(message_id, &mut out, progress).await?;
+            out.flush().await?;
+            Ok(())
+        })
+    }
+
+    /// Reads up to `len` bytes starting at `offset`. Shorter only at the end of the blob.
+    fn read_range(&self, message_id: i32, offset: u64, len: usize) -> StorageFuture<'_, Vec<u8>>;
+
+    fn delete<'a>(&'a self, message_ids: &'a [i32]) -> StorageFuture<'a, ()>;
+
+    /// The most recent `limit` messages that carry a file, newest first.
+    fn list(&self, limit: usize) -> StorageFuture<'_, Vec<RemoteBlob>>;
+
+    /// Up to `limit` messages older than `offset_id`, or the newest ones for 0. Unlike
+    /// `list`, messages without a file count towards the limit, so the whole history
+    /// can be read a page at a time.
+    fn history(&self, offset_id: i32, limit: usize) -> StorageFuture<'_, HistoryPage>;
+
+    /// One entry per id, in order. `None` where the message is gone or has no file.
+    fn lookup<'a>(&'a self, message_ids: &'a [i32]) -> StorageFuture<'a, Vec<Option<RemoteBlob>>>;
+
+    /// Base64 JPEG preview of the blob, if the remote side made one.
+    fn thumbnail(&self, message_id: i32) -> StorageFuture<'_, Option<String>>;
+
+    /// Copies messages of this chat into `to` without sending their bytes again and
+    /// returns the new ids in order. `None` when the two cannot forward between each
+    /// other; the blobs then have to be downloaded and sent again.
+    fn forward_to<'a>(
+        &'a self,
+        _message_ids: &'a [i32],
+        _to: &'a dyn StorageBackend,
+    ) -> StorageFuture<'a, Option<Vec<i32>>> {
+        Box::pin(async move { Ok(None) })
+    }
+
+    /// The Telegram chat the messages are in, for `forward_to`.
+    fn telegram_chat(&self) -> Option<PackedChat> {
+        None
+    }
+}
+
+/// Uploads the file at `path`, split into as many messages as its size needs. The
+/// segments are numbered `name.001`, `name.002`, ... when there is more than one.
+/// Segments already sent are deleted again if a later one fails.
+pub async fn put_segmented(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    name: &str,
+    mime_type: &str,
+    caption: &str,
+    progress: Option<ProgressFn>,
+) -> Result<Vec<Segment>, StorageError> {
+    let size = tokio::fs::metadata(path).await?.len();
+    let count = segment_ranges(size, storage.max_blob_size()).len();
+
+    let mut segments = Vec::new();
+    for (index, range) in segment_ranges(size, storage.max_blob_size())
+        .into_iter()
+        .enumerate()
+    {
+        let transfer = Transfer::new(offset_progress(&progress, range.start));
+        let result = storage
+            .put_range(
+                path,
+                range.clone(),
+                &segment_name(name, index, count),
+                mime_type,
+                caption,
+                &transfer,
+            )
+            .await;
+        match result {
+            Ok(message_id) => segments.push(Segment {
+                message_id,
+                size: range.end - range.start,
+            }),
+            Err(e) => {
+                let sent: Vec<i32> = segments.iter().map(|s| s.message_id).collect();
+                if let Err(cleanup) = storage.delete(&sent).await {
+                    eprintln!("Failed to delete partial upload: {}", cleanup);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(segments)
+}
+
+/// Byte ranges of the segments a blob of `size` bytes is split into. An empty blob
+/// still gets one (empty) segment.
+pub fn segment_ranges(size: u64, limit: u64) -> Vec<Range<u64>> {
+    let count = size.div_ceil(limit).max(1);
+    (0..count)
+        .map(|index| index * limit..((index + 1) * limit).min(size))
+        .collect()
+}
+
+/// Name of segment `index` out of `count`: `name` itself when there is only one.
+pub fn segment_name(name: &str, index: usize, count: usize) -> String {
+    if count == 1 {
+        name.to_string()
+    } else {
+        format!("{}.{:03}", name, index + 1)
+    }
+}
+
+// Reports progress of one segment as progress through the whole file.
+fn offset_progress(progress: &Option<ProgressFn>, offset: u64) -> Option<ProgressFn> {
+    let progress = progress.clone()?;
+    Some(Arc::new(move |done| progress(offset + done)))
+}
+
+/// Seals the file at `path` with the vault and uploads it under an opaque name, so
+/// neither its contents nor its name reach the remote side. `manifest` goes along,
+/// sealed too, completed with how the file was sealed.
+pub async fn put_sealed(
+    storage: &dyn StorageBackend,
+    vault: &Vault,
+    path: &Path,
+    manifest: &Manifest,
+    progress: Option<ProgressFn>,
+) -> Result<(Vec<Segment>, EncryptionInfo), StorageError> {
+    let sealed_path = std::env::temp_dir().join(format!("paperfold_sealed_{}", Uuid::new_v4()));
+    let name = format!("{}.bin", Uuid::new_v4());
+    let result = async {
+        let info = {
+            let (vault, path, sealed_path) =
+                (vault.clone(), path.to_path_buf(), sealed_path.clone());
+            tokio::task::spawn_blocking(move || vault.seal_file(&path, &sealed_path))
+                .await
+                .map_err(|e| StorageError::Io(e.to_string()))??
+        };
+        let caption = Manifest {
+            encryption: Some(info.clone()),
+            ..manifest.clone()
+        }
+        .caption(Some(vault));
+        let segments = put_segmented(
+            storage,
+            &sealed_path,
+            &name,
+            "application/octet-stream",
+            &caption,
+            progress,
+        )
+        .await?;
+        Ok((segments, info))
+    }
+    .await;
+    let _ = tokio::fs::remove_file(&sealed_path).await;
+    result
+}
+
+/// Writes a file's contents to `dest`, joining its segments and decrypting them
+/// when the blob is sealed. The blob comes down into `dest.part` a few chunks at a
+/// time, and a download that was cut short carries on from there. `dest` only
+/// appears once the contents have the stored size and, when one is given, the
+/// stored SHA-256. Progress counts bytes of the stored blob.
+pub async fn download_file(
+    storage: &dyn StorageBackend,
+    segments: &[Segment],
+    encryption: Option<&EncryptionInfo>,
+    vault: Option<&Vault>,
+    sha256: Option<&str>,
+    dest: &Path,
+    progress: Option<ProgressFn>,
+) -> Result<(), StorageError> {
+    let part_path = with_suffix(dest, ".part");
+    match encryption {
+        None => fetch_blob(storage, segments, &part_path, progress).await?,
+        Some(info) => {
+            let vault = vault.ok_or(CryptoError::Locked)?;
+            let sealed_path = with_suffix(dest, ".sealed.part");
+            fetch_blob(storage, segments, &sealed_path, progress).await?;
+            let (vault, info) = (vault.clone(), info.clone());
+            let (src, out) = (sealed_path.clone(), part_path.clone());
+            let opened = tokio::task::spawn_blocking(move || vault.open_file(&info, &src, &out))
+                .await
+                .map_err(|e| StorageError::Io(e.to_string()))?;
+            // A blob that does not open would not open next time either
+            let _ = tokio::fs::remove_file(&sealed_path).await;
+            opened?;
+        }
+    }
+
+    if let Some(expected) = sha256 {
+        let actual = file_sha256(&part_path).await?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            return Err(StorageError::Integrity(format!(
+                "SHA-256 is {}, expected {}",
+                actual, expected
+            )));
+        }
+    }
+    tokio::fs::rename(&part_path, dest).await?;
+    Ok(())
+}
+
+/// Hex SHA-256 of the file at `path`.
+pub async fn file_sha256(path: &Path) -> Result<String, StorageError> {
+    let path = path.to_path_buf();
+    let digest = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let mut file = std::fs::File::open(&path)?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok(hex::encode(hasher.finalize()))
+    })
+    .await
+    .map_err(|e| StorageError::Io(e.to_string()))??;
+    Ok(digest)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Chunks of one download requested at once.
+const PARALLEL_CHUNKS: usize = 8;
+// How often a download's chunk log is written. Chunks fetched after the last write
+// are fetched again when the download resumes.
+const CHUNK_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// Chunks of a blob already in its `.part` file, kept next to it as `.part.json`.
+// Only good for the same segments read in chunks of the same size.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkLog {
+    segments: Vec<Segment>,
+    chunk_size: u64,
+    done: BTreeSet<u64>,
+}
+
+// `len` bytes at `offset` in one segment's message, which go at `position` in the
+// joined blob.
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    index: u64,
+    message_id: i32,
+    offset: u64,
+    position: u64,
+    len: u64,
+}
+
+fn plan_chunks(segments: &[Segment], chunk_size: u64) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut position = 0;
+    for segment in segments {
+        let mut offset = 0;
+        while offset < segment.size {
+            let len = (segment.size - offset).min(chunk_size);
+            chunks.push(Chunk {
+                index: chunks.len() as u64,
+                message_id: segment.message_id,
+                offset,
+                position: position + offset,
+                len,
+            });
+            offset += len;
+        }
+        position += segment.size;
+    }
+    chunks
+}
+
+// Joins the segments into `part_path`, several chunks at a time, picking up the
+// chunks an earlier attempt left there. Every chunk must come back whole, or the
+// joined blob would be silently shifted.
+async fn fetch_blob(
+    storage: &dyn StorageBackend,
+    segments: &[Segment],
+    part_path: &Path,
+    progress: Option<ProgressFn>,
+) -> Result<(), StorageError> {
+    // Photos synced from Saved Messages have no size on record. They are small, so
+    // they come down in one piece.
+    if let [Segment {
+        message_id,
+        size: 0,
+    }] = segments
+    {
+        // Empty files never got a message
+        if *message_id == -1 {
+            tokio::fs::File::create(part_path).await?;
+            return Ok(());
+        }
+        return storage.download_to(*message_id, part_path, progress).await;
+    }
+
+    let log_path = with_suffix(part_path, ".json");
+    let chunk_size = DOWNLOAD_CHUNK_SIZE as u64;
+    let total: u64 = segments.iter().map(|s| s.size).sum();
+    let part_len = tokio::fs::metadata(part_path).await.map(|m| m.len()).ok();
+    let mut log = match read_chunk_log(&log_path).await {
+        Some(log)
+            if log.segments == segments
+                && log.chunk_size == chunk_size
+                && part_len == Some(total) =>
+        {
+            log
+        }
+        _ => {
+            tokio::fs::File::create(part_path)
+                .await?
+                .set_len(total)
+                .await?;
+            ChunkLog {
+                segments: segments.to_vec(),
+                chunk_size,
+                done: BTreeSet::new(),
+            }
+        }
+    };
+
+    let pending: Vec<Chunk> = plan_chunks(segments, chunk_size)
+        .into_iter()
+        .filter(|chunk| !log.done.contains(&chunk.index))
+        .collect();
+    let mut fetched = total - pending.iter().map(|c| c.len).sum::<u64>();
+    if let Some(progress) = &progress {
+        progress(fetched);
+    }
+
+    let mut out = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(part_path)
+        .await?;
+    let mut fetches = futures::stream::iter(pending)
+        .map(|chunk| async move {
+            let data = storage
+                .read_range(chunk.message_id, chunk.offset, chunk.len as usize)
+                .await?;
+            Ok::<_, StorageError>((chunk, data))
+        })
+        .buffer_unordered(PARALLEL_CHUNKS);
+    let mut ticker = tokio::time::interval(CHUNK_LOG_INTERVAL);
+    let result = async {
+        loop {
+            tokio::select! {
+                next = fetches.next() => {
+                    let Some(next) = next else {
+                        break;
+                    };
+                    let (chunk, data) = next?;
+                    if data.len() as u64 != chunk.len {
+                        return Err(StorageError::Integrity(format!(
+                            "Message {} is shorter than the {} bytes on record",
+                            chunk.message_id,
+                            chunk.offset + data.len() as u64
+                        )));
+                    }
+                    out.seek(std::io::SeekFrom::Start(chunk.position)).await?;
+                    out.write_all(&data).await?;
+                    log.done.insert(chunk.index);
+                    fetched += chunk.len;
+                    if let Some(progress) = &progress {
+                        progress(fetched);
+                    }
+                }
+                _ = ticker.tick() => write_chunk_log(&mut out, &log, &log_path).await?,
+            }
+        }
+        out.sync_all().await?;
+        let len = out.metadata().await?.len();
+        if len != total {
+            return Err(StorageError::Integrity(format!(
+                "Downloaded {} bytes, expected {}",
+                len, total
+            )));
+        }
+        Ok(())
+    }
+    .await;
+    drop(fetches);
+
+    match result {
+        Ok(()) => {
+            let _ = tokio::fs::remove_file(&log_path).await;
+            Ok(())
+        }
+        // Nothing worth keeping: the blob is gone or does not match what is on record
+        Err(e @ (StorageError::NotFound(_) | StorageError::Integrity(_))) => {
+            let _ = tokio::fs::remove_file(&log_path).await;
+            let _ = tokio::fs::remove_file(part_path).await;
+            Err(e)
+        }
+        Err(e) => {
+            if let Err(log_error) = write_chunk_log(&mut out, &log, &log_path).await {
+                eprintln!("Failed to save download progress: {}", log_error);
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn read_chunk_log(path: &Path) -> Option<ChunkLog> {
+    let data = tokio::fs::read(path).await.ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+// Chunks only count as done once they are on disk, so the part file is synced first.
+async fn write_chunk_log(
+    out: &mut tokio::fs::File,
+    log: &ChunkLog,
+    path: &Path,
+) -> Result<(), StorageError> {
+    out.flush().await?;
+    out.sync_data().await?;
+    let data = serde_json::to_vec(log).map_err(|e| StorageError::Io(e.to_string()))?;
+    let tmp_path = with_suffix(path, ".tmp");
+    tokio::fs::write(&tmp_path, data).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+// Next item of a grammers iterator (downloads, message history), retried like
+// `retry::call`. A failed fetch leaves the iterator where it was, so asking again
+// picks up at the same place.
+macro_rules! next_retried {
+    ($budget:expr, $iter:expr) => {
+        async {
+            let mut retry = Retry::new($budget);
+            loop {
+                retry.ready().await;
+                match $iter.next().await {
+                    Ok(item) => break Ok(item),
+                    Err(e) => {
+                        if !retry.after(&e).await {
+                            break Err(e);
+                        }
+                    }
+                }
+            }
+        }
+    };
+}
+
+// Telegram caps upload parts at 512 KiB and wants the "big file" API above 10 MiB.
+const UPLOAD_PART_SIZE: usize = 512 * 1024;
+const BIG_FILE_THRESHOLD: u64 = 10 * 1024 * 1024;
+const PARALLEL_PARTS: usize = 16;
+// Most parts a single document may have: 2000 MiB, or 4000 MiB with Premium.
+const MAX_PARTS: u64 = 4000;
+const PREMIUM_MAX_PARTS: u64 = 8000;
+// Download requests must be a multiple of 4 KiB, at most 512 KiB.
+const DOWNLOAD_CHUNK_SIZE: usize = 512 * 1024;
+const MEDIA_CACHE_SIZE: usize = 256;
+
+/// Stores blobs as documents in the user's Saved Messages, or in a channel.
+#[derive(Clone)]
+pub struct TelegramBackend {
+    client: Client,
+    budget: Budget,
+    chat: PackedChat,
+    // Premium accounts may send documents twice as large
+    premium: bool,
+    // Media of messages read recently, so a blob read chunk by chunk is looked up
+    // once rather than for every chunk.
+    media: Arc<Mutex<HashMap<i32, Media>>>,
+}
+
+impl std::fmt::Debug for TelegramBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TelegramBackend").finish()
+    }
+}
+
+impl TelegramBackend {
+    pub async fn saved_messages(client: Client, budget: &Budget) -> Result<Self, StorageError> {
+        let me = retry::call(budget, || client.get_me()).await?;
+        Ok(Self {
+            client,
+            budget: budget.clone(),
+            premium: me.raw.premium,
+            chat: me.pack(),
+            media: Arc::default(),
+        })
+    }
+
+    /// Storage for Saved Messages and every channel in `targets`, new uploads going
+    /// where `targets` says.
+    pub async fn storages(
+        client: Client,
+        budget: &Budget,
+        targets: &StorageTargets,
+    ) -> Result<Storages, StorageError> {
+        let saved = Self::saved_messages(client, budget).await?;
+        let mut storages = Storages::new(Arc::new(saved.clone()));
+        for channel in &targets.channels {
+            storages = storages.with_channel(channel.id, Arc::new(saved.in_channel(channel)));
+        }
+        Ok(storages.upload_to(targets.upload_to))
+    }
+
+    // The same account, storing in `channel` instead.
+    fn in_channel(&self, channel: &StorageChannel) -> Self {
+        Self {
+            client: self.client.clone(),
+            budget: self.budget.clone(),
+            chat: PackedChat {
+                ty: PackedType::Broadcast,
+                id: channel.id,
+                access_hash: Some(channel.access_hash),
+            },
+            premium: self.premium,
+            media: Arc::default(),
+        }
+    }
+
+    /// Creates a private channel to store files in.
+    pub async fn create_channel(
+        client: &Client,
+        budget: &Budget,
+        title: &str,
+    ) -> Result<StorageChannel, StorageError> {
+        let request = tl::functions::channels::CreateChannel {
+            broadcast: true,
+            megagroup: false,
+            for_import: false,
+            forum: false,
+            title: title.to_string(),
+            about: "Files stored by Paperfold".to_string(),
+            geo_point: None,
+            address: None,
+            ttl_period: None,
+        };
+        // Sending it again could leave a second channel behind, so a broken connection
+        // is answered by looking for the channel first.
+        let updates = match retry::call_once(budget, || client.invoke(&request)).await {
+            Ok(updates) => updates,
+            Err(e) if retry::classify(&e) == Failure::Transient => {
+                let created = Self::owned_channels(client, budget)
+                    .await?
+                    .into_iter()
+                    .filter(|c| c.title == title)
+                    .max_by_key(|c| c.id);
+                if let Some(channel) = created {
+                    return Ok(channel);
+                }
+                retry::call_once(budget, || client.invoke(&request))
+                    .await
+                    .map_err(|e| StorageError::Transport(format!("CreateChannel error: {}", e)))?
+            }
+            Err(e) => {
+                return Err(StorageError::Transport(format!(
+                    "CreateChannel error: {}",
+                    e
+                )))
+            }
+        };
+        let chats = match updates {
+            tl::enums::Updates::Updates(u) => u.chats,
+            tl::enums::Updates::Combined(u) => u.chats,
+            _ => Vec::new(),
+        };
+        chats
+            .into_iter()
+            .find_map(|chat| match chat {
+                tl::enums::Chat::Channel(c) => Some(StorageChannel {
+                    id: c.id,
+                    access_hash: c.access_hash.unwrap_or(0),
+                    title: c.title,
+                }),
+                _ => None,
+            })
+            .ok_or_else(|| StorageError::Transport("CreateChannel returned no channel".to_string()))
+    }
+
+    /// Channels the user created, any of which can be used to store files in.
+    pub async fn owned_channels(
+        client: &Client,
+        budget: &Budget,
+    ) -> Result<Vec<StorageChannel>, StorageError> {
+        let mut dialogs = client.iter_dialogs();
+        let mut channels = Vec::new();
+        while let Some(dialog) = next_retried!(budget, dialogs).await? {
+            if let Chat::Channel(channel) = dialog.chat() {
+                if channel.raw.creator && channel.raw.broadcast {
+                    channels.push(StorageChannel {
+                        id: channel.raw.id,
+                        access_hash: channel.raw.access_hash.unwrap_or(0),
+                        title: channel.raw.title.clone(),
+                    });
+                }
+            }
+        }
+        Ok(channels)
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    async fn message(&self, message_id: i32) -> Result<Message, StorageError> {
+        let ids = [message_id];
+        let messages = retry::call(&self.budget, || {
+            self.client.get_messages_by_id(self.chat, &ids)
+        })
+        .await?;
+        messages
+            .into_iter()
+            .next()
+            .flatten()
+            .ok_or(StorageError::NotFound(message_id))
+    }
+
+    async fn downloadable(&self, message_id: i32) -> Result<Downloadable, StorageError> {
+        match self.message(message_id).await?.media() {
+            Some(Media::Photo(p)) => Ok(Downloadable::Media(Media::Photo(p))),
+            Some(Media::Document(d)) => Ok(Downloadable::Media(Media::Document(d))),
+            _ => Err(StorageError::NotFound(message_id)),
+        }
+    }
+
+    async fn cached_media(&self, message_id: i32) -> Result<Media, StorageError> {
+        if let Some(media) = self.media_cache().get(&message_id) {
+            return Ok(media.clone());
+        }
+        let media = match self.message(message_id).await?.media() {
+            Some(media @ (Media::Photo(_) | Media::Document(_))) => media,
+            _ => return Err(StorageError::NotFound(message_id)),
+        };
+        let mut cache = self.media_cache();
+        if cache.len() >= MEDIA_CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(message_id, media.clone());
+        Ok(media)
+    }
+
+    fn media_cache(&self) -> std::sync::MutexGuard<'_, HashMap<i32, Media>> {
+        self.media.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Reads through `upload.GetFile` from the chunk holding `offset`, one chunk per
+    // request.
+    async fn read_chunks(
+        &self,
+        media: Media,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, grammers_client::InvocationError> {
+        let downloadable = Downloadable::Media(media);
+        let first_chunk = offset / DOWNLOAD_CHUNK_SIZE as u64;
+        let mut skip = (offset % DOWNLOAD_CHUNK_SIZE as u64) as usize;
+        let mut stream = self
+            .client
+            .iter_download(&downloadable)
+            .chunk_size(DOWNLOAD_CHUNK_SIZE as i32)
+            .skip_chunks(first_chunk as i32);
+
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let Some(chunk) = next_retried!(&self.budget, stream).await? else {
+                break;
+            };
+            let chunk = &chunk[skip.min(chunk.len())..];
+            skip = 0;
+            let take = chunk.len().min(len - data.len());
+            data.extend_from_slice(&chunk[..take]);
+        }
+        Ok(data)
+    }
+
+    // Sends every part of `range` the transfer does not have yet, a few at a time.
+    async fn save_parts(
+        &self,
+        path: &Path,
+        range: Range<u64>,
+        file_id: i64,
+        total_parts: u32,
+        is_big: bool,
+        transfer: &Transfer,
+    ) -> Result<(), StorageError> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let semaphore = Arc::new(Semaphore::new(PARALLEL_PARTS));
+        // Dropping the set aborts the parts still in flight
+        let mut tasks = JoinSet::new();
+        // Set by the first part that fails, so no more are read or sent
+        let failed = Arc::new(AtomicBool::new(false));
+
+        for part in 0..total_parts {
+            if transfer.is_stopped() || failed.load(Ordering::Acquire) {
+                break;
+            }
+            if transfer.is_done(part) {
+                continue;
+            }
+            let permit = semaphore
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|e| StorageError::Io(e.to_string()))?;
+            // A part may have failed while we waited for a slot
+            if failed.load(Ordering::Acquire) {
+                break;
+            }
+
+            // Every part but the last must be full-sized.
+            let offset = range.start + part as u64 * UPLOAD_PART_SIZE as u64;
+            let len = (range.end - offset).min(UPLOAD_PART_SIZE as u64);
+            let mut buffer = vec![0u8; len as usize];
+            file.seek(std::io::SeekFrom::Start(offset)).await?;
+            file.read_exact(&mut buffer).await?;
+
+            let client = self.client.clone();
+            let budget = self.budget.clone();
+            let transfer = transfer.clone();
+            let failed = failed.clone();
+            tasks.spawn(async move {
+                let result = if is_big {
+                    let request = tl::functions::upload::SaveBigFilePart {
+                        file_id,
+                        file_part: part as i32,
+                        file_total_parts: total_parts as i32,
+                        bytes: buffer,
+                    };
+                    retry::call(&budget, || client.invoke(&request)).await
+                } else {
+                    let request = tl::functions::upload::SaveFilePart {
+                        file_id,
+                        file_part: part as i32,
+                        bytes: buffer,
+                    };
+                    retry::call(&budget, || client.invoke(&request)).await
+                };
+                if let Err(e) = result {
+                    failed.store(true, Ordering::Release);
+                    return Err(StorageError::Transport(format!(
+                        "Part {} failed: {}",
+                        part, e
+                    )));
+                }
+                drop(permit);
+                transfer.mark_done(part, len);
+                Ok(())
+            });
+        }
+
+        while let Some(joined) = tasks.join_next().await {
+            joined.map_err(|e| StorageError::Io(format!("Task join error: {}", e)))??;
+        }
+        if transfer.is_stopped() {
+            return Err(StorageError::Stopped);
+        }
+        Ok(())
+    }
+
+    // Turns uploaded parts into a document in the chat.
+    async fn send_document(
+        &self,
+        blob: UploadedBlob,
+        name: &str,
+        mime_type: &str,
+        caption: &str,
+    ) -> Result<i32, StorageError> {
+        let UploadedBlob {
+            file_id,
+            total_parts,
+            is_big,
+        } = blob;
+        let input_file = if is_big {
+            tl::enums::InputFile::Big(tl::types::InputFileBig {
+                id: file_id,
+                parts: total_parts as i32,
+                name: name.to_string(),
+            })
+        } else {
+            tl::enums::InputFile::File(tl::types::InputFile {
+                id: file_id,
+                parts: total_parts as i32,
+                name: name.to_string(),
+                md5_checksum: "".to_string(),
+            })
+        };
+
+        let input_media =
+            tl::enums::InputMedia::UploadedDocument(tl::types::InputMediaUploadedDocument {
+                file: input_file,
+                mime_type: mime_type.to_string(),
+                attributes: vec![tl::enums::DocumentAttribute::Filename(
+                    tl::types::DocumentAttributeFilename {
+                        file_name: name.to_string(),
+                    },
+                )],
+                ttl_seconds: None,
+                force_file: false,
+                spoiler: false,
+                stickers: None,
+                thumb: None,
+                nosound_video: false,
+            });
+
+        // Telegram drops a repeat with the same random id, so a retried send cannot
+        // post the document twice
+        let request = tl::functions::messages::SendMedia {
+            silent: false,
+            background: false,
+            clear_draft: false,
+            peer: self.chat.to_input_peer(),
+            reply_to: None,
+            media: input_media,
+            message: caption.to_string(),
+            random_id: rand::thread_rng().gen(),
+            reply_markup: None,
+            entities: None,
+            schedule_date: None,
+            send_as: None,
+            noforwards: false,
+            update_stickersets_order: false,
+            invert_media: false,
+            quick_reply_shortcut: None,
+            effect: None,
+        };
+        let updates = retry::call(&self.budget, || self.client.invoke(&request))
+            .await
+            .map_err(|e| StorageError::Transport(format!("SendMedia error: {}", e)))?;
+
+        let message_id = match updates {
+            tl::enums::Updates::Updates(u) => u.updates.iter().find_map(|u| match u {
+                tl::enums::Update::MessageId(id) => Some(id.id),
+                tl::enums::Update::NewMessage(m) => match &m.message {
+                    tl::enums::Message::Message(msg) => Some(msg.id),
+                    _ => None,
+                },
+                tl::enums::Update::NewChannelMessage(m) => match &m.message {
+                    tl::enums::Message::Message(msg) => Some(msg.id),
+                    _ => None,
+                },
+                _ => None,
+            }),
+            _ => None,
+        };
+        message_id
+            .ok_or_else(|| StorageError::Transport("SendMedia returned no message id".to_string()))
+    }
+}
+
+// Parts saved under one file id, ready to become a document.
+struct UploadedBlob {
+    file_id: i64,
+    total_parts: u32,
+    is_big: bool,
+}
+
+fn remote_blob(message: &Message) -> Option<RemoteBlob> {
+    let (name, size, mime_type, is_photo) = match message.media()? {
+        Media::Document(doc) => (
+            doc.name().to_string(),
+            doc.size(),
+            doc.mime_type()
+                .unwrap_or("application/octet-stream")
+                .to_string(),
+            false,
+        ),
+        // Photo sizes are only known per thumbnail size; the largest is the photo
+        Media::Photo(photo) => (
+            String::new(),
+            photo
+                .thumbs()
+                .iter()
+                .map(|t| t.size() as i64)
+                .max()
+                .unwrap_or(0),
+            "image/jpeg".to_string(),
+            true,
+        ),
+        _ => return None,
+    };
+    Some(RemoteBlob {
+        message_id: message.id(),
+        name,
+        size,
+        mime_type,
+        caption: message.text().to_string(),
+        is_photo,
+        date: message.date().timestamp(),
+    })
+}
+
+impl StorageBackend for TelegramBackend {
+    // Parts go up in parallel through the raw upload API, then one SendMedia turns
+    // them into a document.
+    fn put_range<'a>(
+        &'a self,
+        path: &'a Path,
+        range: Range<u64>,
+        name: &'a str,
+        mime_type: &'a str,
+        caption: &'a str,
+        transfer: &'a Transfer,
+    ) -> StorageFuture<'a, i32> {
+        Box::pin(async move {
+            let file_size = range.end - range.start;
+            if file_size > self.max_blob_size() {
+                return Err(StorageError::Transport(format!(
+                    "{} bytes do not fit in one message",
+                    file_size
+                )));
+            }
+            let is_big = file_size > BIG_FILE_THRESHOLD;
+            let total_parts = file_size.div_ceil(UPLOAD_PART_SIZE as u64) as u32;
+
+            // Parts from an earlier run may have expired on Telegram's side; if
+            // SendMedia says so, send them all again once.
+            let mut restarted = false;
+            loop {
+                let file_id = transfer.file_id();
+                self.save_parts(path, range.clone(), file_id, total_parts, is_big, transfer)
+                    .await?;
+                let blob = UploadedBlob {
+                    file_id,
+                    total_parts,
+                    is_big,
+                };
+                match self.send_document(blob, name, mime_type, caption).await {
+                    Err(StorageError::Transport(e))
+                        if !restarted && e.contains("FILE_PART") && e.contains("MISSING") =>
+                    {
+                        restarted = true;
+                        transfer.restart();
+                    }
+                    result => return result,
+                }
+            }
+        })
+    }
+
+    fn max_blob_size(&self) -> u64 {
+        let parts = if self.premium {
+            PREMIUM_MAX_PARTS
+        } else {
+            MAX_PARTS
+        };
+        parts * UPLOAD_PART_SIZE as u64
+    }
+
+    fn append_to<'a>(
+        &'a self,
+        message_id: i32,
+        out: &'a mut tokio::fs::File,
+        progress: Option<ProgressFn>,
+    ) -> StorageFuture<'a, u64> {
+        Box::pin(async move {
+            let downloadable = self.downloadable(message_id).await?;
+            let mut stream = self.client.iter_download(&downloadable);
+            let mut done = 0u64;
+            while let Some(chunk) = next_retried!(&self.budget, stream).await? {
+                out.write_all(&chunk).await?;
+                done += chunk.len() as u64;
+                if let Some(progress) = &progress {
+                    progress(done);
+                }
+            }
+            Ok(done)
+        })
+    }
+
+    fn read_range(&self, message_id: i32, offset: u64, len: usize) -> StorageFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let mut refreshed = false;
+            loop {
+                let media = self.cached_media(message_id).await?;
+                match self.read_chunks(media, offset, len).await {
+                    // File references expire; look the message up again, once
+                    Err(grammers_client::InvocationError::Rpc(rpc))
+                        if !refreshed && rpc.name.starts_with("FILE_REFERENCE_") =>
+                    {
+                        self.media_cache().remove(&message_id);
+                        refreshed = true;
+                    }
+                    result => return Ok(result?),
+                }
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, message_ids: &'a [i32]) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            if !message_ids.is_empty() {
+                retry::call(&self.budget, || {
+                    self.client.delete_messages(self.chat, message_ids)
+                })
+                .await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn list(&self, limit: usize) -> StorageFuture<'_, Vec<RemoteBlob>> {
+        Box::pin(async move {
+            let mut messages = self.client.iter_messages(self.chat).limit(limit);
+            let mut blobs = Vec::new();
+            while let Some(message) = next_retried!(&self.budget, messages).await? {
+                blobs.extend(remote_blob(&message));
+            }
+            Ok(blobs)
+        })
+    }
+
+    fn history(&self, offset_id: i32, limit: usize) -> StorageFuture<'_, HistoryPage> {
+        Box::pin(async move {
+            let mut messages = self
+                .client
+                .iter_messages(self.chat)
+                .offset_id(offset_id)
+                .limit(limit);
+            let mut page = HistoryPage::default();
+            let mut count = 0;
+            while let Some(message) = next_retried!(&self.budget, messages).await? {
+                count += 1;
+                page.next_offset = Some(message.id());
+                page.blobs.extend(remote_blob(&message));
+            }
+            if count < limit {
+                page.next_offset = None;
+            }
+            Ok(page)
+        })
+    }
+
+    fn lookup<'a>(&'a self, message_ids: &'a [i32]) -> StorageFuture<'a, Vec<Option<RemoteBlob>>> {
+        Box::pin(async move {
+            let messages = retry::call(&self.budget, || {
+                self.client.get_messages_by_id(self.chat, message_ids)
+            })
+            .await?;
+            Ok(messages
+                .iter()
+                .map(|m| m.as_ref().and_then(remote_blob))
+                .collect())
+        })
+    }
+
+    fn thumbnail(&self, message_id: i32) -> StorageFuture<'_, Option<String>> {
+        Box::pin(async move {
+            let message = self.message(message_id).await?;
+            Ok(
+                crate::client::utils::extract_thumbnail_base64(
+                    &self.client,
+                    &self.budget,
+                    &message,
+                )
+                .await,
+            )
+        })
+    }
+
+    fn forward_to<'a>(
+        &'a self,
+        message_ids: &'a [i32],
+        to: &'a dyn StorageBackend,
+    ) -> StorageFuture<'a, Option<Vec<i32>>> {
+        Box::pin(async move {
+            let Some(destination) = to.telegram_chat() else {
+                return Ok(None);
+            };
+            // Forwarded again after a broken connection, the messages could end up in
+            // the destination twice.
+            let forwarded = retry::call_once(&self.budget, || {
+                self.client
+                    .forward_messages(destination, message_ids, self.chat)
+            })
+            .await?;
+            let ids: Vec<i32> = forwarded.iter().flatten().map(|m| m.id()).collect();
+            if ids.len() != message_ids.len() {
+                to.delete(&ids).await?;
+                let lost = message_ids
+                    .iter()
+                    .zip(&forwarded)
+                    .find(|(_, m)| m.is_none())
+                    .map_or(message_ids[0], |(id, _)| *id);
+                return Err(StorageError::NotFound(lost));
+            }
+            Ok(Some(ids))
+        })
+    }
+
+    fn telegram_chat(&self) -> Option<PackedChat> {
+        Some(self.chat)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MemoryBlob {
+    name: String,
+    mime_type: String,
+    caption: String,
+    data: Vec<u8>,
+    date: i64,
+}
+
+/// Keeps blobs in memory. Stands in for Telegram in tests and offline runs.
+#[derive(Debug)]
+pub struct MemoryBackend {
+    blobs: Mutex<BTreeMap<i32, MemoryBlob>>,
+    next_id: AtomicI32,
+    max_blob_size: u64,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::with_max_blob_size(MAX_PARTS * UPLOAD_PART_SIZE as u64)
+    }
+
+    /// A backend that holds at most `max_blob_size` bytes per message, so splitting
+    /// can be exercised without gigabytes of data.
+    pub fn with_max_blob_size(max_blob_size: u64) -> Self {
+        Self {
+            blobs: Mutex::new(BTreeMap::new()),
+            next_id: AtomicI32::new(1),
+            max_blob_size,
+        }
+    }
+
+    /// Stores a blob directly, as if it had been uploaded from another device.
+    pub fn insert(&self, name: &str, mime_type: &str, caption: &str, data: Vec<u8>) -> i32 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.blobs().insert(
+            id,
+            MemoryBlob {
+                name: name.to_string(),
+                mime_type: mime_type.to_string(),
+                caption: caption.to_string(),
+                data,
+                date: chrono::Utc::now().timestamp(),
+            },
+        );
+        id
+    }
+
+    pub fn contents(&self, message_id: i32) -> Option<Vec<u8>> {
+        self.blobs().get(&message_id).map(|b| b.data.clone())
+    }
+
+    fn blobs(&self) -> std::sync::MutexGuard<'_, BTreeMap<i32, MemoryBlob>> {
+        // A panic while holding the map cannot leave it half-updated.
+        self.blobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn blob(&self, message_id: i32) -> Result<MemoryBlob, StorageError> {
+        self.blobs()
+            .get(&message_id)
+            .cloned()
+            .ok_or(StorageError::NotFound(message_id))
+    }
+}
+
+fn memory_remote_blob(message_id: i32, blob: &MemoryBlob) -> RemoteBlob {
+    RemoteBlob {
+        message_id,
+        name: blob.name.clone(),
+        size: blob.data.len() as i64,
+        mime_type: blob.mime_type.clone(),
+        caption: blob.caption.clone(),
+        is_photo: false,
+        date: blob.date,
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn put_range<'a>(
+        &'a self,
+        path: &'a Path,
+        range: Range<u64>,
+        name: &'a str,
+        mime_type: &'a str,
+        caption: &'a str,
+        transfer: &'a Transfer,
+    ) -> StorageFuture<'a, i32> {
+        Box::pin(async move {
+            let len = range.end - range.start;
+            if len > self.max_blob_size {
+                return Err(StorageError::Transport(format!(
+                    "{} bytes do not fit in one message",
+                    len
+                )));
+            }
+            let mut file = tokio::fs::File::open(path).await?;
+            file.seek(std::io::SeekFrom::Start(range.start)).await?;
+            let mut data = Vec::with_capacity(len as usize);
+            file.take(len).read_to_end(&mut data).await?;
+            // Parts are counted the way Telegram would see them
+            for (part, chunk) in data.chunks(UPLOAD_PART_SIZE).enumerate() {
+                if transfer.is_stopped() {
+                    return Err(StorageError::Stopped);
+                }
+                transfer.mark_done(part as u32, chunk.len() as u64);
+            }
+            Ok(self.insert(name, mime_type, caption, data))
+        })
+    }
+
+    fn max_blob_size(&self) -> u64 {
+        self.max_blob_size
+    }
+
+    fn append_to<'a>(
+        &'a self,
+        message_id: i32,
+        out: &'a mut tokio::fs::File,
+        progress: Option<ProgressFn>,
+    ) -> StorageFuture<'a, u64> {
+        Box::pin(async move {
+            let blob = self.blob(message_id)?;
+            out.write_all(&blob.data).await?;
+            if let Some(progress) = &progress {
+                progress(blob.data.len() as u64);
+            }
+            Ok(blob.data.len() as u64)
+        })
+    }
+
+    fn read_range(&self, message_id: i32, offset: u64, len: usize) -> StorageFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let blob = self.blob(message_id)?;
+            let start = (offset as usize).min(blob.data.len());
+            let end = start.saturating_add(len).min(blob.data.len());
+            Ok(blob.data[start..end].to_vec())
+        })
+    }
+
+    fn delete<'a>(&'a self, message_ids: &'a [i32]) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let mut blobs = self.blobs();
+            for id in message_ids {
+                blobs.remove(id);
+            }
+            Ok(())
+        })
+    }
+
+    fn list(&self, limit: usize) -> StorageFuture<'_, Vec<RemoteBlob>> {
+        Box::pin(async move {
+            Ok(self
+                .blobs()
+                .iter()
+                .rev()
+                .take(limit)
+                .map(|(id, blob)| memory_remote_blob(*id, blob))
+                .collect())
+        })
+    }
+
+    fn history(&self, offset_id: i32, limit: usize) -> StorageFuture<'_, HistoryPage> {
+        Box::pin(async move {
+            let blobs: Vec<RemoteBlob> = self
+                .blobs()
+                .iter()
+                .rev()
+                .filter(|(id, _)| offset_id == 0 || **id < offset_id)
+                .take(limit)
+                .map(|(id, blob)| memory_remote_blob(*id, blob))
+                .collect();
+            let next_offset = match blobs.last() {
+                Some(last) if blobs.len() == limit => Some(last.message_id),
+                _ => None,
+            };
+            Ok(HistoryPage { blobs, next_offset })
+        })
+    }
+
+    fn lookup<'a>(&'a self, message_ids: &'a [i32]) -> StorageFuture<'a, Vec<Option<RemoteBlob>>> {
+        Box::pin(async move {
+            let blobs = self.blobs();
+            Ok(message_ids
+                .iter()
+                .map(|id| blobs.get(id).map(|blob| memory_remote_blob(*id, blob)))
+                .collect())
+        })
+    }
+
+    fn thumbnail(&self, _message_id: i32) -> StorageFuture<'_, Option<String>> {
+        Box::pin(async move { Ok(None) })
+    }
+}
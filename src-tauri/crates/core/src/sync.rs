@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::db::{Database, DbError, FileMetadata, Folder};
+use crate::storage::{StorageBackend, StorageError};
+
+/// Caption tag of the messages devices exchange metadata changes through.
+pub const METADATA_DELTA_TAG: &str = "#paperfold_metadata_delta";
+const SYNC_STATE_SETTING: &str = "sync_state";
+const SYNC_CONFLICTS_SETTING: &str = "sync_conflicts";
+// Messages read per request while looking for new deltas.
+const PAGE_SIZE: usize = 100;
+
+#[derive(Debug)]
+pub enum SyncError {
+    Db(DbError),
+    Storage(StorageError),
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::Db(e) => write!(f, "{}", e),
+            SyncError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+impl From<DbError> for SyncError {
+    fn from(e: DbError) -> Self {
+        SyncError::Db(e)
+    }
+}
+
+impl From<StorageError> for SyncError {
+    fn from(e: StorageError) -> Self {
+        SyncError::Storage(e)
+    }
+}
+
+impl From<SyncError> for String {
+    fn from(e: SyncError) -> Self {
+        e.to_string()
+    }
+}
+
+/// A hybrid logical clock reading: wall time in milliseconds, a counter for changes
+/// within the same millisecond or behind a clock that ran ahead, and the device.
+/// Ordered in that order, so every device sorts any two stamps the same way.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stamp {
+    #[serde(rename = "t")]
+    pub time: i64,
+    #[serde(rename = "c")]
+    pub counter: u32,
+    #[serde(rename = "d")]
+    pub device: String,
+}
+
+/// Issues stamps that are later than every stamp it issued or observed before.
+#[derive(Debug, Default)]
+pub struct Clock {
+    time: i64,
+    counter: u32,
+}
+
+impl Clock {
+    pub fn tick(&mut self, device: &str) -> Stamp {
+        let now = chrono::Utc::now().timestamp_millis();
+        if now > self.time {
+            self.time = now;
+            self.counter = 0;
+        } else {
+            self.counter += 1;
+        }
+        Stamp {
+            time: self.time,
+            counter: self.counter,
+            device: device.to_string(),
+        }
+    }
+
+    /// The latest stamp it issued or observed, as if `device` had issued it.
+    pub fn latest(&self, device: &str) -> Stamp {
+        Stamp {
+            time: self.time,
+            counter: self.counter,
+            device: device.to_string(),
+        }
+    }
+
+    pub fn observe(&mut self, stamp: &Stamp) {
+        if (stamp.time, stamp.counter) > (self.time, self.counter) {
+            self.time = stamp.time;
+            self.counter = stamp.counter;
+        }
+    }
+}
+
+/// When a folder or file last changed, and when and over what it was last renamed.
+/// Records from before change tracking have none until the first sync stamps them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncStamp {
+    pub changed: Stamp,
+    /// The rename that gave the record its current name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renamed: Option<Stamp>,
+    /// `renamed` as it was before that rename, to tell a rename made after seeing
+    /// another device's from one made at the same time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renamed_over: Option<Stamp>,
+}
+
+/// A folder or file deleted for good, kept so the deletion reaches other devices.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub id: String,
+    pub is_folder: bool,
+    pub stamp: Stamp,
+}
+
+/// The changes one device made since it last published, as sent to the others.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Delta {
+    pub device: String,
+    pub folders: Vec<Folder>,
+    pub files: Vec<FileMetadata>,
+    pub removed: Vec<Tombstone>,
+}
+
+impl Delta {
+    pub fn is_empty(&self) -> bool {
+        self.folders.is_empty() && self.files.is_empty() && self.removed.is_empty()
+    }
+
+    /// The latest stamp in it.
+    pub fn upto(&self) -> Option<Stamp> {
+        let folders = self.folders.iter().filter_map(|f| f.stamp.as_ref());
+        let files = self.files.iter().filter_map(|f| f.stamp.as_ref());
+        folders
+            .chain(files)
+            .map(|s| &s.changed)
+            .chain(self.removed.iter().map(|t| &t.stamp))
+            .max()
+            .cloned()
+    }
+}
+
+/// Two devices renamed the same item without seeing each other's rename. Both end
+/// up with the name from the later rename; the other one is kept here until the
+/// user has looked at it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncConflict {
+    pub id: String,
+    pub is_folder: bool,
+    pub kept_name: String,
+    pub other_name: String,
+    pub detected_at: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncReport {
+    /// Deltas from other devices applied.
+    pub received: usize,
+    /// Whether local changes were sent.
+    pub published: bool,
+    /// Rename conflicts found in this run.
+    pub conflicts: Vec<SyncConflict>,
+    /// Deltas that could not be read. Ones that failed to download are tried again
+    /// next run.
+    pub skipped: usize,
+    /// Deltas every known device has applied, deleted from the chat.
+    pub pruned: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SyncState {
+    // Latest stamp this device has sent
+    published: Option<Stamp>,
+    // Newest message already looked at for deltas from other devices
+    scanned_to: i32,
+    // Deltas at or below `scanned_to` that failed to download
+    #[serde(default)]
+    unread: Vec<i32>,
+    // Delta messages still in the chat, from any device
+    #[serde(default)]
+    deltas: Vec<i32>,
+    // Whether `deltas` and `devices` cover the history before `scanned_to`
+    #[serde(default)]
+    tracked: bool,
+    // Up to which message each other device had applied every delta when it last
+    // published
+    #[serde(default)]
+    devices: HashMap<String, i32>,
+}
+
+impl SyncState {
+    // Every delta up to here has been applied on this device.
+    fn applied_to(&self) -> i32 {
+        self.unread
+            .iter()
+            .map(|id| id - 1)
+            .fold(self.scanned_to, i32::min)
+    }
+}
+
+/// Exchanges metadata changes with the other devices on the account: applies their
+/// deltas that arrived since the last run, oldest first, then sends what changed
+/// here. Conflicting renames are kept for `sync_conflicts`. Deltas every device
+/// that ever published has applied are deleted; a device added later starts from
+/// a backup, not from them.
+pub async fn sync_metadata(
+    db: &Database,
+    storage: &dyn StorageBackend,
+) -> Result<SyncReport, SyncError> {
+    let device = db.device_id().to_string();
+    let mut state: SyncState = db.setting(SYNC_STATE_SETTING)?.unwrap_or_default();
+    let mut report = SyncReport::default();
+
+    // Message ids only grow, so new deltas are above what the last run saw. The
+    // first run that prunes goes through the whole history once, so every device
+    // that published before counts as known.
+    let listed = state.tracked;
+    let mut incoming = std::mem::take(&mut state.unread);
+    let mut newest = state.scanned_to;
+    let mut offset = 0;
+    'scan: loop {
+        let page = storage.history(offset, PAGE_SIZE).await?;
+        for blob in page.blobs {
+            let seen = blob.message_id <= state.scanned_to;
+            if seen && listed {
+                break 'scan;
+            }
+            newest = newest.max(blob.message_id);
+            let Some(header) = blob.caption.strip_prefix(METADATA_DELTA_TAG) else {
+                continue;
+            };
+            state.deltas.push(blob.message_id);
+            let field = |name: &str| {
+                header
+                    .lines()
+                    .find_map(|l| l.strip_prefix(name))
+                    .map(str::trim)
+            };
+            let Some(sender) = field("Device:").filter(|d| *d != device) else {
+                continue;
+            };
+            if !seen {
+                incoming.push(blob.message_id);
+            }
+            // Deltas from before pruning do not say; they hold pruning back
+            let applied = field("Applied:").and_then(|v| v.parse().ok()).unwrap_or(0);
+            let known = state.devices.entry(sender.to_string()).or_insert(applied);
+            *known = (*known).max(applied);
+        }
+        match page.next_offset {
+            Some(next) => offset = next,
+            None => break,
+        }
+    }
+
+    incoming.sort_unstable();
+    incoming.dedup();
+    for message_id in incoming {
+        let path = std::env::temp_dir().join(format!("paperfold_delta_{}.json", Uuid::new_v4()));
+        let result = match storage.download_to(message_id, &path, None).await {
+            Ok(()) => db.read_delta(&path).map_err(SyncError::from),
+            Err(e) => Err(e.into()),
+        };
+        let _ = std::fs::remove_file(&path);
+        // One bad delta must not hold back the ones after it
+        let delta = match result {
+            Ok(delta) => delta,
+            Err(SyncError::Db(DbError::Locked)) => return Err(DbError::Locked.into()),
+            Err(e) => {
+                eprintln!("Skipped metadata delta {}: {}", message_id, e);
+                if matches!(e, SyncError::Storage(_)) {
+                    state.unread.push(message_id);
+                }
+                report.skipped += 1;
+                continue;
+            }
+        };
+        report.conflicts.extend(db.apply_delta(&delta)?);
+        report.received += 1;
+    }
+    state.scanned_to = newest;
+    state.tracked = true;
+    db.set_setting(SYNC_STATE_SETTING, &state)?;
+
+    let delta = db.changes_since(state.published.as_ref())?;
+    if let Some(upto) = delta.upto() {
+        let path = std::env::temp_dir().join(format!("paperfold_delta_{}.json", Uuid::new_v4()));
+        db.export_delta(&delta, &path)?;
+        let caption = format!(
+            "{}\nDevice: {}\nApplied: {}\nChanges: {}",
+            METADATA_DELTA_TAG,
+            device,
+            state.applied_to(),
+            delta.folders.len() + delta.files.len() + delta.removed.len()
+        );
+        let result = storage
+            .put_file(&path, "delta.json", "application/json", &caption, None)
+            .await;
+        let _ = std::fs::remove_file(&path);
+        result?;
+        state.published = Some(upto);
+        db.set_setting(SYNC_STATE_SETTING, &state)?;
+        report.published = true;
+    }
+
+    report.pruned = prune_deltas(db, storage, &mut state).await?;
+
+    if !report.conflicts.is_empty() {
+        let mut conflicts = sync_conflicts(db)?;
+        conflicts.extend(report.conflicts.iter().cloned());
+        db.set_setting(SYNC_CONFLICTS_SETTING, &conflicts)?;
+    }
+    Ok(report)
+}
+
+// Deletes the deltas this and every other known device have applied. A failed
+// delete is tried again next run.
+async fn prune_deltas(
+    db: &Database,
+    storage: &dyn StorageBackend,
+    state: &mut SyncState,
+) -> Result<usize, SyncError> {
+    let applied = state
+        .devices
+        .values()
+        .copied()
+        .fold(state.applied_to(), i32::min);
+    let (done, kept): (Vec<i32>, Vec<i32>) = state.deltas.iter().partition(|&&id| id <= applied);
+    if done.is_empty() {
+        return Ok(0);
+    }
+    if let Err(e) = storage.delete(&done).await {
+        eprintln!("Failed to delete {} metadata deltas: {}", done.len(), e);
+        return Ok(0);
+    }
+    state.deltas = kept;
+    db.set_setting(SYNC_STATE_SETTING, state)?;
+    Ok(done.len())
+}
+
+/// Rename conflicts the user has not dismissed yet, oldest first.
+pub fn sync_conflicts(db: &Database) -> Result<Vec<SyncConflict>, DbError> {
+    Ok(db.setting(SYNC_CONFLICTS_SETTING)?.unwrap_or_default())
+}
+
+/// Forgets the conflicts about item `id`.
+pub fn dismiss_conflict(db: &Database, id: &str) -> Result<(), DbError> {
+    let mut conflicts = sync_conflicts(db)?;
+    conflicts.retain(|c| c.id != id);
+    db.set_setting(SYNC_CONFLICTS_SETTING, &conflicts)
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::db::{Database, DbError};
+use crate::storage::{StorageBackend, StorageError};
+
+const STORAGE_TARGETS_SETTING: &str = "storage_targets";
+/// Title of the channel Paperfold creates when none is given.
+pub const DEFAULT_CHANNEL_TITLE: &str = "Paperfold Storage";
+
+/// A message in one of the chats files are stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct MessageRef {
+    /// `None` for Saved Messages.
+    pub chat_id: Option<i64>,
+    pub message_id: i32,
+}
+
+/// A private channel files can be stored in. The access hash lets it be used
+/// without looking it up among the dialogs first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageChannel {
+    pub id: i64,
+    pub access_hash: i64,
+    pub title: String,
+}
+
+/// The chats files are stored in besides Saved Messages, and which one new uploads
+/// go to. Files already stored stay where they are when that changes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageTargets {
+    pub channels: Vec<StorageChannel>,
+    /// `None` uploads to Saved Messages.
+    pub upload_to: Option<i64>,
+}
+
+impl StorageTargets {
+    pub fn load(db: &Database) -> Result<Self, DbError> {
+        Ok(db.setting(STORAGE_TARGETS_SETTING)?.unwrap_or_default())
+    }
+
+    pub fn save(&self, db: &Database) -> Result<(), DbError> {
+        db.set_setting(STORAGE_TARGETS_SETTING, self)
+    }
+
+    /// Adds `channel`, or updates its title if it is there already.
+    pub fn add_channel(&mut self, channel: StorageChannel) {
+        match self.channels.iter_mut().find(|c| c.id == channel.id) {
+            Some(existing) => *existing = channel,
+            None => self.channels.push(channel),
+        }
+    }
+
+    /// Forgets channel `id`. New uploads go to Saved Messages if they went there.
+    pub fn remove_channel(&mut self, id: i64) -> bool {
+        let before = self.channels.len();
+        self.channels.retain(|c| c.id != id);
+        if self.upload_to == Some(id) {
+            self.upload_to = None;
+        }
+        self.channels.len() != before
+    }
+}
+
+/// The storage of every chat files live in, so each message is read, deleted or
+/// checked in the chat it was sent to. Metadata backups and sync deltas stay in
+/// Saved Messages.
+#[derive(Debug, Clone)]
+pub struct Storages {
+    saved: Arc<dyn StorageBackend>,
+    channels: BTreeMap<i64, Arc<dyn StorageBackend>>,
+    upload_to: Option<i64>,
+}
+
+impl Storages {
+    /// Everything in Saved Messages.
+    pub fn new(saved: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            saved,
+            channels: BTreeMap::new(),
+            upload_to: None,
+        }
+    }
+
+    pub fn with_channel(mut self, id: i64, storage: Arc<dyn StorageBackend>) -> Self {
+        self.channels.insert(id, storage);
+        self
+    }
+
+    /// Sends new uploads to channel `chat_id`, which must have been added.
+    pub fn upload_to(mut self, chat_id: Option<i64>) -> Self {
+        self.upload_to = chat_id;
+        self
+    }
+
+    /// The chat new uploads go to.
+    pub fn upload_chat(&self) -> Option<i64> {
+        self.upload_to
+    }
+
+    pub fn saved_messages(&self) -> &dyn StorageBackend {
+        self.saved.as_ref()
+    }
+
+    /// The storage of chat `chat_id`, `None` being Saved Messages.
+    pub fn get(&self, chat_id: Option<i64>) -> Result<&dyn StorageBackend, StorageError> {
+        match chat_id {
+            None => Ok(self.saved.as_ref()),
+            Some(id) => self
+                .channels
+                .get(&id)
+                .map(|s| s.as_ref())
+                .ok_or(StorageError::UnknownChat(id)),
+        }
+    }
+
+    /// Every chat, Saved Messages first.
+    pub fn chats(&self) -> Vec<(Option<i64>, &dyn StorageBackend)> {
+        std::iter::once((None, self.saved.as_ref()))
+            .chain(self.channels.iter().map(|(id, s)| (Some(*id), s.as_ref())))
+            .collect()
+    }
+
+    /// Deletes `messages` from the chats they are in.
+    pub async fn delete(&self, messages: &[MessageRef]) -> Result<(), StorageError> {
+        let mut by_chat: BTreeMap<Option<i64>, Vec<i32>> = BTreeMap::new();
+        for m in messages {
+            by_chat.entry(m.chat_id).or_default().push(m.message_id);
+        }
+        for (chat_id, message_ids) in by_chat {
+            self.get(chat_id)?.delete(&message_ids).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,726 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::crypto::{CryptoError, EncryptionInfo, Vault};
+use crate::db::{self, Database, DbError, FileMetadata};
+use crate::manifest::Manifest;
+use crate::replicas;
+use crate::storage::{self, PartLog, Segment, StorageError, Transfer};
+use crate::targets::{MessageRef, Storages};
+
+// How often a running job's part log is saved and its progress reported. Parts sent
+// after the last save are sent again when the job resumes.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum UploadError {
+    NotFound,
+    /// The file to upload is missing or unreadable.
+    Source(String),
+    Db(DbError),
+    Storage(StorageError),
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::NotFound => write!(f, "Upload not found"),
+            UploadError::Source(e) => write!(f, "Cannot read the file to upload: {}", e),
+            UploadError::Db(e) => write!(f, "{}", e),
+            UploadError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl From<DbError> for UploadError {
+    fn from(e: DbError) -> Self {
+        UploadError::Db(e)
+    }
+}
+
+impl From<StorageError> for UploadError {
+    fn from(e: StorageError) -> Self {
+        UploadError::Storage(e)
+    }
+}
+
+impl From<CryptoError> for UploadError {
+    fn from(e: CryptoError) -> Self {
+        UploadError::Storage(StorageError::Crypto(e))
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        UploadError::Storage(StorageError::from(e))
+    }
+}
+
+impl From<UploadError> for String {
+    fn from(e: UploadError) -> Self {
+        e.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Paused,
+    /// Stopped on an error; resuming retries it.
+    Failed,
+}
+
+/// A file waiting to be uploaded, or part way there. What has been sent is recorded
+/// per segment down to the upload part, so a job picks up where it stopped after a
+/// pause or a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadJob {
+    pub id: String,
+    pub path: String,
+    pub folder_id: Option<String>,
+    pub name: String,
+    pub mime_type: String,
+    /// Size and modification time (Unix seconds) of the source when it was last
+    /// looked at. A source that changed since is uploaded from the start.
+    pub size: u64,
+    pub modified: i64,
+    pub encrypt: bool,
+    /// Higher runs first; jobs of equal priority run in the order they were added.
+    pub priority: i64,
+    pub created_at: i64,
+    pub state: JobState,
+    pub error: Option<String>,
+    /// Bytes of the stored blob sent so far, out of `total`.
+    pub uploaded: u64,
+    pub total: u64,
+    /// Name the blob goes up under. Opaque for encrypted jobs.
+    pub blob_name: String,
+    /// Planned when the job first runs, then filled in as segments are sent.
+    #[serde(default)]
+    pub segments: Vec<SegmentUpload>,
+    /// Channel the segments go to, chosen with them. `None` for Saved Messages.
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    pub encryption: Option<EncryptionInfo>,
+    /// Hex SHA-256 of the source, taken before anything is sent.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentUpload {
+    /// Byte range of the blob this segment holds.
+    pub start: u64,
+    pub end: u64,
+    pub parts: PartLog,
+    /// Set once the segment is a message.
+    pub message_id: Option<i32>,
+}
+
+impl UploadJob {
+    /// Messages holding the segments sent so far.
+    pub fn sent_messages(&self) -> Vec<MessageRef> {
+        self.segments
+            .iter()
+            .filter_map(|s| s.message_id)
+            .map(|message_id| MessageRef {
+                chat_id: self.chat_id,
+                message_id,
+            })
+            .collect()
+    }
+
+    // Forgets everything sent, for when the blob has to be built again.
+    fn reset(&mut self) {
+        self.segments.clear();
+        self.encryption = None;
+        self.uploaded = 0;
+        self.total = self.size;
+    }
+
+    fn count_uploaded(&mut self) {
+        self.uploaded = self
+            .segments
+            .iter()
+            .map(|s| match s.message_id {
+                Some(_) => s.end - s.start,
+                None => s.parts.bytes,
+            })
+            .sum();
+    }
+}
+
+// The job being run, and how to stop it.
+struct Running {
+    id: String,
+    stop: Arc<AtomicBool>,
+    transfer: Option<Transfer>,
+}
+
+/// The persistent upload queue. Jobs are stored next to the metadata and run one
+/// at a time by whoever loops over `next_job` and `run`; everything else may be
+/// called while a job runs.
+pub struct UploadQueue {
+    db: Arc<Database>,
+    // Sealed copies of encrypted jobs' sources, kept until the job is done
+    dir: PathBuf,
+    running: Mutex<Option<Running>>,
+    // Held while a job row is read, changed and written back
+    edit: Mutex<()>,
+    wake: Notify,
+}
+
+impl UploadQueue {
+    pub fn new(db: Arc<Database>, app_dir: &Path) -> Self {
+        Self {
+            db,
+            dir: app_dir.join("uploads"),
+            running: Mutex::new(None),
+            edit: Mutex::new(()),
+            wake: Notify::new(),
+        }
+    }
+
+    /// Adds the file at `path` to the back of the queue.
+    pub fn enqueue(
+        &self,
+        path: &Path,
+        folder_id: Option<String>,
+        encrypt: bool,
+    ) -> Result<UploadJob, UploadError> {
+        let meta = std::fs::metadata(path)
+            .map_err(|e| UploadError::Source(format!("{}: {}", path.display(), e)))?;
+        if !meta.is_file() {
+            return Err(UploadError::Source(format!(
+                "{} is not a file",
+                path.display()
+            )));
+        }
+        let name = path
+            .file_name()
+            .ok_or_else(|| UploadError::Source("Invalid file name".to_string()))?
+            .to_string_lossy()
+            .to_string();
+
+        let job = UploadJob {
+            id: Uuid::new_v4().to_string(),
+            path: path.to_string_lossy().to_string(),
+            folder_id,
+            mime_type: mime_guess::from_path(path)
+                .first_or_octet_stream()
+                .to_string(),
+            size: meta.len(),
+            modified: modified_secs(&meta),
+            encrypt,
+            priority: 0,
+            created_at: chrono::Utc::now().timestamp(),
+            state: JobState::Queued,
+            error: None,
+            uploaded: 0,
+            total: meta.len(),
+            blob_name: if encrypt {
+                format!("{}.bin", Uuid::new_v4())
+            } else {
+                name.clone()
+            },
+            name,
+            segments: Vec::new(),
+            chat_id: None,
+            encryption: None,
+            sha256: None,
+        };
+        self.db.save_upload_job(&job)?;
+        self.wake.notify_one();
+        Ok(job)
+    }
+
+    /// Every job, in the order they will run.
+    pub fn jobs(&self) -> Result<Vec<UploadJob>, UploadError> {
+        let mut jobs = self.db.upload_jobs()?;
+        jobs.sort_by_key(|job| (Reverse(job.priority), job.created_at));
+        Ok(jobs)
+    }
+
+    /// The job to run next. Jobs still marked running were cut off by a restart and
+    /// count as queued.
+    pub fn next_job(&self) -> Result<Option<UploadJob>, UploadError> {
+        Ok(self
+            .jobs()?
+            .into_iter()
+            .find(|job| matches!(job.state, JobState::Queued | JobState::Running)))
+    }
+
+    /// Waits until a job is added or resumed, or `timeout` passes.
+    pub async fn wait(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.wake.notified()).await;
+    }
+
+    /// Ends a `wait` early, for a runner that should look elsewhere for work.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Keeps the job from running until resumed. A running job stops once its parts
+    /// in flight are sent.
+    pub fn pause(&self, id: &str) -> Result<UploadJob, UploadError> {
+        let job = self
+            .update(id, |job| job.state = JobState::Paused)?
+            .ok_or(UploadError::NotFound)?;
+        self.stop(id);
+        Ok(job)
+    }
+
+    /// Puts a paused or failed job back in the queue.
+    pub fn resume(&self, id: &str) -> Result<UploadJob, UploadError> {
+        let job = self
+            .update(id, |job| {
+                if job.state != JobState::Running {
+                    job.state = JobState::Queued;
+                    job.error = None;
+                }
+            })?
+            .ok_or(UploadError::NotFound)?;
+        self.wake.notify_one();
+        Ok(job)
+    }
+
+    /// Moves the job ahead of (or behind) others. Does not interrupt the running job.
+    pub fn set_priority(&self, id: &str, priority: i64) -> Result<UploadJob, UploadError> {
+        self.update(id, |job| job.priority = priority)?
+            .ok_or(UploadError::NotFound)
+    }
+
+    /// Removes the job. Returns the messages of segments it already sent, for the
+    /// caller to delete; a running job deletes its own when it stops.
+    pub async fn cancel(&self, id: &str) -> Result<Vec<MessageRef>, UploadError> {
+        let job = {
+            let _edit = self.edit_lock();
+            let job = self.db.upload_job(id)?.ok_or(UploadError::NotFound)?;
+            self.db.remove_upload_job(id)?;
+            job
+        };
+        if self.stop(id) {
+            return Ok(Vec::new());
+        }
+        self.remove_sealed_copy(id).await;
+        Ok(job.sent_messages())
+    }
+
+    /// Runs the job until its file is stored, it fails, or it is paused or
+    /// cancelled. A job that has sent nothing yet goes to the upload chat of
+    /// `storages`. Returns the new file, or `None` if the job was stopped. `progress`
+    /// is called with the stored job as it moves along.
+    pub async fn run(
+        &self,
+        id: &str,
+        storages: &Storages,
+        vault: Option<&Vault>,
+        progress: &(dyn Fn(&UploadJob) + Send + Sync),
+    ) -> Result<Option<FileMetadata>, UploadError> {
+        let mut job = self.db.upload_job(id)?.ok_or(UploadError::NotFound)?;
+        *self.running_lock() = Some(Running {
+            id: id.to_string(),
+            stop: Arc::new(AtomicBool::new(false)),
+            transfer: None,
+        });
+        let result = self.upload(&mut job, storages, vault, progress).await;
+        *self.running_lock() = None;
+
+        match result {
+            Ok(file) => Ok(file),
+            Err(UploadError::Storage(StorageError::Stopped)) => {
+                // Paused jobs keep what they sent; cancelled ones clean up
+                if self.db.upload_job(id)?.is_none() {
+                    let sent = job.sent_messages();
+                    if !sent.is_empty() {
+                        if let Err(e) = storages.delete(&sent).await {
+                            eprintln!("Failed to delete cancelled upload: {}", e);
+                        }
+                    }
+                    self.remove_sealed_copy(id).await;
+                }
+                Ok(None)
+            }
+            Err(e) => {
+                let message = e.to_string();
+                if let Some(stored) = self.update(id, |stored| {
+                    stored.state = JobState::Failed;
+                    stored.error = Some(message);
+                })? {
+                    progress(&stored);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn upload(
+        &self,
+        job: &mut UploadJob,
+        storages: &Storages,
+        vault: Option<&Vault>,
+        progress: &(dyn Fn(&UploadJob) + Send + Sync),
+    ) -> Result<Option<FileMetadata>, UploadError> {
+        let stored = self
+            .update(&job.id, |stored| {
+                if stored.state != JobState::Paused {
+                    stored.state = JobState::Running;
+                    stored.error = None;
+                }
+            })?
+            .ok_or(StorageError::Stopped)?;
+        if stored.state == JobState::Paused {
+            return Ok(None);
+        }
+        progress(&stored);
+
+        // A source that changed since it was queued starts over
+        let meta = tokio::fs::metadata(&job.path)
+            .await
+            .map_err(|e| UploadError::Source(format!("{}: {}", job.path, e)))?;
+        if meta.len() != job.size || modified_secs(&meta) != job.modified {
+            self.discard_sent(job, storages).await;
+            job.size = meta.len();
+            job.modified = modified_secs(&meta);
+            job.sha256 = None;
+            job.reset();
+        }
+
+        // Contents already stored are not sent again; the new file shares their blob
+        let sha256 = match job.sha256.clone() {
+            Some(sha256) => sha256,
+            None => {
+                let sha256 = storage::file_sha256(Path::new(&job.path)).await?;
+                job.sha256 = Some(sha256.clone());
+                self.save(job)?;
+                sha256
+            }
+        };
+        let unchanged = self.complete(job, |db| {
+            Ok(db
+                .file_named(job.folder_id.as_deref(), &job.name)?
+                .filter(|f| {
+                    f.sha256
+                        .as_deref()
+                        .is_some_and(|h| h.eq_ignore_ascii_case(&sha256))
+                }))
+        })?;
+        if let Some(file) = unchanged {
+            println!("{} is unchanged, keeping the stored version", job.name);
+            self.discard_sent(job, storages).await;
+            return Ok(Some(file));
+        }
+        let duplicate = self.complete(job, |db| {
+            db.add_duplicate(
+                job.folder_id.clone(),
+                job.name.clone(),
+                job.mime_type.clone(),
+                &sha256,
+                job.encrypt,
+            )
+        })?;
+        if let Some(file) = duplicate {
+            println!("{} is already stored, not uploading it again", job.name);
+            self.discard_sent(job, storages).await;
+            self.prune_versions(&file, storages).await;
+            return Ok(Some(self.replicate(file, storages).await));
+        }
+
+        let blob_path = if job.encrypt {
+            let sealed_path = self.sealed_path(&job.id);
+            // Sealing again picks new file keys, so nothing sent before is usable
+            if job.encryption.is_none() || !sealed_path.exists() {
+                let vault = vault.ok_or(CryptoError::Locked)?.clone();
+                self.discard_sent(job, storages).await;
+                job.reset();
+                tokio::fs::create_dir_all(&self.dir).await?;
+                let (src, dest) = (PathBuf::from(&job.path), sealed_path.clone());
+                let info = tokio::task::spawn_blocking(move || vault.seal_file(&src, &dest))
+                    .await
+                    .map_err(|e| StorageError::Io(e.to_string()))??;
+                job.encryption = Some(info);
+            }
+            sealed_path
+        } else {
+            PathBuf::from(&job.path)
+        };
+
+        if job.segments.is_empty() {
+            job.chat_id = storages.upload_chat();
+        }
+        let storage = storages.get(job.chat_id)?;
+        if job.segments.is_empty() {
+            job.total = tokio::fs::metadata(&blob_path).await?.len();
+            job.segments = storage::segment_ranges(job.total, storage.max_blob_size())
+                .into_iter()
+                .map(|range| SegmentUpload {
+                    start: range.start,
+                    end: range.end,
+                    parts: PartLog::default(),
+                    message_id: None,
+                })
+                .collect();
+        }
+        self.save(job)?;
+
+        // Every message says which file it belongs to, see `Manifest`
+        let caption = Manifest {
+            id: job.id.clone(),
+            sha256: Some(sha256.clone()),
+            created_at: job.created_at,
+            modified_at: Some(job.modified),
+            encryption: job.encryption.clone(),
+            ..Manifest::new(
+                &self.db,
+                job.folder_id.as_deref(),
+                &job.name,
+                &job.mime_type,
+                job.size as i64,
+            )?
+        }
+        .caption(vault);
+
+        let count = job.segments.len();
+        for index in 0..count {
+            if job.segments[index].message_id.is_some() {
+                continue;
+            }
+            let segment = job.segments[index].clone();
+            let transfer = Transfer::resume(segment.parts.clone(), None);
+            self.track(&transfer);
+
+            let name = storage::segment_name(&job.blob_name, index, count);
+            let mime_type = job.mime_type.clone();
+            let upload = storage.put_range(
+                &blob_path,
+                segment.start..segment.end,
+                &name,
+                &mime_type,
+                &caption,
+                &transfer,
+            );
+            tokio::pin!(upload);
+            let mut ticker = tokio::time::interval(SAVE_INTERVAL);
+            let result = loop {
+                tokio::select! {
+                    result = &mut upload => break result,
+                    _ = ticker.tick() => {
+                        job.segments[index].parts = transfer.parts();
+                        job.count_uploaded();
+                        progress(&self.save(job)?);
+                    }
+                }
+            };
+
+            job.segments[index].parts = transfer.parts();
+            if let Ok(message_id) = result {
+                job.segments[index].message_id = Some(message_id);
+            }
+            job.count_uploaded();
+            let stored = self.save(job)?;
+            result?;
+            progress(&stored);
+        }
+
+        let segments: Vec<Segment> = job
+            .segments
+            .iter()
+            .filter_map(|s| {
+                s.message_id.map(|message_id| Segment {
+                    message_id,
+                    size: s.end - s.start,
+                })
+            })
+            .collect();
+        let thumbnail = match (&job.encryption, segments.first()) {
+            (None, Some(first)) => match storage.thumbnail(first.message_id).await {
+                Ok(thumbnail) => thumbnail,
+                Err(e) => {
+                    eprintln!("Failed to fetch thumbnail: {}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        // The file takes the job's id, which its manifest carries
+        let file = self.complete(job, |db| {
+            db.insert_file(FileMetadata {
+                id: job.id.clone(),
+                chat_id: job.chat_id,
+                thumbnail,
+                encryption: job.encryption.clone(),
+                sha256: Some(sha256),
+                ..db::new_file(
+                    job.folder_id.clone(),
+                    job.name.clone(),
+                    job.size as i64,
+                    job.mime_type.clone(),
+                    segments,
+                )
+            })
+            .map(Some)
+        })?;
+        self.remove_sealed_copy(&job.id).await;
+        let Some(file) = file else {
+            return Ok(None);
+        };
+        self.prune_versions(&file, storages).await;
+        Ok(Some(self.replicate(file, storages).await))
+    }
+
+    // Adds the job's file with `add` and retires the job, unless it was cancelled
+    // meanwhile. Nothing is retired when `add` adds nothing. A file already in the
+    // folder under the job's name gets the new contents, and keeps its old ones as
+    // a version.
+    fn complete(
+        &self,
+        job: &UploadJob,
+        add: impl FnOnce(&Database) -> Result<Option<FileMetadata>, DbError>,
+    ) -> Result<Option<FileMetadata>, UploadError> {
+        let _edit = self.edit_lock();
+        if self.db.upload_job(&job.id)?.is_none() {
+            return Err(StorageError::Stopped.into());
+        }
+        let replaced = self.db.file_named(job.folder_id.as_deref(), &job.name)?;
+        let Some(file) = add(&self.db)? else {
+            return Ok(None);
+        };
+        self.db.remove_upload_job(&job.id)?;
+        match replaced {
+            Some(replaced) if replaced.id != file.id => {
+                Ok(Some(self.db.supersede(&replaced.id, &file.id)?))
+            }
+            _ => Ok(Some(file)),
+        }
+    }
+
+    // Deletes the messages of versions the retention policy no longer keeps, once
+    // `file` took over another file's contents. Failures are only logged.
+    async fn prune_versions(&self, file: &FileMetadata, storages: &Storages) {
+        if file.versions.is_empty() {
+            return;
+        }
+        match self.db.prune_versions() {
+            Ok(messages) if !messages.is_empty() => {
+                if let Err(e) = storages.delete(&messages).await {
+                    eprintln!("Failed to delete expired versions from Telegram: {}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to prune file versions: {}", e),
+        }
+    }
+
+    // Gives `file` the copies its folder asks for. The upload stands if that fails;
+    // the next repair makes up for it.
+    async fn replicate(&self, file: FileMetadata, storages: &Storages) -> FileMetadata {
+        let stored = file.clone();
+        match replicas::replicate(&self.db, storages, file).await {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Failed to replicate {}: {}", stored.name, e);
+                // Copies made before the failure are recorded
+                self.db.get_file(&stored.id).unwrap_or(stored)
+            }
+        }
+    }
+
+    // Applies `f` to the stored job and writes it back. `None` if the job is gone.
+    fn update(
+        &self,
+        id: &str,
+        f: impl FnOnce(&mut UploadJob),
+    ) -> Result<Option<UploadJob>, UploadError> {
+        let _edit = self.edit_lock();
+        let Some(mut job) = self.db.upload_job(id)? else {
+            return Ok(None);
+        };
+        f(&mut job);
+        self.db.save_upload_job(&job)?;
+        Ok(Some(job))
+    }
+
+    // Writes the running job's progress, leaving its state to pause and resume. A job
+    // cancelled meanwhile stops the run.
+    fn save(&self, job: &UploadJob) -> Result<UploadJob, UploadError> {
+        let stored = self.update(&job.id, |stored| {
+            stored.size = job.size;
+            stored.modified = job.modified;
+            stored.uploaded = job.uploaded;
+            stored.total = job.total;
+            stored.segments = job.segments.clone();
+            stored.encryption = job.encryption.clone();
+            stored.sha256 = job.sha256.clone();
+        })?;
+        Ok(stored.ok_or(StorageError::Stopped)?)
+    }
+
+    // Deletes the segments a job already sent, before it starts over.
+    async fn discard_sent(&self, job: &UploadJob, storages: &Storages) {
+        let sent = job.sent_messages();
+        if !sent.is_empty() {
+            if let Err(e) = storages.delete(&sent).await {
+                eprintln!("Failed to delete outdated upload segments: {}", e);
+            }
+        }
+        self.remove_sealed_copy(&job.id).await;
+    }
+
+    // Hands the running job's current transfer to `stop`.
+    fn track(&self, transfer: &Transfer) {
+        if let Some(running) = self.running_lock().as_mut() {
+            if running.stop.load(Ordering::SeqCst) {
+                transfer.stop();
+            }
+            running.transfer = Some(transfer.clone());
+        }
+    }
+
+    // Stops the job if it is the one running. Returns whether it was.
+    fn stop(&self, id: &str) -> bool {
+        match self.running_lock().as_ref() {
+            Some(running) if running.id == id => {
+                running.stop.store(true, Ordering::SeqCst);
+                if let Some(transfer) = &running.transfer {
+                    transfer.stop();
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn sealed_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.sealed", id))
+    }
+
+    async fn remove_sealed_copy(&self, id: &str) {
+        let _ = tokio::fs::remove_file(self.sealed_path(id)).await;
+    }
+
+    fn edit_lock(&self) -> MutexGuard<'_, ()> {
+        self.edit.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn running_lock(&self) -> MutexGuard<'_, Option<Running>> {
+        self.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn modified_secs(meta: &std::fs::Metadata) -> i64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64)
+}
@@ -1,6 +1,7 @@
-use grammers_client::types::Chat;
-use grammers_client::Client;
 use paperfold_core::db::FileMetadata;
+use paperfold_core::replicas::readable_copy;
+use paperfold_core::storage::download_file;
+use paperfold_core::Storages;
 use std::path::PathBuf;
 use tokio::fs;
 
@@ -18,22 +19,28 @@ impl CacheManager {
         Self { cache_dir }
     }
 
+    /// Path of the file's blob in the cache, downloaded first if need be. Encrypted
+    /// files are cached sealed; readers open them chunk by chunk.
     pub async fn get_file(
         &self,
         metadata: &FileMetadata,
-        client: &Client,
-        me: &Chat,
+        storages: &Storages,
     ) -> Result<PathBuf, String> {
         // Sanitize name or just use ID to avoid issues?
-        // Using ID_Name for debuggability.
+        // Using ID_Name for debuggability. The first message tells apart the versions
+        // of a file, which keep its id.
         let safe_name = metadata.name.replace("/", "_");
-        let file_name = format!("{}_{}", metadata.id, safe_name);
-        let path = self.cache_dir.join(file_name);
+        let file_name = format!("{}_{}_{}", metadata.id, metadata.message_id, safe_name);
+        let path = self.cache_dir.join(&file_name);
+        let size = metadata
+            .encryption
+            .as_ref()
+            .map_or(metadata.size as u64, |info| info.sealed_size());
 
         // Check if exists and valid
         if path.exists() {
             if let Ok(meta) = fs::metadata(&path).await {
-                if meta.len() == metadata.size as u64 {
+                if meta.len() == size {
                     return Ok(path);
                 }
             }
@@ -47,21 +54,16 @@ impl CacheManager {
             return Ok(path);
         }
 
-        // Download
-        let messages = client
-            .get_messages_by_id(me, &[metadata.message_id])
-            .await
-            .map_err(|e| e.to_string())?;
-
-        let message = messages
-            .first()
-            .and_then(|m| m.as_ref())
-            .ok_or_else(|| "Message not found".to_string())?;
-
-        // grammers download_media takes a string path
-        let path_str = path.to_string_lossy().to_string();
-        message
-            .download_media(&path_str)
+        // The blob comes down next to the final name, so a failed transfer never looks
+        // cached and the next request for the file resumes it. Sealed blobs stay
+        // sealed, so no plaintext is left on disk; their chunk tags stand in for the
+        // checksum of the plaintext. Any copy of the file this device can read will do.
+        let (storage, segments) = readable_copy(storages, metadata).map_err(|e| e.to_string())?;
+        let sha256 = match metadata.encryption {
+            Some(_) => None,
+            None => metadata.sha256.as_deref(),
+        };
+        download_file(storage, &segments, None, None, sha256, &path, None)
             .await
             .map_err(|e| e.to_string())?;
 
@@ -1,7 +1,10 @@
 use dav_server::davpath::DavPath;
 use dav_server::fs::*;
-use grammers_client::Client;
-use paperfold_core::db::Database;
+use paperfold_core::db::{self, Database, DbError, Entry, FileMetadata};
+use paperfold_core::manifest::Manifest;
+use paperfold_core::replicas;
+use paperfold_core::storage::{file_sha256, put_sealed, put_segmented};
+use paperfold_core::{EncryptionInfo, MessageRef, Storages, Vault};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::SystemTime;
@@ -11,60 +14,60 @@ use crate::cache::CacheManager;
 #[derive(Clone)]
 pub struct PaperfoldFS {
     db: Arc<Database>,
-    client: Client,
-    me: grammers_client::types::Chat,
+    storages: Arc<Storages>,
     cache: Arc<CacheManager>,
+    // Unlocked vault handed over by the app. Without it encrypted files cannot be
+    // read and encrypted folders refuse uploads.
+    vault: Option<Vault>,
 }
 
 impl PaperfoldFS {
     pub fn new(
         db: Arc<Database>,
-        client: Client,
-        me: grammers_client::types::Chat,
+        storages: Arc<Storages>,
         cache: Arc<CacheManager>,
+        vault: Option<Vault>,
     ) -> Self {
         PaperfoldFS {
             db,
-            client,
-            me,
+            storages,
             cache,
+            vault,
         }
     }
 
     async fn resolve_path(&self, path: &DavPath) -> Result<(Option<String>, bool), FsError> {
-        let parts = path.as_rel_ospath();
-        if parts.as_os_str().is_empty() || path.as_url_string() == "/" {
-            return Ok((None, true)); // Root
+        let rel_path = path.as_rel_ospath().to_string_lossy().to_string();
+        match self.db.resolve_path(&rel_path).map_err(db_error)? {
+            Some(Entry::Root) => Ok((None, true)),
+            Some(Entry::Folder(folder)) => Ok((Some(folder.id), true)),
+            Some(Entry::File(file)) => Ok((Some(file.id), false)),
+            None => Err(FsError::NotFound),
         }
+    }
+}
 
-        let mut current_folder_id: Option<String> = None;
-        let diff_path = std::path::Path::new(parts);
-        let components: Vec<&str> = diff_path.iter().filter_map(|s| s.to_str()).collect();
-
-        for (i, part) in components.iter().enumerate() {
-            let is_last = i == components.len() - 1;
-
-            let (folders, files) = self.db.list_contents(current_folder_id.clone());
-
-            let found_folder = folders.iter().find(|f| f.name == *part);
-            if let Some(folder) = found_folder {
-                current_folder_id = Some(folder.id.clone());
-                if is_last {
-                    return Ok((Some(folder.id.clone()), true));
-                }
-                continue;
-            }
-
-            if is_last {
-                if let Some(file) = files.iter().find(|f| f.name == *part) {
-                    return Ok((Some(file.id.clone()), false));
-                }
-            }
+// Deletes messages no file refers to any more. Failures are only logged: the
+// metadata is already gone by the time this runs.
+async fn delete_messages(storages: &Storages, messages: &[MessageRef]) {
+    if messages.is_empty() {
+        return;
+    }
+    if let Err(e) = storages.delete(messages).await {
+        eprintln!("Failed to delete messages from Telegram: {}", e);
+    }
+}
 
-            return Err(FsError::NotFound);
+// Maps metadata errors onto the closest WebDAV status code.
+fn db_error(e: DbError) -> FsError {
+    match e {
+        DbError::NotFound(_) => FsError::NotFound,
+        DbError::NameConflict(_) => FsError::Exists,
+        DbError::CycleDetected | DbError::Locked => FsError::Forbidden,
+        DbError::Io(_) | DbError::Corrupt(_) | DbError::SchemaVersion { .. } => {
+            eprintln!("Metadata error: {}", e);
+            FsError::GeneralFailure
         }
-
-        Err(FsError::NotFound)
     }
 }
 
@@ -128,7 +131,7 @@ impl DavFileSystem for PaperfoldFS {
                     if options.write {
                         if options.truncate || options.create {
                             println!("Overwriting existing file: {:?}", path);
-                            let metadata = self.db.get_file(&id).ok_or(FsError::NotFound)?;
+                            let metadata = self.db.get_file(&id).map_err(db_error)?;
                             // We are creating a NEW version.
                             // PaperfoldWriteFile needs to know if it's overwriting?
                             // Actually, if we just use PaperfoldWriteFile::new with same name/parent,
@@ -147,8 +150,8 @@ impl DavFileSystem for PaperfoldFS {
 
                             match PaperfoldWriteFile::new(
                                 self.db.clone(),
-                                self.client.clone(),
-                                self.me.clone(),
+                                self.storages.clone(),
+                                self.vault.clone(),
                                 parent_id,
                                 name,
                             )
@@ -162,12 +165,12 @@ impl DavFileSystem for PaperfoldFS {
                             Err(FsError::Forbidden)
                         }
                     } else {
-                        let metadata = self.db.get_file(&id).ok_or(FsError::NotFound)?;
+                        let metadata = self.db.get_file(&id).map_err(db_error)?;
                         Ok(Box::new(PaperfoldFile::new(
-                            self.client.clone(),
+                            self.storages.clone(),
                             metadata,
-                            self.me.clone(),
                             self.cache.clone(),
+                            self.vault.clone(),
                         )) as Box<dyn DavFile>)
                     }
                 }
@@ -201,8 +204,8 @@ impl DavFileSystem for PaperfoldFS {
                                 println!("Parent resolved: {:?}, creating write file", parent_id);
                                 match PaperfoldWriteFile::new(
                                     self.db.clone(),
-                                    self.client.clone(),
-                                    self.me.clone(),
+                                    self.storages.clone(),
+                                    self.vault.clone(),
                                     parent_id,
                                     name,
                                 )
@@ -241,7 +244,7 @@ impl DavFileSystem for PaperfoldFS {
         Box::pin(async move {
             match self.resolve_path(path).await {
                 Ok((folder_id, true)) => {
-                    let (folders, files) = self.db.list_contents(folder_id);
+                    let (folders, files) = self.db.list_contents(folder_id).map_err(db_error)?;
                     let mut entries: Vec<Box<dyn DavDirEntry>> = Vec::new();
 
                     for f in folders {
@@ -318,7 +321,7 @@ impl DavFileSystem for PaperfoldFS {
                             created: SystemTime::UNIX_EPOCH,
                         }) as Box<dyn DavMetaData>)
                     } else {
-                        let file = self.db.get_file(&id).ok_or(FsError::NotFound)?;
+                        let file = self.db.get_file(&id).map_err(db_error)?;
                         let created = SystemTime::UNIX_EPOCH
                             + std::time::Duration::from_secs(file.created_at as u64);
                         Ok(Box::new(PaperfoldMetaData {
@@ -371,7 +374,9 @@ impl DavFileSystem for PaperfoldFS {
                     match self.resolve_path(path).await {
                         Ok(_) => Err(FsError::Exists),
                         Err(FsError::NotFound) => {
-                            self.db.create_folder(&name, Some(parent_id));
+                            self.db
+                                .create_folder(&name, Some(parent_id))
+                                .map_err(db_error)?;
                             Ok(())
                         }
                         Err(e) => Err(e),
@@ -382,7 +387,7 @@ impl DavFileSystem for PaperfoldFS {
                     match self.resolve_path(path).await {
                         Ok(_) => Err(FsError::Exists),
                         Err(FsError::NotFound) => {
-                            self.db.create_folder(&name, None);
+                            self.db.create_folder(&name, None).map_err(db_error)?;
                             Ok(())
                         }
                         Err(e) => Err(e),
@@ -397,7 +402,8 @@ impl DavFileSystem for PaperfoldFS {
             println!("remove_dir: {:?}", path);
             match self.resolve_path(path).await {
                 Ok((Some(id), true)) => {
-                    self.db.delete_folder(&id);
+                    let messages = self.db.delete_folder(&id).map_err(db_error)?;
+                    delete_messages(&self.storages, &messages).await;
                     Ok(())
                 }
                 Ok((_, false)) => Err(FsError::Forbidden),
@@ -411,7 +417,8 @@ impl DavFileSystem for PaperfoldFS {
             println!("remove_file: {:?}", path);
             match self.resolve_path(path).await {
                 Ok((Some(id), false)) => {
-                    self.db.delete_file(&id);
+                    let messages = self.db.delete_file(&id).map_err(db_error)?;
+                    delete_messages(&self.storages, &messages).await;
                     Ok(())
                 }
                 Ok((_, true)) => Err(FsError::Forbidden), // Is a directory
@@ -454,47 +461,51 @@ impl DavFileSystem for PaperfoldFS {
                 _ => return Err(FsError::NotFound), // Destination parent must exist and be a dir
             };
 
-            // 3. Perform Move/Rename
+            // 3. Perform Move/Rename. dav-server has already removed the destination if
+            // the client asked to overwrite it; a same-named item left over is settled by
+            // the policy chosen in the app.
+            let policy = self.db.conflict_policy().map_err(db_error)?;
             if source_is_dir {
-                if self.db.move_folder(&source_id, target_parent_id, &new_name) {
-                    Ok(())
-                } else {
-                    Err(FsError::GeneralFailure)
-                }
+                self.db
+                    .move_folder(&source_id, target_parent_id, &new_name, policy)
+                    .map_err(db_error)?;
             } else {
-                if self.db.move_file(&source_id, target_parent_id, &new_name) {
-                    Ok(())
-                } else {
-                    Err(FsError::GeneralFailure)
-                }
+                self.db
+                    .move_file(&source_id, target_parent_id, &new_name, policy)
+                    .map_err(db_error)?;
             }
+            Ok(())
         })
     }
 }
 
 #[derive(Debug)]
 pub struct PaperfoldFile {
-    #[allow(dead_code)]
-    client: Client,
-    metadata: paperfold_core::db::FileMetadata,
-    me: grammers_client::types::Chat,
+    storages: Arc<Storages>,
+    metadata: FileMetadata,
     cache: Arc<CacheManager>,
+    vault: Option<Vault>,
     file_handle: Option<tokio::fs::File>,
+    // Plaintext position in an encrypted file, and the chunk last opened there
+    pos: u64,
+    chunk: Option<(u64, Vec<u8>)>,
 }
 
 impl PaperfoldFile {
     pub fn new(
-        client: Client,
-        metadata: paperfold_core::db::FileMetadata,
-        me: grammers_client::types::Chat,
+        storages: Arc<Storages>,
+        metadata: FileMetadata,
         cache: Arc<CacheManager>,
+        vault: Option<Vault>,
     ) -> Self {
         PaperfoldFile {
-            client,
+            storages,
             metadata,
-            me,
             cache,
+            vault,
             file_handle: None,
+            pos: 0,
+            chunk: None,
         }
     }
 
@@ -502,12 +513,19 @@ impl PaperfoldFile {
         if self.file_handle.is_some() {
             return Ok(());
         }
+        if self.metadata.encryption.is_some() && self.vault.is_none() {
+            eprintln!("Cannot read {}: the vault is locked", self.metadata.name);
+            return Err(FsError::Forbidden);
+        }
 
         let path = self
             .cache
-            .get_file(&self.metadata, &self.client, &self.me)
+            .get_file(&self.metadata, &self.storages)
             .await
-            .map_err(|_| FsError::GeneralFailure)?;
+            .map_err(|e| {
+                eprintln!("Failed to fetch {}: {}", self.metadata.name, e);
+                FsError::GeneralFailure
+            })?;
 
         let file = tokio::fs::File::open(&path)
             .await
@@ -515,6 +533,38 @@ impl PaperfoldFile {
         self.file_handle = Some(file);
         Ok(())
     }
+
+    // Reads from the sealed blob in the cache, opening the chunk under the position
+    // in memory.
+    async fn read_sealed(&mut self, info: &EncryptionInfo, count: usize) -> FsResult<bytes::Bytes> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+        if self.pos >= info.plain_size {
+            return Ok(bytes::Bytes::new());
+        }
+        let index = self.pos / info.chunk_size as u64;
+        if self.chunk.as_ref().is_none_or(|(i, _)| *i != index) {
+            let vault = self.vault.as_ref().ok_or(FsError::Forbidden)?;
+            let file = self.file_handle.as_mut().ok_or(FsError::GeneralFailure)?;
+            let (start, len) = info.sealed_range(index);
+            let mut sealed = vec![0u8; len];
+            file.seek(std::io::SeekFrom::Start(start))
+                .await
+                .map_err(|_| FsError::GeneralFailure)?;
+            file.read_exact(&mut sealed)
+                .await
+                .map_err(|_| FsError::GeneralFailure)?;
+            let plain = vault.open_chunk(info, index, &sealed).map_err(|e| {
+                eprintln!("Failed to decrypt {}: {}", self.metadata.name, e);
+                FsError::GeneralFailure
+            })?;
+            self.chunk = Some((index, plain));
+        }
+        let plain = self.chunk.as_ref().map_or(&[][..], |(_, plain)| plain);
+        let start = (self.pos - index * info.chunk_size as u64) as usize;
+        let end = plain.len().min(start + count);
+        self.pos += (end - start) as u64;
+        Ok(bytes::Bytes::copy_from_slice(&plain[start..end]))
+    }
 }
 
 impl DavFile for PaperfoldFile {
@@ -533,6 +583,9 @@ impl DavFile for PaperfoldFile {
     fn read_bytes(&mut self, count: usize) -> FsFuture<'_, bytes::Bytes> {
         Box::pin(async move {
             self.ensure_local().await?;
+            if let Some(info) = self.metadata.encryption.clone() {
+                return self.read_sealed(&info, count).await;
+            }
             let file = self.file_handle.as_mut().ok_or(FsError::GeneralFailure)?;
 
             let mut buf = vec![0u8; count];
@@ -557,6 +610,15 @@ impl DavFile for PaperfoldFile {
     fn seek(&mut self, pos: std::io::SeekFrom) -> FsFuture<'_, u64> {
         Box::pin(async move {
             self.ensure_local().await?;
+            if let Some(info) = &self.metadata.encryption {
+                let target = match pos {
+                    std::io::SeekFrom::Start(n) => Some(n),
+                    std::io::SeekFrom::End(d) => info.plain_size.checked_add_signed(d),
+                    std::io::SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+                };
+                self.pos = target.ok_or(FsError::GeneralFailure)?;
+                return Ok(self.pos);
+            }
             let file = self.file_handle.as_mut().ok_or(FsError::GeneralFailure)?;
             use tokio::io::AsyncSeekExt;
             file.seek(pos).await.map_err(|_| FsError::GeneralFailure)
@@ -579,8 +641,8 @@ impl std::fmt::Debug for PaperfoldWriteFile {
 
 pub struct PaperfoldWriteFile {
     db: Arc<Database>,
-    client: Client,
-    me: grammers_client::types::Chat,
+    storages: Arc<Storages>,
+    vault: Option<Vault>,
     temp_path: std::path::PathBuf,
     file_handle: Option<tokio::fs::File>,
     parent_id: Option<String>,
@@ -591,8 +653,8 @@ pub struct PaperfoldWriteFile {
 impl PaperfoldWriteFile {
     pub async fn new(
         db: Arc<Database>,
-        client: Client,
-        me: grammers_client::types::Chat,
+        storages: Arc<Storages>,
+        vault: Option<Vault>,
         parent_id: Option<String>,
         name: String,
     ) -> FsResult<Self> {
@@ -613,8 +675,8 @@ impl PaperfoldWriteFile {
 
         Ok(Self {
             db,
-            client,
-            me,
+            storages,
+            vault,
             temp_path,
             file_handle: Some(file),
             parent_id,
@@ -622,6 +684,27 @@ impl PaperfoldWriteFile {
             flushed: false,
         })
     }
+
+    // Folds the newly stored `file` into the one it overwrites, if any, and deletes
+    // the messages of versions the retention policy no longer keeps. Returns the
+    // file that now holds the contents.
+    async fn keep_version(
+        &self,
+        replaced: Option<FileMetadata>,
+        file: &FileMetadata,
+    ) -> FsResult<FileMetadata> {
+        let Some(replaced) = replaced else {
+            return Ok(file.clone());
+        };
+        println!("Keeping previous version of {}", replaced.name);
+        let file = self
+            .db
+            .supersede(&replaced.id, &file.id)
+            .map_err(db_error)?;
+        let messages = self.db.prune_versions().map_err(db_error)?;
+        delete_messages(&self.storages, &messages).await;
+        Ok(file)
+    }
 }
 
 impl DavFile for PaperfoldWriteFile {
@@ -697,15 +780,26 @@ impl DavFile for PaperfoldWriteFile {
                 drop(file);
             }
 
-            // Deduplicate: Remove existing file with the same name to prevent "File (1), File (2)..."
-            let (_, files) = self.db.list_contents(self.parent_id.clone());
-            for f in files {
-                if f.name == self.name {
-                    println!("Removing existing file version: {} (id: {})", f.name, f.id);
-                    self.db.delete_file(&f.id);
-                }
+            // Refuse before touching the existing version if this folder needs a key we lack
+            let encrypt = self
+                .db
+                .encrypts_uploads(self.parent_id.as_deref())
+                .map_err(db_error)?;
+            if encrypt && self.vault.is_none() {
+                eprintln!(
+                    "Cannot store {}: its folder is encrypted and locked",
+                    self.name
+                );
+                let _ = std::fs::remove_file(&self.temp_path);
+                return Err(FsError::Forbidden);
             }
 
+            // Overwriting keeps the existing file, with its current contents as a version
+            let replaced = self
+                .db
+                .file_named(self.parent_id.as_deref(), &self.name)
+                .map_err(db_error)?;
+
             let metadata = self
                 .temp_path
                 .metadata()
@@ -714,15 +808,19 @@ impl DavFile for PaperfoldWriteFile {
 
             if size == 0 {
                 println!("Persisting 0-byte file locally: {}", self.name);
-                // Add to DB with special ID -1
-                self.db.add_file(
-                    self.parent_id.clone(),
-                    self.name.clone(),
-                    0,
-                    "application/octet-stream".to_string(), // Default mime
-                    -1,
-                    None,
-                );
+                // Add to DB with no segments, which leaves the special ID -1
+                let file = self
+                    .db
+                    .add_file(
+                        self.parent_id.clone(),
+                        self.name.clone(),
+                        0,
+                        "application/octet-stream".to_string(), // Default mime
+                        Vec::new(),
+                        None,
+                    )
+                    .map_err(db_error)?;
+                self.keep_version(replaced, &file).await?;
 
                 let _ = std::fs::remove_file(&self.temp_path);
                 self.flushed = true;
@@ -736,51 +834,117 @@ impl DavFile for PaperfoldWriteFile {
                 return Ok(());
             }
 
-            let path_str = self.temp_path.to_string_lossy().to_string();
-            println!("Uploading file: {}", path_str);
-
-            let uploaded = self.client.upload_file(&path_str).await.map_err(|e| {
-                println!("Telegram upload error: {:?}", e);
-                FsError::GeneralFailure
-            })?;
-
             let mime_type = mime_guess::from_path(&self.name)
                 .first_or_octet_stream()
                 .to_string();
+            let sha256 = file_sha256(&self.temp_path).await.map_err(|e| {
+                println!("Failed to hash upload: {}", e);
+                FsError::GeneralFailure
+            })?;
 
-            use grammers_client::types::{Attribute, InputMessage};
-
-            let message = InputMessage::text("")
-                .file(uploaded)
-                .mime_type(&mime_type)
-                .attribute(Attribute::FileName(self.name.clone()));
+            if replaced
+                .as_ref()
+                .and_then(|f| f.sha256.as_deref())
+                .is_some_and(|h| h.eq_ignore_ascii_case(&sha256))
+            {
+                println!("{} is unchanged, keeping the stored version", self.name);
+                let _ = std::fs::remove_file(&self.temp_path);
+                self.flushed = true;
+                return Ok(());
+            }
 
-            println!("Sending message to self...");
-            let sent_message = self
-                .client
-                .send_message(&self.me, message)
-                .await
-                .map_err(|e| {
-                    println!("Send message error: {:?}", e);
-                    FsError::GeneralFailure
-                })?;
+            // Contents already stored are not sent again; the new file shares their blob
+            let duplicate = self
+                .db
+                .add_duplicate(
+                    self.parent_id.clone(),
+                    self.name.clone(),
+                    mime_type.clone(),
+                    &sha256,
+                    encrypt,
+                )
+                .map_err(db_error)?;
+            if let Some(file) = duplicate {
+                println!("{} is already stored, not uploading it again", self.name);
+                self.keep_version(replaced, &file).await?;
+                let _ = std::fs::remove_file(&self.temp_path);
+                self.flushed = true;
+                return Ok(());
+            }
 
-            println!("File sent, id: {}", sent_message.id());
+            println!("Uploading file: {:?}", self.temp_path);
+
+            // Every message says which file it belongs to, see `Manifest`
+            let manifest = Manifest {
+                sha256: Some(sha256.clone()),
+                ..Manifest::new(
+                    &self.db,
+                    self.parent_id.as_deref(),
+                    &self.name,
+                    &mime_type,
+                    size as i64,
+                )
+                .map_err(db_error)?
+            };
 
-            let metadata = self
-                .temp_path
-                .metadata()
-                .map_err(|_| FsError::GeneralFailure)?;
-            let size = metadata.len();
+            let chat_id = self.storages.upload_chat();
+            let storage = self.storages.get(chat_id).map_err(|e| {
+                println!("Upload error: {}", e);
+                FsError::GeneralFailure
+            })?;
+            let (segments, encryption) =
+                if let Some(vault) = self.vault.as_ref().filter(|_| encrypt) {
+                    let (segments, encryption) =
+                        put_sealed(storage, vault, &self.temp_path, &manifest, None)
+                            .await
+                            .map_err(|e| {
+                                println!("Upload error: {}", e);
+                                FsError::GeneralFailure
+                            })?;
 
-            self.db.add_file(
-                self.parent_id.clone(),
-                self.name.clone(),
-                size as i64,
-                mime_type,
-                sent_message.id(),
-                None, // No thumbnail for now
-            );
+                    println!("Encrypted file sent in {} message(s)", segments.len());
+                    (segments, Some(encryption))
+                } else {
+                    let segments = put_segmented(
+                        storage,
+                        &self.temp_path,
+                        &self.name,
+                        &mime_type,
+                        &manifest.caption(self.vault.as_ref()),
+                        None,
+                    )
+                    .await
+                    .map_err(|e| {
+                        println!("Upload error: {}", e);
+                        FsError::GeneralFailure
+                    })?;
+
+                    println!("File sent in {} message(s)", segments.len());
+                    (segments, None)
+                };
+
+            // No thumbnail for now
+            let file = self
+                .db
+                .insert_file(FileMetadata {
+                    id: manifest.id,
+                    chat_id,
+                    encryption,
+                    sha256: Some(sha256),
+                    ..db::new_file(
+                        self.parent_id.clone(),
+                        self.name.clone(),
+                        size as i64,
+                        mime_type,
+                        segments,
+                    )
+                })
+                .map_err(db_error)?;
+            let file = self.keep_version(replaced, &file).await?;
+            // The write stands without the copies; the app's sync makes up for them
+            if let Err(e) = replicas::replicate(&self.db, &self.storages, file).await {
+                eprintln!("Failed to replicate {}: {}", self.name, e);
+            }
 
             let _ = std::fs::remove_file(&self.temp_path);
 
@@ -789,3 +953,187 @@ impl DavFile for PaperfoldWriteFile {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use paperfold_core::crypto::PLAIN_CHUNK_SIZE;
+    use paperfold_core::{Credential, MemoryBackend};
+
+    fn open_fs(
+        encrypted: bool,
+    ) -> (
+        PaperfoldFS,
+        Arc<Database>,
+        Arc<MemoryBackend>,
+        std::path::PathBuf,
+    ) {
+        let dir = std::env::temp_dir().join(format!("paperfold_fs_{}", unique_suffix()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Arc::new(Database::new(dir.to_str().unwrap()).unwrap());
+        let backend = Arc::new(MemoryBackend::new());
+        let storages = Arc::new(Storages::new(backend.clone()));
+        let cache = Arc::new(CacheManager::new(&dir));
+        let vault = encrypted.then(|| Vault::create(&dir, &Credential::KeyFile).unwrap());
+        let fs = PaperfoldFS::new(db.clone(), storages, cache, vault);
+        (fs, db, backend, dir)
+    }
+
+    fn unique_suffix() -> u128 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    }
+
+    fn path(p: &str) -> DavPath {
+        DavPath::new(p).unwrap()
+    }
+
+    fn options(write: bool) -> OpenOptions {
+        OpenOptions {
+            read: !write,
+            write,
+            append: false,
+            truncate: write,
+            create: write,
+            create_new: false,
+            size: None,
+            checksum: None,
+        }
+    }
+
+    async fn put(fs: &PaperfoldFS, p: &str, data: &'static [u8]) {
+        let mut file = fs.open(&path(p), options(true)).await.unwrap();
+        file.write_bytes(bytes::Bytes::from_static(data))
+            .await
+            .unwrap();
+        file.flush().await.unwrap();
+    }
+
+    async fn get(fs: &PaperfoldFS, p: &str) -> Vec<u8> {
+        let mut file = fs.open(&path(p), options(false)).await.unwrap();
+        file.read_bytes(1 << 20).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn write_flush_and_read_back() {
+        let (fs, db, backend, dir) = open_fs(false);
+        fs.create_dir(&path("/Docs")).await.unwrap();
+        put(&fs, "/Docs/notes.txt", b"hello paperfold").await;
+
+        let Some(Entry::File(file)) = db.resolve_path("/Docs/notes.txt").unwrap() else {
+            panic!("notes.txt was not stored");
+        };
+        assert_eq!(file.size, 15);
+        assert_eq!(file.mime_type, "text/plain");
+        assert_eq!(
+            backend.contents(file.message_id).unwrap(),
+            b"hello paperfold"
+        );
+        assert_eq!(get(&fs, "/Docs/notes.txt").await, b"hello paperfold");
+        // The second read is served from the cache
+        assert!(std::fs::read_dir(dir.join("cache")).unwrap().count() > 0);
+        assert_eq!(get(&fs, "/Docs/notes.txt").await, b"hello paperfold");
+
+        let meta = fs.metadata(&path("/Docs/notes.txt")).await.unwrap();
+        assert_eq!(meta.len(), 15);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn overwrite_keeps_a_version_and_delete_removes_every_message() {
+        let (fs, db, backend, dir) = open_fs(false);
+        put(&fs, "/a.txt", b"first").await;
+        put(&fs, "/a.txt", b"second").await;
+
+        let files = db.get_all_files().unwrap();
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.versions.len(), 1);
+        let messages = file.referenced_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(get(&fs, "/a.txt").await, b"second");
+
+        fs.remove_file(&path("/a.txt")).await.unwrap();
+        assert!(db.get_all_files().unwrap().is_empty());
+        for m in messages {
+            assert!(backend.contents(m.message_id).is_none());
+        }
+        assert!(matches!(
+            fs.metadata(&path("/a.txt")).await,
+            Err(FsError::NotFound)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn encrypted_files_are_cached_sealed_and_opened_on_read() {
+        let (fs, db, backend, dir) = open_fs(true);
+        fs.create_dir(&path("/Secret")).await.unwrap();
+        let Some(Entry::Folder(folder)) = db.resolve_path("/Secret").unwrap() else {
+            panic!("Secret was not created");
+        };
+        db.set_folder_encrypted(&folder.id, true).unwrap();
+
+        let data: Vec<u8> = (0..PLAIN_CHUNK_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut file = fs
+            .open(&path("/Secret/data.bin"), options(true))
+            .await
+            .unwrap();
+        file.write_bytes(bytes::Bytes::from(data.clone()))
+            .await
+            .unwrap();
+        file.flush().await.unwrap();
+        let Some(Entry::File(stored)) = db.resolve_path("/Secret/data.bin").unwrap() else {
+            panic!("data.bin was not stored");
+        };
+        let info = stored.encryption.clone().unwrap();
+        assert_ne!(backend.contents(stored.message_id).unwrap(), data);
+
+        let mut file = fs
+            .open(&path("/Secret/data.bin"), options(false))
+            .await
+            .unwrap();
+        let mut read = Vec::new();
+        loop {
+            let chunk = file.read_bytes(100_000).await.unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            read.extend_from_slice(&chunk);
+        }
+        assert_eq!(read, data);
+
+        // Reads stop at the end of a chunk and go on from there
+        let boundary = PLAIN_CHUNK_SIZE as u64;
+        let at = file
+            .seek(std::io::SeekFrom::Start(boundary - 10))
+            .await
+            .unwrap();
+        assert_eq!(at, boundary - 10);
+        assert_eq!(
+            file.read_bytes(20).await.unwrap()[..],
+            data[at as usize..][..10]
+        );
+        assert_eq!(
+            file.read_bytes(20).await.unwrap()[..],
+            data[boundary as usize..][..20]
+        );
+
+        // Only the sealed blob reaches the disk
+        let cached: Vec<_> = std::fs::read_dir(dir.join("cache"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(
+            std::fs::metadata(&cached[0]).unwrap().len(),
+            info.sealed_size()
+        );
+        assert_ne!(std::fs::read(&cached[0]).unwrap(), data);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,145 @@
+use dav_server::memls::MemLs;
 use dav_server::DavHandler;
+use paperfold_core::profiles::{self, Profiles, DEFAULT_PROFILE};
+use paperfold_core::{Credential, KeyProtection, Vault};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 mod cache;
 mod fs;
 
+const VAULT_KEY_STDIN_FLAG: &str = "--vault-key-stdin";
+// Lets the daemon run on its own when the vault is passphrase-protected.
+const PASSPHRASE_ENV: &str = "PAPERFOLD_PASSPHRASE";
+
+// The keys of the vaults the app has unlocked, by profile: one "<profile id> <key>"
+// line each on stdin, up to an empty line.
+fn read_vault_keys() -> Result<HashMap<String, String>, String> {
+    let mut keys = HashMap::new();
+    for line in std::io::stdin().lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        let (id, key) = line
+            .split_once(' ')
+            .ok_or("a vault key is missing its profile")?;
+        keys.insert(id.to_string(), key.to_string());
+    }
+    Ok(keys)
+}
+
+// Unlocks the vault of a profile the same ways the app can: the key handed over by
+// the app, the key file, or a passphrase from the environment. None if there is no
+// vault.
+fn open_vault(dir: &Path, handed_key: Option<&str>) -> Result<Option<Vault>, String> {
+    let Some(params) = Vault::load_params(dir)? else {
+        return Ok(None);
+    };
+    if let Some(key) = handed_key {
+        return Ok(Some(Vault::import_key(&params, key)?));
+    }
+    let credential = match params.protection {
+        KeyProtection::KeyFile => Credential::KeyFile,
+        KeyProtection::Passphrase { .. } => match std::env::var(PASSPHRASE_ENV) {
+            Ok(passphrase) => Credential::Passphrase(passphrase),
+            Err(_) => return Ok(None),
+        },
+    };
+    Ok(Some(Vault::unlock(dir, &credential)?))
+}
+
+// The WebDAV handler of one profile, serving its files below `/<profile id>/`.
+async fn open_profile(
+    app_dir: &Path,
+    id: &str,
+    api_id: i32,
+    api_hash: &str,
+    handed_key: Option<&str>,
+) -> Result<fs::PaperfoldFS, String> {
+    let dir = profiles::profile_dir(app_dir, id);
+    let session_path = dir.join("telegram.session");
+
+    let vault = match open_vault(&dir, handed_key) {
+        Ok(vault) => vault,
+        Err(e) => {
+            eprintln!("Failed to unlock the vault of {}: {}", id, e);
+            None
+        }
+    };
+
+    let db = paperfold_core::Database::new(dir.to_str().ok_or("invalid profile path")?)
+        .map(Arc::new)
+        .map_err(|e| format!("Failed to open metadata database: {}", e))?;
+    if let Some(vault) = &vault {
+        db.unlock(vault.metadata_cipher())
+            .map_err(|e| format!("Failed to unlock metadata: {}", e))?;
+    } else if db.is_locked() {
+        return Err(format!(
+            "Metadata is encrypted. Start the daemon from the app or set {}.",
+            PASSPHRASE_ENV
+        ));
+    }
+
+    let client = paperfold_core::client::connect(&session_path, api_id, api_hash, vault.as_ref())
+        .await
+        .map_err(|e| format!("Failed to connect to Telegram: {}", e))?;
+
+    // Storage targets added in the app later are picked up on the next start
+    let targets = paperfold_core::StorageTargets::load(&db)
+        .map_err(|e| format!("Failed to read the storage targets: {}", e))?;
+    let budget = paperfold_core::retry::Budget::open(&dir);
+    let storages = paperfold_core::TelegramBackend::storages(client, &budget, &targets)
+        .await
+        .map(Arc::new)
+        .map_err(|e| format!("Failed to open the storage chats: {}", e))?;
+    let cache = Arc::new(crate::cache::CacheManager::new(&dir));
+    Ok(fs::PaperfoldFS::new(db, storages, cache, vault))
+}
+
+// Serves `fs` below `/<prefix>`, or at the root without one.
+fn dav_handler(fs: fs::PaperfoldFS, locks: Box<MemLs>, prefix: Option<&str>) -> DavHandler {
+    let config = DavHandler::builder()
+        .filesystem(Box::new(fs))
+        .locksystem(locks);
+    match prefix {
+        Some(id) => config.strip_prefix(format!("/{}", id)),
+        None => config,
+    }
+    .build_handler()
+}
+
+// Every profile under its id, and one of them at the root as well.
+struct Routes {
+    profiles: HashMap<String, DavHandler>,
+    root: Option<DavHandler>,
+}
+
+// Hands a request to the profile named by the first segment of its path. Other
+// paths belong to the profile served at the root.
+async fn route(
+    routes: Arc<Routes>,
+    req: hyper::Request<hyper::Body>,
+) -> hyper::Response<dav_server::body::Body> {
+    let id = req
+        .uri()
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    match routes.profiles.get(id).or(routes.root.as_ref()) {
+        Some(handler) => handler.handle(req).await,
+        None => hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body(dav_server::body::Body::from("No such profile"))
+            .unwrap(),
+    }
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
@@ -19,7 +153,6 @@ async fn main() {
     if !app_dir.exists() {
         std::fs::create_dir_all(&app_dir).unwrap();
     }
-    let session_path = app_dir.join("telegram.session");
 
     let api_id_str = std::env::var("TELEGRAM_API_ID").unwrap_or_else(|_| "0".to_string());
     let api_hash = std::env::var("TELEGRAM_API_HASH").unwrap_or_default();
@@ -29,34 +162,69 @@ async fn main() {
         eprintln!("API_ID_MISSING");
     }
 
-    let db = Arc::new(paperfold_core::Database::new(app_dir.to_str().unwrap()));
+    let handed_keys = if std::env::args().any(|arg| arg == VAULT_KEY_STDIN_FLAG) {
+        read_vault_keys().unwrap_or_else(|e| {
+            eprintln!("Failed to read the vault keys: {}", e);
+            HashMap::new()
+        })
+    } else {
+        HashMap::new()
+    };
 
-    let client = match paperfold_core::client::connect(&session_path, api_id, &api_hash).await {
-        Ok(c) => c,
+    let profiles = match Profiles::load(&app_dir) {
+        Ok(profiles) => profiles,
         Err(e) => {
-            eprintln!("Failed to connect to Telegram: {}", e);
-            if api_id != 0 {
-                return;
-            }
+            eprintln!("Failed to read the profiles: {}", e);
             return;
         }
     };
-
-    let me = client.get_me().await.unwrap();
-    let cache = std::sync::Arc::new(crate::cache::CacheManager::new(&app_dir));
-    let fs = fs::PaperfoldFS::new(db, client, grammers_client::types::Chat::User(me), cache);
-
-    let dav_server = DavHandler::builder()
-        .filesystem(Box::new(fs))
-        .locksystem(dav_server::memls::MemLs::new())
-        .build_handler();
+    // A profile that cannot be opened is left out; the others are still served. The
+    // active profile is served at the root too, or the default one if it is left out,
+    // so addresses from before profiles keep working.
+    let mut routes = Routes {
+        profiles: HashMap::new(),
+        root: None,
+    };
+    let mut root_name = None;
+    for profile in &profiles.profiles {
+        let key = handed_keys.get(&profile.id).map(String::as_str);
+        match open_profile(&app_dir, &profile.id, api_id, &api_hash, key).await {
+            Ok(fs) => {
+                println!(
+                    "Serving {} at http://{}/{}/",
+                    profile.name, addr, profile.id
+                );
+                // Both paths lead to the same files, so they share the locks
+                let locks = MemLs::new();
+                if profile.id == profiles.active
+                    || (profile.id == DEFAULT_PROFILE && routes.root.is_none())
+                {
+                    routes.root = Some(dav_handler(fs.clone(), locks.clone(), None));
+                    root_name = Some(&profile.name);
+                }
+                routes.profiles.insert(
+                    profile.id.clone(),
+                    dav_handler(fs, locks, Some(&profile.id)),
+                );
+            }
+            Err(e) => eprintln!("Skipping profile {}: {}", profile.name, e),
+        }
+    }
+    if routes.profiles.is_empty() {
+        eprintln!("No profile could be opened");
+        return;
+    }
+    if let Some(name) = root_name {
+        println!("Serving {} at http://{}/", name, addr);
+    }
+    let routes = Arc::new(routes);
 
     let make_service = hyper::service::make_service_fn(move |_| {
-        let dav_server = dav_server.clone();
+        let routes = routes.clone();
         async move {
             let func = move |req: hyper::Request<hyper::Body>| {
-                let dav_server = dav_server.clone();
-                async move { Ok::<_, hyper::Error>(dav_server.handle(req).await) }
+                let routes = routes.clone();
+                async move { Ok::<_, hyper::Error>(route(routes, req).await) }
             };
             Ok::<_, hyper::Error>(hyper::service::service_fn(func))
         }
@@ -1,31 +1,36 @@
-use grammers_client::types::{Downloadable, InputMessage, LoginToken, Media, PasswordToken};
+use grammers_client::client::auth::AuthorizationError;
+use grammers_client::types::{LoginToken, PasswordToken};
 use grammers_client::{Client, Config, InitParams, SignInError};
 use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 // use base64::{engine::general_purpose, Engine as _};
-use mime_guess;
 
-use grammers_session::Session;
-use grammers_tl_types as tl;
-use rand::Rng;
-use std::collections::{HashMap, VecDeque};
-use std::io::Write; // Standard Sync Write for Zip
+use std::collections::HashMap;
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use tauri::{Emitter, Manager, State, Window};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use zip::write::SimpleFileOptions;
 
 use sysinfo::{Pid, System};
 use tauri_plugin_shell::ShellExt;
 use tokio::sync::Mutex as AsyncMutex;
-use tokio::sync::Semaphore; // New import
 
 use paperfold_core::{
+    backups::{self, BackupDiff, BackupError, BackupInfo, BackupPolicy},
     client,
-    db::{self, Database},
+    db::{self, ConflictPolicy, Database},
+    duplicates::{self, DuplicateGroup},
+    import::{self, ImportFilter, ImportProgress},
+    manifest,
+    profiles::{self, Profile, Profiles},
+    reconcile::{self, OrphanAction, Reconciliation},
+    replicas,
+    retry::{self, Failure},
+    storage::{self, ProgressFn, Segment},
+    sync::{self, SyncConflict, SyncReport},
+    targets::{StorageChannel, DEFAULT_CHANNEL_TITLE},
+    Credential, CryptoError, DbError, KeyProtection, MessageRef, StorageBackend, StorageError,
+    StorageTargets, Storages, TelegramBackend, UploadError, UploadJob, UploadQueue, Vault,
 };
 
 // Secrets moved to .env
@@ -33,27 +38,155 @@ use paperfold_core::{
 const SESSION_FILENAME: &str = "telegram.session";
 const PID_FILENAME: &str = "webdav.pid"; // New constant
 
-fn get_session_path(app_handle: &tauri::AppHandle) -> std::path::PathBuf {
-    let app_dir = app_handle
-        .path()
-        .app_data_dir()
-        .expect("failed to get app data dir");
-    std::fs::create_dir_all(&app_dir).unwrap(); // Ensure dir exists
-    app_dir.join(SESSION_FILENAME)
-}
+// Root folder that messages no file referred to are adopted into
+const RECOVERED_FOLDER: &str = "Recovered";
 
-struct AppState {
-    app_handle: tauri::AppHandle,
+// Returned by check_auth when the vault needs a passphrase before anything can load
+const LOCKED_ERROR: &str = "LOCKED";
+
+// How long the upload runner sleeps when there is nothing it can run. Enqueueing or
+// resuming a job wakes it early.
+const UPLOAD_IDLE_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+// How often metadata changes are exchanged with other devices on the account.
+const METADATA_SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+// How often the backup scheduler checks whether a backup is due.
+const BACKUP_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// Everything that belongs to one profile. Replaced as a whole when the user
+// switches profiles; commands hold on to the one they started with.
+struct ProfileState {
+    id: String,
+    // Session, metadata, vault and sealed upload copies of the profile
+    dir: std::path::PathBuf,
     client: Arc<AsyncMutex<Option<Client>>>,
     phone_token: Mutex<Option<LoginToken>>,
     password_token: Mutex<Option<PasswordToken>>,
     db: Arc<Database>,
+    // Telegram limits the account, so the daemon serving this profile shares it
+    budget: retry::Budget,
+    // Vault key for encrypted files, metadata and session, while unlocked
+    vault: Mutex<Option<Vault>>,
+    uploads: Arc<UploadQueue>,
+}
+
+impl ProfileState {
+    // Opens profile `id` signed out and locked; check_auth takes it from there.
+    fn open(app_dir: &Path, id: &str) -> Result<Self, String> {
+        let dir = profiles::profile_dir(app_dir, id);
+        std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let db = Arc::new(Database::new(dir.to_str().ok_or("Invalid profile path")?)?);
+        // Left over if the app quit without locking
+        wipe_sealed_previews(id);
+        Ok(Self {
+            id: id.to_string(),
+            client: Arc::new(AsyncMutex::new(None)), // Lazy init
+            phone_token: Mutex::new(None),
+            password_token: Mutex::new(None),
+            uploads: Arc::new(UploadQueue::new(db.clone(), &dir)),
+            db,
+            budget: retry::Budget::open(&dir),
+            vault: Mutex::new(None),
+            dir,
+        })
+    }
+}
+
+struct AppState {
+    app_handle: tauri::AppHandle,
+    app_dir: std::path::PathBuf,
+    profile: std::sync::RwLock<Arc<ProfileState>>,
+    // Vault keys of the other profiles unlocked since the app started, handed to
+    // the WebDAV daemon with the open one's
+    vaults: Mutex<HashMap<String, Vault>>,
+    // Profiles switched away from, which commands still running may hold open
+    retired: Mutex<Vec<Weak<ProfileState>>>,
     // webdav_process removed
 }
 
+impl AppState {
+    fn profile(&self) -> Arc<ProfileState> {
+        self.profile.read().unwrap().clone()
+    }
+}
+
+// File storage of the signed-in account: Saved Messages and the storage channels.
+// Commands only talk to Telegram about file contents through this.
+async fn storages(profile: &ProfileState) -> Result<Storages, String> {
+    let client = profile
+        .client
+        .lock()
+        .await
+        .as_ref()
+        .ok_or("Not logged in")?
+        .clone();
+    let targets = StorageTargets::load(&profile.db)?;
+    Ok(TelegramBackend::storages(client, &profile.budget, &targets).await?)
+}
+
+fn unlocked_vault(profile: &ProfileState) -> Option<Vault> {
+    profile.vault.lock().unwrap().clone()
+}
+
+// Where previews of encrypted files are decrypted to. Wiped whenever the profile
+// locks, so their plaintext does not outlive the unlocked vault.
+fn sealed_preview_dir(profile_id: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("paperfold_preview_{}", profile_id))
+}
+
+fn wipe_sealed_previews(profile_id: &str) {
+    let dir = sealed_preview_dir(profile_id);
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            eprintln!("Failed to wipe decrypted previews in {:?}: {}", dir, e);
+        }
+    }
+}
+
+fn credential(passphrase: Option<String>) -> Result<Credential, String> {
+    match passphrase {
+        Some(passphrase) if passphrase.is_empty() => Err("Passphrase cannot be empty".to_string()),
+        Some(passphrase) => Ok(Credential::Passphrase(passphrase)),
+        None => Ok(Credential::KeyFile),
+    }
+}
+
+// Opens the metadata with the vault and seals the session file under it, if it is
+// still plain, before making the vault available to commands.
+fn use_vault(profile: &ProfileState, vault: Vault) -> Result<(), String> {
+    profile.db.unlock(vault.metadata_cipher())?;
+    let session_path = profile.dir.join(SESSION_FILENAME);
+    if session_path.exists() {
+        client::load_session(&session_path, Some(&vault))?;
+    }
+    *profile.vault.lock().unwrap() = Some(vault);
+    Ok(())
+}
+
+// Unlocks the vault, if there is one, before the session and metadata are read. A
+// key file unlocks on its own; a passphrase has to come from the user.
+async fn unlock_on_start(profile: &ProfileState, passphrase: Option<String>) -> Result<(), String> {
+    if unlocked_vault(profile).is_some() {
+        return Ok(());
+    }
+    let dir = profile.dir.clone();
+    let Some(params) = Vault::load_params(&dir)? else {
+        return Ok(());
+    };
+    let credential = match (params.protection, passphrase) {
+        (KeyProtection::KeyFile, _) => Credential::KeyFile,
+        (KeyProtection::Passphrase { .. }, Some(passphrase)) => Credential::Passphrase(passphrase),
+        (KeyProtection::Passphrase { .. }, None) => return Err(LOCKED_ERROR.to_string()),
+    };
+    let vault = tokio::task::spawn_blocking(move || Vault::unlock(&dir, &credential))
+        .await
+        .map_err(|e| e.to_string())??;
+    use_vault(profile, vault)
+}
+
 #[tauri::command]
 async fn login_start(phone: String, state: State<'_, AppState>) -> Result<String, String> {
-    let mut client_guard = state.client.lock().await;
+    let profile = state.profile();
+    let mut client_guard = profile.client.lock().await;
 
     // Force fresh client for new login to prevent stale state (SRP_ID_INVALID)
     *client_guard = None;
@@ -78,13 +211,14 @@ async fn login_start(phone: String, state: State<'_, AppState>) -> Result<String
     for attempt in 0..2 {
         if client_guard.is_none() {
             // Init client if not present
-            let session_path = get_session_path(&state.app_handle);
-            let session = Session::load_file_or_create(&session_path).map_err(|e| e.to_string())?;
+            let session_path = profile.dir.join(SESSION_FILENAME);
+            let session = client::load_session(&session_path, unlocked_vault(&profile).as_ref())?;
 
             let params = InitParams {
                 device_model: "Paperfold Desktop".to_string(),
                 app_version: "0.1.0".to_string(),
                 system_version: "macOS".to_string(),
+                flood_sleep_threshold: 0,
                 ..Default::default()
             };
 
@@ -104,8 +238,8 @@ async fn login_start(phone: String, state: State<'_, AppState>) -> Result<String
         // 0.7.x: request_login_code(phone) only
         match client.request_login_code(&phone).await {
             Ok(token) => {
-                *state.phone_token.lock().unwrap() = Some(token);
-                *state.password_token.lock().unwrap() = None;
+                *profile.phone_token.lock().unwrap() = Some(token);
+                *profile.password_token.lock().unwrap() = None;
                 return Ok("Code sent".to_string());
             }
             Err(e) => {
@@ -118,20 +252,20 @@ async fn login_start(phone: String, state: State<'_, AppState>) -> Result<String
                     *client_guard = None;
 
                     // Delete session file to force fresh auth
-                    let session_path = get_session_path(&state.app_handle);
+                    let session_path = profile.dir.join(SESSION_FILENAME);
                     if session_path.exists() {
                         let _ = std::fs::remove_file(session_path);
                     }
                     continue; // Retry loop
                 }
-                if err_msg.contains("FLOOD_WAIT") {
-                    let seconds = err_msg
-                        .split("value: ")
-                        .nth(1)
-                        .map(|s| s.trim_end_matches(')').trim())
-                        .and_then(|s| s.parse::<u64>().ok())
-                        .unwrap_or(0);
-
+                let flood_wait = match &e {
+                    AuthorizationError::Invoke(e) => match retry::classify(e) {
+                        Failure::FloodWait(wait) => Some(wait.as_secs()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if let Some(seconds) = flood_wait {
                     if seconds > 0 {
                         let hours = seconds / 3600;
                         let minutes = (seconds % 3600) / 60;
@@ -161,7 +295,8 @@ async fn login_complete(
     password: Option<String>,
     state: State<'_, AppState>,
 ) -> Result<String, String> {
-    let mut client_guard = state.client.lock().await;
+    let profile = state.profile();
+    let mut client_guard = profile.client.lock().await;
     let client = client_guard.as_mut().ok_or("Client not initialized")?;
 
     // Check if we are in 2FA mode
@@ -171,18 +306,20 @@ async fn login_complete(
         // If we want retry, we must CLONE it if possible.
         // If PasswordToken is not Clone, we are stuck unless check_password takes valid reference.
 
-        let token_opt = state.password_token.lock().unwrap().clone();
+        let token_opt = profile.password_token.lock().unwrap().clone();
 
         if let Some(token) = token_opt {
             // We pass a clone to check_password
             match client.check_password(token, &pwd).await {
                 Ok(user) => {
                     // Success! Remove from state
-                    *state.password_token.lock().unwrap() = None;
-                    let data = client.session().save();
-                    let session_path = get_session_path(&state.app_handle);
-                    std::fs::write(&session_path, data)
-                        .map_err(|e| format!("Failed to write to {:?}: {}", session_path, e))?;
+                    *profile.password_token.lock().unwrap() = None;
+                    let session_path = profile.dir.join(SESSION_FILENAME);
+                    client::save_session(
+                        &session_path,
+                        client.session(),
+                        unlocked_vault(&profile).as_ref(),
+                    )?;
                     Ok(format!("Logged in as {}", user.first_name()))
                 }
                 Err(e) => {
@@ -200,7 +337,7 @@ async fn login_complete(
         }
     } else {
         // Normal Code Login
-        let token = state
+        let token = profile
             .phone_token
             .lock()
             .unwrap()
@@ -208,15 +345,17 @@ async fn login_complete(
             .ok_or("No login session found")?;
         match client.sign_in(&token, &code).await {
             Ok(user) => {
-                let data = client.session().save();
-                let session_path = get_session_path(&state.app_handle);
-                std::fs::write(&session_path, data)
-                    .map_err(|e| format!("Failed to write to {:?}: {}", session_path, e))?;
+                let session_path = profile.dir.join(SESSION_FILENAME);
+                client::save_session(
+                    &session_path,
+                    client.session(),
+                    unlocked_vault(&profile).as_ref(),
+                )?;
                 Ok(format!("Logged in as {}", user.first_name()))
             }
             Err(SignInError::PasswordRequired(token)) => {
                 // Store token for 2FA step
-                *state.password_token.lock().unwrap() = Some(token);
+                *profile.password_token.lock().unwrap() = Some(token);
                 Err("PASSWORD_REQUIRED".to_string())
             }
             Err(e) => Err(format!("Login failed: {}", e)),
@@ -224,9 +363,15 @@ async fn login_complete(
     }
 }
 
+// Fails with "LOCKED" until the passphrase is given, when the vault has one.
 #[tauri::command]
-async fn check_auth(state: State<'_, AppState>) -> Result<bool, String> {
-    let mut client_guard = state.client.lock().await;
+async fn check_auth(
+    passphrase: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<bool, String> {
+    let profile = state.profile();
+    let mut client_guard = profile.client.lock().await;
+    unlock_on_start(&profile, passphrase).await?;
 
     // Load env vars
     dotenv::dotenv().ok();
@@ -245,13 +390,13 @@ async fn check_auth(state: State<'_, AppState>) -> Result<bool, String> {
     if let Some(client) = client_guard.as_ref() {
         let auth = client.is_authorized().await.map_err(|e| e.to_string())?;
         if auth {
-            let _ = state.db.cleanup_trash(30);
+            purge_expired(client, &profile.budget, &profile.db).await;
         }
         return Ok(auth);
     }
 
     // Try load from file
-    let session_path = get_session_path(&state.app_handle);
+    let session_path = profile.dir.join(SESSION_FILENAME);
     if !session_path.exists() {
         return Ok(false);
     }
@@ -260,12 +405,13 @@ async fn check_auth(state: State<'_, AppState>) -> Result<bool, String> {
         return Ok(false); // Can't connect without secrets
     }
 
-    let session = Session::load_file_or_create(&session_path).map_err(|e| e.to_string())?;
+    let session = client::load_session(&session_path, unlocked_vault(&profile).as_ref())?;
     // Config... (We need repeat config, maybe refactor later but copy-paste for safety now)
     let params = InitParams {
         device_model: "Paperfold Desktop".to_string(),
         app_version: "0.1.0".to_string(),
         system_version: "macOS".to_string(),
+        flood_sleep_threshold: 0,
         ..Default::default()
     };
     let config = Config {
@@ -279,19 +425,58 @@ async fn check_auth(state: State<'_, AppState>) -> Result<bool, String> {
     let authorized = client.is_authorized().await.map_err(|e| e.to_string())?;
 
     if authorized {
-        let _ = state.db.cleanup_trash(30);
+        purge_expired(&client, &profile.budget, &profile.db).await;
     }
 
     *client_guard = Some(client);
     Ok(authorized)
 }
 
+// Deletes stored blobs. Failures are only logged: the metadata is already gone by
+// the time this runs.
+async fn delete_telegram_messages(storages: &Storages, messages: &[MessageRef]) {
+    if messages.is_empty() {
+        return;
+    }
+    match storages.delete(messages).await {
+        Ok(()) => println!("Deleted {} messages from Telegram", messages.len()),
+        Err(e) => eprintln!("Failed to delete messages from Telegram: {}", e),
+    }
+}
+
+// Deletes trash older than 30 days and file versions past the retention policy.
+async fn purge_expired(client: &Client, budget: &retry::Budget, db: &Database) {
+    let mut messages = db.cleanup_trash(30).unwrap_or_else(|e| {
+        eprintln!("Failed to clean up trash: {}", e);
+        Vec::new()
+    });
+    match db.prune_versions() {
+        Ok(pruned) => messages.extend(pruned),
+        Err(e) => eprintln!("Failed to prune file versions: {}", e),
+    }
+    if messages.is_empty() {
+        return;
+    }
+    let targets = match StorageTargets::load(db) {
+        Ok(targets) => targets,
+        Err(e) => {
+            eprintln!("Failed to read the storage targets: {}", e);
+            return;
+        }
+    };
+    match TelegramBackend::storages(client.clone(), budget, &targets).await {
+        Ok(storages) => delete_telegram_messages(&storages, &messages).await,
+        Err(e) => eprintln!("Failed to delete expired items from Telegram: {}", e),
+    }
+}
+
 #[tauri::command]
 async fn logout(state: State<'_, AppState>) -> Result<(), String> {
-    let mut client_guard = state.client.lock().await;
+    let profile = state.profile();
+    let mut client_guard = profile.client.lock().await;
     *client_guard = None;
 
-    let session_path = get_session_path(&state.app_handle);
+    let session_path = profile.dir.join(SESSION_FILENAME);
     if session_path.exists() {
         let _ = std::fs::remove_file(session_path);
     }
@@ -303,7 +488,8 @@ async fn fetch_files(
     folder_id: Option<String>,
     state: State<'_, AppState>,
 ) -> Result<(Vec<db::Folder>, Vec<db::FileMetadata>), String> {
-    Ok(state.db.list_contents(folder_id))
+    let profile = state.profile();
+    Ok(profile.db.list_contents(folder_id)?)
 }
 
 #[tauri::command]
@@ -312,253 +498,121 @@ async fn create_folder(
     parent_id: Option<String>,
     state: State<'_, AppState>,
 ) -> Result<String, String> {
+    let profile = state.profile();
     println!("Creating folder: name={}, parent_id={:?}", name, parent_id);
 
-    let folder_id = state.db.create_folder(&name, parent_id);
+    let folder_id = profile.db.create_folder(&name, parent_id)?;
     println!("Created folder {} (ID: {})", name, folder_id);
     Ok(folder_id)
 }
 
 #[tauri::command]
-async fn upload_file(
+async fn enqueue_upload(
     path: String,
     folder_id: Option<String>,
+    encrypt: Option<bool>,
     state: State<'_, AppState>,
-    window: Window,
-) -> Result<db::FileMetadata, String> {
-    let client_guard = state.client.lock().await;
-    let client = client_guard
-        .as_ref()
-        .ok_or("Client not initialized")?
-        .clone(); // Clone client for use in spawned tasks
-
-    let file_path = Path::new(&path);
-    if !file_path.exists() {
-        return Err("File not found".to_string());
-    }
-    let file_name = file_path
-        .file_name()
-        .ok_or("Invalid file name")?
-        .to_string_lossy()
-        .to_string();
-    let file_size = tokio::fs::metadata(&path)
-        .await
-        .map_err(|e| e.to_string())?
-        .len();
-
-    let mut file = tokio::fs::File::open(&path)
-        .await
-        .map_err(|e| e.to_string())?;
-
-    // Generate a unique file_id
-    let file_id: i64 = rand::thread_rng().gen();
-
-    let is_big = file_size > 10 * 1024 * 1024;
-    // If encrypted, we must read slightly less so that (chunk + tag) <= 512KB
-    // AES-GCM Tag is 16 bytes. 512*1024 - 16 = 524272.
-    // This ensures the OUTPUT is exactly 512KB (divisible by 1KB).
-    let chunk_size = 512 * 1024;
-    let total_parts = (file_size as usize + chunk_size - 1) / chunk_size;
-
-    let semaphore = Arc::new(Semaphore::new(16)); // Max 16 parallel uploads
-    let uploaded_bytes = Arc::new(AtomicU64::new(0));
-    let mut tasks = Vec::new();
-
-    let mut part_index = 0;
-
-    #[derive(Clone, serde::Serialize)]
-    struct ProgressPayload {
-        path: String,
-        progress: f64,
+) -> Result<UploadJob, String> {
+    let profile = state.profile();
+    // Encrypted folders encrypt everything uploaded below them unless told otherwise
+    let encrypt = match encrypt {
+        Some(encrypt) => encrypt,
+        None => profile.db.encrypts_uploads(folder_id.as_deref())?,
+    };
+    if encrypt && unlocked_vault(&profile).is_none() {
+        return Err(CryptoError::Locked.into());
     }
+    Ok(profile
+        .uploads
+        .enqueue(Path::new(&path), folder_id, encrypt)?)
+}
 
-    loop {
-        let mut buffer = vec![0u8; chunk_size];
-        let n = file.read(&mut buffer).await.map_err(|e| e.to_string())?;
-        if n == 0 {
-            break;
-        }
-        buffer.truncate(n);
-
-        let final_buffer = buffer;
-
-        let permit = semaphore
-            .clone()
-            .acquire_owned()
-            .await
-            .map_err(|e| e.to_string())?;
-        let client_clone = client.clone();
-        let path_clone = path.clone();
-        let window_clone = window.clone();
-        let uploaded_bytes_clone = uploaded_bytes.clone();
-        let current_part = part_index;
-
-        let task = tokio::spawn(async move {
-            let part_bytes = final_buffer; // Use encrypted buffer
-            let part_len = part_bytes.len() as u64;
-
-            let result = if is_big {
-                client_clone
-                    .invoke(&tl::functions::upload::SaveBigFilePart {
-                        file_id,
-                        file_part: current_part as i32,
-                        file_total_parts: total_parts as i32,
-                        bytes: part_bytes,
-                    })
-                    .await
-            } else {
-                client_clone
-                    .invoke(&tl::functions::upload::SaveFilePart {
-                        file_id,
-                        file_part: current_part as i32,
-                        bytes: part_bytes,
-                    })
-                    .await
-            };
-
-            drop(permit);
-
-            if let Err(e) = result {
-                return Err(format!("Part {} failed: {}", current_part, e));
-            }
+#[tauri::command]
+async fn list_uploads(state: State<'_, AppState>) -> Result<Vec<UploadJob>, String> {
+    let profile = state.profile();
+    Ok(profile.uploads.jobs()?)
+}
 
-            // Update progress (using original file size for percentage logic is fine,
-            // though actual transfer is higher)
-            let previous = uploaded_bytes_clone.fetch_add(part_len, Ordering::SeqCst);
-            // Approximation for progress simplicity
-            let percentage = ((previous + part_len) as f64
-                / (file_size as f64 * (if is_big { 1.0 } else { 1.0 }/* factor? */))
-                * 100.0)
-                .min(100.0);
-
-            // To be precise on progress with encryption, we might overshoot 100% relative to source size
-            // but that's acceptable UI glitch for now.
-
-            let _ = window_clone.emit(
-                "upload-progress",
-                ProgressPayload {
-                    path: path_clone,
-                    progress: percentage,
-                },
-            );
+#[tauri::command]
+async fn pause_upload(id: String, state: State<'_, AppState>) -> Result<UploadJob, String> {
+    let profile = state.profile();
+    Ok(profile.uploads.pause(&id)?)
+}
 
-            Ok(())
-        });
+#[tauri::command]
+async fn resume_upload(id: String, state: State<'_, AppState>) -> Result<UploadJob, String> {
+    let profile = state.profile();
+    Ok(profile.uploads.resume(&id)?)
+}
 
-        tasks.push(task);
-        part_index += 1;
-    }
+#[tauri::command]
+async fn set_upload_priority(
+    id: String,
+    priority: i64,
+    state: State<'_, AppState>,
+) -> Result<UploadJob, String> {
+    let profile = state.profile();
+    Ok(profile.uploads.set_priority(&id, priority)?)
+}
 
-    // Wait for all uploads
-    for task in tasks {
-        match task.await {
-            Ok(result) => result?,
-            Err(e) => return Err(format!("Task join error: {}", e)),
-        }
+#[tauri::command]
+async fn cancel_upload(id: String, state: State<'_, AppState>) -> Result<(), String> {
+    let profile = state.profile();
+    // Segments a stopped job already sent would otherwise sit in their chat
+    let sent = profile.uploads.cancel(&id).await?;
+    if !sent.is_empty() {
+        storages(&profile).await?.delete(&sent).await?;
     }
+    Ok(())
+}
 
-    // Construct InputFile
-    let input_file = if is_big {
-        tl::enums::InputFile::Big(tl::types::InputFileBig {
-            id: file_id,
-            parts: total_parts as i32,
-            name: file_name.clone(),
-        })
-    } else {
-        tl::enums::InputFile::File(tl::types::InputFile {
-            id: file_id,
-            parts: total_parts as i32,
-            name: file_name.clone(),
-            md5_checksum: "".to_string(),
-        })
-    };
-
-    let mime_type = mime_guess::from_path(&path)
-        .first_or_octet_stream()
-        .to_string();
-
-    let input_media =
-        tl::enums::InputMedia::UploadedDocument(tl::types::InputMediaUploadedDocument {
-            file: input_file,
-            mime_type: mime_type.clone(),
-            attributes: vec![tl::enums::DocumentAttribute::Filename(
-                tl::types::DocumentAttributeFilename {
-                    file_name: file_name.clone(),
-                },
-            )],
-            ttl_seconds: None,
-            force_file: false,
-            spoiler: false,
-            stickers: None,
-            thumb: None,
-            nosound_video: false,
-        });
-
-    let input_peer = tl::enums::InputPeer::PeerSelf;
-    let random_id: i64 = rand::thread_rng().gen();
-
-    let updates = client
-        .invoke(&tl::functions::messages::SendMedia {
-            silent: false,
-            background: false,
-            clear_draft: false,
-            peer: input_peer,
-            reply_to: None,
-            media: input_media,
-            message: "".to_string(),
-            random_id,
-            reply_markup: None,
-            entities: None,
-            schedule_date: None,
-            send_as: None,
-            noforwards: false,
-            update_stickersets_order: false,
-            invert_media: false,
-            quick_reply_shortcut: None,
-            effect: None,
-        })
-        .await
-        .map_err(|e| format!("SendMedia error: {}", e))?;
+#[derive(Clone, serde::Serialize)]
+struct UploadFinished {
+    id: String,
+    file: db::FileMetadata,
+}
 
-    let msg_id = match updates {
-        tl::enums::Updates::Updates(u) => u
-            .updates
-            .iter()
-            .find_map(|u| match u {
-                tl::enums::Update::MessageId(id) => Some(id.id),
-                tl::enums::Update::NewMessage(m) => match &m.message {
-                    tl::enums::Message::Message(msg) => Some(msg.id),
-                    _ => None,
-                },
-                _ => None,
-            })
-            .unwrap_or(0),
-        _ => 0,
-    };
+// Works through the upload queue one job at a time for as long as the app runs.
+// Jobs wait while the metadata is locked or nobody is signed in. Only the queue of
+// the open profile is worked on.
+async fn run_uploads(handle: tauri::AppHandle) {
+    let state = handle.state::<AppState>();
+    loop {
+        let profile = state.profile();
+        let job = match profile.uploads.next_job() {
+            Ok(Some(job)) => job,
+            Ok(None) | Err(UploadError::Db(DbError::Locked)) => {
+                profile.uploads.wait(UPLOAD_IDLE_WAIT).await;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Failed to read the upload queue: {}", e);
+                profile.uploads.wait(UPLOAD_IDLE_WAIT).await;
+                continue;
+            }
+        };
+        let Ok(storages) = storages(&profile).await else {
+            profile.uploads.wait(UPLOAD_IDLE_WAIT).await;
+            continue;
+        };
+        let vault = unlocked_vault(&profile);
 
-    let mut thumbnail = None;
-    if msg_id != 0 {
-        // Don't extract thumbnail if encrypted - it might act weird or fail,
-        // plus we don't want to leak visual data if strict vault.
-        if let Ok(chat) = client.get_me().await {
-            if let Ok(messages) = client.get_messages_by_id(&chat, &[msg_id]).await {
-                if let Some(Some(msg)) = messages.first() {
-                    thumbnail = client::utils::extract_thumbnail_base64(&client, msg).await;
-                }
+        let emitter = handle.clone();
+        let progress = move |job: &UploadJob| {
+            let _ = emitter.emit("upload-progress", job);
+        };
+        match profile
+            .uploads
+            .run(&job.id, &storages, vault.as_ref(), &progress)
+            .await
+        {
+            Ok(Some(file)) => {
+                let _ = handle.emit("upload-finished", UploadFinished { id: job.id, file });
             }
+            Ok(None) => {}
+            Err(e) => eprintln!("Upload of {} failed: {}", job.name, e),
         }
     }
-
-    let metadata = state.db.add_file(
-        folder_id,
-        file_name,
-        file_size as i64, // We store original size? Or encrypted? Storing original is better for UI.
-        mime_type,
-        msg_id,
-        thumbnail,
-    );
-
-    Ok(metadata)
 }
 
 #[tauri::command]
@@ -566,18 +620,25 @@ async fn preview_file(
     state: State<'_, AppState>,
     id: String, // Changed to UUID to lookup metadata
 ) -> Result<String, String> {
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Client not initialized")?;
+    let profile = state.profile();
+    let storages = storages(&profile).await?;
 
-    let file = state.db.get_file(&id).ok_or("File not found")?;
+    let file = profile.db.get_file(&id)?;
 
     // Download to temp dir
-    let temp_dir = std::env::temp_dir();
+    let temp_dir = match file.encryption {
+        Some(_) => {
+            let dir = sealed_preview_dir(&profile.id);
+            std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+            dir
+        }
+        None => std::env::temp_dir(),
+    };
     let temp_name = file.name.clone();
     let target_path = temp_dir.join(&temp_name);
     let target_path_str = target_path.to_string_lossy().to_string();
 
-    // If file already exists in temp, return it (simple cache) - FOR ENCRYPTED this is raw encrypted
+    // If file already exists in temp, return it (simple cache)
     // We need final path
     let final_path = target_path.clone();
     let final_path_str = final_path.to_string_lossy().to_string();
@@ -586,30 +647,19 @@ async fn preview_file(
         return Ok(final_path_str);
     }
 
-    let chat = client.get_me().await.map_err(|e| e.to_string())?;
-    let messages = client
-        .get_messages_by_id(&chat, &[file.message_id])
-        .await
-        .map_err(|e| e.to_string())?;
-
-    let message_opt = messages.first().ok_or("Message not found")?;
-    let message = message_opt.as_ref().ok_or("Message is empty/deleted")?;
-
-    if let Some(media) = message.media() {
-        if matches!(media, Media::Photo(_) | Media::Document(_)) {
-            let downloadable = Downloadable::Media(media);
-            client
-                .download_media(&downloadable, target_path_str.as_str())
-                .await
-                .map_err(|e| e.to_string())?;
-
-            Ok(target_path_str)
-        } else {
-            Err("Unsupported media type for preview".to_string())
-        }
-    } else {
-        Err("No media found".to_string())
-    }
+    let (storage, segments) = replicas::readable_copy(&storages, &file)?;
+    storage::download_file(
+        storage,
+        &segments,
+        file.encryption.as_ref(),
+        unlocked_vault(&profile).as_ref(),
+        file.sha256.as_deref(),
+        &target_path,
+        None,
+    )
+    .await?;
+
+    Ok(target_path_str)
 }
 
 #[derive(serde::Serialize)]
@@ -623,9 +673,12 @@ struct UserProfile {
 
 #[tauri::command]
 async fn get_current_user(state: State<'_, AppState>) -> Result<UserProfile, String> {
-    let mut client_guard = state.client.lock().await;
+    let profile = state.profile();
+    let mut client_guard = profile.client.lock().await;
     let client = client_guard.as_mut().ok_or("Client not initialized")?;
-    let me = client.get_me().await.map_err(|e| e.to_string())?;
+    let me = retry::call(&profile.budget, || client.get_me())
+        .await
+        .map_err(|e| e.to_string())?;
 
     Ok(UserProfile {
         id: me.id(),
@@ -638,13 +691,104 @@ async fn get_current_user(state: State<'_, AppState>) -> Result<UserProfile, Str
 
 #[tauri::command]
 fn trash_item(state: State<AppState>, id: String, is_folder: bool) -> Result<(), String> {
-    state.db.trash_item(&id, is_folder);
-    Ok(())
+    let profile = state.profile();
+    Ok(profile.db.trash_item(&id, is_folder)?)
 }
 
 #[tauri::command]
 fn restore_item(state: State<AppState>, id: String, is_folder: bool) -> Result<(), String> {
-    state.db.restore_item(&id, is_folder);
+    let profile = state.profile();
+    Ok(profile.db.restore_item(&id, is_folder)?)
+}
+
+#[tauri::command]
+async fn find_duplicates(state: State<'_, AppState>) -> Result<Vec<DuplicateGroup>, String> {
+    let profile = state.profile();
+    let files = profile.db.get_all_files()?;
+    // Decoding every thumbnail takes a while on large libraries
+    tokio::task::spawn_blocking(move || duplicates::find_duplicates(&files))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Keeps the newest of the files and trashes the others. Returns the trashed ids.
+#[tauri::command]
+fn trash_duplicates(state: State<AppState>, ids: Vec<String>) -> Result<Vec<String>, String> {
+    let profile = state.profile();
+    let mut files = ids
+        .iter()
+        .map(|id| profile.db.get_file(id))
+        .collect::<Result<Vec<_>, _>>()?;
+    duplicates::sort_newest_first(&mut files);
+    let trashed: Vec<String> = files.iter().skip(1).map(|f| f.id.clone()).collect();
+    profile.db.trash_files(&trashed)?;
+    Ok(trashed)
+}
+
+#[tauri::command]
+fn list_versions(state: State<AppState>, id: String) -> Result<Vec<db::FileVersion>, String> {
+    let profile = state.profile();
+    Ok(profile.db.get_file(&id)?.versions)
+}
+
+#[tauri::command]
+async fn download_version(
+    file_id: String,
+    version_id: String,
+    save_path: String,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let profile = state.profile();
+    let file = profile.db.get_file(&file_id)?;
+    let version = file
+        .versions
+        .into_iter()
+        .find(|v| v.id == version_id)
+        .ok_or("Version not found")?;
+    let storages = storages(&profile).await?;
+    storage::download_file(
+        storages.get(version.chat_id)?,
+        &version.blob_segments(),
+        version.encryption.as_ref(),
+        unlocked_vault(&profile).as_ref(),
+        version.sha256.as_deref(),
+        Path::new(&save_path),
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+// The current contents become the newest version, so the restore can be undone.
+#[tauri::command]
+fn restore_version(
+    state: State<AppState>,
+    id: String,
+    version_id: String,
+) -> Result<db::FileMetadata, String> {
+    let profile = state.profile();
+    Ok(profile.db.restore_version(&id, &version_id)?)
+}
+
+#[tauri::command]
+fn get_version_retention(state: State<AppState>) -> Result<db::VersionRetention, String> {
+    let profile = state.profile();
+    Ok(profile.db.version_retention()?)
+}
+
+// Versions the new policy no longer keeps are deleted right away.
+#[tauri::command]
+async fn set_version_retention(
+    state: State<'_, AppState>,
+    retention: db::VersionRetention,
+) -> Result<(), String> {
+    let profile = state.profile();
+    profile.db.set_version_retention(&retention)?;
+    let messages = profile.db.prune_versions()?;
+    if !messages.is_empty() {
+        let storages = storages(&profile).await?;
+        delete_telegram_messages(&storages, &messages).await;
+    }
     Ok(())
 }
 
@@ -654,72 +798,35 @@ async fn delete_item_permanently(
     id: String,
     is_folder: bool,
 ) -> Result<(), String> {
+    let profile = state.profile();
     println!("Deleting item permanently: {} (folder: {})", id, is_folder);
 
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?;
+    let storages = storages(&profile).await?;
 
-    let mut messages_to_delete = Vec::new();
-
-    if is_folder {
-        // Get all files in the folder to be deleted
-        let files = state.db.delete_folder(&id);
-        for f in files {
-            messages_to_delete.push(f.message_id);
-        }
+    // Messages of every file in the subtree, for folders
+    let messages_to_delete = if is_folder {
+        profile.db.delete_folder(&id)?
     } else {
-        // Get file to get message id
-        if let Some(file) = state.db.get_file(&id) {
-            messages_to_delete.push(file.message_id);
-            state.db.delete_file(&id);
-        }
-    }
+        profile.db.delete_file(&id)?
+    };
 
-    if !messages_to_delete.is_empty() {
-        // Fetch chat (me) to delete messages
-        match client.get_me().await {
-            Ok(chat) => {
-                if let Err(e) = client.delete_messages(&chat, &messages_to_delete).await {
-                    eprintln!("Failed to delete messages from Telegram: {}", e);
-                } else {
-                    println!("Deleted messages from Telegram");
-                }
-            }
-            Err(e) => eprintln!("Failed to get_me for deletion: {}", e),
-        }
-    }
+    delete_telegram_messages(&storages, &messages_to_delete).await;
 
     Ok(())
 }
 
 #[tauri::command]
 async fn empty_trash(state: State<'_, AppState>) -> Result<(), String> {
+    let profile = state.profile();
     println!("Emptying trash...");
 
     // 0 days means delete everything in trash
-    let files = state.db.cleanup_trash(0);
-    let mut messages_to_delete = Vec::new();
-
-    for f in files {
-        messages_to_delete.push(f.message_id);
-    }
+    let messages_to_delete = profile.db.cleanup_trash(0)?;
 
     if !messages_to_delete.is_empty() {
-        let mut client_guard = state.client.lock().await;
-        if let Some(client) = client_guard.as_mut() {
-            match client.get_me().await {
-                Ok(chat) => {
-                    if let Err(e) = client.delete_messages(&chat, &messages_to_delete).await {
-                        eprintln!("Failed to delete messages from Telegram: {}", e);
-                    } else {
-                        println!(
-                            "Deleted {} messages from Telegram",
-                            messages_to_delete.len()
-                        );
-                    }
-                }
-                Err(e) => eprintln!("Failed to get_me for deletion: {}", e),
-            }
+        match storages(&profile).await {
+            Ok(storages) => delete_telegram_messages(&storages, &messages_to_delete).await,
+            Err(e) => eprintln!("Failed to delete emptied trash from Telegram: {}", e),
         }
     }
 
@@ -728,7 +835,8 @@ async fn empty_trash(state: State<'_, AppState>) -> Result<(), String> {
 
 #[tauri::command]
 fn fetch_trash(state: State<AppState>) -> Result<(Vec<db::Folder>, Vec<db::FileMetadata>), String> {
-    Ok(state.db.list_trash())
+    let profile = state.profile();
+    Ok(profile.db.list_trash()?)
 }
 
 #[tauri::command]
@@ -737,94 +845,35 @@ async fn delete_item(
     is_folder: bool,
     state: State<'_, AppState>,
 ) -> Result<(), String> {
+    let profile = state.profile();
     // Soft delete now
-    state.db.trash_item(&id, is_folder);
-    Ok(())
+    Ok(profile.db.trash_item(&id, is_folder)?)
 }
 
+// Imports the files in Saved Messages that no entry refers to yet, see
+// `import::import_saved_messages`. Emits "import-progress" after every page.
 #[tauri::command]
-async fn sync_saved_messages(state: State<'_, AppState>) -> Result<usize, String> {
+async fn sync_saved_messages(
+    state: State<'_, AppState>,
+    window: Window,
+    filter: Option<ImportFilter>,
+) -> Result<usize, String> {
+    let profile = state.profile();
     println!("Syncing saved messages...");
-    let client_guard = state.client.lock().await;
-    let client = client_guard.as_ref().ok_or("Client not initialized")?;
-
-    let chat = client.get_me().await.map_err(|e| e.to_string())?;
-
-    // Get existing IDs to avoid duplicates
-    let existing_ids = state.db.get_existing_message_ids();
-    let existing_set: std::collections::HashSet<i32> = existing_ids.into_iter().collect();
-
-    // Ensure 'Telegram Sync' folder exists
-    let sync_folder_name = "Telegram Sync";
-    let folders = state.db.get_all_folders();
-    let sync_folder_id = match folders
-        .iter()
-        .find(|f| f.name == sync_folder_name && f.parent_id.is_none())
-    {
-        Some(f) => f.id.clone(),
-        None => {
-            println!("Creating 'Telegram Sync' folder...");
-            state.db.create_folder(sync_folder_name, None)
-        }
+    let storages = storages(&profile).await?;
+    let filter = filter.unwrap_or_default();
+    let progress = move |p: &ImportProgress| {
+        let _ = window.emit("import-progress", p);
     };
+    let report =
+        import::import_saved_messages(&profile.db, storages.saved_messages(), &filter, &progress)
+            .await?;
 
-    let mut count = 0;
-    // Limit to last 200 for now to be fast
-    let mut messages = client.iter_messages(&chat).limit(200);
-
-    while let Some(message) = messages.next().await.map_err(|e| e.to_string())? {
-        if existing_set.contains(&message.id()) {
-            // Check if it's in root (folder_id is None). If so, move it to sync folder.
-            // This fixes "files outside folder" issue for legacy sync items.
-            let _ = state
-                .db
-                .move_file_to_sync_folder(message.id(), &sync_folder_id);
-            continue;
-        }
-
-        if let Some(media) = message.media() {
-            let (name, size, mime_type, thumbnail) = match media {
-                Media::Document(doc) => (
-                    doc.name().to_string(),
-                    doc.size(),
-                    doc.mime_type()
-                        .unwrap_or("application/octet-stream")
-                        .to_string(),
-                    None, // Thumbnails are complex to extract without download, skip for now or implement later
-                ),
-                Media::Photo(_photo) => (
-                    format!("Photo_{}.jpg", message.id()), // Photos don't have names usually
-                    0, // Size is hard to get for photo without detail check? Photo has sizes vec.
-                    "image/jpeg".to_string(),
-                    None,
-                ),
-                _ => continue,
-            };
-
-            // Only add if it has a name (skip stickers etc if they end up here)
-            if name.is_empty() && size == 0 {
-                continue;
-            }
-
-            // check again if we have it by name? No, ID is source of truth.
-
-            // Add to DB
-            state.db.add_file(
-                Some(sync_folder_id.clone()), // Sync to "Telegram Sync" folder
-                // User asked to "fetch previous stored data". Root is fine or create a folder.
-                // Let's dump in root for visibility.
-                name,
-                size as i64,
-                mime_type,
-                message.id(),
-                thumbnail,
-            );
-            count += 1;
-        }
-    }
-
-    println!("Synced {} new files", count);
-    Ok(count)
+    println!(
+        "Synced {} new files out of {} scanned",
+        report.imported, report.scanned
+    );
+    Ok(report.imported)
 }
 
 #[tauri::command]
@@ -834,52 +883,23 @@ async fn download_file_core(
     state: State<'_, AppState>,
     window: Window,
 ) -> Result<String, String> {
+    let profile = state.profile();
     println!("Downloading file: id={}, save_path={}", file_id, save_path);
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Client not initialized")?;
+    let storages = storages(&profile).await?;
 
-    let file_meta = state.db.get_file(&file_id).ok_or("File not found")?;
-    let message_id = file_meta.message_id;
+    let file_meta = profile.db.get_file(&file_id)?;
     let total_size = file_meta.size;
-    let chat = client.get_me().await.map_err(|e| e.to_string())?;
-
-    let messages = client
-        .get_messages_by_id(&chat, &[message_id])
-        .await
-        .map_err(|e| e.to_string())?;
-
-    let message_opt = messages.first().ok_or("Message list empty")?;
-    let message = match message_opt {
-        Some(m) => m,
-        None => return Err("Message not found".to_string()),
-    };
 
-    if let Some(media) = message.media() {
-        let downloadable = match media {
-            Media::Photo(p) => Downloadable::Media(Media::Photo(p)),
-            Media::Document(d) => Downloadable::Media(Media::Document(d)),
-            _ => return Err("Unsupported media type".to_string()),
-        };
-
-        let mut file_out = tokio::fs::File::create(&save_path)
-            .await
-            .map_err(|e| e.to_string())?;
-        let mut stream = client.iter_download(&downloadable);
-        let mut downloaded_size: i64 = 0;
-
-        #[derive(Clone, serde::Serialize)]
-        struct DownloadProgress {
-            id: String,
-            progress: u32,
-        }
-
-        while let Some(chunk) = stream.next().await.map_err(|e| e.to_string())? {
-            file_out
-                .write_all(&chunk)
-                .await
-                .map_err(|e| e.to_string())?;
-            downloaded_size += chunk.len() as i64;
+    #[derive(Clone, serde::Serialize)]
+    struct DownloadProgress {
+        id: String,
+        progress: u32,
+    }
 
+    let progress: ProgressFn = {
+        let window = window.clone();
+        let file_id = file_id.clone();
+        Arc::new(move |downloaded_size: u64| {
             if total_size > 0 {
                 let progress = (downloaded_size as f64 / total_size as f64 * 100.0) as u32;
                 let _ = window.emit(
@@ -890,21 +910,31 @@ async fn download_file_core(
                     },
                 );
             }
-        }
+        })
+    };
 
-        // Ensure 100% is sent
-        let _ = window.emit(
-            "download-progress",
-            DownloadProgress {
-                id: file_id.clone(),
-                progress: 100,
-            },
-        );
+    let (storage, segments) = replicas::readable_copy(&storages, &file_meta)?;
+    storage::download_file(
+        storage,
+        &segments,
+        file_meta.encryption.as_ref(),
+        unlocked_vault(&profile).as_ref(),
+        file_meta.sha256.as_deref(),
+        Path::new(&save_path),
+        Some(progress),
+    )
+    .await?;
+
+    // Ensure 100% is sent
+    let _ = window.emit(
+        "download-progress",
+        DownloadProgress {
+            id: file_id.clone(),
+            progress: 100,
+        },
+    );
 
-        Ok("Download complete".to_string())
-    } else {
-        Err("No media found in message".to_string())
-    }
+    Ok("Download complete".to_string())
 }
 
 #[tauri::command]
@@ -914,38 +944,72 @@ async fn rename_item(
     new_name: String,
     state: State<'_, AppState>,
 ) -> Result<(), String> {
+    let profile = state.profile();
     println!(
         "Renaming item: id={}, is_folder={}, new_name={}",
         id, is_folder, new_name
     );
     if is_folder {
-        if state.db.rename_folder(&id, &new_name) {
-            Ok(())
-        } else {
-            Err("Folder not found".to_string())
-        }
+        profile.db.rename_folder(&id, &new_name)?;
     } else {
-        if state.db.rename_file(&id, &new_name) {
-            Ok(())
-        } else {
-            Err("File not found".to_string())
-        }
+        profile.db.rename_file(&id, &new_name)?;
     }
+    Ok(())
 }
 
+// Moves a file or folder into `target_folder_id` (the root when `None`), renaming it
+// to `new_name` if given. Without a policy the one from the settings applies.
+// Returns the name the item ended up with.
 #[tauri::command]
-async fn toggle_star(
+async fn move_item(
     id: String,
     is_folder: bool,
+    target_folder_id: Option<String>,
+    new_name: Option<String>,
+    policy: Option<ConflictPolicy>,
     state: State<'_, AppState>,
-) -> Result<(), String> {
-    if state.db.toggle_star(&id, is_folder) {
-        Ok(())
+) -> Result<String, String> {
+    let profile = state.profile();
+    let policy = match policy {
+        Some(policy) => policy,
+        None => profile.db.conflict_policy()?,
+    };
+    let name = match new_name {
+        Some(name) => name,
+        None if is_folder => profile.db.get_folder_by_id(&id)?.name,
+        None => profile.db.get_file(&id)?.name,
+    };
+    if is_folder {
+        Ok(profile
+            .db
+            .move_folder(&id, target_folder_id, &name, policy)?)
     } else {
-        Err("Item not found".to_string())
+        Ok(profile.db.move_file(&id, target_folder_id, &name, policy)?)
     }
 }
 
+#[tauri::command]
+fn get_conflict_policy(state: State<AppState>) -> Result<ConflictPolicy, String> {
+    let profile = state.profile();
+    Ok(profile.db.conflict_policy()?)
+}
+
+#[tauri::command]
+fn set_conflict_policy(state: State<AppState>, policy: ConflictPolicy) -> Result<(), String> {
+    let profile = state.profile();
+    Ok(profile.db.set_conflict_policy(policy)?)
+}
+
+#[tauri::command]
+async fn toggle_star(
+    id: String,
+    is_folder: bool,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let profile = state.profile();
+    Ok(profile.db.toggle_star(&id, is_folder)?)
+}
+
 #[derive(serde::Serialize)]
 pub struct EnrichedFolder {
     #[serde(flatten)]
@@ -964,15 +1028,17 @@ pub struct EnrichedFile {
 async fn fetch_starred(
     state: State<'_, AppState>,
 ) -> Result<(Vec<EnrichedFolder>, Vec<EnrichedFile>), String> {
-    let (folders, files) = state.db.get_starred();
+    let profile = state.profile();
+    let (folders, files) = profile.db.get_starred()?;
 
     let enriched_folders = folders
         .into_iter()
         .map(|f| {
             let path_display = if let Some(fid) = &f.parent_id {
-                state
+                profile
                     .db
                     .lookup_folder_name(fid)
+                    .ok()
                     .map(|name| format!("In: {}", name))
             } else {
                 Some("In: My Drive".to_string())
@@ -988,9 +1054,10 @@ async fn fetch_starred(
         .into_iter()
         .map(|f| {
             let path_display = if let Some(fid) = &f.folder_id {
-                state
+                profile
                     .db
                     .lookup_folder_name(fid)
+                    .ok()
                     .map(|name| format!("In: {}", name))
             } else {
                 Some("In: My Drive".to_string())
@@ -1010,12 +1077,14 @@ async fn search_items(
     query: String,
     state: State<'_, AppState>,
 ) -> Result<(Vec<db::Folder>, Vec<db::FileMetadata>), String> {
-    Ok(state.db.search_items(&query))
+    let profile = state.profile();
+    Ok(profile.db.search_items(&query)?)
 }
 
 #[tauri::command]
 async fn get_storage_usage(state: State<'_, AppState>) -> Result<String, String> {
-    let bytes = state.db.get_total_usage();
+    let profile = state.profile();
+    let bytes = profile.db.get_total_usage()?;
 
     const KB: f64 = 1024.0;
     const MB: f64 = KB * 1024.0;
@@ -1035,7 +1104,8 @@ async fn get_storage_usage(state: State<'_, AppState>) -> Result<String, String>
 
 #[tauri::command]
 async fn get_folder_stats(state: State<'_, AppState>, id: String) -> Result<(i64, i32), String> {
-    Ok(state.db.get_folder_stats(&id))
+    let profile = state.profile();
+    Ok(profile.db.get_folder_stats(&id)?)
 }
 
 #[tauri::command]
@@ -1053,11 +1123,12 @@ async fn update_folder_metadata(
     description: Option<String>,
     view_mode: Option<String>,
 ) -> Result<(), String> {
+    let profile = state.profile();
     println!(
         "Updating folder metadata: id={}, color={:?}, icon={:?}, gradient={:?}, emoji={:?}",
         id, color, icon, gradient, emoji
     );
-    let success = state.db.update_folder_metadata(
+    let result = profile.db.update_folder_metadata(
         &id,
         color,
         icon,
@@ -1070,186 +1141,714 @@ async fn update_folder_metadata(
         description,
         view_mode,
     );
-    if success {
-        println!("Update successful for id={}", id);
-        Ok(())
-    } else {
-        println!("Update failed: Folder not found for id={}", id);
-        Err("Folder not found".to_string())
+    match result {
+        Ok(()) => {
+            println!("Update successful for id={}", id);
+            Ok(())
+        }
+        Err(e) => {
+            println!("Update failed for id={}: {}", id, e);
+            Err(e.into())
+        }
     }
 }
 
 #[tauri::command]
-async fn backup_metadata(state: State<'_, AppState>) -> Result<String, String> {
-    println!("Starting metadata backup...");
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
+fn set_folder_encryption(
+    state: State<AppState>,
+    id: String,
+    encrypted: bool,
+) -> Result<(), String> {
+    let profile = state.profile();
+    Ok(profile.db.set_folder_encrypted(&id, encrypted)?)
+}
 
-    // 1. Get metadata path
-    let app_dir = state
-        .app_handle
-        .path()
-        .app_data_dir()
-        .map_err(|e| e.to_string())?;
-    let metadata_path = app_dir.join("metadata.json");
+// Copies to keep of files in the folder, each in a different storage chat. `None`
+// goes back to the parent's setting.
+#[tauri::command]
+fn set_folder_replicas(
+    state: State<AppState>,
+    id: String,
+    replicas: Option<u32>,
+) -> Result<(), String> {
+    let profile = state.profile();
+    Ok(profile.db.set_folder_replicas(&id, replicas)?)
+}
 
-    if !metadata_path.exists() {
-        return Err("No metadata file found to backup".to_string());
-    }
+#[derive(serde::Serialize)]
+struct VaultStatus {
+    configured: bool,
+    unlocked: bool,
+    // "passphrase" or "key_file" once configured
+    protection: Option<String>,
+    metadata_encrypted: bool,
+}
 
-    // 2. Upload file
-    // For small files like metadata.json, we can use a simpler upload or just re-use the manual logic.
-    // Since client.upload_file is convenient but we want to tag it...
-    // Let's use the file upload helper if available or standard part upload.
-    // We'll trust client.upload_file for simplicity if available, else manual.
-    // grammers-client has `upload_file` which returns an UploadedFile.
+#[tauri::command]
+fn get_vault_status(state: State<AppState>) -> Result<VaultStatus, String> {
+    let profile = state.profile();
+    let dir = profile.dir.clone();
+    let params = Vault::load_params(&dir)?;
+    let unlocked = profile.vault.lock().unwrap().is_some();
+    Ok(VaultStatus {
+        configured: params.is_some(),
+        unlocked,
+        protection: params.map(|p| match p.protection {
+            KeyProtection::Passphrase { .. } => "passphrase".to_string(),
+            KeyProtection::KeyFile => "key_file".to_string(),
+        }),
+        metadata_encrypted: profile.db.is_encrypted()?,
+    })
+}
 
-    let uploaded_file = client
-        .upload_file(&metadata_path)
+// Unlocks the vault. The first unlock sets it up, protected by this passphrase or,
+// without one, by a key file, and encrypts the metadata and session from then on.
+#[tauri::command]
+async fn unlock_vault(
+    passphrase: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let profile = state.profile();
+    let dir = profile.dir.clone();
+    let credential = credential(passphrase)?;
+    // Key derivation is deliberately slow; keep it off the async runtime
+    let vault = tokio::task::spawn_blocking(move || Vault::open_or_create(&dir, &credential))
         .await
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| e.to_string())??;
+    use_vault(&profile, vault)
+}
+
+// Switches the vault to a new passphrase, or to the key file when `new_passphrase`
+// is None. Only the vault key is re-wrapped; nothing sealed under it changes.
+#[tauri::command]
+async fn change_vault_passphrase(
+    current_passphrase: Option<String>,
+    new_passphrase: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let profile = state.profile();
+    let dir = profile.dir.clone();
+    let current = credential(current_passphrase)?;
+    let new = credential(new_passphrase)?;
+    let vault = tokio::task::spawn_blocking(move || {
+        let mut vault = Vault::unlock(&dir, &current)?;
+        vault.protect(&dir, &new)?;
+        Ok::<_, CryptoError>(vault)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    *profile.vault.lock().unwrap() = Some(vault);
+    Ok(())
+}
 
-    // 3. Send to "Saved Messages" (Me)
-    let me = client.get_me().await.map_err(|e| e.to_string())?;
+// Forgets the vault key. Encrypted metadata locks with it, and so does the session,
+// so the client is dropped until check_auth unlocks again.
+#[tauri::command]
+async fn lock_vault(state: State<'_, AppState>) -> Result<(), String> {
+    let profile = state.profile();
+    *profile.vault.lock().unwrap() = None;
+    wipe_sealed_previews(&profile.id);
+    if profile.db.is_encrypted()? {
+        profile.db.lock()?;
+        *profile.client.lock().await = None;
+    }
+    Ok(())
+}
 
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let caption = format!("#paperfold_metadata_backup\nTimestamp: {}", timestamp);
+#[tauri::command]
+fn list_profiles(state: State<'_, AppState>) -> Result<Profiles, String> {
+    Ok(Profiles::load(&state.app_dir)?)
+}
 
-    client
-        .send_message(&me, InputMessage::text(&caption).file(uploaded_file))
-        .await
+// Adds a profile nobody is signed in to yet. Switching to it leads to the login.
+#[tauri::command]
+fn add_profile(state: State<'_, AppState>, name: String) -> Result<Profile, String> {
+    let mut profiles = Profiles::load(&state.app_dir)?;
+    let profile = profiles.add(&name)?;
+    std::fs::create_dir_all(profiles::profile_dir(&state.app_dir, &profile.id))
         .map_err(|e| e.to_string())?;
+    profiles.save(&state.app_dir)?;
+    Ok(profile)
+}
+
+// Opens profile `id` in place of the current one, without a restart. It starts out
+// signed out and locked until check_auth runs for it. Commands already running
+// finish against the old profile; its queued uploads wait until it is open again.
+#[tauri::command]
+async fn switch_profile(state: State<'_, AppState>, id: String) -> Result<Profile, String> {
+    let mut profiles = Profiles::load(&state.app_dir)?;
+    let profile = profiles.get(&id)?.clone();
+    if state.profile().id != id {
+        let opened = Arc::new(ProfileState::open(&state.app_dir, &id)?);
+        let old = {
+            let mut current = state.profile.write().unwrap();
+            profiles.set_active(&id)?;
+            profiles.save(&state.app_dir)?;
+            std::mem::replace(&mut *current, opened)
+        };
+        // The upload runner may be waiting on the old queue
+        old.uploads.wake();
+        {
+            let mut vaults = state.vaults.lock().unwrap();
+            vaults.remove(&id);
+            match unlocked_vault(&old) {
+                Some(vault) => vaults.insert(old.id.clone(), vault),
+                None => vaults.remove(&old.id),
+            };
+        }
+        let mut retired = state.retired.lock().unwrap();
+        retired.retain(|p| p.strong_count() > 0);
+        retired.push(Arc::downgrade(&old));
+        println!("Switched to profile {}", profile.name);
+    }
+    let _ = state.app_handle.emit("profile-changed", &profile);
+    Ok(profile)
+}
 
+// Deletes a profile with its session, metadata, vault and cache from this computer.
+// What it stored on Telegram stays there. The open profile cannot be removed, nor
+// one whose database is still open: by a command that started before switching
+// away from it, or by the WebDAV daemon.
+#[tauri::command]
+fn remove_profile(state: State<'_, AppState>, id: String) -> Result<(), String> {
+    // Held so the profile cannot be switched to while it is being removed
+    let _current = state.profile.write().unwrap();
+    let in_use = state
+        .retired
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .any(|p| p.id == id);
+    if in_use {
+        return Err("The profile is still in use. Try again in a moment.".to_string());
+    }
+    if webdav_running(&state.app_handle) {
+        return Err("Stop the WebDAV server before removing a profile.".to_string());
+    }
+    let mut profiles = Profiles::load(&state.app_dir)?;
+    let removed = profiles.remove(&id)?;
+    profiles.save(&state.app_dir)?;
+    profiles::remove_profile_dir(&state.app_dir, &id)?;
+    state.vaults.lock().unwrap().remove(&id);
+    println!("Removed profile {}", removed.name);
+    Ok(())
+}
+
+// Uploads a backup of the metadata, then deletes the backups the policy no longer
+// keeps.
+#[tauri::command]
+async fn backup_metadata(state: State<'_, AppState>) -> Result<BackupInfo, String> {
+    let profile = state.profile();
+    println!("Starting metadata backup...");
+    let storages = storages(&profile).await?;
+    let storage = storages.saved_messages();
+    let info = run_backup(&profile.db, storage).await?;
     println!("Backup uploaded successfully.");
-    Ok(format!("Backup successful! Timestamp: {}", timestamp))
+    Ok(info)
+}
+
+async fn run_backup(db: &Database, storage: &dyn StorageBackend) -> Result<BackupInfo, String> {
+    let info = backups::create_backup(db, storage).await?;
+    match backups::prune_backups(db, storage).await {
+        Ok(0) => {}
+        Ok(n) => println!("Deleted {} old metadata backups", n),
+        Err(e) => eprintln!("Failed to prune metadata backups: {}", e),
+    }
+    Ok(info)
+}
+
+#[tauri::command]
+async fn list_backups(state: State<'_, AppState>) -> Result<Vec<BackupInfo>, String> {
+    let profile = state.profile();
+    let storages = storages(&profile).await?;
+    let storage = storages.saved_messages();
+    Ok(backups::list_backups(storage).await?)
+}
+
+// What restoring the backup in `message_id` would change.
+#[tauri::command]
+async fn diff_backup(state: State<'_, AppState>, message_id: i32) -> Result<BackupDiff, String> {
+    let profile = state.profile();
+    let storages = storages(&profile).await?;
+    let storage = storages.saved_messages();
+    let (folders, files) = backups::load_backup(&profile.db, storage, message_id).await?;
+    let (current_folders, current_files) =
+        (profile.db.get_all_folders()?, profile.db.get_all_files()?);
+    Ok(backups::diff(
+        (&folders, &files),
+        (&current_folders, &current_files),
+    ))
 }
 
+// Restores the backup in `message_id`, or the newest one. With `merge` only the items
+// missing from the current metadata are added back; otherwise the backup replaces it,
+// after a copy of it is kept as metadata.json.old.
 #[tauri::command]
-async fn restore_metadata(state: State<'_, AppState>) -> Result<String, String> {
+async fn restore_metadata(
+    state: State<'_, AppState>,
+    message_id: Option<i32>,
+    merge: Option<bool>,
+) -> Result<String, String> {
+    let profile = state.profile();
     println!("Restoring metadata from backup...");
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
+    let storages = storages(&profile).await?;
+    let storage = storages.saved_messages();
+
+    let message_id = match message_id {
+        Some(id) => id,
+        None => {
+            backups::list_backups(storage)
+                .await?
+                .first()
+                .ok_or(BackupError::NotFound)?
+                .message_id
+        }
+    };
+    let (folders, files) = backups::load_backup(&profile.db, storage, message_id).await?;
+
+    if merge.unwrap_or(false) {
+        let (folders, files) = profile.db.merge_contents(folders, files)?;
+        return Ok(format!(
+            "Merged {} folders and {} files from the backup.",
+            folders, files
+        ));
+    }
+
+    profile
+        .db
+        .export_snapshot(&profile.dir.join("metadata.json.old"))?;
+    profile.db.replace_contents(folders, files)?;
 
-    let me = client.get_me().await.map_err(|e| e.to_string())?;
+    Ok("Backup restored successfully. Your dashboard will refresh.".to_string())
+}
 
-    // 1. Search for latest backup
-    let mut messages = client.iter_messages(&me).limit(50); // Check last 50 messages
+// Deletes the backups the policy no longer keeps, returning how many went.
+#[tauri::command]
+async fn prune_backups(state: State<'_, AppState>) -> Result<usize, String> {
+    let profile = state.profile();
+    let storages = storages(&profile).await?;
+    let storage = storages.saved_messages();
+    Ok(backups::prune_backups(&profile.db, storage).await?)
+}
 
-    let mut backup_msg = None;
+#[tauri::command]
+fn get_backup_policy(state: State<'_, AppState>) -> Result<BackupPolicy, String> {
+    let profile = state.profile();
+    Ok(BackupPolicy::load(&profile.db)?)
+}
 
-    while let Some(msg) = messages.next().await.map_err(|e| e.to_string())? {
-        if msg.text().contains("#paperfold_metadata_backup") {
-            backup_msg = Some(msg);
-            break;
+#[tauri::command]
+fn set_backup_policy(state: State<'_, AppState>, policy: BackupPolicy) -> Result<(), String> {
+    let profile = state.profile();
+    Ok(policy.save(&profile.db)?)
+}
+
+// Takes a backup whenever the policy says one is due, while logged in and unlocked.
+async fn run_scheduled_backups(handle: tauri::AppHandle) {
+    let state = handle.state::<AppState>();
+    loop {
+        tokio::time::sleep(BACKUP_CHECK_INTERVAL).await;
+        let profile = state.profile();
+        let due = BackupPolicy::load(&profile.db).and_then(|policy| {
+            let last = backups::last_backup_at(&profile.db)?;
+            Ok(policy.is_due(last, chrono::Utc::now().timestamp()))
+        });
+        match due {
+            Ok(true) => {}
+            Ok(false) | Err(DbError::Locked) => continue,
+            Err(e) => {
+                eprintln!("Failed to read the backup policy: {}", e);
+                continue;
+            }
+        }
+        let Ok(storages) = storages(&profile).await else {
+            continue;
+        };
+        let storage = storages.saved_messages();
+        match run_backup(&profile.db, storage).await {
+            Ok(info) => println!("Scheduled metadata backup in message {}", info.message_id),
+            Err(e) => eprintln!("Scheduled metadata backup failed: {}", e),
         }
     }
+}
 
-    if let Some(msg) = backup_msg {
-        // 2. Download it
-        let app_dir = state
-            .app_handle
-            .path()
-            .app_data_dir()
-            .map_err(|e| e.to_string())?;
-        let metadata_path = app_dir.join("metadata.json");
+// Exchanges metadata changes with the other devices on the account, see
+// `sync::sync_metadata`. Emits "metadata-changed" when something arrived.
+#[tauri::command]
+async fn sync_metadata(state: State<'_, AppState>, window: Window) -> Result<SyncReport, String> {
+    let profile = state.profile();
+    let storages = storages(&profile).await?;
+    let storage = storages.saved_messages();
+    let report = sync::sync_metadata(&profile.db, storage).await?;
+    if report.received > 0 {
+        let _ = window.emit("metadata-changed", ());
+    }
+    Ok(report)
+}
+
+#[tauri::command]
+fn list_sync_conflicts(state: State<'_, AppState>) -> Result<Vec<SyncConflict>, String> {
+    let profile = state.profile();
+    Ok(sync::sync_conflicts(&profile.db)?)
+}
+
+#[tauri::command]
+fn dismiss_sync_conflict(state: State<'_, AppState>, id: String) -> Result<(), String> {
+    let profile = state.profile();
+    Ok(sync::dismiss_conflict(&profile.db, &id)?)
+}
 
-        // Backup current one locally
-        if metadata_path.exists() {
-            let backup_local = app_dir.join("metadata.json.old");
-            let _ = tokio::fs::rename(&metadata_path, &backup_local).await;
+async fn run_metadata_sync(handle: tauri::AppHandle) {
+    let state = handle.state::<AppState>();
+    loop {
+        tokio::time::sleep(METADATA_SYNC_INTERVAL).await;
+        let profile = state.profile();
+        let Ok(storages) = storages(&profile).await else {
+            continue;
+        };
+        let storage = storages.saved_messages();
+        match sync::sync_metadata(&profile.db, storage).await {
+            Ok(report) => {
+                if report.received > 0 {
+                    let _ = handle.emit("metadata-changed", ());
+                }
+                if !report.conflicts.is_empty() {
+                    let _ = handle.emit("sync-conflicts", &report.conflicts);
+                }
+            }
+            Err(sync::SyncError::Db(DbError::Locked)) => {}
+            Err(e) => eprintln!("Metadata sync failed: {}", e),
         }
+    }
+}
 
-        // Fix: Use media() instead of message for download
-        let media = msg.media().ok_or("No media in backup message")?;
-        let downloadable = Downloadable::Media(media);
+#[tauri::command]
+fn get_storage_targets(state: State<'_, AppState>) -> Result<StorageTargets, String> {
+    let profile = state.profile();
+    Ok(StorageTargets::load(&profile.db)?)
+}
 
-        let _ = client
-            .download_media(&downloadable, &metadata_path)
-            .await
-            .map_err(|e| e.to_string())?;
+// Channels the user created, to pick an existing one as a storage target.
+#[tauri::command]
+async fn list_owned_channels(state: State<'_, AppState>) -> Result<Vec<StorageChannel>, String> {
+    let profile = state.profile();
+    let client = profile
+        .client
+        .lock()
+        .await
+        .as_ref()
+        .ok_or("Not logged in")?
+        .clone();
+    Ok(TelegramBackend::owned_channels(&client, &profile.budget).await?)
+}
 
-        // 3. Reload DB (Hot Reload)
-        state.db.reload();
+// Creates a private channel and adds it to the storage targets. New uploads go
+// there when `upload_to` is set.
+#[tauri::command]
+async fn create_storage_channel(
+    state: State<'_, AppState>,
+    title: Option<String>,
+    upload_to: Option<bool>,
+) -> Result<StorageTargets, String> {
+    let profile = state.profile();
+    let client = profile
+        .client
+        .lock()
+        .await
+        .as_ref()
+        .ok_or("Not logged in")?
+        .clone();
+    let title = title
+        .filter(|t| !t.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_CHANNEL_TITLE.to_string());
+    let channel = TelegramBackend::create_channel(&client, &profile.budget, &title).await?;
+    println!("Created storage channel {} ({})", channel.title, channel.id);
+
+    let mut targets = StorageTargets::load(&profile.db)?;
+    if upload_to.unwrap_or(false) {
+        targets.upload_to = Some(channel.id);
+    }
+    targets.add_channel(channel);
+    targets.save(&profile.db)?;
+    Ok(targets)
+}
 
-        Ok("Backup restored successfully. Your dashboard will refresh.".to_string())
-    } else {
-        Err("No backup found in Saved Messages.".to_string())
+// Adds channel `id`, one of list_owned_channels, to the storage targets. Looked up
+// again here: its access hash does not survive the trip through JavaScript numbers.
+#[tauri::command]
+async fn add_storage_channel(
+    state: State<'_, AppState>,
+    id: i64,
+) -> Result<StorageTargets, String> {
+    let profile = state.profile();
+    let client = profile
+        .client
+        .lock()
+        .await
+        .as_ref()
+        .ok_or("Not logged in")?
+        .clone();
+    let channel = TelegramBackend::owned_channels(&client, &profile.budget)
+        .await?
+        .into_iter()
+        .find(|c| c.id == id)
+        .ok_or(StorageError::UnknownChat(id))?;
+    let mut targets = StorageTargets::load(&profile.db)?;
+    targets.add_channel(channel);
+    targets.save(&profile.db)?;
+    Ok(targets)
+}
+
+// Only a channel no file or upload is stored in can go; the channel itself stays
+// on Telegram.
+#[tauri::command]
+fn remove_storage_channel(state: State<'_, AppState>, id: i64) -> Result<StorageTargets, String> {
+    let profile = state.profile();
+    let in_use = profile
+        .db
+        .referenced_messages()?
+        .into_iter()
+        .chain(
+            profile
+                .uploads
+                .jobs()?
+                .iter()
+                .flat_map(|job| job.sent_messages()),
+        )
+        .any(|m| m.chat_id == Some(id));
+    if in_use {
+        return Err("Files are still stored in this channel.".to_string());
+    }
+    let mut targets = StorageTargets::load(&profile.db)?;
+    if !targets.remove_channel(id) {
+        return Err(StorageError::UnknownChat(id).into());
     }
+    targets.save(&profile.db)?;
+    Ok(targets)
 }
 
+// Where new uploads go: a storage channel, or Saved Messages for `None`. Files
+// already stored stay where they are.
 #[tauri::command]
-async fn sync_files(state: State<'_, AppState>) -> Result<String, String> {
-    println!("Syncing files with Telegram...");
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
+fn set_upload_target(
+    state: State<'_, AppState>,
+    chat_id: Option<i64>,
+) -> Result<StorageTargets, String> {
+    let profile = state.profile();
+    let mut targets = StorageTargets::load(&profile.db)?;
+    if let Some(id) = chat_id {
+        if !targets.channels.iter().any(|c| c.id == id) {
+            return Err(StorageError::UnknownChat(id).into());
+        }
+    }
+    targets.upload_to = chat_id;
+    targets.save(&profile.db)?;
+    Ok(targets)
+}
 
-    let me = client.get_me().await.map_err(|e| e.to_string())?;
+// Rebuilds the whole library from the manifests in the captions of Saved Messages
+// and the storage channels, for when both the database and its backups are lost.
+// Replaces what is there now, after keeping a copy next to it as restore_metadata
+// does.
+#[tauri::command]
+async fn rebuild_from_telegram(state: State<'_, AppState>) -> Result<String, String> {
+    let profile = state.profile();
+    println!("Rebuilding library from Telegram...");
+    let storages = storages(&profile).await?;
+
+    let mut chats = Vec::new();
+    for (chat_id, storage) in storages.chats() {
+        let mut blobs = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = storage.history(offset, 100).await?;
+            blobs.extend(page.blobs);
+            match page.next_offset {
+                Some(next) => offset = next,
+                None => break,
+            }
+        }
+        chats.push((chat_id, blobs));
+    }
 
-    // 1. Get all local files
-    let all_files = state.db.get_all_files();
-    if all_files.is_empty() {
-        return Ok("No files to sync.".to_string());
+    let rebuilt = manifest::rebuild(&chats, unlocked_vault(&profile).as_ref());
+    if rebuilt.files.is_empty() {
+        return Err("No files with a manifest found on Telegram.".to_string());
     }
 
-    println!("Checking {} files...", all_files.len());
+    profile
+        .db
+        .export_snapshot(&profile.dir.join("metadata.json.old"))?;
 
-    // 2. Batch check availability
-    let mut missing_ids = Vec::new();
-    let batch_size = 50;
+    let (files, folders) = (rebuilt.files.len(), rebuilt.folders.len());
+    profile
+        .db
+        .replace_contents(rebuilt.folders, rebuilt.files)?;
 
-    for chunk in all_files.chunks(batch_size) {
-        let message_ids: Vec<i32> = chunk.iter().map(|f| f.message_id).collect();
+    println!(
+        "Rebuilt {} files in {} folders, skipped {} messages",
+        files, folders, rebuilt.skipped
+    );
+    Ok(format!(
+        "Rebuilt {} files in {} folders from Telegram ({} messages could not be used).",
+        files, folders, rebuilt.skipped
+    ))
+}
 
-        // get_messages_by_id returns specific messages.
-        // If a message is deleted, it might return None or an empty message depending on API.
-        // grammers: returns Vec<Option<Message>> usually, or filtered list?
-        // Let's check the return type docs/usage.
-        // In preview_file we used get_messages_by_id and it returned a list.
-        // If I request IDs [1, 2, 3] and 2 is deleted, does it return [Some, None, Some] or [Msg1, Msg3]?
-        // grammers-client `get_messages_by_id` returns `Result<Vec<Option<Message>>>`.
+#[tauri::command]
+async fn sync_files(state: State<'_, AppState>) -> Result<String, String> {
+    let profile = state.profile();
+    println!("Syncing files with Telegram...");
+    let storages = storages(&profile).await?;
 
-        let messages = client
-            .get_messages_by_id(&me, &message_ids)
-            .await
-            .map_err(|e| e.to_string())?;
+    // Lost copies are sent again from an intact one; only files with none left go
+    let report = replicas::repair(&profile.db, &storages).await?;
+    if report.checked == 0 {
+        return Ok("No files to sync.".to_string());
+    }
+    for (id, e) in &report.failed {
+        eprintln!("Could not repair {}: {}", id, e);
+    }
+    println!(
+        "Checked {} files: {} copies made, {} promoted, {} lost, {} failed",
+        report.checked,
+        report.copies_made,
+        report.promoted.len(),
+        report.lost.len(),
+        report.failed.len()
+    );
 
-        // We iterate input IDs and result messages in parallel?
-        // Docs say: "The returned list will have the same length as the input IDs."
-
-        for (i, msg_opt) in messages.iter().enumerate() {
-            let is_missing = match msg_opt {
-                Some(msg) => {
-                    // Check if empty or media missing?
-                    // Safe to assume if it exists it's fine, unless "Empty" type.
-                    // For now, if Some(msg), check if it has media if we expect it.
-                    // But just existence logic:
-                    msg.media().is_none() // If no media, handle as "content deleted" for our file app?
-                                          // Actually, text messages don't have media. Our files MUST have media.
-                                          // So if media is missing, it's effectively a broken link for us.
-                }
-                None => true,
-            };
+    let mut summary = Vec::new();
+    if report.copies_made > 0 {
+        summary.push(format!("Made {} new copies.", report.copies_made));
+    }
+    if !report.lost.is_empty() {
+        summary.push(format!("Removed {} deleted files.", report.lost.len()));
+    }
+    if !report.failed.is_empty() {
+        summary.push(format!(
+            "{} files could not be repaired yet.",
+            report.failed.len()
+        ));
+    }
+    if summary.is_empty() {
+        Ok("Sync complete. All files are up to date.".to_string())
+    } else {
+        Ok(format!("Sync complete. {}", summary.join(" ")))
+    }
+}
 
-            if is_missing {
-                missing_ids.push(chunk[i].id.clone());
-            }
+// Walks the whole of every storage chat and compares it with the metadata both ways,
+// one report per chat. Unless this is a dry run, orphans are then handled as
+// `orphans` says: the ones in `selected`, picked from a dry run's report, or all of
+// them. Saved Messages also holds the user's own photos and documents, so without a
+// pick only messages with a Paperfold manifest are deleted. Files whose messages are
+// gone are only reported; sync_files removes them.
+#[tauri::command]
+async fn reconcile_storage(
+    state: State<'_, AppState>,
+    orphans: OrphanAction,
+    dry_run: bool,
+    selected: Option<Vec<MessageRef>>,
+) -> Result<Vec<Reconciliation>, String> {
+    let profile = state.profile();
+    println!("Reconciling metadata with Telegram...");
+    let storages = storages(&profile).await?;
+    let pending: Vec<MessageRef> = profile
+        .uploads
+        .jobs()?
+        .iter()
+        .flat_map(|job| job.sent_messages())
+        .collect();
+    let files = profile.db.get_all_files()?;
+
+    let mut reports = Vec::new();
+    for (chat_id, storage) in storages.chats() {
+        let remote = storage.list(usize::MAX).await?;
+        let report = reconcile::reconcile(chat_id, &files, &pending, &remote);
+        println!(
+            "{}: {} orphaned messages ({} bytes), {} entries with missing messages ({} bytes)",
+            chat_id.map_or("Saved Messages".to_string(), |id| format!("Channel {}", id)),
+            report.orphans.len(),
+            report.orphan_bytes,
+            report.missing.len(),
+            report.missing_bytes
+        );
+        let picked: Vec<storage::RemoteBlob> = report
+            .orphans
+            .iter()
+            .filter(|blob| match &selected {
+                Some(selected) => selected.contains(&MessageRef {
+                    chat_id,
+                    message_id: blob.message_id,
+                }),
+                None => {
+                    orphans != OrphanAction::Delete || manifest::Manifest::is_tagged(&blob.caption)
+                }
+            })
+            .cloned()
+            .collect();
+        if !dry_run && !picked.is_empty() {
+            handle_orphans(&profile, storage, chat_id, &picked, orphans).await?;
         }
+        reports.push(report);
     }
+    Ok(reports)
+}
 
-    let removed_count = missing_ids.len();
-    if removed_count > 0 {
-        println!("Found {} missing files. Removing...", removed_count);
-        state.db.delete_files_by_ids(&missing_ids);
-        Ok(format!(
-            "Sync complete. Removed {} deleted files.",
-            removed_count
-        ))
-    } else {
-        Ok("Sync complete. All files are up to date.".to_string())
+async fn handle_orphans(
+    profile: &ProfileState,
+    storage: &dyn StorageBackend,
+    chat_id: Option<i64>,
+    orphans: &[storage::RemoteBlob],
+    action: OrphanAction,
+) -> Result<(), String> {
+    match action {
+        OrphanAction::Keep => {}
+        OrphanAction::Adopt => {
+            let folder_id = match profile
+                .db
+                .get_all_folders()?
+                .into_iter()
+                .find(|f| f.name == RECOVERED_FOLDER && f.parent_id.is_none() && !f.trashed)
+            {
+                Some(f) => f.id,
+                None => profile.db.create_folder(RECOVERED_FOLDER, None)?,
+            };
+            for file in reconcile::recovered_files(orphans) {
+                profile.db.insert_file(db::FileMetadata {
+                    chat_id,
+                    ..db::new_file(
+                        Some(folder_id.clone()),
+                        file.name,
+                        file.size,
+                        file.mime_type,
+                        file.segments,
+                    )
+                })?;
+            }
+            println!("Adopted {} orphaned messages", orphans.len());
+        }
+        OrphanAction::Delete => {
+            // A file may have been added for one of them since the scan
+            let referenced: std::collections::HashSet<MessageRef> =
+                profile.db.referenced_messages()?.into_iter().collect();
+            let message_ids: Vec<i32> = orphans
+                .iter()
+                .map(|blob| blob.message_id)
+                .filter(|id| {
+                    !referenced.contains(&MessageRef {
+                        chat_id,
+                        message_id: *id,
+                    })
+                })
+                .collect();
+            storage.delete(&message_ids).await?;
+            println!("Deleted {} orphaned messages", message_ids.len());
+        }
     }
+    Ok(())
 }
 
 #[tauri::command]
@@ -1259,176 +1858,127 @@ async fn download_folder(
     base_path: String,
     window: Window,
 ) -> Result<String, String> {
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
+    let profile = state.profile();
+    let storages = storages(&profile).await?;
 
-    // Drop guard so we can await async calls
-    drop(client_guard);
-
-    let all_files = state.db.get_all_files();
-    let all_folders = state.db.get_all_folders();
+    let root_folder = profile.db.get_folder_by_id(&folder_id)?;
+    let root_fs_path = std::path::Path::new(&base_path).join(&root_folder.name);
 
-    // Build Maps for O(1) lookup
-    let mut file_map: HashMap<String, Vec<db::FileMetadata>> = HashMap::new();
-    for f in all_files {
-        file_map
-            .entry(f.folder_id.clone().unwrap_or_default())
-            .or_default()
-            .push(f);
-    }
+    // Everything below the folder, placed by its path relative to it
+    let subtree = profile.db.subtree(&folder_id)?;
 
-    let mut folder_map: HashMap<String, Vec<db::Folder>> = HashMap::new();
-    for f in &all_folders {
-        folder_map
-            .entry(f.parent_id.clone().unwrap_or_default())
-            .or_default()
-            .push(f.clone());
+    // 1. Create directories
+    tokio::fs::create_dir_all(&root_fs_path)
+        .await
+        .map_err(|e| e.to_string())?;
+    for (path, _) in &subtree.folders {
+        tokio::fs::create_dir_all(root_fs_path.join(path))
+            .await
+            .map_err(|e| e.to_string())?;
     }
 
-    let root_folder = all_folders
-        .iter()
-        .find(|f| f.id == folder_id)
-        .ok_or("Folder not found")?;
-
-    // Queue: (folder_id, fs_path_to_create)
-    let mut queue = VecDeque::new();
-    let root_fs_path = std::path::Path::new(&base_path).join(&root_folder.name);
-    queue.push_back((folder_id.clone(), root_fs_path));
-
-    while let Some((curr_id, curr_path)) = queue.pop_front() {
-        // 1. Create directory
-        tokio::fs::create_dir_all(&curr_path)
+    // 2. Download files, a few at a time. Each comes down next to its final name and
+    // is renamed once complete, so running this again resumes what was cut short.
+    const PARALLEL_FILES: usize = 3;
+    let slots = Arc::new(tokio::sync::Semaphore::new(PARALLEL_FILES));
+    let mut downloads = tokio::task::JoinSet::new();
+    let vault = unlocked_vault(&profile);
+    for (path, f) in subtree.files {
+        let final_path = root_fs_path.join(path);
+        let permit = slots
+            .clone()
+            .acquire_owned()
             .await
             .map_err(|e| e.to_string())?;
 
-        // 2. Download files in this folder
-        if let Some(files) = file_map.get(&curr_id) {
-            for f in files {
-                // Fetch valid message/media
-                let chat = client.get_me().await.map_err(|e| e.to_string())?;
-                let messages = client
-                    .get_messages_by_id(&chat, &[f.message_id])
-                    .await
-                    .map_err(|e| e.to_string())?;
-
-                if let Some(Some(msg)) = messages.first() {
-                    if let Some(media) = msg.media() {
-                        let downloadable = match media {
-                            Media::Photo(p) => Downloadable::Media(Media::Photo(p)),
-                            Media::Document(d) => Downloadable::Media(Media::Document(d)),
-                            _ => continue,
-                        };
-
-                        let final_path = curr_path.join(&f.name);
-                        let part_path = curr_path.join(format!("{}.part", f.name));
-
-                        // Emit progress
-                        let _ = window.emit(
-                            "download-progress",
-                            serde_json::json!({
-                                "id": f.id,
-                                "progress": 50.0, // Indeterminate / In Progress
-                                "status": format!("Downloading {}...", f.name)
-                            }),
-                        );
-
-                        match client
-                            .download_media(&downloadable, part_path.clone())
-                            .await
-                        {
-                            Ok(_) => {
-                                // Rename part to final
-                                let _ = std::fs::rename(&part_path, &final_path);
-                            }
-                            Err(e) => {
-                                // Clean up part file if failed
-                                let _ = std::fs::remove_file(&part_path);
-                                return Err(e.to_string());
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        // Emit progress
+        let _ = window.emit(
+            "download-progress",
+            serde_json::json!({
+                "id": f.id,
+                "progress": 50.0, // Indeterminate / In Progress
+                "status": format!("Downloading {}...", f.name)
+            }),
+        );
 
-        // 3. Queue subfolders
-        if let Some(subs) = folder_map.get(&curr_id) {
-            for sub in subs {
-                queue.push_back((sub.id.clone(), curr_path.join(&sub.name)));
+        let (storages, vault) = (storages.clone(), vault.clone());
+        downloads.spawn(async move {
+            let _permit = permit;
+            let (storage, segments) =
+                replicas::readable_copy(&storages, &f).map_err(|e| e.to_string())?;
+            let result = storage::download_file(
+                storage,
+                &segments,
+                f.encryption.as_ref(),
+                vault.as_ref(),
+                f.sha256.as_deref(),
+                &final_path,
+                None,
+            )
+            .await;
+            match result {
+                // Message is gone from Telegram, nothing to download
+                Ok(()) | Err(StorageError::NotFound(_)) => Ok(()),
+                Err(e) => Err(e.to_string()),
             }
-        }
+        });
+    }
+    while let Some(result) = downloads.join_next().await {
+        result.map_err(|e| e.to_string())??;
     }
 
     Ok("Folder downloaded successfully.".to_string())
 }
 
 #[tauri::command]
+// Returns the files left out of the archives, each as "path: reason".
 async fn download_all(
     target_dir: String,
     state: State<'_, AppState>,
     window: Window,
-) -> Result<(), String> {
-    let mut client_guard = state.client.lock().await;
-    let client = client_guard.as_mut().ok_or("Not logged in")?.clone();
-    drop(client_guard);
+) -> Result<Vec<String>, String> {
+    let profile = state.profile();
+    let storages = storages(&profile).await?;
 
-    let all_files = state.db.get_all_files();
-    let all_folders = state.db.get_all_folders();
-
-    // Mapping ID -> Path
-    let mut folder_map = HashMap::new();
-    for f in &all_folders {
-        folder_map.insert(f.id.clone(), f.clone());
-    }
+    let all_files = profile.db.get_all_files()?;
 
     struct FileEntry {
-        // id field removed as unused
+        id: String,
         name: String,
         relative_path: std::path::PathBuf,
         size: i64,
-        message_id: i32,
+        chat_id: Option<i64>,
+        segments: Vec<Segment>,
+        encryption: Option<paperfold_core::EncryptionInfo>,
+        sha256: Option<String>,
     }
 
     let mut entries = Vec::new();
     for file in all_files {
+        // Trashing a folder trashes everything inside it, so this also skips files
+        // in trashed folders
         if file.trashed {
             continue;
         }
 
-        // Check if any parent is trashed
-        let mut valid = true;
-        let mut temp_parts = Vec::new();
-        let mut curr = file.folder_id.clone();
-
-        while let Some(pid) = curr {
-            if let Some(f) = folder_map.get(&pid) {
-                if f.trashed {
-                    valid = false;
-                    break;
-                }
-                temp_parts.push(f.name.clone());
-                curr = f.parent_id.clone();
-            } else {
-                curr = None;
-            }
-        }
-
-        if valid {
-            let mut path = std::path::PathBuf::new();
-            temp_parts.reverse();
-            for p in temp_parts {
-                path.push(p);
-            }
-            path.push(&file.name);
-
-            entries.push(FileEntry {
-                // id: file.id,
-                name: file.name,
-                relative_path: path,
-                size: file.size,
-                message_id: file.message_id,
-            });
-        }
+        let path = profile.db.path_of(&file.id)?;
+        // The first copy this device can read, if any
+        let copy = file
+            .copies()
+            .into_iter()
+            .find(|c| storages.get(c.chat_id).is_ok())
+            .unwrap_or_else(|| file.copies().remove(0));
+        let segments = file.copy_segments(&copy);
+        entries.push(FileEntry {
+            id: file.id,
+            name: file.name,
+            relative_path: std::path::PathBuf::from(path.trim_start_matches('/')),
+            size: file.size,
+            chat_id: copy.chat_id,
+            segments,
+            encryption: file.encryption,
+            sha256: file.sha256,
+        });
     }
 
     // Packet Logic (Limit 1.9GB)
@@ -1452,6 +2002,8 @@ async fn download_all(
     }
 
     // Zipping
+    let vault = unlocked_vault(&profile);
+    let mut failed = Vec::new();
     let total_packets = packets.len();
     for (i, packet) in packets.into_iter().enumerate() {
         let zip_name = if total_packets > 1 {
@@ -1482,49 +2034,48 @@ async fn download_all(
                 }),
             );
 
-            let temp_name = format!("temp_dl_{}", uuid::Uuid::new_v4());
+            // Named after the file, so running the export again resumes its download
+            let temp_name = format!("temp_dl_{}", entry.id);
             let temp_path = std::env::temp_dir().join(&temp_name);
-            let temp_path_str = temp_path.to_string_lossy().to_string();
-
-            // Fetch message/media
-            if let Ok(chat) = client.get_me().await {
-                if let Ok(messages) = client.get_messages_by_id(&chat, &[entry.message_id]).await {
-                    if let Some(Some(msg)) = messages.first() {
-                        if let Some(media) = msg.media() {
-                            let downloadable = match media {
-                                Media::Photo(p) => Downloadable::Media(Media::Photo(p)),
-                                Media::Document(d) => Downloadable::Media(Media::Document(d)),
-                                _ => continue,
-                            };
-
-                            if client
-                                .download_media(&downloadable, &temp_path_str)
-                                .await
-                                .is_ok()
-                            {
-                                if let Ok(content) = std::fs::read(&temp_path) {
-                                    let final_content = content;
-                                    // IDE SYNC: Removed Vault Logic
-
-                                    let path_str =
-                                        entry.relative_path.to_string_lossy().to_string();
-                                    let _ = zip.start_file(path_str, options);
-                                    let _ = zip.write_all(&final_content);
-                                }
-                                let _ = std::fs::remove_file(&temp_path);
-                            }
-                        }
-                    }
+
+            // Files that cannot be fetched (or decrypted) are left out of the archive
+            // and reported. Failing to write the archive fails the export.
+            let path_str = entry.relative_path.to_string_lossy().to_string();
+            let fetched = match storages.get(entry.chat_id) {
+                Ok(storage) => storage::download_file(
+                    storage,
+                    &entry.segments,
+                    entry.encryption.as_ref(),
+                    vault.as_ref(),
+                    entry.sha256.as_deref(),
+                    &temp_path,
+                    None,
+                )
+                .await
+                .and_then(|_| Ok(std::fs::File::open(&temp_path)?)),
+                Err(e) => Err(e),
+            };
+            match fetched {
+                Ok(mut content) => {
+                    // Stream into the archive; joined segments can be larger than memory
+                    zip.start_file(path_str, options.large_file(entry.size > u32::MAX as i64))
+                        .map_err(|e| e.to_string())?;
+                    std::io::copy(&mut content, &mut zip).map_err(|e| e.to_string())?;
+                }
+                Err(e) => {
+                    eprintln!("Left {} out of the export: {}", path_str, e);
+                    failed.push(format!("{}: {}", path_str, e));
                 }
             }
+            let _ = std::fs::remove_file(&temp_path);
         }
-        let _ = zip.finish().map_err(|e| e.to_string())?;
+        zip.finish().map_err(|e| e.to_string())?;
 
         // Rename part to final
         std::fs::rename(&part_path, &final_path).map_err(|e| e.to_string())?;
     }
 
-    Ok(())
+    Ok(failed)
 }
 
 fn get_pid_file_path(app_handle: &tauri::AppHandle) -> std::path::PathBuf {
@@ -1545,6 +2096,7 @@ fn is_process_running(pid: u32) -> bool {
 
 #[tauri::command]
 async fn start_webdav(state: State<'_, AppState>) -> Result<String, String> {
+    let profile = state.profile();
     let pid_path = get_pid_file_path(&state.app_handle);
 
     // Check if already running via PID file
@@ -1567,10 +2119,34 @@ async fn start_webdav(state: State<'_, AppState>) -> Result<String, String> {
         .sidecar("paperfold-daemon")
         .map_err(|e| format!("Failed to create sidecar command: {}", e))?;
 
-    let (mut _rx, child) = sidecar_command
+    // Hand the unlocked vault keys over on stdin, never on the command line: one
+    // "<profile id> <key>" line each, then an empty line. The daemon unlocks the
+    // vaults of the other profiles on its own where it can.
+    let mut vaults = state.vaults.lock().unwrap().clone();
+    if let Some(vault) = unlocked_vault(&profile) {
+        vaults.insert(profile.id.clone(), vault);
+    }
+    let sidecar_command = if vaults.is_empty() {
+        sidecar_command
+    } else {
+        sidecar_command.args(["--vault-key-stdin"])
+    };
+
+    let (mut _rx, mut child) = sidecar_command
         .spawn()
         .map_err(|e| format!("Failed to spawn sidecar: {}", e))?;
 
+    if !vaults.is_empty() {
+        let mut keys: String = vaults
+            .iter()
+            .map(|(id, vault)| format!("{} {}\n", id, vault.export_key()))
+            .collect();
+        keys.push('\n');
+        child
+            .write(keys.as_bytes())
+            .map_err(|e| format!("Failed to pass the vault keys to the sidecar: {}", e))?;
+    }
+
     // Get PID and save it
     let pid = child.pid();
     std::fs::write(&pid_path, pid.to_string())
@@ -1624,27 +2200,31 @@ async fn stop_webdav(state: State<'_, AppState>) -> Result<String, String> {
 
 #[tauri::command]
 async fn get_webdav_status(state: State<'_, AppState>) -> Result<bool, String> {
-    let pid_path = get_pid_file_path(&state.app_handle);
+    Ok(webdav_running(&state.app_handle))
+}
+
+fn webdav_running(app_handle: &tauri::AppHandle) -> bool {
+    let pid_path = get_pid_file_path(app_handle);
     if !pid_path.exists() {
-        return Ok(false);
+        return false;
     }
 
     let pid_str = match std::fs::read_to_string(&pid_path) {
         Ok(s) => s,
-        Err(_) => return Ok(false),
+        Err(_) => return false,
     };
 
     let pid = match pid_str.trim().parse::<u32>() {
         Ok(p) => p,
-        Err(_) => return Ok(false),
+        Err(_) => return false,
     };
 
     if is_process_running(pid) {
-        Ok(true)
+        true
     } else {
         // Stale
         let _ = std::fs::remove_file(&pid_path);
-        Ok(false)
+        false
     }
 }
 
@@ -1655,18 +2235,41 @@ pub fn run() {
             let _app_handle = app.handle();
             let app_dir = app.path().app_data_dir().unwrap();
             std::fs::create_dir_all(&app_dir).unwrap();
-
-            let db = Arc::new(Database::new(app_dir.to_str().unwrap()));
+            let profiles = Profiles::load(&app_dir)?;
+            let profile = ProfileState::open(&app_dir, &profiles.active)?;
 
             app.manage(AppState {
                 app_handle: app.handle().clone(),
-                client: Arc::new(AsyncMutex::new(None)), // Lazy init
-                phone_token: Mutex::new(None),
-                password_token: Mutex::new(None),
-                db,
+                app_dir,
+                profile: std::sync::RwLock::new(Arc::new(profile)),
+                vaults: Mutex::new(HashMap::new()),
+                retired: Mutex::new(Vec::new()),
                 // webdav_process removed
             });
 
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(run_uploads(handle));
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(run_scheduled_backups(handle));
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(run_metadata_sync(handle));
+
+            // Pick up changes made by the WebDAV daemon, which shares the database
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                let state = handle.state::<AppState>();
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+                    match state.profile().db.refresh() {
+                        Ok(true) => {
+                            let _ = handle.emit("metadata-changed", ());
+                        }
+                        Ok(false) | Err(DbError::Locked) => {}
+                        Err(e) => eprintln!("Failed to check metadata for changes: {}", e),
+                    }
+                }
+            });
+
             Ok(())
         })
         .plugin(tauri_plugin_dialog::init())
@@ -1681,15 +2284,30 @@ pub fn run() {
             logout,
             fetch_files,
             create_folder,
-            upload_file,
+            enqueue_upload,
+            list_uploads,
+            pause_upload,
+            resume_upload,
+            set_upload_priority,
+            cancel_upload,
             download_file_core,
             delete_item,
             delete_item_permanently,
             trash_item,
             restore_item,
+            find_duplicates,
+            trash_duplicates,
+            list_versions,
+            download_version,
+            restore_version,
+            get_version_retention,
+            set_version_retention,
             fetch_trash,
             empty_trash,
             rename_item,
+            move_item,
+            get_conflict_policy,
+            set_conflict_policy,
             update_folder_metadata,
             get_folder_stats,
             get_storage_usage,
@@ -1699,16 +2317,41 @@ pub fn run() {
             fetch_starred,
             search_items,
             backup_metadata,
+            list_backups,
+            diff_backup,
             restore_metadata,
+            prune_backups,
+            get_backup_policy,
+            set_backup_policy,
+            rebuild_from_telegram,
+            sync_metadata,
+            list_sync_conflicts,
+            dismiss_sync_conflict,
+            get_storage_targets,
+            list_owned_channels,
+            create_storage_channel,
+            add_storage_channel,
+            remove_storage_channel,
+            set_upload_target,
             sync_files,
+            reconcile_storage,
             sync_saved_messages,
             download_folder,
             download_all,
             start_webdav,
             stop_webdav,
-            get_webdav_status
+            get_webdav_status,
+            set_folder_encryption,
+            set_folder_replicas,
+            get_vault_status,
+            unlock_vault,
+            change_vault_passphrase,
+            lock_vault,
+            list_profiles,
+            add_profile,
+            switch_profile,
+            remove_profile
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
-
@@ -1,33 +1,61 @@
 import { useState, useEffect } from 'react';
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 import './index.css';
 import Auth from './components/Auth';
 import Dashboard from './components/Dashboard';
+import Unlock from './components/Unlock';
 
 function App() {
   const [isAuthenticated, setIsAuthenticated] = useState(false);
+  const [isLocked, setIsLocked] = useState(false);
   const [loading, setLoading] = useState(true);
 
   useEffect(() => {
     async function checkAuth() {
+      setIsAuthenticated(false);
+      setIsLocked(false);
       try {
         const isAuth = await invoke<boolean>('check_auth');
         if (isAuth) {
           setIsAuthenticated(true);
         }
       } catch (e) {
-        console.error("Auth check failed", e);
+        // The vault needs its passphrase before the session can be read
+        if (e === 'LOCKED') {
+          setIsLocked(true);
+        } else {
+          console.error("Auth check failed", e);
+        }
       } finally {
         setLoading(false);
       }
     }
     checkAuth();
+    // Every profile has its own session and vault
+    const unlistenPromise = listen('profile-changed', () => {
+      setLoading(true);
+      checkAuth();
+    });
+    return () => {
+      unlistenPromise.then(unlisten => unlisten());
+    };
   }, []);
 
+  const handleUnlock = async (passphrase: string) => {
+    const isAuth = await invoke<boolean>('check_auth', { passphrase });
+    setIsLocked(false);
+    setIsAuthenticated(isAuth);
+  };
+
   if (loading) {
     return <div className="min-h-screen flex items-center justify-center bg-background text-foreground">Loading...</div>;
   }
 
+  if (isLocked) {
+    return <Unlock onUnlock={handleUnlock} />;
+  }
+
   return (
     <div className="min-h-screen bg-background text-foreground font-sans">
       {isAuthenticated ? (
@@ -35,6 +35,9 @@ import {
     Smile,
     Grid,
     Server,
+    Pause,
+    Play,
+    ArrowUpToLine,
 } from 'lucide-react';
 import FileCard, { FileItem } from './FileCard';
 import { invoke } from '@tauri-apps/api/core';
@@ -46,7 +49,7 @@ import { check } from '@tauri-apps/plugin-updater';
 import { relaunch } from '@tauri-apps/plugin-process';
 // We will add opener import after we verify package.json
 // For now, let's just wait.
-import { FileMetadata, Folder } from '../types';
+import { BackupInfo, FileMetadata, Folder, Profiles, UploadJob } from '../types';
 import { motion, AnimatePresence } from 'framer-motion';
 
 interface UserProfile {
@@ -57,6 +60,34 @@ interface UserProfile {
     phone?: string;
 }
 
+interface UploadEntry {
+    id: string;
+    name: string;
+    status: 'pending' | 'uploading' | 'paused' | 'completed' | 'error';
+    progress: number;
+    priority: number;
+    targetFolderId: string | null;
+    error?: string | null;
+}
+
+function toUploadEntry(job: UploadJob): UploadEntry {
+    const status = {
+        queued: 'pending',
+        running: 'uploading',
+        paused: 'paused',
+        failed: 'error',
+    }[job.state] as UploadEntry['status'];
+    return {
+        id: job.id,
+        name: job.name,
+        status,
+        progress: job.total > 0 ? Math.min(100, job.uploaded / job.total * 100) : 0,
+        priority: job.priority,
+        targetFolderId: job.folder_id,
+        error: job.error,
+    };
+}
+
 export default function Dashboard({ onLogout }: { onLogout: () => void }) {
     const [view, setView] = useState<'grid' | 'list'>('grid');
     const [currentFolder, setCurrentFolder] = useState<string | null>(null);
@@ -107,6 +138,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
     const [storageUsage, setStorageUsage] = useState<string>("0 KB");
     const [isUploadProgressMinimized, setIsUploadProgressMinimized] = useState(false);
     const [user, setUser] = useState<UserProfile | null>(null);
+    const [profileId, setProfileId] = useState<string>('default');
 
     // Search State
     const [searchQuery, setSearchQuery] = useState("");
@@ -206,78 +238,85 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
         { name: 'Waves', value: 'repeating-radial-gradient(circle at 0 0, transparent 0, rgba(255,255,255,0.1) 10px), repeating-linear-gradient(rgba(255,255,255,0.1), rgba(255,255,255,0.1))' }
     ];
 
-    const [uploadQueue, setUploadQueue] = useState<{ path: string, name: string, status: 'pending' | 'uploading' | 'completed' | 'error', progress: number, targetFolderId?: string | null }[]>([]);
+    // Jobs live in the backend queue and survive restarts; finished ones stay listed here until dismissed
+    const [uploadQueue, setUploadQueue] = useState<UploadEntry[]>([]);
 
     const [folderStats, setFolderStats] = useState<Record<string, { size: number, count: number }>>({});
     const [isRestoreConfirmOpen, setIsRestoreConfirmOpen] = useState(false);
 
-    // Real Progress Listener
     useEffect(() => {
-        let unlisten: () => void;
-        async function setupListener() {
-            unlisten = await listen<{ path: string, progress: number }>('upload-progress', (event) => {
-                setUploadQueue(prev => prev.map(item => {
-                    if (item.path === event.payload.path && item.status === 'uploading') {
-                        return { ...item, progress: event.payload.progress };
-                    }
-                    return item;
-                }));
+        invoke<UploadJob[]>('list_uploads')
+            .then(jobs => setUploadQueue(jobs.map(toUploadEntry)))
+            .catch(console.error);
+
+        let unlistenProgress: (() => void) | undefined;
+        let unlistenFinished: (() => void) | undefined;
+        let isMounted = true;
+        async function setupListeners() {
+            const uProgress = await listen<UploadJob>('upload-progress', (event) => {
+                const entry = toUploadEntry(event.payload);
+                setUploadQueue(prev => prev.some(item => item.id === entry.id)
+                    ? prev.map(item => item.id === entry.id ? entry : item)
+                    : [...prev, entry]);
             });
+            const uFinished = await listen<{ id: string, file: FileMetadata }>('upload-finished', (event) => {
+                setUploadQueue(prev => prev.map(item => item.id === event.payload.id ? { ...item, status: 'completed', progress: 100 } : item));
+                setRefresh(prev => prev + 1);
+            });
+            if (isMounted) {
+                unlistenProgress = uProgress;
+                unlistenFinished = uFinished;
+            } else {
+                uProgress();
+                uFinished();
+            }
         }
-        setupListener();
+        setupListeners();
         return () => {
-            if (unlisten) unlisten();
+            isMounted = false;
+            if (unlistenProgress) unlistenProgress();
+            if (unlistenFinished) unlistenFinished();
         };
     }, []);
 
-    useEffect(() => {
-        const processQueue = async () => {
-            // Concurrency Control: Max 3 parallel files
-            const activeuploads = uploadQueue.filter(item => item.status === 'uploading').length;
-            if (activeuploads >= 3) return;
-
-            const pendingItemIndex = uploadQueue.findIndex(item => item.status === 'pending');
-            if (pendingItemIndex === -1) return;
-
-            const item = uploadQueue[pendingItemIndex];
-
-            // Mark as uploading immediately to prevent duplicate triggers
-            setUploadQueue(prev => prev.map((q, i) => i === pendingItemIndex ? { ...q, status: 'uploading', progress: 0 } : q));
-
+    const enqueueUploads = async (paths: string[], targetFolderId: string | null) => {
+        for (const path of paths) {
             try {
-                console.log("Starting upload:", item.path, "Target Folder:", (item as any).targetFolderId);
-                // We use the folder that was active WHEN the file was added. 
-                // Currently, we just use 'currentFolder' from state, which might have changed since the file was added to queue?
-                // The user complained about files appearing in wrong folders.
-                // We should probably store the target folder IN the queue item.
-                // For now, let's stick to the requested parallel change, but note that `currentFolder` here is risky if user navigates away.
-                // BETTER: Use the currentFolder at the time of processing? Or should we have captured it?
-                // Standard behavior: Upload to the folder you are IN when it starts? Or when you added it?
-                // Usually when you added it. 
-                // I'll leave `currentFolder` as is for now but this is a potential bug vector for the visibility issue.
-
-                await invoke('upload_file', {
-                    path: item.path,
-                    folder_id: (item as any).targetFolderId,
-                    folderId: (item as any).targetFolderId
-                });
-
-                // Mark as completed
-                setUploadQueue(prev => prev.map((q, i) => i === pendingItemIndex ? { ...q, status: 'completed', progress: 100 } : q));
-                setRefresh(prev => prev + 1);
+                const job = await invoke<UploadJob>('enqueue_upload', { path, folderId: targetFolderId });
+                setUploadQueue(prev => prev.some(item => item.id === job.id) ? prev : [...prev, toUploadEntry(job)]);
             } catch (e) {
-                console.error("Upload failed for", item.path, e);
-                setUploadQueue(prev => prev.map((q, i) => i === pendingItemIndex ? { ...q, status: 'error', progress: 0 } : q));
-                alert(`Failed to upload ${item.name}: ${e}`);
+                console.error("Failed to queue upload", path, e);
+                alert(`Failed to upload ${path.split(/[/\\]/).pop()}: ${e}`);
             }
-        };
+        }
+    };
 
-        processQueue();
+    const updateUpload = (job: UploadJob) => {
+        setUploadQueue(prev => prev.map(item => item.id === job.id ? toUploadEntry(job) : item));
+    };
 
-    }, [uploadQueue]); // Re-run when queue changes
+    const handlePauseUpload = (id: string) => {
+        invoke<UploadJob>('pause_upload', { id }).then(updateUpload).catch(e => alert(`Failed to pause upload: ${e}`));
+    };
+
+    const handleResumeUpload = (id: string) => {
+        invoke<UploadJob>('resume_upload', { id }).then(updateUpload).catch(e => alert(`Failed to resume upload: ${e}`));
+    };
+
+    const handleCancelUpload = (id: string) => {
+        invoke('cancel_upload', { id })
+            .then(() => setUploadQueue(prev => prev.filter(item => item.id !== id)))
+            .catch(e => alert(`Failed to cancel upload: ${e}`));
+    };
+
+    const handleUploadToTop = (id: string) => {
+        const top = Math.max(0, ...uploadQueue.map(item => item.priority)) + 1;
+        invoke<UploadJob>('set_upload_priority', { id, priority: top }).then(updateUpload).catch(e => alert(`Failed to reorder upload: ${e}`));
+    };
 
     useEffect(() => {
         invoke<UserProfile>('get_current_user').then(setUser).catch(console.error);
+        invoke<Profiles>('list_profiles').then(p => setProfileId(p.active)).catch(console.error);
     }, []);
 
     // Update Page Title
@@ -378,6 +417,16 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
         fetchFiles();
     }, [currentFolder, refresh]);
 
+    // The WebDAV daemon writes to the same database
+    useEffect(() => {
+        const unlistenPromise = listen('metadata-changed', () => {
+            setRefresh(prev => prev + 1);
+        });
+        return () => {
+            unlistenPromise.then(unlisten => unlisten());
+        };
+    }, []);
+
     const [selectedItemIds, setSelectedItemIds] = useState<Set<string>>(new Set());
 
     const handleNavigate = (folderId: string | null, folderName: string) => {
@@ -457,8 +506,8 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
         // Upload queue items are always first and unsorted (or sorted by add time implicit)
         const uploadItems: FileItem[] = uploadQueue
             .filter(q => (q.status === 'pending' || q.status === 'uploading') && q.targetFolderId === currentFolder) // Filter by current folder
-            .map((q, i) => ({
-                id: `upload-${i}`,
+            .map(q => ({
+                id: `upload-${q.id}`,
                 name: q.name,
                 type: 'file' as const,
                 size: q.status === 'uploading' ? 'Uploading...' : 'Queued',
@@ -726,16 +775,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             if (selected) {
                 const paths = Array.isArray(selected) ? selected : [selected];
                 // Add to queue with the CURRENT folder as the target
-                const targetFolderId = currentFolder;
-                const newItems = paths.map(path => ({
-                    path,
-                    name: path.split(/[/\\]/).pop() || 'Unknown File',
-                    status: 'pending' as const,
-                    progress: 0,
-                    targetFolderId: targetFolderId // Store it!
-                }));
-                setUploadQueue(prev => [...prev, ...newItems]);
-                // We don't trigger loading here, queue handles it visible logic
+                await enqueueUploads(paths, currentFolder);
             }
         } catch (e) {
             console.error("Upload selection failed", e);
@@ -964,8 +1004,8 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
 
     const handleMetadataBackup = async () => {
         try {
-            const res = await invoke<string>('backup_metadata');
-            alert(res);
+            const res = await invoke<BackupInfo>('backup_metadata');
+            alert(`Backup successful! ${res.files ?? 0} files and ${res.folders ?? 0} folders saved.`);
         } catch (e) {
             alert("Backup failed: " + e);
         }
@@ -995,7 +1035,10 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
             });
             if (selected && typeof selected === 'string') {
                 setUpdateStatus("Preparing download...");
-                await invoke('download_all', { targetDir: selected as string });
+                const failed = await invoke<string[]>('download_all', { targetDir: selected as string });
+                if (failed.length > 0) {
+                    alert(`${failed.length} file(s) could not be downloaded:\n` + failed.join("\n"));
+                }
                 setUpdateStatus("Download Completed!");
                 setTimeout(() => setUpdateStatus(null), 3000);
             }
@@ -1114,19 +1157,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                     }
 
                     if (activePaths && activePaths.length > 0) {
-                        const targetFolderId = currentFolderRef.current;
-                        const newItems = activePaths.map(path => ({
-                            path,
-                            name: path.split(/[/\\]/).pop() || 'Unknown File',
-                            status: 'pending' as const,
-                            progress: 0,
-                            targetFolderId: targetFolderId
-                        }));
-                        setUploadQueue(prev => {
-                            // Deduplicate based on path if needed, but for now just appending.
-                            // The issue was multiple listeners.
-                            return [...prev, ...newItems];
-                        });
+                        enqueueUploads(activePaths, currentFolderRef.current);
                     }
                 });
                 if (isMounted) unlistenDrop = uDrop;
@@ -1941,7 +1972,7 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                             </span>
                             {isWebDavRunning && !isWebDavLoading && (
                                 <span className="text-[9px] text-cyan-400 font-mono mt-0.5 select-all">
-                                    {window.navigator.userAgent.includes("Linux") ? "dav://127.0.0.1:17432" : "http://127.0.0.1:17432"}
+                                    {window.navigator.userAgent.includes("Linux") ? "dav" : "http"}://127.0.0.1:17432/{profileId}/
                                 </span>
                             )}
                         </div>
@@ -2593,8 +2624,9 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                                     {isUploadProgressMinimized ? <ChevronUp className="w-4 h-4" /> : <ChevronDown className="w-4 h-4" />}
                                 </button>
                                 <button
-                                    onClick={() => setUploadQueue([])}
+                                    onClick={() => setUploadQueue(prev => prev.filter(i => i.status !== 'completed'))}
                                     className="p-1 hover:bg-white/10 rounded transition-colors text-gray-400 hover:text-white"
+                                    title="Clear finished"
                                 >
                                     <X className="w-4 h-4" />
                                 </button>
@@ -2604,8 +2636,8 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                         {/* List */}
                         {!isUploadProgressMinimized && (
                             <div className="max-h-60 overflow-y-auto bg-black/20 custom-scrollbar">
-                                {uploadQueue.map((item, index) => (
-                                    <div key={index} className="px-4 py-3 border-b border-white/5 flex items-center gap-3 last:border-0 hover:bg-white/5 transition-colors">
+                                {uploadQueue.map((item) => (
+                                    <div key={item.id} className="px-4 py-3 border-b border-white/5 flex items-center gap-3 last:border-0 hover:bg-white/5 transition-colors">
                                         <div className="p-2 bg-cyan-500/10 text-cyan-400 rounded-lg">
                                             <FileIcon className="w-4 h-4" />
                                         </div>
@@ -2628,15 +2660,39 @@ export default function Dashboard({ onLogout }: { onLogout: () => void }) {
                                                     />
                                                 </div>
                                             ) : (
-                                                <p className="text-[10px] text-gray-500 font-mono uppercase">
+                                                <p className="text-[10px] text-gray-500 font-mono uppercase truncate" title={item.error || undefined}>
                                                     {item.status === 'pending' && "Queued"}
+                                                    {item.status === 'paused' && `Paused at ${Math.round(item.progress)}%`}
                                                     {item.status === 'completed' && <span className="text-green-400">Success</span>}
-                                                    {item.status === 'error' && <span className="text-red-400">Failed</span>}
+                                                    {item.status === 'error' && <span className="text-red-400">Failed{item.error ? `: ${item.error}` : ''}</span>}
                                                 </p>
                                             )}
                                         </div>
+                                        {item.status !== 'completed' && (
+                                            <div className="flex items-center gap-0.5">
+                                                {(item.status === 'pending' || item.status === 'paused') && (
+                                                    <button onClick={() => handleUploadToTop(item.id)} className="p-1 hover:bg-white/10 rounded transition-colors text-gray-500 hover:text-white" title="Upload next">
+                                                        <ArrowUpToLine className="w-3 h-3" />
+                                                    </button>
+                                                )}
+                                                {(item.status === 'pending' || item.status === 'uploading') && (
+                                                    <button onClick={() => handlePauseUpload(item.id)} className="p-1 hover:bg-white/10 rounded transition-colors text-gray-500 hover:text-white" title="Pause">
+                                                        <Pause className="w-3 h-3" />
+                                                    </button>
+                                                )}
+                                                {(item.status === 'paused' || item.status === 'error') && (
+                                                    <button onClick={() => handleResumeUpload(item.id)} className="p-1 hover:bg-white/10 rounded transition-colors text-gray-500 hover:text-white" title={item.status === 'error' ? "Retry" : "Resume"}>
+                                                        <Play className="w-3 h-3" />
+                                                    </button>
+                                                )}
+                                                <button onClick={() => handleCancelUpload(item.id)} className="p-1 hover:bg-white/10 rounded transition-colors text-gray-500 hover:text-red-400" title="Cancel">
+                                                    <X className="w-3 h-3" />
+                                                </button>
+                                            </div>
+                                        )}
                                         <div>
                                             {item.status === 'pending' && <div className="w-3 h-3 rounded-full border-2 border-gray-600" />}
+                                            {item.status === 'paused' && <Pause className="w-3 h-3 text-gray-500" />}
                                             {item.status === 'uploading' && <Loader2 className="w-3 h-3 text-cyan-400 animate-spin" />}
                                             {item.status === 'completed' && <CheckCircle className="w-3 h-3 text-green-400" />}
                                             {item.status === 'error' && <AlertCircle className="w-3 h-3 text-red-400" />}
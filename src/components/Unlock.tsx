@@ -0,0 +1,68 @@
+import { useState } from 'react';
+import { motion } from 'framer-motion';
+import { Lock, Loader2 } from 'lucide-react';
+
+interface UnlockProps {
+    onUnlock: (passphrase: string) => Promise<void>;
+}
+
+export default function Unlock({ onUnlock }: UnlockProps) {
+    const [passphrase, setPassphrase] = useState('');
+    const [loading, setLoading] = useState(false);
+    const [error, setError] = useState<string | null>(null);
+
+    const handleSubmit = async (e: React.FormEvent) => {
+        e.preventDefault();
+        setLoading(true);
+        setError(null);
+        try {
+            await onUnlock(passphrase);
+        } catch (err: any) {
+            setError(typeof err === 'string' ? err : "Failed to unlock");
+        } finally {
+            setLoading(false);
+        }
+    };
+
+    return (
+        <div className="h-screen w-full bg-[#030712] text-white flex items-center justify-center font-sans">
+            <div data-tauri-drag-region className="fixed top-0 left-0 right-0 h-10 z-[9999]" />
+
+            <motion.div
+                initial={{ opacity: 0, y: 20 }}
+                animate={{ opacity: 1, y: 0 }}
+                transition={{ duration: 0.6 }}
+                className="w-full max-w-sm px-6"
+            >
+                <div className="flex items-center gap-3 mb-8">
+                    <div className="w-10 h-10 rounded-xl bg-blue-600 flex items-center justify-center shadow-[0_0_20px_rgba(37,99,235,0.5)]">
+                        <Lock className="w-5 h-5" />
+                    </div>
+                    <div>
+                        <h1 className="text-xl font-bold tracking-tight">Paperfold is locked</h1>
+                        <p className="text-sm text-gray-500">Enter your vault passphrase to continue.</p>
+                    </div>
+                </div>
+
+                <form onSubmit={handleSubmit} className="space-y-6">
+                    <input
+                        type="password"
+                        value={passphrase}
+                        onChange={(e) => setPassphrase(e.target.value)}
+                        placeholder="Vault passphrase"
+                        className="w-full h-14 bg-white/5 border border-white/10 rounded-xl px-4 text-lg focus:outline-none focus:border-blue-500/50 focus:bg-white/10 transition-all placeholder:text-gray-600"
+                        autoFocus
+                    />
+                    <motion.button whileHover={{ scale: 1.02 }} whileTap={{ scale: 0.98 }} type="submit" disabled={loading || !passphrase} className="w-full h-14 bg-gradient-to-r from-blue-600 to-blue-500 text-white font-semibold rounded-xl transition-all flex items-center justify-center gap-2 disabled:opacity-50 disabled:cursor-not-allowed shadow-lg">{loading ? <Loader2 className="animate-spin w-5 h-5" /> : "Unlock"}</motion.button>
+                </form>
+
+                {error && (
+                    <motion.div initial={{ opacity: 0, marginTop: 0 }} animate={{ opacity: 1, marginTop: 16 }} className="p-4 bg-red-500/10 border border-red-500/20 rounded-xl flex items-center gap-3 text-red-200 text-sm backdrop-blur-sm">
+                        <div className="w-1.5 h-1.5 rounded-full bg-red-500 shadow-[0_0_10px_red]" />
+                        {error}
+                    </motion.div>
+                )}
+            </motion.div>
+        </div>
+    );
+}
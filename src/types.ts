@@ -1,3 +1,24 @@
+export interface EncryptionInfo {
+    cipher: string;
+    key_id: string;
+    file_salt: string;
+    nonce_prefix: string;
+    chunk_size: number;
+    plain_size: number;
+}
+
+export interface Segment {
+    message_id: number;
+    size: number;
+}
+
+// Another copy of a file's blob, in a different storage chat
+export interface Replica {
+    chat_id?: number | null;
+    message_id: number;
+    segments?: Segment[];
+}
+
 export interface FileMetadata {
     id: string;
     folder_id: string | null;
@@ -5,11 +26,143 @@ export interface FileMetadata {
     size: number;
     mime_type: string;
     message_id: number;
+    chat_id?: number | null; // storage channel; absent for Saved Messages
     created_at: number;
     is_starred?: boolean;
     thumbnail?: string;
     path_display?: string;
+    encryption?: EncryptionInfo | null;
+    segments?: Segment[];
+    sha256?: string;
+    replicas?: Replica[];
+    versions?: FileVersion[]; // Newest first
+
+}
+
+export interface FileVersion {
+    id: string;
+    size: number;
+    mime_type: string;
+    message_id: number;
+    chat_id?: number | null;
+    segments?: Segment[];
+    replicas?: Replica[];
+    encryption?: EncryptionInfo | null;
+    sha256?: string;
+    thumbnail: string | null;
+    created_at: number;
+    replaced_at: number;
+}
+
+export type OrphanAction = 'keep' | 'adopt' | 'delete';
+
+export interface RemoteBlob {
+    message_id: number;
+    name: string;
+    size: number; // 0 for photos, whose size is unknown
+    mime_type: string;
+    caption: string;
+    is_photo: boolean;
+    date: number; // Unix seconds
+}
+
+export interface MissingBlob {
+    file_id: string;
+    version_id: string | null; // Set when only a kept version is affected
+    name: string;
+    size: number;
+    missing_message_ids: number[];
+}
+
+export interface Reconciliation {
+    chat_id: number | null; // null for Saved Messages
+    orphans: RemoteBlob[];
+    orphan_bytes: number;
+    missing: MissingBlob[];
+    missing_bytes: number;
+}
+
+export interface ImportFilter {
+    since?: number | null; // Unix seconds
+    until?: number | null;
+    mime_types?: string[]; // "application/pdf", or "image/" for the whole family
+    has_caption?: boolean;
+}
+
+// Payload of the "import-progress" event
+export interface ImportProgress {
+    scanned: number;
+    imported: number;
+    reached: number | null; // Date of the oldest message reached so far
+    done: boolean;
+}
+
+export interface VersionRetention {
+    keep_versions: number | null; // null keeps every version
+    keep_days: number | null;
+}
+
+export interface BackupInfo {
+    message_id: number;
+    created_at: number; // Unix seconds
+    files: number | null; // null for backups taken before counts were recorded
+    folders: number | null;
+    size: number;
+}
+
+export interface BackupDiffEntry {
+    id: string;
+    name: string;
+    is_folder: boolean;
+}
+
+export interface BackupDiff {
+    added: BackupDiffEntry[]; // only in the backup
+    removed: BackupDiffEntry[]; // only in the current metadata
+    changed: BackupDiffEntry[];
+}
+
+export interface BackupPolicy {
+    interval_hours: number | null; // null turns scheduled backups off
+    keep_backups: number | null;
+    keep_days: number | null;
+}
+
+export interface SyncConflict {
+    id: string;
+    is_folder: boolean;
+    kept_name: string; // the name every device ended up with
+    other_name: string; // the rename that lost
+    detected_at: number; // Unix seconds
+}
+
+export interface SyncReport {
+    received: number; // deltas applied from other devices
+    published: boolean;
+    conflicts: SyncConflict[];
+    skipped: number; // deltas that could not be read
+    pruned: number; // deltas every device has applied, deleted
+}
+
+export interface StorageChannel {
+    id: number;
+    title: string;
+}
+
+export interface StorageTargets {
+    channels: StorageChannel[];
+    upload_to: number | null; // null uploads to Saved Messages
+}
 
+export interface Profile {
+    id: string; // also the WebDAV path of the profile
+    name: string;
+    created_at: number;
+}
+
+export interface Profiles {
+    profiles: Profile[];
+    active: string;
 }
 
 export interface Folder {
@@ -30,5 +183,51 @@ export interface Folder {
     description?: string;
     view_mode?: 'grid' | 'list';
     last_modified?: number;
+    encrypted?: boolean;
+    replicas?: number | null; // copies to keep; absent inherits the parent's
+
+}
+
+// What a move does when the destination already holds an item with the same name
+export type ConflictPolicy = 'fail' | 'auto_rename' | 'overwrite';
+
+export interface PartLog {
+    file_id: number;
+    done: number[];
+    bytes: number;
+}
+
+export interface SegmentUpload {
+    start: number;
+    end: number;
+    parts: PartLog;
+    message_id: number | null;
+}
+
+export interface UploadJob {
+    id: string;
+    path: string;
+    folder_id: string | null;
+    name: string;
+    mime_type: string;
+    size: number;
+    modified: number;
+    encrypt: boolean;
+    priority: number;
+    created_at: number;
+    state: 'queued' | 'running' | 'paused' | 'failed';
+    error: string | null;
+    uploaded: number;
+    total: number;
+    blob_name: string;
+    segments: SegmentUpload[];
+    chat_id: number | null; // chosen when the job first runs
+    encryption: EncryptionInfo | null;
+    sha256: string | null;
+}
 
+export interface DuplicateGroup {
+    kind: 'content' | 'name_and_size' | 'similar_image';
+    files: FileMetadata[]; // Newest first
+    reclaimable: number;
 }